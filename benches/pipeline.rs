@@ -0,0 +1,187 @@
+use clap::ValueEnum;
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use std::hint::black_box;
+use std::io;
+
+use live_long_and_prospero::codegen::x86::{self, X86Config};
+use live_long_and_prospero::corpus;
+use live_long_and_prospero::ir::edit::Editor;
+use live_long_and_prospero::ir::memoize::MemoBuilder;
+use live_long_and_prospero::ir::simplify::Simplify;
+use live_long_and_prospero::ir::tee::Tee;
+use live_long_and_prospero::ir::{self, Insts};
+use live_long_and_prospero::render::{self, TileOrder};
+
+fn workloads() -> Vec<(String, String)> {
+    let mut workloads: Vec<_> = corpus::SHAPES
+        .iter()
+        .map(|shape| (shape.name.to_string(), shape.text.to_string()))
+        .collect();
+    workloads.push(("circles-100".to_string(), corpus::circles(100)));
+    workloads.push(("circles-1000".to_string(), corpus::circles(1000)));
+    if let Some(text) = corpus::prospero() {
+        workloads.push(("prospero".to_string(), text));
+    }
+    workloads
+}
+
+fn read(text: &str) -> Insts {
+    ir::io::read(text.as_bytes(), Insts::default()).unwrap()
+}
+
+fn passes(c: &mut Criterion) {
+    for (name, text) in workloads() {
+        let insts = read(&text);
+
+        let mut group = c.benchmark_group("passes");
+        group.bench_with_input(BenchmarkId::new("parse", &name), &text, |b, text| {
+            b.iter(|| read(text))
+        });
+        group.bench_with_input(BenchmarkId::new("simplify", &name), &text, |b, text| {
+            b.iter(|| ir::io::read(text.as_bytes(), Simplify::new(Insts::default())).unwrap())
+        });
+        group.bench_with_input(
+            BenchmarkId::new("simplify-interned", &name),
+            &text,
+            |b, text| {
+                b.iter(|| {
+                    ir::io::read(text.as_bytes(), Simplify::interned(Insts::default())).unwrap()
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("reassociate", &name),
+            &insts,
+            |b, insts| b.iter(|| ir::reassociate::reassociate(&insts.pool, Insts::default())),
+        );
+        group.bench_with_input(BenchmarkId::new("reorder", &name), &insts, |b, insts| {
+            b.iter_batched_ref(
+                || insts.clone(),
+                ir::reorder::reorder,
+                criterion::BatchSize::LargeInput,
+            )
+        });
+        group.bench_with_input(BenchmarkId::new("memoize", &name), &text, |b, text| {
+            b.iter(|| ir::io::read(text.as_bytes(), MemoBuilder::new()).unwrap())
+        });
+        group.finish();
+    }
+}
+
+// Change one circle's radius halfway through the union, so half of the min
+// tree depends on the edit.
+fn edit(c: &mut Criterion) {
+    let text = corpus::circles(1000);
+    let edit = "r_500 const 0.05";
+    let edited: String = text
+        .lines()
+        .map(|line| {
+            if line.starts_with("r_500 ") {
+                edit
+            } else {
+                line
+            }
+        })
+        .flat_map(|line| [line, "\n"])
+        .collect();
+
+    let mut group = c.benchmark_group("edit");
+    group.bench_function("rebuild", |b| {
+        b.iter(|| {
+            let insts = ir::io::read(edited.as_bytes(), Simplify::new(Insts::default())).unwrap();
+            insts.replay(MemoBuilder::new())
+        })
+    });
+    group.bench_function("edit", |b| {
+        b.iter_batched_ref(
+            || Editor::new(&text).unwrap(),
+            |editor| {
+                editor.edit(edit).unwrap();
+                editor.memoized()
+            },
+            criterion::BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+fn codegen(c: &mut Criterion) {
+    for (name, text) in workloads() {
+        let memoized = ir::io::read(text.as_bytes(), MemoBuilder::new()).unwrap();
+
+        let mut group = c.benchmark_group("codegen");
+        group.bench_with_input(BenchmarkId::new("x86", &name), &memoized, |b, memoized| {
+            b.iter(|| x86::write(io::sink(), X86Config::default(), memoized).unwrap())
+        });
+        group.finish();
+    }
+}
+
+fn interpret(c: &mut Criterion) {
+    const SIZE: u16 = 64;
+    for (name, text) in workloads() {
+        let (insts, memoized) = ir::io::read(
+            text.as_bytes(),
+            Tee::new(Insts::default(), MemoBuilder::new()),
+        )
+        .unwrap();
+
+        let mut group = c.benchmark_group("interpret");
+        group.sample_size(10);
+        group.bench_with_input(BenchmarkId::new("tree", &name), &insts, |b, insts| {
+            b.iter(|| ir::interp::interp_image(insts, black_box(SIZE)))
+        });
+        group.bench_with_input(BenchmarkId::new("bytecode", &name), &insts, |b, insts| {
+            b.iter(|| ir::bytecode::interp_image(insts, black_box(SIZE)))
+        });
+        group.bench_with_input(
+            BenchmarkId::new("memoized", &name),
+            &memoized,
+            |b, memoized| b.iter(|| ir::bytecode::interp_memoized(memoized, black_box(SIZE))),
+        );
+        group.finish();
+    }
+}
+
+// Large renders, where the order tiles are visited in decides how much of
+// the memoized rows and columns, and of the image, stays in cache.
+fn render(c: &mut Criterion) {
+    const SIZE: u16 = 1024;
+    for (name, text) in workloads() {
+        if name != "circles-100" && name != "prospero" {
+            continue;
+        }
+        let memoized = ir::io::read(text.as_bytes(), MemoBuilder::new()).unwrap();
+
+        let mut group = c.benchmark_group("render");
+        group.sample_size(10);
+        for &order in TileOrder::value_variants() {
+            let config = render::Config {
+                tile_size: 16,
+                tile_order: order,
+                ..render::Config::default()
+            };
+            let id = format!("{name}/{}", order.to_possible_value().unwrap().get_name());
+            group.bench_with_input(
+                BenchmarkId::new("memoized", id),
+                &memoized,
+                |b, memoized| b.iter(|| render::render_memoized(memoized, black_box(SIZE), config)),
+            );
+        }
+        group.finish();
+    }
+}
+
+// Packing and writing a large image, which is all the interpreter does per
+// pixel besides evaluating the program.
+fn output(c: &mut Criterion) {
+    const SIZE: u16 = 4096;
+    let insts = read(&corpus::circles(10));
+    let image = ir::bytecode::interp_image(&insts, SIZE);
+    let mut group = c.benchmark_group("output");
+    group.bench_function("pbm", |b| b.iter(|| image.write_pbm(io::sink()).unwrap()));
+    group.finish();
+}
+
+criterion_group!(benches, passes, edit, codegen, interpret, render, output);
+criterion_main!(benches);
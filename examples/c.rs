@@ -0,0 +1,56 @@
+use clap::Parser;
+use live_long_and_prospero::codegen;
+use live_long_and_prospero::ir;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+
+#[derive(Parser)]
+struct Cli {
+    /// Path prefix for the generated files; `.h`, `.c`, a `.json` manifest
+    /// describing the functions, and a `.harness.c` test harness are appended
+    output: PathBuf,
+
+    /// Sort the constant pool by value and each function's outputs by a hash
+    /// of what they compute, so regenerating the files after changing a pass
+    /// only changes what the pass actually changed
+    #[arg(long)]
+    stable: bool,
+
+    /// Read a checkpoint written by `memoize --checkpoint` instead of a
+    /// program, skipping memoization
+    #[arg(long)]
+    memoized: bool,
+}
+
+fn main() -> ir::io::Result<()> {
+    let cli = Cli::parse();
+    let input = std::io::stdin().lock();
+    let mut memoized = if cli.memoized {
+        ir::io::read_checkpoint(input)?
+    } else {
+        ir::io::read(input, ir::memoize::MemoBuilder::new())?
+    };
+    if cli.stable {
+        memoized.canonicalize();
+    }
+
+    let header = cli.output.with_extension("h");
+    let source = cli.output.with_extension("c");
+    codegen::c::write_header(BufWriter::new(File::create(&header)?), &memoized)?;
+    let header_name = header.file_name().unwrap().to_string_lossy();
+    codegen::c::write_source(
+        BufWriter::new(File::create(&source)?),
+        &memoized,
+        &header_name,
+    )?;
+    let manifest = cli.output.with_extension("json");
+    codegen::c::manifest(&memoized).write(BufWriter::new(File::create(&manifest)?))?;
+    let harness = cli.output.with_extension("harness.c");
+    codegen::harness::write(
+        BufWriter::new(File::create(&harness)?),
+        &memoized,
+        &codegen::c::loop_nest(),
+    )?;
+    Ok(())
+}
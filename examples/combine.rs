@@ -0,0 +1,45 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use clap::{Parser, Subcommand};
+use live_long_and_prospero::ir::{self, Insts, combine};
+
+/// Combine programs in Matt Keeter's format into one, simplifying across the
+/// join, and write it to stdout
+#[derive(Parser)]
+struct Cli {
+    #[command(subcommand)]
+    op: Op,
+}
+
+#[derive(Subcommand)]
+enum Op {
+    /// Everything inside either shape
+    Union { a: PathBuf, b: PathBuf },
+    /// Only what's inside both shapes
+    Intersection { a: PathBuf, b: PathBuf },
+    /// What's inside A but not inside B
+    Difference { a: PathBuf, b: PathBuf },
+    /// Grow the shape by DISTANCE, or shrink it if DISTANCE is negative
+    Offset {
+        a: PathBuf,
+        #[arg(allow_negative_numbers = true)]
+        distance: f32,
+    },
+}
+
+fn read(path: &Path) -> ir::io::Result<Insts> {
+    ir::io::read(BufReader::new(File::open(path)?), Insts::default())
+}
+
+fn main() -> ir::io::Result<()> {
+    let insts = match Cli::parse().op {
+        Op::Union { a, b } => combine::union(&read(&a)?, &read(&b)?),
+        Op::Intersection { a, b } => combine::intersection(&read(&a)?, &read(&b)?),
+        Op::Difference { a, b } => combine::difference(&read(&a)?, &read(&b)?),
+        Op::Offset { a, distance } => combine::offset(&read(&a)?, distance),
+    };
+    ir::io::write(std::io::stdout().lock(), insts.pool)?;
+    Ok(())
+}
@@ -0,0 +1,393 @@
+use clap::{Parser, ValueEnum};
+use std::fmt;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "alloc-stats")]
+use live_long_and_prospero::alloc;
+use live_long_and_prospero::codegen::abi::part_name;
+use live_long_and_prospero::codegen::x86::{self, X86Config};
+use live_long_and_prospero::codegen::{harness, traffic};
+use live_long_and_prospero::image::Tolerance;
+use live_long_and_prospero::ir::memoize::MemoBuilder;
+use live_long_and_prospero::ir::reassociate;
+use live_long_and_prospero::ir::simplify::Simplify;
+use live_long_and_prospero::ir::{self, Insts};
+use live_long_and_prospero::render;
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Pass {
+    Simplify,
+    /// `simplify`, with a smaller table for finding duplicate expressions
+    SimplifyInterned,
+    /// `simplify`, also fusing each `min` of a `max` into one `clamp`
+    SimplifyClamps,
+    Reassociate,
+    Reorder,
+    /// Fuse products into the sums that use them, as `fma`
+    Fuse,
+    /// Replace each `powi` with the squares and products it stands for
+    ExpandPowi,
+}
+
+/// A sequence of passes separated by `+`, or `none` for the input as parsed.
+#[derive(Clone, Debug)]
+struct Pipeline(Vec<Pass>);
+
+impl FromStr for Pipeline {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "none" {
+            return Ok(Pipeline(Vec::new()));
+        }
+        s.split('+')
+            .map(|pass| Pass::from_str(pass, true))
+            .collect::<Result<_, _>>()
+            .map(Pipeline)
+    }
+}
+
+impl fmt::Display for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "none");
+        }
+        for (idx, pass) in self.0.iter().enumerate() {
+            if idx > 0 {
+                write!(f, "+")?;
+            }
+            write!(f, "{}", pass.to_possible_value().unwrap().get_name())?;
+        }
+        Ok(())
+    }
+}
+
+impl Pipeline {
+    fn run(
+        &self,
+        mut insts: Insts,
+        reassociate: reassociate::Config,
+        profiles: &mut Vec<(String, Profile)>,
+    ) -> Insts {
+        for pass in self.0.iter() {
+            let name = pass.to_possible_value().unwrap().get_name().to_string();
+            let profile;
+            (insts, profile) = measure(|| match pass {
+                Pass::Simplify => insts.replay(Simplify::new(Insts::default())),
+                Pass::SimplifyInterned => insts.replay(Simplify::interned(Insts::default())),
+                Pass::SimplifyClamps => insts.replay(Simplify::new(Insts::default()).fuse_clamps()),
+                Pass::Reassociate => {
+                    reassociate::reassociate_with(reassociate, &insts.pool, Insts::default())
+                }
+                Pass::Reorder => {
+                    ir::reorder::reorder(&mut insts);
+                    insts
+                }
+                Pass::Fuse => ir::fuse::fuse(&insts.pool, Insts::default()),
+                Pass::ExpandPowi => ir::powi::expand(&insts.pool, Insts::default()),
+            });
+            profiles.push((name, profile));
+        }
+        insts
+    }
+}
+
+/// What it cost to run one pass.
+struct Profile {
+    time: Duration,
+    #[cfg(feature = "alloc-stats")]
+    memory: alloc::Usage,
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:>10}", format!("{:.2?}", self.time))?;
+        #[cfg(feature = "alloc-stats")]
+        write!(
+            f,
+            " {:>12} {:>12} {:>12}",
+            self.memory.peak, self.memory.retained, self.memory.allocations
+        )?;
+        Ok(())
+    }
+}
+
+fn measure<T>(f: impl FnOnce() -> T) -> (T, Profile) {
+    let start = Instant::now();
+    #[cfg(feature = "alloc-stats")]
+    let (result, memory) = alloc::measure(f);
+    #[cfg(not(feature = "alloc-stats"))]
+    let result = f();
+    let profile = Profile {
+        time: start.elapsed(),
+        #[cfg(feature = "alloc-stats")]
+        memory,
+    };
+    (result, profile)
+}
+
+#[cfg(feature = "alloc-stats")]
+#[global_allocator]
+static ALLOC: alloc::Tracking = alloc::Tracking;
+
+#[derive(Parser)]
+struct Cli {
+    /// Input program in Matt Keeter's format
+    input: PathBuf,
+
+    /// Passes to run before memoizing, such as `simplify+reassociate+reorder`;
+    /// repeat to compare several pipelines
+    #[arg(long, required = true)]
+    pipeline: Vec<Pipeline>,
+
+    /// Also assemble each result with a generated test harness and report how
+    /// long it takes to render an image of this size, then check how well the
+    /// cost model predicted those times
+    #[arg(long)]
+    run: Option<u16>,
+
+    /// Also report how long each pass takes, and when built with the
+    /// `alloc-stats` feature, how many bytes it needs at its peak and keeps
+    /// afterward, and how many allocations it makes
+    #[arg(long)]
+    profile: bool,
+
+    /// Also estimate how many bytes each function reads and writes from the
+    /// buffers between functions while rendering an image of this size
+    #[arg(long)]
+    traffic: Option<u16>,
+
+    /// Also render each result and check it against the input as parsed,
+    /// failing unless every pixel is within this tolerance: `exact` bits,
+    /// `ulps:N`, or `sign:MARGIN` for the same sign wherever the input is
+    /// farther than MARGIN from the contour
+    #[arg(long)]
+    check: Option<Tolerance>,
+
+    /// Number of pixels wide/tall to render for --check
+    #[arg(long, default_value_t = 256)]
+    check_size: u16,
+
+    #[command(flatten)]
+    reassociate: reassociate::Config,
+
+    #[command(flatten)]
+    config: X86Config,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+    let (input, profile) = measure(|| ir::io::read_file(&cli.input, Insts::default()));
+    let (input, _) = input?;
+    let mut profiles = vec![("input".to_string(), vec![("parse".to_string(), profile)])];
+    let mut measurements = Vec::new();
+    let mut traffic = Vec::new();
+    let mut checks = Vec::new();
+    let reference = cli
+        .check
+        .map(|_| ir::interp::interp_image(&input, cli.check_size));
+
+    println!(
+        "{:30} {:>5} {:>8} {:>8} {:>6} {:>9} {:>8} {:>10}",
+        "pipeline", "func", "ir", "x86", "slots", "evictions", "cost", "runtime"
+    );
+    for pipeline in cli.pipeline.iter() {
+        let mut passes = Vec::new();
+        let insts = pipeline.run(input.clone(), cli.reassociate, &mut passes);
+        let (memoized, profile) = measure(|| insts.replay(MemoBuilder::new()));
+        passes.push(("memoize".to_string(), profile));
+        profiles.push((pipeline.to_string(), passes));
+        let summaries = x86::summarize(cli.config, &memoized);
+        let runtime = match cli.run {
+            Some(size) => Some(run(cli.config, &memoized, size)?),
+            None => None,
+        };
+
+        let name = pipeline.to_string();
+        if let (Some(tolerance), Some(reference)) = (cli.check, &reference) {
+            let image =
+                render::render_memoized(&memoized, cli.check_size, render::Config::default());
+            checks.push((name.clone(), image.check(reference, tolerance)));
+        }
+        if let Some(size) = cli.traffic {
+            let size = usize::from(size);
+            let nest = x86::loop_nest(cli.config, &memoized)?;
+            traffic.push((
+                name.clone(),
+                traffic::traffic(&memoized, &nest, [size, size, 1]),
+            ));
+        }
+        if let (Some(size), Some(runtime)) = (cli.run, runtime) {
+            let cost = x86::image_cost(cli.config, &memoized, &summaries, size);
+            let pixels = f64::from(size) * f64::from(size);
+            measurements.push((
+                name.clone(),
+                cost as f64 / pixels,
+                runtime.as_secs_f64() * 1e9 / pixels,
+            ));
+        }
+        for summary in summaries.iter().filter(|summary| summary.insts > 0) {
+            println!(
+                "{:30} {:>5} {:>8} {:>8} {:>6} {:>9} {:>8}",
+                name,
+                part_name(summary.vars, summary.part),
+                summary.insts,
+                summary.x86_insts,
+                summary.stack_slots,
+                summary.stats.evictions,
+                summary.cost,
+            );
+        }
+        let total =
+            |field: fn(&x86::FuncSummary) -> usize| -> usize { summaries.iter().map(field).sum() };
+        println!(
+            "{:30} {:>5} {:>8} {:>8} {:>6} {:>9} {:>8} {:>10}",
+            name,
+            "total",
+            total(|summary| summary.insts),
+            total(|summary| summary.x86_insts),
+            total(|summary| summary.stack_slots.into()),
+            total(|summary| summary.stats.evictions.try_into().unwrap()),
+            total(|summary| summary.cost),
+            runtime.map_or("-".to_string(), |runtime| format!("{runtime:.2?}")),
+        );
+    }
+
+    if !measurements.is_empty() {
+        // The model's units are arbitrary, so find the time per unit that
+        // fits the measurements best (by least squares), and see how far
+        // each prediction is off with that scale. With only one pipeline
+        // that fit is exact, so compare several.
+        let dot = |f: fn(&(String, f64, f64)) -> f64| measurements.iter().map(f).sum::<f64>();
+        let ns_per_unit = dot(|m| m.1 * m.2) / dot(|m| m.1 * m.1);
+        println!();
+        println!(
+            "{:30} {:>10} {:>12} {:>12} {:>8}",
+            "pipeline", "cost/px", "predicted", "measured", "error"
+        );
+        for (name, cost, measured) in measurements.iter() {
+            let predicted = cost * ns_per_unit;
+            println!(
+                "{name:30} {cost:>10.2} {:>12} {:>12} {:>+7.1}%",
+                format!("{predicted:.2}ns"),
+                format!("{measured:.2}ns"),
+                (predicted - measured) / measured * 100.0,
+            );
+        }
+        println!("fitted {ns_per_unit:.3}ns per unit of cost");
+    }
+
+    if let Some(size) = cli.traffic {
+        let pixels = f64::from(size) * f64::from(size);
+        println!();
+        println!(
+            "{:30} {:>5} {:>8} {:>8} {:>8} {:>8} {:>10} {:>10}",
+            "pipeline", "func", "calls", "read", "consts", "written", "bytes/px", "footprint"
+        );
+        for (name, funcs) in traffic.iter() {
+            for func in funcs.iter() {
+                println!(
+                    "{:30} {:>5} {:>8} {:>8} {:>8} {:>8} {:>10.2} {:>10}",
+                    name,
+                    part_name(func.vars, 0),
+                    func.calls,
+                    func.read,
+                    func.consts,
+                    func.written,
+                    func.total() as f64 / pixels,
+                    func.footprint,
+                );
+            }
+            let total: usize = funcs.iter().map(|func| func.total()).sum();
+            let footprint: usize = funcs.iter().map(|func| func.footprint).sum();
+            println!(
+                "{:30} {:>5} {:>8} {:>8} {:>8} {:>8} {:>10.2} {:>10}",
+                name,
+                "total",
+                "",
+                "",
+                "",
+                "",
+                total as f64 / pixels,
+                footprint
+            );
+        }
+    }
+
+    if !checks.is_empty() {
+        println!();
+        for (name, check) in checks.iter() {
+            match check {
+                Ok(()) => println!("{name:30} ok"),
+                Err(mismatch) => println!("{name:30} {mismatch}"),
+            }
+        }
+    }
+
+    if cli.profile {
+        println!();
+        print!("{:30} {:>12} {:>10}", "pipeline", "pass", "time");
+        #[cfg(feature = "alloc-stats")]
+        print!(" {:>12} {:>12} {:>12}", "peak", "retained", "allocations");
+        println!();
+        for (name, passes) in profiles.iter() {
+            for (pass, profile) in passes.iter() {
+                println!("{name:30} {pass:>12} {profile}");
+            }
+        }
+    }
+
+    let failed = checks.iter().filter(|(_, check)| check.is_err()).count();
+    if failed > 0 {
+        return Err(format!("{failed} pipelines failed --check").into());
+    }
+    Ok(())
+}
+
+// Assemble and link against a generated test harness in a scratch directory, then
+// time one run of the result with its image output discarded.
+fn run(
+    config: X86Config,
+    memoized: &ir::memoize::Memoized,
+    size: u16,
+) -> Result<Duration, Box<dyn std::error::Error>> {
+    let dir = std::env::temp_dir().join(format!("compare-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let asm = dir.join("out.s");
+    let harness = dir.join("harness.c");
+    let exe = dir.join("out");
+    x86::write(BufWriter::new(File::create(&asm)?), config, memoized)?;
+    harness::write(
+        BufWriter::new(File::create(&harness)?),
+        memoized,
+        &x86::loop_nest(config, memoized)?,
+    )?;
+
+    let status = Command::new("gcc")
+        .args(["-O2", "-o"])
+        .arg(&exe)
+        .arg(harness)
+        .arg(&asm)
+        .arg("-lm")
+        .status()?;
+    if !status.success() {
+        return Err(format!("gcc failed: {status}").into());
+    }
+
+    let start = Instant::now();
+    let status = Command::new(&exe)
+        .arg(size.to_string())
+        .stdout(Stdio::null())
+        .status()?;
+    let elapsed = start.elapsed();
+    std::fs::remove_dir_all(&dir)?;
+    if !status.success() {
+        return Err(format!("{} failed: {status}", exe.display()).into());
+    }
+    Ok(elapsed)
+}
@@ -0,0 +1,64 @@
+use clap::Parser;
+use live_long_and_prospero::codegen::abi::Manifest;
+use live_long_and_prospero::image::{PbmWriter, Tile};
+use live_long_and_prospero::runtime;
+use std::path::PathBuf;
+
+#[derive(Parser)]
+struct Cli {
+    /// Shared object assembled from the output of the `x86` example
+    library: PathBuf,
+
+    /// Manifest written by `x86 --manifest` for the same program
+    manifest: PathBuf,
+
+    /// Number of pixels wide/tall to render
+    #[arg(default_value_t = 512)]
+    size: u16,
+
+    /// Render only this part of the image, given as WIDTHxHEIGHT+LEFT+TOP
+    #[arg(long, value_parser = parse_tile)]
+    tile: Option<Tile>,
+
+    /// Another build of the same program, such as with a different
+    /// `--stride`, and its manifest; the widest one this CPU supports is used
+    #[arg(long, num_args = 2, value_names = ["LIBRARY", "MANIFEST"])]
+    variant: Vec<PathBuf>,
+}
+
+fn parse_tile(s: &str) -> Result<Tile, String> {
+    let parse = || {
+        let (width, rest) = s.split_once('x')?;
+        let (height, rest) = rest.split_once('+')?;
+        let (left, top) = rest.split_once('+')?;
+        Some(Tile {
+            left: left.parse().ok()?,
+            top: top.parse().ok()?,
+            width: width.parse().ok()?,
+            height: height.parse().ok()?,
+        })
+    };
+    parse().ok_or_else(|| format!("expected WIDTHxHEIGHT+LEFT+TOP, not {s:?}"))
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+    let mut variants = Vec::new();
+    let paths = [cli.library, cli.manifest].into_iter().chain(cli.variant);
+    let paths: Vec<PathBuf> = paths.collect();
+    for pair in paths.chunks(2) {
+        let manifest = Manifest::parse(&std::fs::read_to_string(&pair[1])?)?;
+        variants.push((pair[0].clone(), manifest));
+    }
+    // SAFETY: the user promises these libraries came from our x86 backend
+    let library = unsafe { runtime::dlopen_best(&variants)? };
+    let out = std::io::stdout().lock();
+    let tile = cli.tile.unwrap_or(Tile::full(cli.size));
+    if !tile.fits(cli.size) {
+        return Err(format!("tile doesn't fit in a {0}x{0} image", cli.size).into());
+    }
+    // write each band of rows as soon as it's finished
+    let mut pbm = PbmWriter::new(out, tile.width, tile.height.into())?;
+    library.render_rows(cli.size, tile, |row| pbm.write_row(row))?;
+    Ok(pbm.flush()?)
+}
@@ -0,0 +1,11 @@
+use live_long_and_prospero::ir;
+
+fn main() -> ir::io::Result<()> {
+    let (insts, metadata) =
+        ir::io::read_with_metadata(std::io::stdin().lock(), ir::Insts::default())?;
+    let insts = ir::fuse::fuse(&insts.pool, ir::Insts::default());
+    let mut out = std::io::stdout().lock();
+    metadata.write(&mut out)?;
+    ir::io::write(out, insts.pool.iter().cloned())?;
+    Ok(())
+}
@@ -1,12 +1,180 @@
+use clap::{ArgGroup, Parser, ValueEnum};
+use live_long_and_prospero::cancel::CancelToken;
+use live_long_and_prospero::image::{Orientation, PbmWriter};
 use live_long_and_prospero::ir;
+use live_long_and_prospero::ir::interp::Precision;
+use std::io::Write;
+
+#[derive(Parser)]
+#[command(group(ArgGroup::new("probe").args(["explain", "trace"]).multiple(true)))]
+struct Cli {
+    /// Number of pixels wide/tall to render
+    #[arg(default_value_t = 512)]
+    size: u16,
+
+    /// Instead of a black-and-white image, write a 16-bit grayscale image
+    /// where each pixel holds the index of the instruction which won the
+    /// min/max tree at that point
+    #[arg(long)]
+    ids: bool,
+
+    /// Whether the first row of the image is at the top (y-up) or bottom
+    /// (y-down) of the coordinate space
+    #[arg(long, default_value_t = Orientation::default(), value_enum)]
+    orientation: Orientation,
+
+    /// Evaluate an N×N grid of points within each pixel and write a grayscale
+    /// image of how many were inside the shape, to antialias edges
+    #[arg(long, default_value_t = 1)]
+    supersample: u16,
+
+    /// How to evaluate the program
+    #[arg(long, default_value_t = Engine::Tree, value_enum)]
+    engine: Engine,
+
+    /// How the tree engine rounds intermediate values, to check whether a
+    /// difference from compiled output comes from rounding or from which
+    /// operations ran
+    #[arg(long, default_value_t = Precision::default(), value_enum, conflicts_with_all = ["ids", "supersample"])]
+    precision: Precision,
+
+    /// Instead of a black-and-white image, write a color image of the field
+    /// within PIXELS of the contour, where the colors cycle once per pixel of
+    /// distance, so diffing two renders shows even tiny differences in value
+    #[arg(
+        long,
+        value_name = "PIXELS",
+        num_args = 0..=1,
+        default_missing_value = "4",
+        conflicts_with = "ids"
+    )]
+    contour: Option<f32>,
+
+    /// Instead of an image, print the tree of intermediate values feeding
+    /// the result at one pixel of a SIZE×SIZE image, counting columns from
+    /// the left and rows from the first one written
+    #[arg(long, value_name = "COL,ROW", value_parser = parse_pixel)]
+    explain: Option<[u16; 2]>,
+
+    /// Instead of an image, list the instructions which never influenced the
+    /// result at any pixel of a SIZE×SIZE image, following only the operand
+    /// each min and max picked, as candidates for pruning at that resolution
+    #[arg(long, conflicts_with_all = ["ids", "explain"])]
+    influence: bool,
+
+    /// Instead of an image, write a JSON Lines trace of every instruction's
+    /// inputs and output at each of these pixels, counted like --explain's,
+    /// for external tools to visualize; repeat to probe several pixels
+    #[arg(long, value_name = "COL,ROW", value_parser = parse_pixel, conflicts_with_all = ["ids", "explain", "influence"])]
+    trace: Vec<[u16; 2]>,
+
+    /// With --explain or --trace, only follow the operand each min and max
+    /// picked
+    #[arg(long, requires = "probe")]
+    winners: bool,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Engine {
+    /// Match on each instruction at every pixel
+    Tree,
+    /// Compile to bytecode first
+    Bytecode,
+    /// Memoize, then compile each function to bytecode
+    Memoized,
+}
+
+fn parse_pixel(text: &str) -> Result<[u16; 2], String> {
+    let (col, row) = text.split_once(',').ok_or("expected COL,ROW")?;
+    let parse = |n: &str| n.trim().parse::<u16>().map_err(|e| e.to_string());
+    Ok([parse(col)?, parse(row)?])
+}
 
 fn main() -> ir::io::Result<()> {
-    let size = if let Some(arg) = std::env::args().nth(1) {
-        arg.parse().expect("number of pixels wide/tall to render")
-    } else {
-        512
+    let cli = Cli::parse();
+    let input = std::io::stdin().lock();
+    let out = std::io::stdout().lock();
+    if let Some([col, row]) = cli.explain {
+        let insts = ir::io::read(input, ir::Insts::default())?;
+        let point = ir::interp::pixel_point(cli.size, cli.orientation, col, row);
+        let explanation = ir::interp::explain(&insts, &point, cli.winners);
+        explanation.write(out, &insts)?;
+        return Ok(());
+    }
+    if !cli.trace.is_empty() {
+        let insts = ir::io::read(input, ir::Insts::default())?;
+        let mut out = std::io::BufWriter::new(out);
+        for (idx, &[col, row]) in cli.trace.iter().enumerate() {
+            let point = ir::interp::pixel_point(cli.size, cli.orientation, col, row);
+            let explanation = ir::interp::explain(&insts, &point, cli.winners);
+            explanation.write_trace(&mut out, &insts, idx, &point)?;
+        }
+        out.flush()?;
+        return Ok(());
+    }
+    if cli.influence {
+        let insts = ir::io::read(input, ir::Insts::default())?;
+        let influenced = ir::interp::influence(&insts, cli.size);
+        let mut out = std::io::BufWriter::new(out);
+        for (idx, inst) in insts.pool.iter().enumerate() {
+            if !influenced[idx] {
+                ir::io::write_inst(&mut out, idx, inst, Default::default())?;
+                writeln!(out)?;
+            }
+        }
+        let never = influenced.iter().filter(|&&influenced| !influenced).count();
+        eprintln!(
+            "{never} of {} instructions never influenced a pixel at {}×{}",
+            insts.pool.len(),
+            cli.size,
+            cli.size
+        );
+        return Ok(());
+    }
+    if cli.ids {
+        let insts = ir::io::read(input, ir::Insts::default())?;
+        ir::interp::interp_ids(out, &insts, cli.size, cli.orientation)?;
+        return Ok(());
+    }
+    if cli.supersample > 1 {
+        let insts = ir::io::read(input, ir::Insts::default())?;
+        let mut image = ir::interp::interp_coverage(&insts, cli.size, cli.supersample);
+        image.orient(cli.orientation);
+        image.write_pgm(out)?;
+        return Ok(());
+    }
+    let mut image = match cli.engine {
+        Engine::Tree if cli.precision != Precision::F32 => {
+            let insts = ir::io::read(input, ir::Insts::default())?;
+            ir::interp::interp_precision(&insts, cli.size, cli.precision)
+        }
+        Engine::Tree if cli.contour.is_none() => {
+            // write each row as soon as it's finished
+            let insts = ir::io::read(input, ir::Insts::default())?;
+            let mut pbm = PbmWriter::new(out, cli.size, cli.size.into())?;
+            let cancel = CancelToken::default();
+            ir::interp::interp_rows(&insts, cli.size, cli.orientation, &cancel, |row| {
+                pbm.write_row(row).map_err(ir::io::Error::from)
+            })?;
+            return Ok(pbm.flush()?);
+        }
+        Engine::Tree => {
+            let insts = ir::io::read(input, ir::Insts::default())?;
+            ir::interp::interp_image(&insts, cli.size)
+        }
+        Engine::Bytecode => {
+            let insts = ir::io::read(input, ir::Insts::default())?;
+            ir::bytecode::interp_image(&insts, cli.size)
+        }
+        Engine::Memoized => {
+            let memoized = ir::io::read(input, ir::memoize::MemoBuilder::new())?;
+            ir::bytecode::interp_memoized(&memoized, cli.size)
+        }
     };
-    let insts = ir::io::read(std::io::stdin().lock(), ir::Insts::default())?;
-    ir::interp::interp(std::io::stdout().lock(), &insts, size)?;
+    image.orient(cli.orientation);
+    match cli.contour {
+        Some(pixels) => image.write_contour_ppm(out, pixels)?,
+        None => image.write_pbm(out)?,
+    }
     Ok(())
 }
@@ -1,7 +1,41 @@
+use clap::Parser;
 use live_long_and_prospero::ir;
 
+#[derive(Parser)]
+struct Cli {
+    /// How to spell constants in the output
+    #[arg(long, default_value_t = ir::io::ConstFormat::default(), value_enum)]
+    const_format: ir::io::ConstFormat,
+
+    /// Annotate each load with where its value comes from, and each store
+    /// with the loads which read it
+    #[arg(long)]
+    annotate: bool,
+
+    /// Compute values again in each function that uses them, instead of
+    /// storing and loading them, when that takes at most this many
+    /// instructions
+    #[arg(long, default_value_t = 0)]
+    inline_cost: u16,
+
+    /// Write a checkpoint, which `x86 --memoized` and `c --memoized` read
+    /// back exactly, instead of a listing
+    #[arg(long)]
+    checkpoint: bool,
+}
+
 fn main() -> ir::io::Result<()> {
-    let memoized = ir::io::read(std::io::stdin().lock(), ir::memoize::MemoBuilder::new())?;
-    ir::io::write_memoized(std::io::stdout().lock(), &memoized)?;
+    let cli = Cli::parse();
+    let builder = ir::memoize::MemoBuilder::new().with_inline_cost(cli.inline_cost);
+    let (memoized, metadata) = ir::io::read_with_metadata(std::io::stdin().lock(), builder)?;
+    let mut out = std::io::stdout().lock();
+    metadata.write(&mut out)?;
+    if cli.checkpoint {
+        ir::io::write_checkpoint(out, &memoized)?;
+    } else if cli.annotate {
+        ir::io::write_memoized_annotated(out, &memoized, cli.const_format)?;
+    } else {
+        ir::io::write_memoized_with_format(out, &memoized, cli.const_format)?;
+    }
     Ok(())
 }
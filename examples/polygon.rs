@@ -0,0 +1,33 @@
+use std::io::BufRead;
+
+use live_long_and_prospero::ir::{self, InstSink, Var};
+
+/// Read a polygon's corners from stdin, one `x y` pair per line (or separated
+/// by a comma, as SVG writes them), and write a program computing its signed
+/// distance in Matt Keeter's format. Blank lines and lines starting with `#`
+/// are skipped.
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut points = Vec::new();
+    for line in std::io::stdin().lock().lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let coords: Vec<f32> = line
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|s| !s.is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()?;
+        let &[x, y] = coords.as_slice() else {
+            return Err(format!("expected two coordinates, got {line:?}").into());
+        };
+        points.push([x, y]);
+    }
+
+    let mut insts = ir::Insts::default();
+    let vars = [insts.push_var(Var::X), insts.push_var(Var::Y)];
+    ir::polygon::polygon(&mut insts, vars, &points)?;
+    ir::io::write(std::io::stdout().lock(), insts.pool)?;
+    Ok(())
+}
@@ -1,7 +1,83 @@
+use clap::Parser;
 use live_long_and_prospero::ir;
+use std::io::{Read, Write};
+
+#[derive(Parser)]
+struct Cli {
+    /// How to spell constants in the output
+    #[arg(long, default_value_t = ir::io::ConstFormat::default(), value_enum)]
+    const_format: ir::io::ConstFormat,
+
+    /// Skip malformed lines, reporting them on stderr, instead of stopping at
+    /// the first one
+    #[arg(long)]
+    lenient: bool,
+
+    /// Also warn on stderr about each add, sub, or sqrt which interval
+    /// arithmetic over the view says could lose at least BITS bits of
+    /// precision to cancellation
+    #[arg(long, value_name = "BITS", num_args = 0..=1, default_missing_value = "12")]
+    audit: Option<f32>,
+
+    /// Write the program in as few bytes as possible, with short names, the
+    /// shortest spelling of each constant, and no comments or metadata
+    #[arg(long, conflicts_with = "const_format")]
+    minify: bool,
+
+    /// Put instructions in the order `reorder` would, dropping any the
+    /// result doesn't depend on, so programs that differ only in the order
+    /// they were written in come out the same
+    #[arg(long)]
+    canonical: bool,
+
+    /// Report on stderr how many bytes the output takes compared to the input
+    #[arg(long)]
+    stats: bool,
+}
+
+// Cells along each axis of the view for `--audit`; finer cells give tighter
+// intervals, so fewer false alarms, at the cost of time.
+const AUDIT_CELLS: u16 = 32;
 
 fn main() -> ir::io::Result<()> {
-    let insts = ir::io::read(std::io::stdin().lock(), ir::Insts::default())?;
-    ir::io::write(std::io::stdout().lock(), insts.pool.into_iter())?;
+    let cli = Cli::parse();
+    let mut input = Vec::new();
+    std::io::stdin().lock().read_to_end(&mut input)?;
+    let (mut insts, metadata) = if cli.lenient {
+        let (insts, metadata, diagnostics) =
+            ir::io::read_lenient(&input[..], ir::Insts::default())?;
+        for diagnostic in diagnostics {
+            eprintln!("warning: {diagnostic}");
+        }
+        (insts, metadata)
+    } else {
+        ir::io::read_with_metadata(&input[..], ir::Insts::default())?
+    };
+    if let Some(bits) = cli.audit {
+        let view = ir::bounds::BoundingBox::square(1.0);
+        for finding in ir::accuracy::audit(&insts, view, AUDIT_CELLS, bits) {
+            eprintln!("warning: {finding}");
+        }
+    }
+    if cli.canonical {
+        ir::reorder::reorder(&mut insts);
+    }
+    let mut out = Vec::new();
+    if cli.minify {
+        ir::io::write_minified(&mut out, &insts.pool)?;
+    } else {
+        metadata.write(&mut out)?;
+        ir::io::write_with_format(&mut out, insts.pool, cli.const_format)?;
+    }
+    if cli.stats {
+        let saved = input.len() as isize - out.len() as isize;
+        eprintln!(
+            "{} bytes in, {} bytes out, {saved} saved ({:.1}%)",
+            input.len(),
+            out.len(),
+            100.0 * saved as f64 / input.len().max(1) as f64,
+        );
+    }
+    std::io::stdout().lock().write_all(&out)?;
     Ok(())
 }
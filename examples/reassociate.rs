@@ -1,8 +1,19 @@
+use clap::Parser;
 use live_long_and_prospero::ir;
 
+#[derive(Parser)]
+struct Cli {
+    #[command(flatten)]
+    config: ir::reassociate::Config,
+}
+
 fn main() -> ir::io::Result<()> {
-    let insts = ir::io::read(std::io::stdin().lock(), ir::Insts::default())?;
-    let insts = ir::reassociate::reassociate(&insts.pool, ir::Insts::default());
-    ir::io::write(std::io::stdout().lock(), insts.pool.iter().cloned())?;
+    let cli = Cli::parse();
+    let (insts, metadata) =
+        ir::io::read_with_metadata(std::io::stdin().lock(), ir::Insts::default())?;
+    let insts = ir::reassociate::reassociate_with(cli.config, &insts.pool, ir::Insts::default());
+    let mut out = std::io::stdout().lock();
+    metadata.write(&mut out)?;
+    ir::io::write(out, insts.pool.iter().cloned())?;
     Ok(())
 }
@@ -0,0 +1,169 @@
+use std::fs::File;
+use std::io::{BufReader, Write};
+use std::path::PathBuf;
+
+use clap::Parser;
+use live_long_and_prospero::cancel::CancelToken;
+use live_long_and_prospero::image::{Orientation, PbmWriter};
+use live_long_and_prospero::ir::{self, Insts};
+use live_long_and_prospero::render;
+
+// Bisect the search region into a 256×256 grid at most, which is plenty
+// precise for framing a view.
+const FIT_DEPTH: u32 = 8;
+
+#[derive(Parser)]
+struct Cli {
+    /// Number of pixels wide/tall to render
+    #[arg(default_value_t = 512)]
+    size: u16,
+
+    /// Memoize the program first, so values which depend only on x or only on
+    /// y are computed once per column or row instead of at every pixel
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set, value_parser = clap::builder::BoolishValueParser::new())]
+    memoize: bool,
+
+    /// Skip regions which interval arithmetic proves are entirely inside or
+    /// outside the shape, only evaluating pixels near the contour
+    #[arg(long, conflicts_with = "memoize")]
+    adaptive: bool,
+
+    /// Whether the first row of the image is at the top (y-up) or bottom
+    /// (y-down) of the coordinate space
+    #[arg(long, default_value_t = Orientation::default(), value_enum)]
+    orientation: Orientation,
+
+    /// Search the square from -EXTENT to EXTENT for the shape's bounding box,
+    /// and zoom the view to fit it
+    #[arg(long, value_name = "EXTENT", num_args = 0..=1, default_missing_value = "1")]
+    fit: Option<f32>,
+
+    /// Instead of a black-and-white image, write a grayscale image of how
+    /// steep the field is at each pixel, from white where it's flat to black
+    /// where the gradient's magnitude reaches MAX, to spot kinks and precision
+    /// problems that the shape's outline hides
+    #[arg(
+        long,
+        value_name = "MAX",
+        num_args = 0..=1,
+        default_missing_value = "2",
+        conflicts_with = "adaptive"
+    )]
+    gradient: Option<f32>,
+
+    /// Instead of a black-and-white image, write a color image of the field
+    /// within PIXELS of the contour, where the colors cycle once per pixel of
+    /// distance, so diffing two renders shows even tiny differences in value
+    #[arg(
+        long,
+        value_name = "PIXELS",
+        num_args = 0..=1,
+        default_missing_value = "4",
+        conflicts_with_all = ["adaptive", "gradient"]
+    )]
+    contour: Option<f32>,
+
+    /// Write an animation morphing the input into the program in this file,
+    /// as a stream of images, one per frame
+    #[arg(long)]
+    morph: Option<PathBuf>,
+
+    /// How many frames the morph takes, including both ends
+    #[arg(long, default_value_t = 30, requires = "morph")]
+    frames: u16,
+
+    /// Write each row as soon as it's rendered instead of holding the whole
+    /// image in memory, for images too big to fit
+    #[arg(long, conflicts_with_all = ["gradient", "contour", "morph"])]
+    stream: bool,
+
+    #[command(flatten)]
+    config: render::Config,
+}
+
+fn main() -> ir::io::Result<()> {
+    let cli = Cli::parse();
+    let mut insts = ir::io::read(std::io::stdin().lock(), Insts::default())?;
+    let mut target = match &cli.morph {
+        Some(path) => Some(ir::io::read(
+            BufReader::new(File::open(path)?),
+            Insts::default(),
+        )?),
+        None => None,
+    };
+    if let Some(extent) = cli.fit {
+        // frame both ends of a morph the same way
+        let framed = match &target {
+            Some(target) => ir::combine::union(&insts, target),
+            None => insts.clone(),
+        };
+        let region = ir::bounds::BoundingBox::square(extent);
+        match ir::bounds::bounding_box(&framed, region, FIT_DEPTH) {
+            Some(bounds) => {
+                eprintln!("bounding box: x {:?}, y {:?}", bounds.x, bounds.y);
+                insts = ir::bounds::fit(&insts, bounds);
+                target = target.map(|target| ir::bounds::fit(&target, bounds));
+            }
+            None => eprintln!("shape is empty within {extent}; not fitting"),
+        }
+    }
+
+    let mut out = std::io::stdout().lock();
+    match &target {
+        Some(target) => {
+            let last = f32::from(cli.frames.max(2) - 1);
+            for frame in 0..cli.frames {
+                let t = f32::from(frame) / last;
+                let insts = ir::combine::lerp(&insts, target, t);
+                write_frame(&cli, &insts, &mut out)?;
+            }
+        }
+        None => write_frame(&cli, &insts, &mut out)?,
+    }
+    Ok(())
+}
+
+fn write_frame(cli: &Cli, insts: &Insts, out: impl Write) -> ir::io::Result<()> {
+    if cli.stream {
+        return stream(cli, insts, out);
+    }
+    let mut image = if cli.adaptive {
+        render::render_adaptive(insts, cli.size, cli.config)
+    } else if cli.memoize && cli.size >= cli.config.memoize_min_size {
+        let memoized = insts.replay(ir::memoize::MemoBuilder::new());
+        render::render_memoized(&memoized, cli.size, cli.config)
+    } else {
+        render::render(insts, cli.size, cli.config)
+    };
+    if let Some(max) = cli.gradient {
+        image = image.gradient();
+        for pixel in image.pixels.iter_mut() {
+            *pixel /= max;
+        }
+    }
+    image.orient(cli.orientation);
+    if let Some(pixels) = cli.contour {
+        image.write_contour_ppm(out, pixels)?;
+    } else if cli.gradient.is_some() {
+        image.write_pgm(out)?;
+    } else {
+        image.write_pbm(out)?;
+    }
+    Ok(())
+}
+
+fn stream(cli: &Cli, insts: &Insts, out: impl Write) -> ir::io::Result<()> {
+    let mut pbm = PbmWriter::new(out, cli.size, cli.size.into())?;
+    let emit = |row: &[f32]| pbm.write_row(row).map_err(ir::io::Error::from);
+    let (size, orientation, config) = (cli.size, cli.orientation, cli.config);
+    let cancel = CancelToken::default();
+    if cli.adaptive {
+        render::stream_rows_adaptive(insts, size, orientation, config, &cancel, emit)?;
+    } else if cli.memoize && cli.size >= cli.config.memoize_min_size {
+        let memoized = insts.replay(ir::memoize::MemoBuilder::new());
+        render::stream_rows_memoized(&memoized, size, orientation, config, &cancel, emit)?;
+    } else {
+        render::stream_rows(insts, size, orientation, config, &cancel, emit)?;
+    }
+    Ok(pbm.flush()?)
+}
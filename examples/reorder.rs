@@ -1,8 +1,11 @@
 use live_long_and_prospero::ir;
 
 fn main() -> ir::io::Result<()> {
-    let mut insts = ir::io::read(std::io::stdin().lock(), ir::Insts::default())?;
+    let (mut insts, metadata) =
+        ir::io::read_with_metadata(std::io::stdin().lock(), ir::Insts::default())?;
     ir::reorder::reorder(&mut insts);
-    ir::io::write(std::io::stdout().lock(), insts.pool.iter().cloned())?;
+    let mut out = std::io::stdout().lock();
+    metadata.write(&mut out)?;
+    ir::io::write(out, insts.pool.iter().cloned())?;
     Ok(())
 }
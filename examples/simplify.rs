@@ -2,7 +2,14 @@ use live_long_and_prospero::ir;
 
 fn main() -> ir::io::Result<()> {
     let sink = ir::simplify::Simplify::new(ir::Insts::default());
-    let insts = ir::io::read(std::io::stdin().lock(), sink)?;
-    ir::io::write(std::io::stdout().lock(), insts.pool.iter().cloned())?;
+    // a file named on the command line may include others, which this
+    // flattens into one program
+    let (insts, metadata) = match std::env::args_os().nth(1) {
+        Some(path) => ir::io::read_file(path.as_ref(), sink)?,
+        None => ir::io::read_with_metadata(std::io::stdin().lock(), sink)?,
+    };
+    let mut out = std::io::stdout().lock();
+    metadata.write(&mut out)?;
+    ir::io::write(out, insts.pool.iter().cloned())?;
     Ok(())
 }
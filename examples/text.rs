@@ -0,0 +1,38 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use live_long_and_prospero::font;
+use live_long_and_prospero::ir::{self, InstSink, Var};
+
+#[derive(Parser)]
+struct Cli {
+    /// TrueType or OpenType font file to take glyph outlines from
+    font: PathBuf,
+
+    /// Text to draw; `\n` starts a new line
+    text: String,
+
+    /// Which face to use if the file is a font collection
+    #[arg(long, default_value_t = 0)]
+    index: u32,
+
+    /// How many straight edges to replace each curve with
+    #[arg(long, default_value_t = 4)]
+    segments: u16,
+}
+
+/// Write a program in Matt Keeter's format which draws some text, to feed to
+/// the other examples.
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+    let data = std::fs::read(&cli.font)?;
+    let face = ttf_parser::Face::parse(&data, cli.index)?;
+    let text = cli.text.replace("\\n", "\n");
+    let contours = font::outlines(&face, &text, cli.segments);
+
+    let mut insts = ir::Insts::default();
+    let vars = [insts.push_var(Var::X), insts.push_var(Var::Y)];
+    ir::polygon::polygons(&mut insts, vars, &contours)?;
+    ir::io::write(std::io::stdout().lock(), insts.pool)?;
+    Ok(())
+}
@@ -0,0 +1,80 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use clap::Parser;
+use live_long_and_prospero::cancel::CancelToken;
+use live_long_and_prospero::engine::Compiled;
+use live_long_and_prospero::image::{Image, Orientation};
+use live_long_and_prospero::render;
+use live_long_and_prospero::watch::{Error, watch};
+
+#[derive(Parser)]
+struct Cli {
+    /// Program to render again every time it's saved
+    input: PathBuf,
+
+    /// Where to write each image, replacing the previous one all at once so
+    /// viewers never see half of it; without this, every image is written to
+    /// stdout as a stream of PBMs for a viewer to read from a pipe
+    output: Option<PathBuf>,
+
+    /// Number of pixels wide/tall to render
+    #[arg(long, default_value_t = 512)]
+    size: u16,
+
+    /// Whether the first row of the image is at the top (y-up) or bottom
+    /// (y-down) of the coordinate space
+    #[arg(long, default_value_t = Orientation::default(), value_enum)]
+    orientation: Orientation,
+
+    #[command(flatten)]
+    config: render::Config,
+}
+
+fn main() -> Result<(), Error> {
+    let cli = Cli::parse();
+    watch(&cli.input, |text| {
+        let start = Instant::now();
+        // a typo shouldn't end the session, so report it and keep watching
+        match Compiled::new(text) {
+            Ok(compiled) => {
+                let cancel = CancelToken::default();
+                let mut image = compiled.render(cli.size, cli.config, &cancel).unwrap();
+                image.orient(cli.orientation);
+                match write(&cli, &image) {
+                    Ok(()) => eprintln!("rendered in {:?}", start.elapsed()),
+                    Err(e) => {
+                        eprintln!("error: {e}");
+                        return ControlFlow::Break(());
+                    }
+                }
+            }
+            Err(e) => eprintln!("{}: {e}", cli.input.display()),
+        }
+        ControlFlow::Continue(())
+    })
+}
+
+fn write(cli: &Cli, image: &Image) -> std::io::Result<()> {
+    let Some(output) = &cli.output else {
+        let mut out = std::io::stdout().lock();
+        image.write_pbm(&mut out)?;
+        return out.flush();
+    };
+    let tmp = temporary(output);
+    let mut out = BufWriter::new(File::create(&tmp)?);
+    image.write_pbm(&mut out)?;
+    out.flush()?;
+    drop(out);
+    fs::rename(tmp, output)
+}
+
+// Next to the output, so renaming it over the output can't cross filesystems.
+fn temporary(output: &Path) -> PathBuf {
+    let mut name = output.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    output.with_file_name(name)
+}
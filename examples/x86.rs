@@ -12,6 +12,33 @@ struct Cli {
     #[arg(long, default_value_t = true, action = clap::ArgAction::Set, value_parser = clap::builder::BoolishValueParser::new())]
     memoize: bool,
 
+    /// Compute memoized values again in each function that uses them,
+    /// instead of storing and loading them, when that takes at most this many
+    /// instructions
+    #[arg(long, default_value_t = 0)]
+    inline_cost: u16,
+
+    /// Read a checkpoint written by `memoize --checkpoint` instead of a
+    /// program, skipping memoization
+    #[arg(long)]
+    memoized: bool,
+
+    /// Compile each function named in this file with the load-sinking policy
+    /// it gives, and record there the policy --tune-sink-loads picks for any
+    /// other function, so the next run doesn't have to tune them again
+    #[arg(long)]
+    tuning: Option<std::path::PathBuf>,
+
+    /// Also write a JSON description of the generated functions' arguments,
+    /// outputs, and buffer sizes to this file
+    #[arg(long)]
+    manifest: Option<std::path::PathBuf>,
+
+    /// Also write a C test harness which renders an image with the generated
+    /// code to this file
+    #[arg(long)]
+    harness: Option<std::path::PathBuf>,
+
     #[command(flatten)]
     config: codegen::x86::X86Config,
 }
@@ -19,11 +46,34 @@ struct Cli {
 fn main() -> ir::io::Result<()> {
     let cli = Cli::parse();
     let input = std::io::stdin().lock();
-    let memoized = if cli.memoize {
-        ir::io::read(input, ir::memoize::MemoBuilder::default())?
+    let memoized = if cli.memoized {
+        ir::io::read_checkpoint(input)?
+    } else if cli.memoize {
+        let builder = ir::memoize::MemoBuilder::new().with_inline_cost(cli.inline_cost);
+        ir::io::read(input, builder)?
     } else {
         ir::io::read(input, ir::memoize::UnmemoBuilder::default())?
     };
-    codegen::x86::write(std::io::stdout().lock(), cli.config, &memoized)?;
+    if let Some(path) = &cli.tuning {
+        let mut tuning = match std::fs::read_to_string(path) {
+            Ok(text) => codegen::x86::Tuning::parse(&text).map_err(std::io::Error::other)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Default::default(),
+            Err(err) => return Err(err.into()),
+        };
+        let out = std::io::stdout().lock();
+        codegen::x86::write_tuned(out, cli.config, &memoized, &mut tuning)?;
+        tuning.write(std::io::BufWriter::new(std::fs::File::create(path)?))?;
+    } else {
+        codegen::x86::write(std::io::stdout().lock(), cli.config, &memoized)?;
+    }
+    if let Some(path) = cli.manifest {
+        let out = std::io::BufWriter::new(std::fs::File::create(path)?);
+        codegen::x86::manifest(cli.config, &memoized).write(out)?;
+    }
+    if let Some(path) = cli.harness {
+        let out = std::io::BufWriter::new(std::fs::File::create(path)?);
+        let nest = codegen::x86::loop_nest(cli.config, &memoized).map_err(std::io::Error::other)?;
+        codegen::harness::write(out, &memoized, &nest)?;
+    }
     Ok(())
 }
@@ -0,0 +1,67 @@
+/* C interface to live-long-and-prospero, implemented in src/ffi.rs.
+ *
+ * Link against the shared library that `cargo build --release` leaves in
+ * target/release. Every function is safe to call from any thread; a program
+ * may be evaluated or rendered by many threads at once. Strings are UTF-8 and
+ * don't need to be NUL-terminated, since their length is passed separately. */
+#ifndef LIVE_LONG_AND_PROSPERO_H
+#define LIVE_LONG_AND_PROSPERO_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Incremented whenever a function's signature or meaning changes. */
+#define LLP_API_VERSION 1
+
+typedef struct llp_context llp_context;
+typedef struct llp_program llp_program;
+
+/* The signature of llp_eval, for callers which store it as a callback. */
+typedef float (*llp_point_fn)(const llp_program *program, float x, float y, float z);
+
+uint32_t llp_api_version(void);
+
+/* A message describing the last failure on this thread, or NULL. It stays
+ * valid until the next call into this library from the same thread. A bug
+ * inside the library is reported the same way, as an "internal error", and
+ * makes the function that hit it fail as described below. */
+const char *llp_last_error(void);
+
+/* A context caches compiled programs by their text. `threads` is how many
+ * threads llp_render uses, or 0 for one per CPU. Returns NULL on failure. */
+llp_context *llp_context_new(size_t threads);
+void llp_context_free(llp_context *context);
+
+/* Parse, optimize, and compile program text in Matt Keeter's format. Returns
+ * NULL if it doesn't parse. Free the result with llp_program_free, which may
+ * happen before or after the context is freed. */
+llp_program *llp_compile(llp_context *context, const char *text, size_t len);
+void llp_program_free(llp_program *program);
+
+/* The program's value at one point, or at `count` points whose coordinates
+ * are in `xs`, `ys`, and `zs`. On failure, including a NULL pointer when
+ * `count` isn't 0, the value is NaN, or every value in `out` is. */
+float llp_eval(const llp_program *program, float x, float y, float z);
+void llp_eval_batch(const llp_program *program, const float *xs, const float *ys,
+                    const float *zs, float *out, size_t count);
+
+/* Fill `out`, which must hold size * size floats, with the program's value at
+ * every pixel of an image covering -1 to 1 on both axes, top row first.
+ * Returns 0 on success, or nonzero on failure, including a `size` under 2. */
+int llp_render(const llp_context *context, const llp_program *program, uint16_t size,
+               float *out);
+
+/* The simplified and reassociated program as text, or NULL if it doesn't
+ * parse. Free the result with llp_string_free. */
+char *llp_optimize(const char *text, size_t len);
+void llp_string_free(char *string);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,91 @@
+//! Compile a shape while building the crate that uses it, so the binary
+//! carries neither the parser nor the optimizer and pays nothing at startup.
+//!
+//! This can't be re-exported from `live-long-and-prospero` behind a feature:
+//! the macro has to run the parser and optimizer, so it depends on that
+//! crate, and Cargo rejects the cycle even through an optional dependency.
+//! Depend on this crate directly instead.
+
+use std::path::PathBuf;
+
+use live_long_and_prospero::codegen::rust::{write_fn, write_program};
+use live_long_and_prospero::engine::optimize;
+use live_long_and_prospero::ir::bytecode::Program;
+use proc_macro::{Delimiter, Group, Literal, Span, TokenStream, TokenTree};
+
+/// `prospero_include!("shape.vm")` parses and optimizes the shape at
+/// build time and expands to an `fn(f32, f32, f32) -> f32` of x, y, and z,
+/// written out as plain Rust which needs nothing at run time.
+///
+/// `prospero_include!("shape.vm", bytecode)` expands to the optimized
+/// [`Program`] instead, which is smaller for large shapes and compiles much
+/// faster, but needs `live-long-and-prospero` (without its default `std`
+/// feature is enough) to run it.
+///
+/// Paths are relative to the directory holding the calling crate's
+/// `Cargo.toml`, like `include_str!` paths are relative to the file.
+#[proc_macro]
+pub fn prospero_include(input: TokenStream) -> TokenStream {
+    match expand(input) {
+        Ok(tokens) => tokens,
+        Err((span, msg)) => compile_error(span, &msg),
+    }
+}
+
+fn expand(input: TokenStream) -> Result<TokenStream, (Span, String)> {
+    let mut tokens = input.into_iter();
+    let Some(TokenTree::Literal(lit)) = tokens.next() else {
+        return Err((Span::call_site(), "expected a string literal path".into()));
+    };
+    let span = lit.span();
+    let path = unquote(&lit.to_string()).ok_or((span, "expected a string literal path".into()))?;
+    let bytecode = match (tokens.next(), tokens.next(), tokens.next()) {
+        (None, ..) => false,
+        (Some(TokenTree::Punct(p)), Some(TokenTree::Ident(id)), None)
+            if p.as_char() == ',' && id.to_string() == "bytecode" =>
+        {
+            true
+        }
+        (Some(TokenTree::Punct(p)), None, None) if p.as_char() == ',' => false,
+        _ => return Err((span, "expected `\"path\"` or `\"path\", bytecode`".into())),
+    };
+
+    let dir = std::env::var_os("CARGO_MANIFEST_DIR").unwrap_or_default();
+    let path = PathBuf::from(dir).join(path);
+    let error = |e: &dyn std::fmt::Display| (span, format!("{}: {e}", path.display()));
+    let text = std::fs::read_to_string(&path).map_err(|e| error(&e))?;
+    let insts = optimize(&text).map_err(|e| error(&e))?;
+
+    let mut out = Vec::new();
+    if bytecode {
+        write_program(&mut out, &Program::compile(&insts))
+    } else {
+        write_fn(&mut out, &insts)
+    }
+    .map_err(|e| error(&e))?;
+    let expr = String::from_utf8(out).unwrap();
+
+    // Mention the file with `include_str!` too, which is how Cargo learns
+    // to rebuild the caller when the shape changes.
+    let path = Literal::string(&path.to_string_lossy());
+    let code = format!("{{ const _: &str = include_str!({path}); {expr} }}");
+    Ok(code.parse().unwrap())
+}
+
+// Only plain and raw string literals without escapes are accepted, which
+// covers any reasonable path.
+fn unquote(lit: &str) -> Option<String> {
+    let raw = lit.strip_prefix('r').map(|s| s.trim_matches('#'));
+    let body = raw.unwrap_or(lit).strip_prefix('"')?.strip_suffix('"')?;
+    (raw.is_some() || !body.contains('\\')).then(|| body.to_string())
+}
+
+fn compile_error(span: Span, msg: &str) -> TokenStream {
+    let mut msg = Literal::string(msg);
+    msg.set_span(span);
+    let mut group = Group::new(Delimiter::Parenthesis, TokenTree::from(msg).into());
+    group.set_span(span);
+    let mut tokens: TokenStream = "::core::compile_error!".parse().unwrap();
+    tokens.extend([TokenTree::from(group)]);
+    tokens
+}
@@ -0,0 +1,18 @@
+use live_long_and_prospero::compile;
+use live_long_and_prospero_macros::prospero_include;
+
+#[test]
+fn test_include() {
+    let shape = prospero_include!("../corpus/circle.vm");
+    let program = prospero_include!("../corpus/circle.vm", bytecode);
+    let expected = compile(include_str!("../../corpus/circle.vm")).unwrap();
+    let mut regs = program.registers();
+    for [x, y] in [[0.0, 0.0], [0.25, -0.5], [1.0, 1.0], [-0.3, 0.4]] {
+        let want = expected(x, y, 0.0);
+        assert_eq!(shape(x, y, 0.0).to_bits(), want.to_bits());
+        assert_eq!(
+            program.eval(&mut regs, &[x, y, 0.0]).to_bits(),
+            want.to_bits()
+        );
+    }
+}
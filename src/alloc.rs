@@ -0,0 +1,91 @@
+//! A global allocator which counts what it hands out, for finding out how
+//! much memory each pass needs. It only takes effect in programs which
+//! install it with `#[global_allocator]`, as the `compare` example does when
+//! built with the `alloc-stats` feature. The counters are shared by every
+//! thread, so measurements taken while other threads are allocating include
+//! their allocations too.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// The system allocator, plus bookkeeping for [`measure`].
+pub struct Tracking;
+
+impl Tracking {
+    fn grow(size: usize) {
+        let current = CURRENT.fetch_add(size, Relaxed) + size;
+        PEAK.fetch_max(current, Relaxed);
+        ALLOCATIONS.fetch_add(1, Relaxed);
+    }
+
+    fn shrink(size: usize) {
+        CURRENT.fetch_sub(size, Relaxed);
+    }
+}
+
+unsafe impl GlobalAlloc for Tracking {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc(layout) };
+        if !ptr.is_null() {
+            Tracking::grow(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc_zeroed(layout) };
+        if !ptr.is_null() {
+            Tracking::grow(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) };
+        Tracking::shrink(layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = unsafe { System.realloc(ptr, layout, new_size) };
+        if !new.is_null() {
+            // count the whole new block before freeing the old one, since
+            // that's the worst case for a copying reallocation
+            Tracking::grow(new_size);
+            Tracking::shrink(layout.size());
+        }
+        new
+    }
+}
+
+/// How much memory a piece of code allocated, as seen by [`Tracking`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Usage {
+    /// The most memory in use at once while it ran, beyond what was already
+    /// allocated when it started.
+    pub peak: usize,
+    /// Memory it allocated and didn't free, such as its result.
+    pub retained: usize,
+    /// How many times it called the allocator, including reallocations.
+    pub allocations: usize,
+}
+
+/// Run `f` and report how much memory it used. Measurements can be nested:
+/// the outer one still sees the peak of the inner one. Without [`Tracking`]
+/// installed as the global allocator, every count is zero.
+pub fn measure<T>(f: impl FnOnce() -> T) -> (T, Usage) {
+    let start = CURRENT.load(Relaxed);
+    let outer_peak = PEAK.swap(start, Relaxed);
+    let allocations = ALLOCATIONS.load(Relaxed);
+    let result = f();
+    let peak = PEAK.fetch_max(outer_peak, Relaxed);
+    let usage = Usage {
+        peak: peak.saturating_sub(start),
+        retained: CURRENT.load(Relaxed).saturating_sub(start),
+        allocations: ALLOCATIONS.load(Relaxed) - allocations,
+    };
+    (result, usage)
+}
@@ -0,0 +1,283 @@
+use std::io;
+use std::str::FromStr;
+use thiserror::Error;
+
+use crate::ir::memoize::{Memoized, MemoizedFunc};
+use crate::ir::{Var, VarSet};
+
+/// Version of the contract between generated code and its caller, which both
+/// backends export as the `abi_version` symbol. Bump this whenever the
+/// argument order, the meaning of `stride`, or the layout of the memory
+/// spaces changes. The generated harness checks it before running anything.
+pub const ABI_VERSION: u32 = 5;
+
+/// The symbol for one function in the chain that a memoized function was
+/// split into: the first keeps the function's own name.
+pub fn part_name(vars: VarSet, part: usize) -> String {
+    if part == 0 {
+        format!("{vars:?}")
+    } else {
+        format!("{vars:?}_part{part}")
+    }
+}
+
+/// The memory spaces `func` takes pointers to, in argument order: every
+/// function's space up to and including its own, which is where it stores its
+/// outputs. Location 0 of the `x` and `y` spaces holds the input coordinate.
+pub fn params<'a>(
+    memoized: &'a Memoized,
+    func: &MemoizedFunc,
+) -> impl Iterator<Item = &'a MemoizedFunc> {
+    memoized.funcs.iter().take(func.vars.idx())
+}
+
+/// A description of the generated functions, for tools which need to call
+/// them without parsing the assembly. It's stored as JSON alongside the
+/// generated code.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Manifest {
+    pub abi_version: u32,
+    pub stride: u16,
+    /// Whether `xy` stores one bit per point rather than one float; see
+    /// [`X86Config::pack_bits`](super::x86::X86Config::pack_bits).
+    pub xy_packed: bool,
+    /// Which variable varies across the lanes of a vector; see
+    /// [`X86Config::vector_axis`](super::x86::X86Config::vector_axis).
+    pub vector_axis: Var,
+    pub consts: usize,
+    pub functions: Vec<FunctionManifest>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FunctionManifest {
+    /// The function's symbol; its output count is in `{name}_size`.
+    pub name: String,
+    /// Number of locations in the function's memory space, including any
+    /// scratch space its parts use.
+    pub outputs: usize,
+    /// How many functions to call in order, named as by [`part_name`].
+    pub parts: usize,
+    pub insts: usize,
+    pub params: Vec<Param>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Param {
+    pub space: String,
+    /// Where the pointer is passed, if the backend has a fixed assignment:
+    /// a register, or a stack slot addressed as it is on entry.
+    pub register: Option<String>,
+}
+
+#[derive(Debug, Error)]
+pub enum ManifestError {
+    #[error("manifest line {0}: expected `\"key\": value`")]
+    Syntax(usize),
+    #[error("manifest line {0}: invalid value")]
+    Value(usize),
+    #[error("manifest line {0}: {1:?} appears outside any function")]
+    Orphan(usize, String),
+    #[error("manifest is missing {0:?}")]
+    Missing(&'static str),
+}
+
+impl Manifest {
+    pub fn new(
+        memoized: &Memoized,
+        stride: u16,
+        xy_packed: bool,
+        vector_axis: Var,
+        registers: Option<&[&str]>,
+    ) -> Manifest {
+        let functions = memoized
+            .funcs
+            .iter()
+            .map(|func| FunctionManifest {
+                name: format!("{:?}", func.vars),
+                outputs: func.outputs.len(),
+                parts: 1,
+                insts: func.insts.len(),
+                params: params(memoized, func)
+                    .enumerate()
+                    .map(|(arg, space)| Param {
+                        space: format!("{:?}", space.vars),
+                        register: registers.map(|registers| registers[arg].to_string()),
+                    })
+                    .collect(),
+            })
+            .collect();
+        Manifest {
+            abi_version: ABI_VERSION,
+            stride,
+            xy_packed,
+            vector_axis,
+            consts: memoized.consts.len(),
+            functions,
+        }
+    }
+
+    pub fn function(&self, name: &str) -> Option<&FunctionManifest> {
+        self.functions.iter().find(|func| func.name == name)
+    }
+
+    pub fn write(&self, mut out: impl io::Write) -> io::Result<()> {
+        writeln!(out, "{{")?;
+        writeln!(out, "  \"abi_version\": {},", self.abi_version)?;
+        writeln!(out, "  \"stride\": {},", self.stride)?;
+        writeln!(out, "  \"xy_packed\": {},", self.xy_packed)?;
+        writeln!(out, "  \"vector_axis\": \"{}\",", self.vector_axis.name())?;
+        writeln!(out, "  \"consts\": {},", self.consts)?;
+        writeln!(out, "  \"functions\": [")?;
+        for (idx, func) in self.functions.iter().enumerate() {
+            writeln!(out, "    {{")?;
+            writeln!(out, "      \"name\": \"{}\",", func.name)?;
+            writeln!(out, "      \"size_symbol\": \"{}_size\",", func.name)?;
+            writeln!(out, "      \"outputs\": {},", func.outputs)?;
+            writeln!(out, "      \"parts\": {},", func.parts)?;
+            writeln!(out, "      \"insts\": {},", func.insts)?;
+            write!(out, "      \"params\": [")?;
+            for (arg, param) in func.params.iter().enumerate() {
+                if arg > 0 {
+                    write!(out, ", ")?;
+                }
+                write!(out, "{{ \"space\": \"{}\"", param.space)?;
+                if let Some(register) = &param.register {
+                    write!(out, ", \"register\": \"{register}\"")?;
+                }
+                write!(out, " }}")?;
+            }
+            writeln!(out, "]")?;
+            let comma = if idx + 1 < self.functions.len() {
+                ","
+            } else {
+                ""
+            };
+            writeln!(out, "    }}{comma}")?;
+        }
+        writeln!(out, "  ]")?;
+        writeln!(out, "}}")
+    }
+
+    /// Read back a manifest in the layout that [`Manifest::write`] produces.
+    /// This isn't a general JSON parser: it expects one key per line, and
+    /// each function's parameters on a single line.
+    pub fn parse(text: &str) -> Result<Manifest, ManifestError> {
+        let mut abi_version = None;
+        let mut stride = None;
+        let mut xy_packed = None;
+        let mut vector_axis = None;
+        let mut consts = None;
+        let mut functions: Vec<FunctionManifest> = Vec::new();
+
+        for (line_number, line) in text.lines().enumerate() {
+            let line_number = line_number + 1;
+            let line = line.trim().trim_end_matches(',');
+            if matches!(line, "{" | "}" | "]" | "") {
+                continue;
+            }
+            let (key, value) = line
+                .strip_prefix('"')
+                .and_then(|line| line.split_once("\": "))
+                .ok_or(ManifestError::Syntax(line_number))?;
+            let string = || value.trim_matches('"').to_string();
+            let orphan = || ManifestError::Orphan(line_number, key.to_string());
+            match key {
+                "abi_version" => abi_version = Some(scalar(value, line_number)?),
+                "stride" => stride = Some(scalar(value, line_number)?),
+                "xy_packed" => xy_packed = Some(scalar(value, line_number)?),
+                "vector_axis" => {
+                    let var = VarSet::ALL
+                        .into_iter()
+                        .find(|var| string() == var.name().to_string());
+                    vector_axis = Some(var.ok_or(ManifestError::Value(line_number))?)
+                }
+                "consts" => consts = Some(scalar(value, line_number)?),
+                "functions" | "size_symbol" => {}
+                "name" => functions.push(FunctionManifest {
+                    name: string(),
+                    outputs: 0,
+                    parts: 1,
+                    insts: 0,
+                    params: Vec::new(),
+                }),
+                "outputs" => {
+                    functions.last_mut().ok_or_else(orphan)?.outputs = scalar(value, line_number)?
+                }
+                "parts" => {
+                    functions.last_mut().ok_or_else(orphan)?.parts = scalar(value, line_number)?
+                }
+                "insts" => {
+                    functions.last_mut().ok_or_else(orphan)?.insts = scalar(value, line_number)?
+                }
+                "params" => {
+                    functions.last_mut().ok_or_else(orphan)?.params =
+                        parse_params(value, line_number)?
+                }
+                _ => return Err(ManifestError::Syntax(line_number)),
+            }
+        }
+
+        Ok(Manifest {
+            abi_version: abi_version.ok_or(ManifestError::Missing("abi_version"))?,
+            stride: stride.ok_or(ManifestError::Missing("stride"))?,
+            xy_packed: xy_packed.ok_or(ManifestError::Missing("xy_packed"))?,
+            vector_axis: vector_axis.ok_or(ManifestError::Missing("vector_axis"))?,
+            consts: consts.ok_or(ManifestError::Missing("consts"))?,
+            functions,
+        })
+    }
+}
+
+fn scalar<T: FromStr>(value: &str, line_number: usize) -> Result<T, ManifestError> {
+    value.parse().map_err(|_| ManifestError::Value(line_number))
+}
+
+fn parse_params(value: &str, line_number: usize) -> Result<Vec<Param>, ManifestError> {
+    let inner = value
+        .strip_prefix('[')
+        .and_then(|value| value.strip_suffix(']'))
+        .ok_or(ManifestError::Syntax(line_number))?;
+    inner
+        .split('}')
+        .map(|param| param.trim_start_matches([',', ' ', '{']).trim())
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let mut space = None;
+            let mut register = None;
+            for field in param.split(", ") {
+                let (key, value) = field
+                    .split_once(": ")
+                    .ok_or(ManifestError::Syntax(line_number))?;
+                let value = value.trim_matches('"').to_string();
+                match key.trim_matches('"') {
+                    "space" => space = Some(value),
+                    "register" => register = Some(value),
+                    _ => return Err(ManifestError::Syntax(line_number)),
+                }
+            }
+            Ok(Param {
+                space: space.ok_or(ManifestError::Syntax(line_number))?,
+                register,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corpus;
+    use crate::ir::memoize::MemoBuilder;
+
+    #[test]
+    fn test_manifest_round_trip() {
+        let memoized = corpus::shape("fenced").unwrap().read(MemoBuilder::new());
+        for registers in [None, Some(&["a", "b", "c", "d", "e", "f", "g"][..])] {
+            let manifest = Manifest::new(&memoized, 4, registers.is_some(), Var::Z, registers);
+            let mut text = Vec::new();
+            manifest.write(&mut text).unwrap();
+            let parsed = Manifest::parse(std::str::from_utf8(&text).unwrap()).unwrap();
+            assert_eq!(parsed, manifest);
+        }
+    }
+}
@@ -0,0 +1,300 @@
+use std::io;
+
+use crate::ir::io::ConstFormat;
+use crate::ir::memoize::{Memoized, MemoizedFunc};
+use crate::ir::{BinOp, Inst, TernOp, UnOp, Var, VarSet};
+
+use super::abi::{ABI_VERSION, Manifest, params};
+use super::loops::LoopNest;
+
+// Opcodes for the bytecode interpreter in `INTERPRETER`. Each instruction is
+// an opcode followed by its operands, all as 16-bit words. Every instruction
+// except `store` and `end` writes its result to the next register in order,
+// so register numbers match instruction indexes in the memoized function.
+const OP_LOAD: u16 = 0;
+const OP_NEG: u16 = 1;
+const OP_SQUARE: u16 = 2;
+const OP_SQRT: u16 = 3;
+const OP_ADD: u16 = 4;
+const OP_SUB: u16 = 5;
+const OP_MUL: u16 = 6;
+const OP_MIN: u16 = 7;
+const OP_MAX: u16 = 8;
+const OP_STORE: u16 = 9;
+const OP_END: u16 = 10;
+const OP_ABS: u16 = 11;
+const OP_FMA: u16 = 12;
+const OP_EXP: u16 = 13;
+const OP_LN: u16 = 14;
+const OP_SIN: u16 = 15;
+const OP_COS: u16 = 16;
+const OP_FLOOR: u16 = 17;
+const OP_CEIL: u16 = 18;
+const OP_ROUND: u16 = 19;
+const OP_MOD: u16 = 20;
+const OP_RECIP: u16 = 21;
+const OP_RSQRT: u16 = 22;
+const OP_CLAMP: u16 = 23;
+const OP_LERP: u16 = 24;
+
+const OPCODES: [(&str, u16); 25] = [
+    ("OP_LOAD", OP_LOAD),
+    ("OP_NEG", OP_NEG),
+    ("OP_SQUARE", OP_SQUARE),
+    ("OP_SQRT", OP_SQRT),
+    ("OP_ADD", OP_ADD),
+    ("OP_SUB", OP_SUB),
+    ("OP_MUL", OP_MUL),
+    ("OP_MIN", OP_MIN),
+    ("OP_MAX", OP_MAX),
+    ("OP_STORE", OP_STORE),
+    ("OP_END", OP_END),
+    ("OP_ABS", OP_ABS),
+    ("OP_FMA", OP_FMA),
+    ("OP_EXP", OP_EXP),
+    ("OP_LN", OP_LN),
+    ("OP_SIN", OP_SIN),
+    ("OP_COS", OP_COS),
+    ("OP_FLOOR", OP_FLOOR),
+    ("OP_CEIL", OP_CEIL),
+    ("OP_ROUND", OP_ROUND),
+    ("OP_MOD", OP_MOD),
+    ("OP_RECIP", OP_RECIP),
+    ("OP_RSQRT", OP_RSQRT),
+    ("OP_CLAMP", OP_CLAMP),
+    ("OP_LERP", OP_LERP),
+];
+
+const INTERPRETER: &str = "\
+static void run(const uint16_t *code, float *const *spaces) {
+  float *dst = regs;
+  for(;;) {
+    switch(*code++) {
+    case OP_LOAD: *dst++ = spaces[code[0]][code[1]]; code += 2; break;
+    case OP_NEG: *dst++ = -regs[code[0]]; code += 1; break;
+    case OP_SQUARE: *dst++ = regs[code[0]] * regs[code[0]]; code += 1; break;
+    case OP_SQRT: *dst++ = sqrtf(regs[code[0]]); code += 1; break;
+    case OP_ABS: *dst++ = fabsf(regs[code[0]]); code += 1; break;
+    case OP_EXP: *dst++ = expf(regs[code[0]]); code += 1; break;
+    case OP_LN: *dst++ = logf(regs[code[0]]); code += 1; break;
+    case OP_SIN: *dst++ = sinf(regs[code[0]]); code += 1; break;
+    case OP_COS: *dst++ = cosf(regs[code[0]]); code += 1; break;
+    case OP_FLOOR: *dst++ = floorf(regs[code[0]]); code += 1; break;
+    case OP_CEIL: *dst++ = ceilf(regs[code[0]]); code += 1; break;
+    case OP_ROUND: *dst++ = rintf(regs[code[0]]); code += 1; break;
+    case OP_RECIP: *dst++ = 1.0f / regs[code[0]]; code += 1; break;
+    case OP_RSQRT: *dst++ = 1.0f / sqrtf(regs[code[0]]); code += 1; break;
+    case OP_ADD: *dst++ = regs[code[0]] + regs[code[1]]; code += 2; break;
+    case OP_SUB: *dst++ = regs[code[0]] - regs[code[1]]; code += 2; break;
+    case OP_MUL: *dst++ = regs[code[0]] * regs[code[1]]; code += 2; break;
+    case OP_MIN: *dst++ = fminf(regs[code[0]], regs[code[1]]); code += 2; break;
+    case OP_MAX: *dst++ = fmaxf(regs[code[0]], regs[code[1]]); code += 2; break;
+    case OP_MOD: *dst++ = regs[code[0]] - regs[code[1]] * floorf(regs[code[0]] / regs[code[1]]); code += 2; break;
+    case OP_FMA: *dst++ = fmaf(regs[code[0]], regs[code[1]], regs[code[2]]); code += 3; break;
+    case OP_CLAMP: *dst++ = fminf(fmaxf(regs[code[0]], regs[code[1]]), regs[code[2]]); code += 3; break;
+    case OP_LERP: *dst++ = regs[code[0]] + (regs[code[1]] - regs[code[0]]) * regs[code[2]]; code += 3; break;
+    case OP_STORE: spaces[code[0]][code[1]] = regs[code[2]]; code += 3; break;
+    default: return;
+    }
+  }
+}
+";
+
+/// Write a C header declaring the functions and sizes which [`write_source`]
+/// defines. The functions have the same signatures as the ones generated by
+/// the x86 backend, so they can be driven by the harness that
+/// [`harness::write`](super::harness::write) generates from [`loop_nest`].
+pub fn write_header(mut out: impl io::Write, memoized: &Memoized) -> io::Result<()> {
+    writeln!(out, "#pragma once")?;
+    writeln!(out, "#include <stdint.h>")?;
+    writeln!(out)?;
+    writeln!(out, "extern const uint16_t stride;")?;
+    writeln!(out, "extern const uint32_t abi_version;")?;
+    writeln!(out, "extern const uint8_t xy_packed;")?;
+    writeln!(out, "extern const uint8_t vector_axis;")?;
+    for func in memoized.funcs.iter() {
+        writeln!(out, "extern const uint16_t {:?}_size;", func.vars)?;
+    }
+    writeln!(out)?;
+    for func in memoized.funcs.iter() {
+        write_signature(&mut out, memoized, func)?;
+        writeln!(out, ";")?;
+    }
+    Ok(())
+}
+
+/// Write a portable C implementation of the memoized program, consisting of
+/// the constant pool, a bytecode array for each function, and a small
+/// interpreter for that bytecode. Everything except the scratch registers is
+/// `const`, so it can live in flash on microcontrollers. The functions share
+/// those registers, so they are not reentrant.
+pub fn write_source(mut out: impl io::Write, memoized: &Memoized, header: &str) -> io::Result<()> {
+    writeln!(out, "#include <math.h>")?;
+    writeln!(out, "#include \"{header}\"")?;
+    writeln!(out)?;
+    writeln!(out, "const uint16_t stride = 1;")?;
+    writeln!(out, "const uint32_t abi_version = {ABI_VERSION};")?;
+    writeln!(out, "const uint8_t xy_packed = 0;")?;
+    writeln!(out, "const uint8_t vector_axis = {};", Var::X as u8)?;
+    for func in memoized.funcs.iter() {
+        writeln!(
+            out,
+            "const uint16_t {:?}_size = {};",
+            func.vars,
+            func.outputs.len()
+        )?;
+    }
+
+    writeln!(out)?;
+    writeln!(out, "static const float consts[] = {{")?;
+    for &value in memoized.consts.iter() {
+        writeln!(out, "  {}f,", ConstFormat::Exponent.display(value))?;
+    }
+    // C doesn't allow empty initializers before C23
+    if memoized.consts.is_empty() {
+        writeln!(out, "  0.0f,")?;
+    }
+    writeln!(out, "}};")?;
+
+    let regs = memoized
+        .funcs
+        .iter()
+        .map(|func| func.insts.len())
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    writeln!(out)?;
+    writeln!(out, "static float regs[{regs}];")?;
+    writeln!(out)?;
+    writeln!(out, "enum {{")?;
+    for (name, value) in OPCODES {
+        writeln!(out, "  {name} = {value},")?;
+    }
+    writeln!(out, "}};")?;
+    writeln!(out)?;
+    write!(out, "{INTERPRETER}")?;
+
+    for func in memoized.funcs.iter() {
+        writeln!(out)?;
+        writeln!(out, "static const uint16_t {:?}_code[] = {{", func.vars)?;
+        write_code(&mut out, func)?;
+        writeln!(out, "}};")?;
+        writeln!(out)?;
+        write_signature(&mut out, memoized, func)?;
+        writeln!(out, " {{")?;
+        write!(out, "  float *const spaces[] = {{ (float *) consts")?;
+        for space in params(memoized, func) {
+            write!(out, ", {:?}_buf", space.vars)?;
+        }
+        writeln!(out, " }};")?;
+        writeln!(out, "  run({:?}_code, spaces);", func.vars)?;
+        writeln!(out, "}}")?;
+    }
+    Ok(())
+}
+
+// Arguments follow `abi::params`, matching the x86 backend.
+fn write_signature(
+    mut out: impl io::Write,
+    memoized: &Memoized,
+    func: &MemoizedFunc,
+) -> io::Result<()> {
+    write!(out, "void {:?}(", func.vars)?;
+    for (idx, space) in params(memoized, func).enumerate() {
+        if idx > 0 {
+            write!(out, ", ")?;
+        }
+        write!(out, "float *{:?}_buf", space.vars)?;
+    }
+    write!(out, ")")
+}
+
+/// Describe the functions that [`write_source`] generates. They're ordinary C
+/// functions, so there's no fixed register assignment.
+pub fn manifest(memoized: &Memoized) -> Manifest {
+    Manifest::new(memoized, 1, false, Var::X, None)
+}
+
+/// The loops for rendering an image with the functions that [`write_source`]
+/// generates, for [`harness::write`](super::harness::write).
+pub fn loop_nest() -> LoopNest {
+    LoopNest::new(VarSet::from(Var::X) | VarSet::from(Var::Y), 1, Var::X)
+}
+
+fn write_code(mut out: impl io::Write, func: &MemoizedFunc) -> io::Result<()> {
+    for (idx, inst) in func.insts.iter().enumerate() {
+        let words = match *inst {
+            Inst::Const { .. } | Inst::Var { .. } => {
+                unimplemented!("{inst:?} not allowed in memoized functions")
+            }
+            Inst::Load { vars, loc } => vec![OP_LOAD, vars.idx().try_into().unwrap(), loc],
+            Inst::UnOp { op, arg } => {
+                let op = match op {
+                    UnOp::Neg => OP_NEG,
+                    UnOp::Square => OP_SQUARE,
+                    UnOp::Sqrt => OP_SQRT,
+                    UnOp::Abs => OP_ABS,
+                    UnOp::Exp => OP_EXP,
+                    UnOp::Ln => OP_LN,
+                    UnOp::Sin => OP_SIN,
+                    UnOp::Cos => OP_COS,
+                    UnOp::Floor => OP_FLOOR,
+                    UnOp::Ceil => OP_CEIL,
+                    UnOp::Round => OP_ROUND,
+                    UnOp::Recip => OP_RECIP,
+                    UnOp::Rsqrt => OP_RSQRT,
+                    UnOp::Barrier => unreachable!("barriers are removed during memoization"),
+                    UnOp::PowI(_) => unreachable!("powi is expanded during memoization"),
+                };
+                vec![op, arg.idx().try_into().unwrap()]
+            }
+            Inst::BinOp { op, args: [a, b] } => {
+                let op = match op {
+                    BinOp::Add => OP_ADD,
+                    BinOp::Sub => OP_SUB,
+                    BinOp::Mul => OP_MUL,
+                    BinOp::Min => OP_MIN,
+                    BinOp::Max => OP_MAX,
+                    BinOp::Mod => OP_MOD,
+                };
+                vec![op, a.idx().try_into().unwrap(), b.idx().try_into().unwrap()]
+            }
+            Inst::Fma { args } => {
+                let mut words = vec![OP_FMA];
+                words.extend(args.map(|arg| u16::try_from(arg.idx()).unwrap()));
+                words
+            }
+            Inst::TernOp { op, args } => {
+                let mut words = vec![match op {
+                    TernOp::Clamp => OP_CLAMP,
+                    TernOp::Lerp => OP_LERP,
+                }];
+                words.extend(args.map(|arg| u16::try_from(arg.idx()).unwrap()));
+                words
+            }
+        };
+        write_words(&mut out, &words, &format!("v{idx}"))?;
+    }
+
+    let space = func.vars.idx().try_into().unwrap();
+    for (loc, &reg) in func.outputs.iter().enumerate() {
+        if let Some(reg) = reg {
+            let words = [
+                OP_STORE,
+                space,
+                loc.try_into().unwrap(),
+                reg.idx().try_into().unwrap(),
+            ];
+            write_words(&mut out, &words, &format!("store v{reg}"))?;
+        }
+    }
+    write_words(&mut out, &[OP_END], "end")
+}
+
+fn write_words(mut out: impl io::Write, words: &[u16], comment: &str) -> io::Result<()> {
+    write!(out, " ")?;
+    for word in words {
+        write!(out, " {word},")?;
+    }
+    writeln!(out, " // {comment}")
+}
@@ -0,0 +1,438 @@
+use std::io;
+
+use crate::ir::memoize::Memoized;
+use crate::ir::{Var, VarSet};
+
+use super::abi::{ABI_VERSION, part_name};
+use super::loops::{BAND_ROWS, Buffer, LoopNest, Step, check_xy};
+
+// Everything before the generated code: the harness's state, and helpers for
+// filling in coordinates and counting the points inside the shape. The loops
+// cover a tile `width` by `height` pixels, whose top left corner is at
+// (`left`, `top`) in an image `size` pixels across.
+const PRELUDE: &str = "\
+static unsigned long size;
+static unsigned long left;
+static unsigned long top;
+static unsigned long width;
+static unsigned long height;
+static float scale;
+static float x_offset;
+static float y_offset;
+static int y_down;
+static uint16_t *counts;
+
+// aligned for the widest vectors any backend uses, 16 floats
+static float *alloc_floats(size_t len) {
+  return aligned_alloc(64, (sizeof(float) * len + 63) & ~(size_t) 63);
+}
+
+// Each lane's coordinate is computed on its own, the same way for any number
+// of lanes, so wider vectors see exactly the same points.
+static void init_x(float *buf, unsigned long col, uint16_t lanes) {
+  for(uint16_t i = 0; i < lanes; ++i) {
+    buf[i] = (float) (left + col + i) * scale - 1.0f + x_offset;
+  }
+}
+
+static void init_y(float *buf, unsigned long row, uint16_t lanes) {
+  for(uint16_t i = 0; i < lanes; ++i) {
+    // rows past the bottom of the image are computed but never counted
+    long r = (long) (top + row + i);
+    buf[i] = (float) (y_down ? r : (long) size - 1 - r) * scale - 1.0f + y_offset;
+  }
+}
+
+// Count which of `lanes` adjacent results are inside the shape.
+static void accumulate(const float *out, unsigned long row, unsigned long col, uint16_t lanes, int along_x) {
+  // with packed output, the generated code has already done the sign test
+  uint16_t bits = *(const uint16_t *) out;
+  for(uint16_t j = 0; j < lanes; ++j) {
+    unsigned long r = along_x ? row : row + j;
+    unsigned long c = along_x ? col + j : col;
+    if(r < height && c < width && (xy_packed ? (bits >> j) & 1 : out[j] == 0.0f || !signbit(out[j]))) {
+      ++counts[r * width + c];
+    }
+  }
+}
+
+static void usage(const char *argv0) {
+  fprintf(stderr, \"usage: %s [size [y-up|y-down [samples [WIDTHxHEIGHT+LEFT+TOP]]]]\\n\", argv0);
+  exit(EXIT_FAILURE);
+}
+";
+
+// Everything after the generated code. With supersampling, each pixel is
+// the average of a samples*samples grid of points evenly spaced within it,
+// and the whole loop nest runs once per point in that grid. Given a tile, only
+// that part of the image is rendered and written out, so several machines can
+// each render part of an image too big for one. Either way, rows are
+// rendered and written out a band at a time.
+const MAIN: &str = "\
+int main(int argc, char **argv) {
+  check_abi();
+  size = 512;
+  unsigned long samples = 1;
+  if(argc > 1) {
+    char *end = NULL;
+    size = strtoul(argv[1], &end, 0);
+    if(*end != '\\0' || argc > 5) {
+      usage(argv[0]);
+    }
+  }
+  if(argc > 2) {
+    if(strcmp(argv[2], \"y-down\") == 0) {
+      y_down = 1;
+    } else if(strcmp(argv[2], \"y-up\") != 0) {
+      usage(argv[0]);
+    }
+  }
+  if(argc > 3) {
+    char *end = NULL;
+    samples = strtoul(argv[3], &end, 0);
+    if(*end != '\\0' || samples < 1 || samples > 255) {
+      usage(argv[0]);
+    }
+  }
+  width = height = size;
+  if(argc > 4) {
+    int end = 0;
+    if(sscanf(argv[4], \"%lux%lu+%lu+%lu%n\", &width, &height, &left, &top, &end) != 4 || argv[4][end] != '\\0') {
+      usage(argv[0]);
+    }
+    if(width < 1 || height < 1 || left + width > size || top + height > size) {
+      fprintf(stderr, \"tile must be nonempty and inside the %lux%lu image\\n\", size, size);
+      exit(EXIT_FAILURE);
+    }
+  }
+
+  // Render a band of rows at a time and write each out before starting on
+  // the next, so memory use doesn't grow with the height of the image.
+  unsigned long tile_top = top;
+  unsigned long tile_height = height;
+  height = tile_height < band ? tile_height : band;
+  alloc_buffers();
+  counts = malloc(width * height * sizeof(uint16_t));
+
+  size_t row_size;
+  if(samples == 1) {
+    printf(\"P4 %ld %ld\\n\", width, tile_height);
+    row_size = (width + 7) / 8;
+  } else {
+    printf(\"P5 %ld %ld 255\\n\", width, tile_height);
+    row_size = width;
+  }
+  uint8_t *row_buffer = malloc(row_size);
+  scale = 2.0f / (size - 1);
+  float step = scale / samples;
+  float first = (step - scale) / 2.0f;
+  float total = samples * samples;
+  for(unsigned long band_top = 0UL; band_top < tile_height; band_top += band) {
+    top = tile_top + band_top;
+    height = tile_height - band_top < band ? tile_height - band_top : band;
+    memset(counts, 0, width * height * sizeof(uint16_t));
+    for(unsigned long sy = 0UL; sy < samples; ++sy) {
+      y_offset = first + sy * step;
+      for(unsigned long sx = 0UL; sx < samples; ++sx) {
+        x_offset = first + sx * step;
+        render();
+      }
+    }
+
+    for(unsigned long row = 0UL; row < height; ++row) {
+      uint16_t *row_counts = counts + row * width;
+      if(samples == 1) {
+        memset(row_buffer, 0, row_size);
+        for(unsigned long col = 0UL; col < width; ++col) {
+          if(row_counts[col]) {
+            row_buffer[col >> 3] |= 0x80 >> (col & 7);
+          }
+        }
+      } else {
+        for(unsigned long col = 0UL; col < width; ++col) {
+          row_buffer[col] = lroundf((1.0f - row_counts[col] / total) * 255.0f);
+        }
+      }
+      fwrite(row_buffer, 1, row_size, stdout);
+    }
+  }
+
+  exit(EXIT_SUCCESS);
+}
+";
+
+/// Write a C program which drives code from either backend following `nest`,
+/// and writes the resulting image to stdout as a PBM, or as a PGM with
+/// supersampling. It refuses to run code generated with a different ABI
+/// version, stride, or vector axis than the nest was built for. Writing a
+/// harness for `memoized` fails if the program uses z, which the nest
+/// doesn't cover.
+pub fn write(mut out: impl io::Write, memoized: &Memoized, nest: &LoopNest) -> io::Result<()> {
+    check_xy(memoized).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    writeln!(
+        out,
+        "// test harness for code with stride {} vectorized along {}",
+        nest.stride,
+        nest.vector_axis.name()
+    )?;
+    writeln!(
+        out,
+        "// compile with: gcc -Wall -O2 -o <output> <this file> <output>.s -lm"
+    )?;
+    writeln!(out, "#include <math.h>")?;
+    writeln!(out, "#include <stdint.h>")?;
+    writeln!(out, "#include <stdio.h>")?;
+    writeln!(out, "#include <stdlib.h>")?;
+    writeln!(out, "#include <string.h>")?;
+    writeln!(out)?;
+    for buffer in nest.buffers.iter() {
+        for part in 0..buffer.parts {
+            write!(out, "extern void {}(", part_name(buffer.func, part))?;
+            for (idx, param) in params(nest, buffer.func).enumerate() {
+                if idx > 0 {
+                    write!(out, ", ")?;
+                }
+                write!(out, "float *{:?}_buf", param.func)?;
+            }
+            writeln!(out, ");")?;
+        }
+    }
+    for buffer in nest.buffers.iter() {
+        writeln!(out, "extern const uint16_t {:?}_size;", buffer.func)?;
+    }
+    writeln!(out, "extern const uint16_t stride;")?;
+    writeln!(out, "extern const uint32_t abi_version;")?;
+    writeln!(out, "extern const uint8_t xy_packed;")?;
+    writeln!(out, "extern const uint8_t vector_axis;")?;
+    writeln!(out)?;
+    for buffer in nest.buffers.iter() {
+        writeln!(out, "static float *{:?}_buf;", buffer.func)?;
+    }
+    write!(out, "{PRELUDE}")?;
+
+    writeln!(out)?;
+    writeln!(
+        out,
+        "// Refuse to run code generated for a different calling convention or"
+    )?;
+    writeln!(
+        out,
+        "// loop nest, or with buffer sizes it can't have, rather than computing"
+    )?;
+    writeln!(out, "// garbage.")?;
+    writeln!(out, "static void check_abi(void) {{")?;
+    let expected = [
+        ("abi_version", ABI_VERSION),
+        ("stride", nest.stride.into()),
+        ("vector_axis", nest.vector_axis as u32),
+    ];
+    for (name, value) in expected {
+        writeln!(out, "  if({name} != {value}) {{")?;
+        writeln!(
+            out,
+            "    fprintf(stderr, \"generated code has {name} %u but this harness expects {value}\\n\", (unsigned) {name});"
+        )?;
+        writeln!(out, "    exit(EXIT_FAILURE);")?;
+        writeln!(out, "  }}")?;
+    }
+    for buffer in nest.buffers.iter() {
+        // location 0 of each input function's buffer holds its coordinates,
+        // and the output function's holds the final result, which is its
+        // only output unless it was split and needs scratch space
+        let (test, expected) = if buffer.func == nest.output && buffer.parts == 1 {
+            ("!=", "1")
+        } else if buffer.func == nest.output || single_var(buffer.func).is_some() {
+            ("<", "at least 1")
+        } else {
+            continue;
+        };
+        let name = format!("{:?}_size", buffer.func);
+        writeln!(out, "  if({name} {test} 1) {{")?;
+        writeln!(
+            out,
+            "    fprintf(stderr, \"{name} is %u but should be {expected}\\n\", (unsigned) {name});"
+        )?;
+        writeln!(out, "    exit(EXIT_FAILURE);")?;
+        writeln!(out, "  }}")?;
+    }
+    writeln!(out, "}}")?;
+
+    writeln!(out)?;
+    writeln!(out, "static void alloc_buffers(void) {{")?;
+    for buffer in nest.buffers.iter() {
+        write!(out, "  {:?}_buf = alloc_floats(", buffer.func)?;
+        for level in buffer.levels.iter() {
+            write!(
+                out,
+                "({} + {}) / {} * ",
+                extent(level.var),
+                level.lanes - 1,
+                level.lanes
+            )?;
+        }
+        writeln!(out, "{:?}_size * {});", buffer.func, buffer.lanes)?;
+    }
+    writeln!(out, "}}")?;
+
+    writeln!(out)?;
+    writeln!(
+        out,
+        "// the most rows to render at once, lined up with every group of rows"
+    )?;
+    writeln!(
+        out,
+        "static const unsigned long band = {};",
+        nest.band_rows(BAND_ROWS)
+    )?;
+
+    writeln!(out)?;
+    writeln!(out, "static void render(void) {{")?;
+    let mut loops = Vec::new();
+    write_steps(&mut out, nest, &nest.body, &mut loops)?;
+    writeln!(out, "}}")?;
+
+    writeln!(out)?;
+    write!(out, "{MAIN}")
+}
+
+// The memory spaces each generated function takes pointers to, in argument
+// order, which is every function up to and including its own.
+fn params(nest: &LoopNest, func: VarSet) -> impl Iterator<Item = &Buffer> {
+    nest.buffers
+        .iter()
+        .filter(move |param| param.func.idx() <= func.idx())
+}
+
+fn single_var(func: VarSet) -> Option<Var> {
+    let mut vars = func;
+    vars.next().filter(|_| vars.next().is_none())
+}
+
+// Each loop's counter is named after its variable and depth, like `y1`.
+struct Counter {
+    name: String,
+    var: Var,
+    lanes: u16,
+}
+
+fn write_steps(
+    out: &mut impl io::Write,
+    nest: &LoopNest,
+    steps: &[Step],
+    loops: &mut Vec<Counter>,
+) -> io::Result<()> {
+    let indent = "  ".repeat(loops.len() + 1);
+    for step in steps {
+        match step {
+            Step::Loop(level, body) => {
+                let name = format!("{}{}", level.var.name(), loops.len());
+                let lanes = level.lanes;
+                match loops.iter().rfind(|outer| outer.var == level.var) {
+                    Some(outer) => writeln!(
+                        out,
+                        "{indent}for(unsigned long {name} = {outer}; {name} < {outer} + {outer_lanes} && {name} < {extent}; {name} += {lanes}) {{",
+                        outer = outer.name,
+                        outer_lanes = outer.lanes,
+                        extent = extent(level.var),
+                    )?,
+                    None => writeln!(
+                        out,
+                        "{indent}for(unsigned long {name} = 0UL; {name} < {extent}; {name} += {lanes}) {{",
+                        extent = extent(level.var),
+                    )?,
+                }
+                loops.push(Counter {
+                    name,
+                    var: level.var,
+                    lanes,
+                });
+                write_steps(out, nest, body, loops)?;
+                loops.pop();
+                writeln!(out, "{indent}}}")?;
+            }
+            &Step::Call(func) => {
+                let pos = |var| &counter(loops, var).name;
+                let pointer = |buffer| pointer(buffer, loops);
+                let own = nest.buffer(func).unwrap();
+                if let Some(var) = single_var(func) {
+                    writeln!(
+                        out,
+                        "{indent}init_{}({}, {}, {});",
+                        var.name(),
+                        pointer(own),
+                        pos(var),
+                        own.lanes
+                    )?;
+                }
+                for part in 0..own.parts {
+                    write!(out, "{indent}{}(", part_name(func, part))?;
+                    for (idx, param) in params(nest, func).enumerate() {
+                        if idx > 0 {
+                            write!(out, ", ")?;
+                        }
+                        if (param.func | func) == func {
+                            write!(out, "{}", pointer(param))?;
+                        } else {
+                            write!(out, "NULL")?;
+                        }
+                    }
+                    writeln!(out, ");")?;
+                }
+                if func == nest.output {
+                    writeln!(
+                        out,
+                        "{indent}accumulate({}, {}, {}, {}, {});",
+                        pointer(own),
+                        pos(Var::Y),
+                        pos(Var::X),
+                        own.lanes,
+                        u8::from(own.lane_var == Var::X)
+                    )?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// The number of points the harness visits along `var`.
+fn extent(var: Var) -> &'static str {
+    match var {
+        Var::X => "width",
+        Var::Y => "height",
+        Var::Z => unimplemented!("the harness only renders 2D images"),
+    }
+}
+
+fn counter(loops: &[Counter], var: Var) -> &Counter {
+    loops.iter().rfind(|counter| counter.var == var).unwrap()
+}
+
+// The same arithmetic as `Buffer::offset`, as a C expression. Loops always
+// start on a multiple of their own lanes, so the lane within the buffer is
+// only needed when the reader visits fewer points at a time.
+fn pointer(buffer: &Buffer, loops: &[Counter]) -> String {
+    let mut expr = format!("{:?}_buf", buffer.func);
+    let mut group = String::new();
+    for level in buffer.levels.iter() {
+        let index = format!("{} / {}", counter(loops, level.var).name, level.lanes);
+        group = if group.is_empty() {
+            index
+        } else {
+            format!(
+                "({group}) * (({} + {}) / {}) + {index}",
+                extent(level.var),
+                level.lanes - 1,
+                level.lanes
+            )
+        };
+    }
+    if !group.is_empty() {
+        expr += &format!(" + ({group}) * {:?}_size * {}", buffer.func, buffer.lanes);
+    }
+    let reader = counter(loops, buffer.lane_var);
+    if !reader.lanes.is_multiple_of(buffer.lanes) {
+        expr += &format!(" + {} % {}", reader.name, buffer.lanes);
+    }
+    expr
+}
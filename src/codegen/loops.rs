@@ -0,0 +1,380 @@
+use thiserror::Error;
+
+use crate::ir::memoize::Memoized;
+use crate::ir::{Var, VarSet};
+
+/// A loop over every point along `var`, `lanes` at a time. Nested inside
+/// another loop over the same variable, it only visits the points in the
+/// outer loop's current group.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Level {
+    pub var: Var,
+    pub lanes: u16,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Step {
+    Loop(Level, Vec<Step>),
+    /// Call the generated function for these variables, which stores its
+    /// outputs in its [`Buffer`] at the current position. For a function of
+    /// one variable, the caller first stores that variable's coordinates in
+    /// location 0.
+    Call(VarSet),
+}
+
+/// Where a function's outputs live between the call that stores them and the
+/// calls that read them.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Buffer {
+    pub func: VarSet,
+    /// How many functions fill the buffer, called one after another; see
+    /// [`split`](crate::ir::split::split).
+    pub parts: usize,
+    /// Values per location, one for each point along `lane_var`.
+    pub lanes: u16,
+    pub lane_var: Var,
+    /// Loops around the call which don't also enclose every reader,
+    /// outermost first, at most one per variable. The buffer holds a
+    /// separate set of outputs for each of their iterations.
+    pub levels: Vec<Level>,
+}
+
+impl Buffer {
+    /// Number of floats to allocate for a grid `extent[var]` points along
+    /// each `Var`, if the function has `outputs` locations.
+    pub fn len(&self, extent: [usize; 3], outputs: usize) -> usize {
+        let groups: usize = self
+            .levels
+            .iter()
+            .map(|level| extent[level.var as usize].div_ceil(level.lanes.into()))
+            .product();
+        groups * outputs * usize::from(self.lanes)
+    }
+
+    /// Index of the first float to pass to a function which is visiting the
+    /// points starting at `pos`, indexed by `Var`. A reader with fewer lanes
+    /// than this buffer gets a pointer to its own lane, so it can broadcast
+    /// from there.
+    pub fn offset(&self, pos: [usize; 3], extent: [usize; 3], outputs: usize) -> usize {
+        let group = self.levels.iter().fold(0, |group, level| {
+            let var = level.var as usize;
+            let lanes = usize::from(level.lanes);
+            group * extent[var].div_ceil(lanes) + pos[var] / lanes
+        });
+        let lanes = usize::from(self.lanes);
+        group * outputs * lanes + pos[self.lane_var as usize] % lanes
+    }
+}
+
+/// The loop nests only cover x and y, so rendering a program which also
+/// uses z would report `xy`'s intermediate values as the result.
+#[derive(Debug, Error)]
+#[error("program uses z, but only images of x and y can be rendered")]
+pub struct UsesZ;
+
+/// Check that no function of z in `memoized` computes anything, so an
+/// image's loop nest renders the final result.
+pub fn check_xy(memoized: &Memoized) -> Result<(), UsesZ> {
+    let uses_z = memoized
+        .funcs
+        .iter()
+        .any(|func| func.vars.contains(Var::Z) && !func.insts.is_empty());
+    if uses_z { Err(UsesZ) } else { Ok(()) }
+}
+
+/// How many rows the C harness and [`runtime`](crate::runtime) render at
+/// once, before [rounding up](LoopNest::band_rows) to fit the loop nest. Small
+/// enough that even a band 65535 pixels wide only needs a few tens of
+/// megabytes.
+pub const BAND_ROWS: u16 = 256;
+
+/// The loops a caller runs to evaluate every function of a memoized program
+/// over a grid, and which buffers each one fills. Both the generated C
+/// harness and [`runtime`](crate::runtime) follow this description, so
+/// changes to the loop structure only need to be made here.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LoopNest {
+    pub body: Vec<Step>,
+    pub buffers: Vec<Buffer>,
+    /// The function whose location 0 holds the final result.
+    pub output: VarSet,
+    /// The backend's vector width, which the code must have been generated
+    /// with.
+    pub stride: u16,
+    pub vector_axis: Var,
+}
+
+impl LoopNest {
+    /// Describe the loops for evaluating every function that depends only on
+    /// `vars`, for code generated with the given vector `stride` and
+    /// `vector_axis`. Each function's loops are ordered so that its vector
+    /// lanes run along the innermost one, and functions share loops wherever
+    /// they can.
+    pub fn new(vars: VarSet, stride: u16, vector_axis: Var) -> LoopNest {
+        let vars: Vec<Var> = vars.collect();
+        let mut funcs: Vec<VarSet> = (1..1 << vars.len())
+            .map(|mask: usize| {
+                vars.iter()
+                    .enumerate()
+                    .filter(|&(bit, _)| mask & 1 << bit != 0)
+                    .fold(VarSet::default(), |set, (_, &var)| set | var.into())
+            })
+            .collect();
+        // Subsets come before the functions that read them. The lane
+        // variable's own function goes first, so the loop over the other
+        // variable, which is inserted next, is the one later functions nest
+        // inside, after both of their inputs have been computed.
+        funcs.sort_by_key(|&func| (func != vector_axis.into(), func));
+
+        let mut body = Vec::new();
+        let paths: Vec<Vec<usize>> = funcs
+            .iter()
+            .map(|&func| {
+                let mut order: Vec<Var> = func.collect();
+                order.sort_by_key(|&var| (var == vector_axis, std::cmp::Reverse(var)));
+                // only along z do the functions without it stay scalar
+                let vectorized = vector_axis != Var::Z || func.contains(vector_axis);
+                let levels: Vec<Level> = order
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, &var)| Level {
+                        var,
+                        lanes: if vectorized && idx + 1 == order.len() {
+                            stride
+                        } else {
+                            1
+                        },
+                    })
+                    .collect();
+                insert(&mut body, &levels, func)
+            })
+            .collect();
+
+        let mut buffers: Vec<Buffer> = funcs
+            .iter()
+            .zip(&paths)
+            .map(|(&func, path)| {
+                // loops shared with every reader don't need separate copies
+                let shared = funcs
+                    .iter()
+                    .zip(&paths)
+                    .filter(|&(&reader, _)| reader != func && (reader | func) == reader)
+                    .map(|(_, reader)| common_prefix(path, reader))
+                    .min()
+                    .unwrap_or(path.len());
+                let enclosing = enclosing_levels(&body, path);
+                let mut levels: Vec<Level> = Vec::new();
+                for &level in &enclosing[shared..] {
+                    levels.retain(|outer| outer.var != level.var);
+                    levels.push(level);
+                }
+                let innermost = *enclosing.last().unwrap();
+                Buffer {
+                    func,
+                    parts: 1,
+                    lanes: innermost.lanes,
+                    lane_var: innermost.var,
+                    levels,
+                }
+            })
+            .collect();
+        // callers pass the buffers to each function in order of their spaces
+        buffers.sort_by_key(|buffer| buffer.func);
+
+        LoopNest {
+            body,
+            buffers,
+            output: *funcs.last().unwrap(),
+            stride,
+            vector_axis,
+        }
+    }
+
+    pub fn buffer(&self, func: VarSet) -> Option<&Buffer> {
+        self.buffers.iter().find(|buffer| buffer.func == func)
+    }
+
+    /// How many times the loops call each function to cover a grid
+    /// `extent[var]` points along each `Var`, for weighing each function's
+    /// cost by how often it runs.
+    pub fn calls(&self, extent: [usize; 3]) -> Vec<(VarSet, usize)> {
+        let mut calls = Vec::new();
+        count_calls(&self.body, extent, [None; 3], &mut calls);
+        calls
+    }
+
+    /// The fewest rows, at least `rows`, in a band of the image such that
+    /// every loop over y starts its groups on the same rows as it would
+    /// across the whole image. Rendering one such band at a time gives
+    /// exactly the same pixels as rendering everything at once, but buffers
+    /// only need room for one band.
+    pub fn band_rows(&self, rows: u16) -> u16 {
+        let group = y_group(&self.body, 1);
+        rows.max(1).div_ceil(group) * group
+    }
+}
+
+// The least common multiple of the lanes of every loop over y in `body`, and
+// `group`.
+fn y_group(body: &[Step], mut group: u16) -> u16 {
+    for step in body {
+        if let Step::Loop(level, inner) = step {
+            if level.var == Var::Y {
+                let (mut a, mut b) = (group, level.lanes);
+                while b != 0 {
+                    (a, b) = (b, a % b);
+                }
+                group = group / a * level.lanes;
+            }
+            group = y_group(inner, group);
+        }
+    }
+    group
+}
+
+// Loops nested inside another loop over the same variable split up its
+// groups of points, so between them they visit every point along that
+// variable once per group of the innermost loop's lanes.
+fn count_calls(
+    body: &[Step],
+    extent: [usize; 3],
+    lanes: [Option<u16>; 3],
+    calls: &mut Vec<(VarSet, usize)>,
+) {
+    for step in body {
+        match step {
+            Step::Loop(level, inner) => {
+                let mut lanes = lanes;
+                lanes[level.var as usize] = Some(level.lanes);
+                count_calls(inner, extent, lanes, calls);
+            }
+            &Step::Call(func) => {
+                let count = extent
+                    .iter()
+                    .zip(lanes)
+                    .filter_map(|(&extent, lanes)| Some(extent.div_ceil(lanes?.into())))
+                    .product();
+                calls.push((func, count));
+            }
+        }
+    }
+}
+
+// Add a call nested inside loops for `levels`, reusing the last step at each
+// depth if it's a loop which visits the same points or a coarser grouping of
+// them. Reusing an earlier loop would run the call before the ones added
+// since, whose outputs it may read. Returns the index of each enclosing loop
+// in its parent's body.
+fn insert(mut body: &mut Vec<Step>, mut levels: &[Level], func: VarSet) -> Vec<usize> {
+    let mut path = Vec::new();
+    while let Some(&level) = levels.first() {
+        let reuse = match body.last() {
+            Some(Step::Loop(outer, _))
+                if outer.var == level.var && outer.lanes % level.lanes == 0 =>
+            {
+                Some(body.len() - 1)
+            }
+            _ => None,
+        };
+        let idx = reuse.unwrap_or_else(|| {
+            body.push(Step::Loop(level, Vec::new()));
+            body.len() - 1
+        });
+        path.push(idx);
+        let Step::Loop(outer, inner) = &mut body[idx] else {
+            unreachable!()
+        };
+        if *outer == level {
+            levels = &levels[1..];
+        }
+        body = inner;
+    }
+    body.push(Step::Call(func));
+    path
+}
+
+fn common_prefix(a: &[usize], b: &[usize]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+fn enclosing_levels(mut body: &[Step], path: &[usize]) -> Vec<Level> {
+    path.iter()
+        .map(|&idx| {
+            let Step::Loop(level, inner) = &body[idx] else {
+                unreachable!()
+            };
+            body = inner;
+            *level
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_nest() {
+        let [x, y] = [Var::X, Var::Y].map(VarSet::from);
+        let nest = LoopNest::new(x | y, 4, Var::X);
+        let level = |var, lanes| Level { var, lanes };
+        assert_eq!(
+            nest.body,
+            [
+                Step::Loop(level(Var::X, 4), vec![Step::Call(x)]),
+                Step::Loop(
+                    level(Var::Y, 4),
+                    vec![
+                        Step::Call(y),
+                        Step::Loop(
+                            level(Var::Y, 1),
+                            vec![Step::Loop(level(Var::X, 4), vec![Step::Call(x | y)])]
+                        ),
+                    ]
+                ),
+            ]
+        );
+        // every column's x values are kept for every row, but y only needs
+        // the current group of rows
+        assert_eq!(nest.buffer(x).unwrap().levels, [level(Var::X, 4)]);
+        assert_eq!(nest.buffer(y).unwrap().levels, []);
+        assert_eq!(nest.buffer(y).unwrap().offset([8, 6, 0], [10, 10, 1], 3), 2);
+        assert_eq!(
+            nest.buffer(x).unwrap().offset([8, 6, 0], [10, 10, 1], 3),
+            24
+        );
+        assert_eq!(nest.calls([10, 10, 1]), [(x, 3), (y, 3), (x | y, 30)]);
+        assert_eq!([nest.band_rows(10), nest.band_rows(256)], [12, 256]);
+    }
+
+    #[test]
+    fn test_column_nest() {
+        let [x, y] = [Var::X, Var::Y].map(VarSet::from);
+        let nest = LoopNest::new(x | y, 4, Var::Y);
+        let level = |var, lanes| Level { var, lanes };
+        // the mirror image of the nest along x
+        assert_eq!(
+            nest.body,
+            [
+                Step::Loop(level(Var::Y, 4), vec![Step::Call(y)]),
+                Step::Loop(
+                    level(Var::X, 4),
+                    vec![
+                        Step::Call(x),
+                        Step::Loop(
+                            level(Var::X, 1),
+                            vec![Step::Loop(level(Var::Y, 4), vec![Step::Call(x | y)])]
+                        ),
+                    ]
+                ),
+            ]
+        );
+        let funcs: Vec<VarSet> = nest.buffers.iter().map(|buffer| buffer.func).collect();
+        assert_eq!(funcs, [x, y, x | y]);
+        assert_eq!(nest.buffer(x | y).unwrap().lane_var, Var::Y);
+        assert_eq!(nest.buffer(y).unwrap().levels, [level(Var::Y, 4)]);
+        assert_eq!(nest.buffer(x).unwrap().levels, []);
+        assert_eq!(nest.calls([10, 10, 1]), [(y, 3), (x, 3), (x | y, 30)]);
+        assert_eq!(nest.band_rows(10), 12);
+    }
+}
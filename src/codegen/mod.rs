@@ -2,7 +2,13 @@ use std::num::{NonZero, TryFromIntError};
 
 use crate::ir::VarSet;
 
+pub mod abi;
+pub mod c;
+pub mod harness;
+pub mod loops;
 pub mod regalloc;
+pub mod rust;
+pub mod traffic;
 pub mod x86;
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -15,18 +15,35 @@ use super::{MemorySpace, Register};
 pub struct Config {
     /// On some architectures, arithmetic instructions can also load a value from
     /// memory without needing to place it in a register first. This reduces
-    /// register pressure, at the cost of potentially duplicating loads.
-    #[arg(long, default_value_t = SinkLoads::default(), value_enum)]
-    pub sink_loads: SinkLoads,
+    /// register pressure, at the cost of potentially duplicating loads. Unless
+    /// given, this is `spill-any`, or `require-dead` on targets with only a
+    /// few registers.
+    #[arg(long, value_enum)]
+    pub sink_loads: Option<SinkLoads>,
 }
 
-#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+// With this many registers or fewer, nearly every register holds a live value
+// whenever a sunk load could be patched, so evicting one of them usually costs
+// more than loading the value twice.
+const FEW_REGISTERS: usize = 6;
+
+impl Config {
+    /// The load-sinking policy for a target with `regs` registers.
+    pub fn sink_loads(self, regs: usize) -> SinkLoads {
+        self.sink_loads.unwrap_or(if regs <= FEW_REGISTERS {
+            SinkLoads::RequireDead
+        } else {
+            SinkLoads::SpillAny
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
 pub enum SinkLoads {
     /// Don't sink loads
     None,
     /// Sink loads unless a register is available, even if that register
     /// requires spilling live values
-    #[default]
     SpillAny,
     /// Sink loads unless a register is available, preferring registers which
     /// don't require spilling live values
@@ -69,10 +86,40 @@ pub trait Target {
         reg: Register,
         other: Option<(MemorySpace, Location)>,
     );
+
+    /// Whether a value can be stored to this memory space and loaded back
+    /// unchanged, so the allocator can use its locations as spill slots.
+    fn can_spill_to(&self, _mem: MemorySpace) -> bool {
+        true
+    }
+}
+
+/// Counters describing how the allocator behaved, to help tune its heuristics
+/// for different workloads.
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    /// Registers taken away from a live value, forcing it to be spilled
+    pub evictions: u32,
+    /// Evicted values which had nowhere in memory yet, so they must be
+    /// stored where they're defined, to the stack or to a location that's
+    /// free at that point
+    pub spills: u32,
+    /// Loads which were sunk into an instruction
+    pub sunk_loads: u32,
+    /// Sunk loads which were later patched to use a clean register
+    pub sink_hits: u32,
+    /// Sunk loads which were needed again, but no register was clean
+    pub sink_misses: u32,
+    /// Sunk loads which were needed again after falling out of the queue
+    pub sink_expired: u32,
+    /// For each hit, how many sunk loads had been queued since the one that
+    /// got patched
+    pub hits_by_distance: [u32; DIRTY_POOL_SIZE],
 }
 
 pub struct Registers<T> {
-    config: Config,
+    sink_loads: SinkLoads,
+    stats: Stats,
     allocs: Vec<Allocation>,
     recent: Lru,
     live: Vec<Option<InstIdx>>,
@@ -86,7 +133,8 @@ pub struct Registers<T> {
 impl<T: Target> Registers<T> {
     pub fn new(config: Config, allocs: Vec<Allocation>, regs: usize, target: T) -> Self {
         Registers {
-            config,
+            sink_loads: config.sink_loads(regs),
+            stats: Stats::default(),
             allocs,
             recent: Lru::new(regs),
             live: vec![None; regs],
@@ -110,8 +158,10 @@ impl<T: Target> Registers<T> {
             self.target.emit_store(reg, mem, loc);
             // Any place we're going to store to, not just stack slots, can be
             // safely used as a spill slot for earlier instructions.
-            self.free_slots.push((self.free_generation, mem, loc));
-            self.free_generation += 1;
+            if self.target.can_spill_to(mem) {
+                self.free_slots.push((self.free_generation, mem, loc));
+                self.free_generation += 1;
+            }
         }
         reg
     }
@@ -126,7 +176,7 @@ impl<T: Target> Registers<T> {
         }
 
         // Otherwise, pick a register and hope nobody needs it too soon.
-        let reg = Register::try_from(self.recent.pop()).unwrap();
+        let reg = self.recent.pop();
 
         if let Some((mem, loc)) = self.clobber(idx, reg, self.free_generation) {
             // Some later instruction wants this value in this register, so load
@@ -150,6 +200,7 @@ impl<T: Target> Registers<T> {
 
         // Was the selected register already holding another value?
         let live = self.live[reg.idx()].replace(idx)?;
+        self.stats.evictions += 1;
 
         let alloc = &mut self.allocs[live.idx()];
         debug_assert_eq!(RegisterState::Reg(reg), alloc.reg);
@@ -158,17 +209,20 @@ impl<T: Target> Registers<T> {
         // by ensuring it has a memory location allocated.
         let (mem, loc) = if let Some(mem) = alloc.mem {
             (mem, alloc.loc)
-        } else if let Some(pos) = self
-            .free_slots
-            .iter()
-            .rposition(|&(generation, _, _)| generation < free_generation)
-        {
-            let (_, mem, loc) = self.free_slots.swap_remove(pos);
-            (mem, loc)
         } else {
-            let new_slot = self.stack_slots;
-            self.stack_slots += 1;
-            (MemorySpace::STACK, new_slot)
+            self.stats.spills += 1;
+            if let Some(pos) = self
+                .free_slots
+                .iter()
+                .rposition(|&(generation, _, _)| generation < free_generation)
+            {
+                let (_, mem, loc) = self.free_slots.swap_remove(pos);
+                (mem, loc)
+            } else {
+                let new_slot = self.stack_slots;
+                self.stack_slots += 1;
+                (MemorySpace::STACK, new_slot)
+            }
         };
 
         // Remember that this value is only in memory now.
@@ -207,10 +261,11 @@ impl<T: Target> Registers<T> {
     }
 
     pub fn sink_load(&mut self, idx: InstIdx, patch_at: usize) -> bool {
-        if self.config.sink_loads != SinkLoads::None && self.float_load(idx).is_none() {
-            match self.config.sink_loads {
+        if self.sink_loads != SinkLoads::None && self.float_load(idx).is_none() {
+            match self.sink_loads {
                 SinkLoads::None | SinkLoads::All => {}
                 SinkLoads::RequireDead | SinkLoads::PreferDead | SinkLoads::SpillAny => {
+                    self.stats.sunk_loads += 1;
                     let pool_idx = self
                         .dirty_pool
                         .push_load(idx, patch_at, self.free_generation);
@@ -223,16 +278,30 @@ impl<T: Target> Registers<T> {
         }
     }
 
+    /// Note that the instruction a load was just sunk into also uses `reg`, so
+    /// the load can't be patched to use that register instead. Allocating the
+    /// instruction's other operands before sinking keeps any reloads from
+    /// landing between the sunk load and the instruction, but then they have
+    /// to be marked this way.
+    pub fn mark_used(&mut self, reg: Register) {
+        self.dirty_pool.mark_dirty(reg);
+    }
+
     fn float_load(&mut self, idx: InstIdx) -> Option<Register> {
         let reg = &mut self.allocs[idx.idx()].reg;
         match *reg {
             RegisterState::Unallocated => None,
             RegisterState::Reg(register) => Some(register),
             RegisterState::SunkLoad(pool_idx) => {
-                let (mut clean_regs, free_generation, patch_at) =
-                    self.dirty_pool.get_clean_regs(pool_idx, idx);
+                let Some((mut clean_regs, free_generation, patch_at, distance)) =
+                    self.dirty_pool.get_clean_regs(pool_idx, idx)
+                else {
+                    self.stats.sink_expired += 1;
+                    *reg = RegisterState::Unallocated;
+                    return None;
+                };
 
-                match self.config.sink_loads {
+                match self.sink_loads {
                     SinkLoads::PreferDead => {
                         let dead_regs = clean_regs & dead_regs(&self.live);
                         if dead_regs != 0 {
@@ -244,9 +313,12 @@ impl<T: Target> Registers<T> {
                 }
 
                 if clean_regs == 0 {
+                    self.stats.sink_misses += 1;
                     *reg = RegisterState::Unallocated;
                     None
                 } else {
+                    self.stats.sink_hits += 1;
+                    self.stats.hits_by_distance[distance] += 1;
                     let clean_reg = self.recent.pop_first_in(clean_regs);
                     let other = self.clobber(idx, clean_reg, free_generation);
                     self.target.patch_sunk_load(patch_at, clean_reg, other);
@@ -256,12 +328,12 @@ impl<T: Target> Registers<T> {
         }
     }
 
-    pub fn finish(self) -> (T, Location) {
-        (self.target, self.stack_slots)
+    pub fn finish(self) -> (T, Location, Stats) {
+        (self.target, self.stack_slots, self.stats)
     }
 }
 
-fn dead_regs(items: &Vec<Option<InstIdx>>) -> u32 {
+fn dead_regs(items: &[Option<InstIdx>]) -> u32 {
     let mut dead_regs = 0;
     for (reg, live) in items.iter().enumerate() {
         if live.is_none() {
@@ -281,10 +353,13 @@ struct QueuedLoad {
 // after the load; on the Prospero challenge with 15 registers, usually there
 // are only registers that are clean and not live within a couple of loads,
 // and the longest was 27 loads later. So allowing up to 32 loads in the queue
-// is generous.
+// is generous. With fewer registers, every register gets dirty sooner, so
+// there's no need to shrink it: with 4 registers, hits come within 5 loads.
+const DIRTY_POOL_SIZE: usize = 32;
+
 struct DirtyPool {
-    loads: [QueuedLoad; 32],
-    patch_at: [usize; 32],
+    loads: [QueuedLoad; DIRTY_POOL_SIZE],
+    patch_at: [usize; DIRTY_POOL_SIZE],
     front: usize,
     dirty_before: Vec<usize>,
 }
@@ -321,11 +396,15 @@ impl DirtyPool {
         self.dirty_before[i.idx()] = self.front;
     }
 
-    fn get_clean_regs(&self, idx: DirtyPoolIndex, load: InstIdx) -> (u32, u16, usize) {
+    fn get_clean_regs(
+        &self,
+        idx: DirtyPoolIndex,
+        load: InstIdx,
+    ) -> Option<(u32, u16, usize, usize)> {
         let mut idx = usize::from(idx);
         let queued_load = self.loads[idx];
         if queued_load.inst != Some(load) {
-            return (0, 0, 0);
+            return None;
         }
         let patch_at = self.patch_at[idx];
 
@@ -341,7 +420,8 @@ impl DirtyPool {
                 result |= 1 << reg;
             }
         }
-        (result, queued_load.free_generation, patch_at)
+        let distance = self.front - 1 - idx;
+        Some((result, queued_load.free_generation, patch_at, distance))
     }
 }
 
@@ -0,0 +1,107 @@
+use std::io;
+
+use crate::ir::bytecode::Program;
+use crate::ir::{BinOp, Inst, Insts, TernOp, UnOp, powi};
+
+/// Write a Rust expression evaluating the program at one point, as a plain
+/// `fn(f32, f32, f32) -> f32` of x, y, and z which needs nothing from this
+/// crate at run time. Every instruction becomes one `let`, and constants are
+/// spelled as their bits so they come back exactly. Any `powi` is expanded
+/// first, since `f32::powi` doesn't promise to round the same way.
+pub fn write_fn(mut out: impl io::Write, insts: &Insts) -> io::Result<()> {
+    if powi::contains(&insts.pool) {
+        return write_fn(out, &powi::expand(&insts.pool, Insts::default()));
+    }
+    writeln!(out, "{{")?;
+    writeln!(out, "#[allow(unused_variables, clippy::all)]")?;
+    writeln!(out, "fn shape(x: f32, y: f32, z: f32) -> f32 {{")?;
+    for (idx, inst) in insts.pool.iter().enumerate() {
+        write!(out, "let v{idx}: f32 = ")?;
+        match *inst {
+            Inst::Const { value } => write!(out, "f32::from_bits({:#010x})", value.bits())?,
+            Inst::Var { var } => write!(out, "{}", var.name())?,
+            Inst::UnOp { op, arg } => match op {
+                UnOp::Neg => write!(out, "-v{arg}")?,
+                UnOp::Square => write!(out, "v{arg} * v{arg}")?,
+                UnOp::Sqrt => write!(out, "v{arg}.sqrt()")?,
+                UnOp::Abs => write!(out, "v{arg}.abs()")?,
+                UnOp::Exp => write!(out, "v{arg}.exp()")?,
+                UnOp::Ln => write!(out, "v{arg}.ln()")?,
+                UnOp::Sin => write!(out, "v{arg}.sin()")?,
+                UnOp::Cos => write!(out, "v{arg}.cos()")?,
+                UnOp::Floor => write!(out, "v{arg}.floor()")?,
+                UnOp::Ceil => write!(out, "v{arg}.ceil()")?,
+                UnOp::Round => write!(out, "v{arg}.round_ties_even()")?,
+                UnOp::Recip => write!(out, "1.0 / v{arg}")?,
+                UnOp::Rsqrt => write!(out, "1.0 / v{arg}.sqrt()")?,
+                UnOp::Barrier => write!(out, "v{arg}")?,
+                UnOp::PowI(_) => unreachable!("powi was expanded above"),
+            },
+            Inst::BinOp { op, args: [a, b] } => match op {
+                BinOp::Add => write!(out, "v{a} + v{b}")?,
+                BinOp::Sub => write!(out, "v{a} - v{b}")?,
+                BinOp::Mul => write!(out, "v{a} * v{b}")?,
+                BinOp::Min => write!(out, "v{a}.min(v{b})")?,
+                BinOp::Max => write!(out, "v{a}.max(v{b})")?,
+                BinOp::Mod => write!(out, "v{a} - v{b} * (v{a} / v{b}).floor()")?,
+            },
+            Inst::Fma { args: [a, b, c] } => write!(out, "v{a}.mul_add(v{b}, v{c})")?,
+            Inst::TernOp {
+                op,
+                args: [a, b, c],
+            } => match op {
+                TernOp::Clamp => write!(out, "v{a}.max(v{b}).min(v{c})")?,
+                TernOp::Lerp => write!(out, "v{a} + (v{b} - v{a}) * v{c}")?,
+            },
+            Inst::Load { .. } => unimplemented!("load instruction in Rust source"),
+        }
+        writeln!(out, ";")?;
+    }
+    writeln!(out, "v{}", insts.pool.len() - 1)?;
+    writeln!(out, "}}")?;
+    writeln!(out, "shape")?;
+    writeln!(out, "}}")
+}
+
+/// Write a Rust expression which rebuilds `program` with
+/// [`Program::decode`] from arrays of its encoding, so the only cost at run
+/// time is checking it.
+pub fn write_program(mut out: impl io::Write, program: &Program) -> io::Result<()> {
+    let (words, consts) = program.encode();
+    write!(
+        out,
+        "::live_long_and_prospero::ir::bytecode::Program::decode(&["
+    )?;
+    for word in words {
+        write!(out, "{word},")?;
+    }
+    write!(out, "], &[")?;
+    for value in consts {
+        write!(out, "f32::from_bits({:#010x}),", value.to_bits())?;
+    }
+    writeln!(out, "]).unwrap()")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::io::read;
+
+    #[test]
+    fn test_write_fn() {
+        let text = "x var-x\ny var-y\nr const 0.5\nx2 square x\ny2 square y\n\
+            d2 add x2 y2\nd sqrt d2\nout sub r d\n";
+        let insts = read(text.as_bytes(), Insts::default()).unwrap();
+        let mut out = Vec::new();
+        write_fn(&mut out, &insts).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(
+            out.contains("let v2: f32 = f32::from_bits(0x3f000000);\n"),
+            "{out}"
+        );
+        assert!(
+            out.contains("let v6: f32 = v5.sqrt();\nlet v7: f32 = v2 - v6;\nv7\n"),
+            "{out}"
+        );
+    }
+}
@@ -0,0 +1,117 @@
+use crate::ir::memoize::Memoized;
+use crate::ir::{Inst, VarSet};
+
+use super::loops::LoopNest;
+
+// Every value in a buffer is an `f32`.
+const VALUE_BYTES: usize = 4;
+
+/// Estimated memory traffic of one memoized function while rendering a grid,
+/// from the loads and stores in its IR and how the [`LoopNest`] calls it.
+/// Register spills aren't counted, and neither is packing the result into
+/// bits, which the estimate treats like any other output.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Traffic {
+    pub vars: VarSet,
+    pub calls: usize,
+    /// Bytes each call reads from buffers, including its own input
+    /// coordinates. A value from a buffer whose lanes run along another
+    /// variable is broadcast from one float.
+    pub read: usize,
+    /// Bytes of constants each call reads, counting one float per load.
+    /// These almost always hit in the L1 cache, so they're kept separate.
+    pub consts: usize,
+    /// Bytes each call's outputs take, plus the caller storing the input
+    /// coordinates of a function of one variable.
+    pub written: usize,
+    /// Bytes of buffer holding this function's outputs at any one time,
+    /// which is what needs to stay in cache between writing and reading it.
+    pub footprint: usize,
+}
+
+impl Traffic {
+    /// Bytes read and written from buffers over the whole grid.
+    pub fn total(&self) -> usize {
+        (self.read + self.written) * self.calls
+    }
+}
+
+/// Estimate the buffer traffic of each function that `nest` calls to cover a
+/// grid `extent[var]` points along each `Var`, in the order of its buffers.
+pub fn traffic(memoized: &Memoized, nest: &LoopNest, extent: [usize; 3]) -> Vec<Traffic> {
+    let calls = nest.calls(extent);
+    nest.buffers
+        .iter()
+        .map(|buffer| {
+            let func = &memoized.funcs[buffer.func.idx() - 1];
+            let lanes = usize::from(buffer.lanes);
+            let mut traffic = Traffic {
+                vars: buffer.func,
+                calls: calls
+                    .iter()
+                    .filter(|&&(vars, _)| vars == buffer.func)
+                    .map(|&(_, count)| count)
+                    .sum(),
+                read: 0,
+                consts: 0,
+                written: func.outputs.iter().flatten().count() * lanes * VALUE_BYTES,
+                footprint: buffer.len(extent, func.outputs.len()) * VALUE_BYTES,
+            };
+            for inst in func.insts.iter() {
+                let &Inst::Load { vars, .. } = inst else {
+                    continue;
+                };
+                if vars == VarSet::default() {
+                    traffic.consts += VALUE_BYTES;
+                    continue;
+                }
+                if vars == func.vars {
+                    // the caller stores the coordinates that this reads
+                    traffic.written += lanes * VALUE_BYTES;
+                }
+                let from = nest.buffer(vars).unwrap();
+                let width = if from.lane_var == buffer.lane_var {
+                    lanes
+                } else {
+                    1
+                };
+                traffic.read += width * VALUE_BYTES;
+            }
+            traffic
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::Var;
+    use crate::ir::memoize::MemoBuilder;
+
+    #[test]
+    fn test_circle_traffic() {
+        let text = "x var-x\ny var-y\nx2 square x\ny2 square y\nr2 add x2 y2\n\
+            r sqrt r2\nhalf const 0.5\nout sub half r\n";
+        let memoized = crate::ir::io::read(text.as_bytes(), MemoBuilder::new()).unwrap();
+        let [x, y] = [Var::X, Var::Y].map(VarSet::from);
+        let nest = LoopNest::new(x | y, 4, Var::X);
+        let traffic = traffic(&memoized, &nest, [10, 10, 1]);
+        let summary: Vec<_> = traffic
+            .iter()
+            .map(|t| (t.vars, t.calls, t.read, t.consts, t.written, t.footprint))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                // 4 coordinates in, and their squares out, for 12 columns
+                // rounded up to the vector width
+                (x, 3, 16, 0, 32, 2 * 12 * 4),
+                // likewise for rows, but only the current group of them is kept
+                (y, 3, 16, 0, 32, 2 * 4 * 4),
+                // four squares of x and one of y, broadcast
+                (x | y, 30, 16 + 4, 4, 16, 4 * 4),
+            ]
+        );
+        assert_eq!(traffic[2].total(), 30 * 36);
+    }
+}
@@ -1,14 +1,29 @@
-use clap::Args;
+use clap::{Args, ValueEnum};
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
 use std::io;
+use thiserror::Error;
 
+use crate::ir::interval::Interval;
+use crate::ir::io::ConstFormat;
 use crate::ir::memoize::{Memoized, MemoizedFunc};
-use crate::ir::{BinOp, Inst, InstIdx, Location, UnOp, Var, VarSet};
+use crate::ir::split::split;
+use crate::ir::{BinOp, Inst, InstIdx, Location, TernOp, UnOp, Var, VarSet};
 
-use super::regalloc::{Allocation, Config, Registers, Target};
+use super::abi::{ABI_VERSION, Manifest, part_name};
+use super::loops::{LoopNest, UsesZ, check_xy};
+use super::regalloc::{Allocation, Config, Registers, SinkLoads, Stats, Target};
 use super::{MemorySpace, Register};
 
-const STRIDE: u8 = 4;
+// Where the pointers to the memory spaces for variables are passed, in
+// argument order, following the System V calling convention: the first six in
+// registers, and the seventh on the stack, just above the return address.
+const ARGS: [&str; 7] = ["%rdi", "%rsi", "%rdx", "%rcx", "%r8", "%r9", "8(%rsp)"];
+
+// The registers that generated code addresses those memory spaces through.
+// The prologue loads the seventh pointer into `%r10`, which the calling
+// convention lets us clobber.
+const ARG_REGISTERS: [&str; 7] = ["%rdi", "%rsi", "%rdx", "%rcx", "%r8", "%r9", "%r10"];
 
 #[derive(Args, Clone, Copy, Debug)]
 pub struct X86Config {
@@ -16,6 +31,104 @@ pub struct X86Config {
     #[arg(long, default_value_t = true, action = clap::ArgAction::Set, value_parser = clap::builder::BoolishValueParser::new())]
     pub vectorize: bool,
 
+    /// Store only one copy of each constant and broadcast it to every vector
+    /// lane when it's loaded. This shrinks read-only data by a factor of the
+    /// vector width, but constants can no longer be used directly as memory
+    /// operands, which increases register pressure.
+    #[arg(long)]
+    pub broadcast_consts: bool,
+
+    /// Don't vectorize programs with fewer than this many instructions in
+    /// total; for tiny programs, the overhead of setting up vector operands
+    /// can outweigh the benefit of processing several points at once.
+    #[arg(long, default_value_t = 0)]
+    pub vectorize_min_insts: usize,
+
+    /// Compile each function with every load-sinking policy and keep
+    /// whichever one has the lowest estimated cost, instead of using the
+    /// policy given by --sink-loads
+    #[arg(long)]
+    pub tune_sink_loads: bool,
+
+    /// Compute `recip` and `rsqrt` from the `vrcpps` and `vrsqrtps`
+    /// estimates, refined with one Newton-Raphson step, instead of with
+    /// exact division and square roots. The results can be off by a few
+    /// ulps, and differ between CPU vendors, but take a fraction of the
+    /// time.
+    #[arg(long)]
+    pub approx_recip: bool,
+
+    /// Write comments after each function with statistics about how it was
+    /// compiled
+    #[arg(long)]
+    pub stats: bool,
+
+    /// Annotate each instruction with a comment showing which registers hold
+    /// live values at that point
+    #[arg(long)]
+    pub live_ranges: bool,
+
+    /// Have the `xy` function test the sign of each result itself and store
+    /// one bit per point, set where the value is non-negative, instead of
+    /// storing the values. This is all a black-and-white image needs, and
+    /// it's a small fraction of the memory traffic. Programs which use z
+    /// store values as usual, since functions of z read them from `xy`.
+    #[arg(long)]
+    pub pack_bits: bool,
+
+    /// Use the value ranges computed during memoization to replace `min` and
+    /// `max` with a copy of one operand when that operand always wins
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set, value_parser = clap::builder::BoolishValueParser::new())]
+    pub use_ranges: bool,
+
+    /// Where a `neg` is the only use of a `sub`, or of a tree of `min` and
+    /// `max` over such values, compute the negated value directly by
+    /// swapping operands and operators, instead of flipping its sign with a
+    /// separate `xor` afterward
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set, value_parser = clap::builder::BoolishValueParser::new())]
+    pub fold_negations: bool,
+
+    /// Which points share a vector when vectorizing
+    #[arg(long, default_value_t = VectorAxis::default(), value_enum)]
+    pub vector_axis: VectorAxis,
+
+    /// How many points share a vector when vectorizing
+    #[arg(long, default_value_t = Stride::default(), value_enum)]
+    pub stride: Stride,
+
+    /// Split functions with more IR instructions than this into a chain of
+    /// smaller functions, `xy`, `xy_part1`, and so on, which the caller runs
+    /// one after another. Values that cross from one part to the next go
+    /// through scratch locations after the function's outputs. This keeps
+    /// each part's code small enough to stay in the instruction cache.
+    #[arg(long)]
+    pub max_insts: Option<usize>,
+
+    /// How to spell constants in the comments beside the constant pool
+    #[arg(long, default_value_t = ConstFormat::default(), value_enum)]
+    pub const_format: ConstFormat,
+
+    /// Sort the constant pool by value and each function's outputs by a hash
+    /// of what they compute, and name each constant's label after its bits,
+    /// so regenerating code after changing a pass only changes what the pass
+    /// actually changed
+    #[arg(long)]
+    pub stable: bool,
+
+    /// End functions which use vectors wider than 128 bits with
+    /// `vzeroupper`, so the caller's SSE code doesn't pay for switching out
+    /// of AVX state. Callers which are built for AVX themselves don't need
+    /// it, and nor do chained parts of one function, but the Rust runtime
+    /// and the C harness usually aren't.
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set, value_parser = clap::builder::BoolishValueParser::new())]
+    pub vzeroupper: bool,
+
+    /// How many vector registers the generated code may use, from `%xmm0`
+    /// up. The 32-bit ABI only has 8, and fewer still is a good way to test
+    /// how the allocator copes under pressure. `fma` needs at least 3.
+    #[arg(long, default_value_t = 16, value_parser = clap::value_parser!(u8).range(3..=16))]
+    pub registers: u8,
+
     #[command(flatten)]
     pub regalloc: Config,
 }
@@ -25,69 +138,440 @@ impl Default for X86Config {
         X86Config {
             regalloc: Config::default(),
             vectorize: true,
+            broadcast_consts: false,
+            vectorize_min_insts: 0,
+            tune_sink_loads: false,
+            approx_recip: false,
+            stats: false,
+            live_ranges: false,
+            pack_bits: false,
+            use_ranges: true,
+            fold_negations: true,
+            vector_axis: VectorAxis::default(),
+            stride: Stride::default(),
+            max_insts: None,
+            const_format: ConstFormat::default(),
+            stable: false,
+            vzeroupper: true,
+            registers: 16,
+        }
+    }
+}
+
+/// Which points the lanes of a vector hold.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum VectorAxis {
+    /// Adjacent columns, or adjacent rows in functions that don't depend on
+    /// x. Every function is vectorized, which suits 2D images.
+    #[default]
+    X,
+    /// Adjacent rows, or adjacent columns in functions that don't depend on
+    /// y. Every function is vectorized, which suits tall, narrow images and
+    /// callers that consume a column at a time.
+    Y,
+    /// Adjacent z-slices, for volume rendering. Only functions that depend on
+    /// z are vectorized; the rest compute one point at a time, and their
+    /// results are shared by every slice.
+    Z,
+}
+
+impl VectorAxis {
+    pub fn var(self) -> Var {
+        match self {
+            VectorAxis::X => Var::X,
+            VectorAxis::Y => Var::Y,
+            VectorAxis::Z => Var::Z,
         }
     }
 }
 
-pub fn write(mut out: impl io::Write, config: X86Config, memoized: &Memoized) -> io::Result<()> {
-    let stride = if config.vectorize { STRIDE } else { 1 };
+/// The width of the vector registers to use, in lanes of one `f32` each.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum Stride {
+    /// 128-bit `xmm` registers
+    #[default]
+    #[value(name = "4")]
+    Four,
+    /// 256-bit `ymm` registers
+    #[value(name = "8")]
+    Eight,
+    /// 512-bit `zmm` registers, which need a CPU with AVX-512
+    #[value(name = "16")]
+    Sixteen,
+}
+
+impl Stride {
+    pub fn lanes(self) -> u8 {
+        match self {
+            Stride::Four => 4,
+            Stride::Eight => 8,
+            Stride::Sixteen => 16,
+        }
+    }
+}
+
+/// The load-sinking policy that [`X86Config::tune_sink_loads`] picked for
+/// each generated function, by name, so later runs can reuse the choices
+/// instead of compiling every function once per policy again. A policy only
+/// decides how good the code is, never whether it's correct, so choices
+/// made for an older version of a program are still safe to apply to it.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Tuning {
+    choices: BTreeMap<String, SinkLoads>,
+}
+
+#[derive(Debug, Error)]
+pub enum TuningError {
+    #[error("tuning line {0}: expected a function name and a policy")]
+    Syntax(usize),
+    #[error("tuning line {0}: unknown policy {1:?}")]
+    Policy(usize, String),
+}
+
+impl Tuning {
+    pub fn get(&self, name: &str) -> Option<SinkLoads> {
+        self.choices.get(name).copied()
+    }
+
+    pub fn set(&mut self, name: impl Into<String>, sink_loads: SinkLoads) {
+        self.choices.insert(name.into(), sink_loads);
+    }
+
+    /// Write each choice as a line of the function's name and the policy,
+    /// spelled as for `--sink-loads`.
+    pub fn write(&self, mut out: impl io::Write) -> io::Result<()> {
+        for (name, sink_loads) in self.choices.iter() {
+            let policy = sink_loads.to_possible_value().unwrap();
+            writeln!(out, "{name} {}", policy.get_name())?;
+        }
+        Ok(())
+    }
+
+    /// Read choices in the form [`Tuning::write`] writes them.
+    pub fn parse(text: &str) -> Result<Tuning, TuningError> {
+        let mut tuning = Tuning::default();
+        for (line_number, line) in text.lines().enumerate() {
+            let line_number = line_number + 1;
+            match line.split_ascii_whitespace().collect::<Vec<_>>()[..] {
+                [] => {}
+                [name, policy] => {
+                    let sink_loads = SinkLoads::from_str(policy, false)
+                        .map_err(|_| TuningError::Policy(line_number, policy.to_string()))?;
+                    tuning.set(name, sink_loads);
+                }
+                _ => return Err(TuningError::Syntax(line_number)),
+            }
+        }
+        Ok(tuning)
+    }
+}
+
+pub fn write(out: impl io::Write, config: X86Config, memoized: &Memoized) -> io::Result<()> {
+    write_tuned(out, config, memoized, &mut Tuning::default())
+}
+
+/// Like [`write`], but compiling each function that `tuning` has a choice
+/// for with that load-sinking policy, without tuning it again, and adding
+/// the policy that [`X86Config::tune_sink_loads`] picks for every other
+/// function to `tuning`.
+pub fn write_tuned(
+    mut out: impl io::Write,
+    mut config: X86Config,
+    memoized: &Memoized,
+    tuning: &mut Tuning,
+) -> io::Result<()> {
+    let _span = tracing::info_span!("x86").entered();
+    let canonical;
+    let memoized = if config.stable {
+        let mut copy = memoized.clone();
+        copy.canonicalize();
+        canonical = copy;
+        &canonical
+    } else {
+        memoized
+    };
+    let widened;
+    let memoized = if config.use_ranges {
+        widened = widen_approximate_ranges(config, memoized);
+        &widened
+    } else {
+        memoized
+    };
+    config.vectorize &= should_vectorize(config, memoized);
+    config.pack_bits &= xy_is_result(memoized);
+    let stride = stride(config);
+    let const_copies = const_copies(config);
 
     writeln!(
         out,
-        "# compile with: gcc -Wall -g -O2 -o <output> examples/x86-harness.c <output>.s"
+        "# compile with: gcc -Wall -g -O2 -o <output> <harness>.c <output>.s -lm"
     )?;
     writeln!(out, ".section .rodata")?;
-    writeln!(out, ".align {}", 4 * stride)?;
+    writeln!(out, ".align {}", 4 * const_copies)?;
     writeln!(out, "consts:")?;
-    for (idx, value) in memoized.consts.iter().enumerate() {
-        write!(out, ".L{idx}:")?;
-        for _ in 0..stride {
-            writeln!(out, " .long {:#08x}", value.bits())?;
+    for (idx, &value) in memoized.consts.iter().enumerate() {
+        if config.stable {
+            write!(out, ".Lc{:08x}:", value.bits())?;
+        } else {
+            write!(out, ".L{idx}:")?;
+        }
+        for copy in 0..const_copies {
+            write!(out, " .long {:#08x}", value.bits())?;
+            if copy == 0 {
+                write!(out, " # {}", config.const_format.display(value))?;
+            }
+            writeln!(out)?;
         }
     }
 
-    // constant with only the sign bit of an f32 set, used in `neg`
+    // constant with only the sign bit of an f32 set, used in `neg` and `abs`
     let neg_const = memoized.consts.len().try_into().unwrap();
-    for _ in 0..stride {
+    for _ in 0..const_copies {
         writeln!(out, ".long {:#08x}", 1 << 31)?;
     }
+    // +0.0, added to packed results so that -0.0 counts as inside
+    if config.pack_bits {
+        for _ in 0..const_copies {
+            writeln!(out, ".long 0")?;
+        }
+    }
+    // constants for lowering `exp`, `ln`, `sin`, `cos`, `recip`, and
+    // `rsqrt`, at `math_consts`
+    let uses_math = memoized.funcs.iter().any(|func| {
+        func.insts
+            .iter()
+            .any(|inst| matches!(*inst, Inst::UnOp { op, .. } if math_steps(config, op).is_some()))
+    });
+    if uses_math {
+        for (bits, comment) in MATH_CONSTS {
+            for copy in 0..const_copies {
+                write!(out, ".long {bits:#08x}")?;
+                if copy == 0 && !comment.is_empty() {
+                    write!(out, " # {comment}")?;
+                }
+                writeln!(out)?;
+            }
+        }
+    }
 
+    // keep the multi-byte symbols naturally aligned for callers that read
+    // them through typed pointers
+    writeln!(out, ".p2align 2")?;
+    writeln!(out, ".globl abi_version")?;
+    writeln!(out, "abi_version: .long {ABI_VERSION}")?;
     writeln!(out, ".globl stride")?;
     writeln!(out, "stride: .short {}", stride)?;
+    writeln!(out, ".globl xy_packed")?;
+    writeln!(out, "xy_packed: .byte {}", u8::from(config.pack_bits))?;
+    writeln!(out, ".globl vector_axis")?;
+    writeln!(out, "vector_axis: .byte {}", config.vector_axis.var() as u8)?;
 
-    Ok(for func in memoized.funcs.iter() {
+    for func in memoized.funcs.iter() {
+        let parts = parts(config, func);
         writeln!(out)?;
         writeln!(out, ".section .rodata")?;
+        writeln!(out, ".p2align 1")?;
         writeln!(out, ".globl {:?}_size", func.vars)?;
         writeln!(out, "{:?}_size:", func.vars)?;
-        writeln!(out, ".short {}", func.outputs.len())?;
+        writeln!(out, ".short {}", parts[0].outputs.len())?;
 
-        writeln!(out)?;
-        writeln!(out, ".text")?;
-        writeln!(out, ".p2align 4")?;
-        writeln!(out, ".globl {:?}", func.vars)?;
-        writeln!(out, "{:?}:", func.vars)?;
-        let vectors = if config.vectorize {
-            &[func.vars, Var::X.into()][..]
-        } else {
-            &[]
-        };
-        write_func(
-            &mut out,
-            config.regalloc,
-            neg_const,
-            func,
-            vectors.iter().copied(),
-        )?;
-    })
+        for (idx, part) in parts.iter().enumerate() {
+            let name = part_name(func.vars, idx);
+            writeln!(out)?;
+            writeln!(out, ".text")?;
+            writeln!(out, ".p2align 4")?;
+            writeln!(out, ".globl {name}")?;
+            writeln!(out, "{name}:")?;
+            let vectors = vectors(config, part);
+            write_func(&mut out, config, neg_const, part, &vectors, &name, tuning)?;
+        }
+    }
+    Ok(())
+}
+
+// The chain of functions to generate in place of `func`.
+fn parts(config: X86Config, func: &MemoizedFunc) -> Vec<MemoizedFunc> {
+    match config.max_insts {
+        Some(max_insts) => split(func, max_insts),
+        None => vec![func.clone()],
+    }
+}
+
+/// Describe the functions that [`write`] generates, including which register
+/// each memory space's pointer is passed in.
+pub fn manifest(mut config: X86Config, memoized: &Memoized) -> Manifest {
+    config.vectorize &= should_vectorize(config, memoized);
+    config.pack_bits &= xy_is_result(memoized);
+    let stride = stride(config);
+    let mut manifest = Manifest::new(
+        memoized,
+        stride.into(),
+        config.pack_bits,
+        config.vector_axis.var(),
+        Some(&ARGS),
+    );
+    for (function, func) in manifest.functions.iter_mut().zip(&memoized.funcs) {
+        let parts = parts(config, func);
+        function.outputs = parts[0].outputs.len();
+        function.parts = parts.len();
+    }
+    manifest
+}
+
+/// The loops for rendering an image with the functions that [`write`]
+/// generates, for [`harness::write`](super::harness::write), unless the
+/// program uses z.
+pub fn loop_nest(config: X86Config, memoized: &Memoized) -> Result<LoopNest, UsesZ> {
+    check_xy(memoized)?;
+    Ok(xy_loop_nest(config, memoized))
+}
+
+// The same loops whether or not the program uses z, for estimating how
+// often each function of x and y runs.
+fn xy_loop_nest(mut config: X86Config, memoized: &Memoized) -> LoopNest {
+    config.vectorize &= should_vectorize(config, memoized);
+    let stride = stride(config);
+    let xy = VarSet::from(Var::X) | VarSet::from(Var::Y);
+    let mut nest = LoopNest::new(xy, stride.into(), config.vector_axis.var());
+    for buffer in nest.buffers.iter_mut() {
+        buffer.parts = parts(config, &memoized.funcs[buffer.func.idx() - 1]).len();
+    }
+    nest
+}
+
+/// What [`write`] would generate for one memoized function, without the
+/// assembly text itself.
+#[derive(Clone, Debug)]
+pub struct FuncSummary {
+    pub vars: VarSet,
+    /// Which function of the chain this is, if `--max-insts` split it.
+    pub part: usize,
+    pub insts: usize,
+    pub x86_insts: usize,
+    pub stack_slots: Location,
+    pub cost: usize,
+    pub stats: Stats,
+}
+
+/// Run code generation for every function in `memoized` and report the size
+/// of the result, for comparing the effects of different passes and options.
+pub fn summarize(mut config: X86Config, memoized: &Memoized) -> Vec<FuncSummary> {
+    let widened;
+    let memoized = if config.use_ranges {
+        widened = widen_approximate_ranges(config, memoized);
+        &widened
+    } else {
+        memoized
+    };
+    config.vectorize &= should_vectorize(config, memoized);
+    config.pack_bits &= xy_is_result(memoized);
+    let neg_const = memoized.consts.len().try_into().unwrap();
+    memoized
+        .funcs
+        .iter()
+        .flat_map(|func| parts(config, func).into_iter().enumerate())
+        .map(|(part, func)| {
+            let (target, stack_slots, stats, _) =
+                compile_func(config, neg_const, &func, &vectors(config, &func));
+            FuncSummary {
+                vars: func.vars,
+                part,
+                insts: func.insts.len(),
+                x86_insts: target
+                    .insts
+                    .iter()
+                    .filter(|inst| !matches!(inst, X86Inst::Placeholder))
+                    .count(),
+                stack_slots,
+                cost: target.cost(stack_slots),
+                stats,
+            }
+        })
+        .collect()
+}
+
+/// The cost model's estimate for rendering a `size`×`size` image: the
+/// [cost](FuncSummary::cost) of each function from [`summarize`], times how
+/// many times the [`loop_nest`] calls it. Functions of z aren't counted.
+pub fn image_cost(
+    config: X86Config,
+    memoized: &Memoized,
+    summaries: &[FuncSummary],
+    size: u16,
+) -> usize {
+    let size = usize::from(size);
+    let calls = xy_loop_nest(config, memoized).calls([size, size, 1]);
+    summaries
+        .iter()
+        .map(|summary| {
+            let count = calls
+                .iter()
+                .find(|&&(func, _)| func == summary.vars)
+                .map_or(0, |&(_, count)| count);
+            summary.cost * count
+        })
+        .sum()
+}
+
+// Vector code only pays for its extra setup on larger programs.
+fn should_vectorize(config: X86Config, memoized: &Memoized) -> bool {
+    let insts: usize = memoized.funcs.iter().map(|func| func.insts.len()).sum();
+    insts >= config.vectorize_min_insts
+}
+
+// Whether `xy` stores the final result, rather than values which functions
+// of z read from it and which packing would destroy.
+fn xy_is_result(memoized: &Memoized) -> bool {
+    check_xy(memoized).is_ok()
+}
+
+// How many points vectorized functions process at once.
+fn stride(config: X86Config) -> u8 {
+    if config.vectorize {
+        config.stride.lanes()
+    } else {
+        1
+    }
+}
+
+// How many times each constant is repeated in the pool, so vector
+// instructions can use it directly as a memory operand.
+fn const_copies(config: X86Config) -> u8 {
+    if config.broadcast_consts {
+        1
+    } else {
+        stride(config)
+    }
+}
+
+fn vectors(config: X86Config, func: &MemoizedFunc) -> Vec<VarSet> {
+    if !config.vectorize {
+        return Vec::new();
+    }
+    match config.vector_axis {
+        VectorAxis::X | VectorAxis::Y => vec![func.vars, config.vector_axis.var().into()],
+        // every space that depends on z has a separate value per slice
+        VectorAxis::Z if func.vars.contains(Var::Z) => {
+            let [x, y, z] = [Var::X, Var::Y, Var::Z].map(VarSet::from);
+            vec![z, x | z, y | z, x | y | z]
+        }
+        VectorAxis::Z => Vec::new(),
+    }
 }
 
 fn emit(
-    config: Config,
+    config: X86Config,
     neg_const: Location,
     func: &MemoizedFunc,
-    vectors: impl IntoIterator<Item = VarSet>,
-) -> (X86Target, Location) {
+    vectors: &[VarSet],
+) -> (X86Target, Location, Stats) {
+    let copied;
+    let func = if func.outputs.iter().flatten().any(|def| is_load(func, *def)) {
+        copied = copy_loaded_outputs(func);
+        &copied
+    } else {
+        func
+    };
     let mut allocs: Vec<Allocation> = func
         .insts
         .iter()
@@ -113,20 +597,99 @@ fn emit(
         alloc
     });
 
-    let mut regs = Registers::new(config, allocs, 16, X86Target::new(vectors));
+    // `exp`, `ln`, `sin`, `cos`, `recip`, `rsqrt`, and `mod` take several
+    // instructions each, and every intermediate value gets an allocation of
+    // its own after the constants they use
+    let math_alloc = allocs.len();
+    let math_const = math_consts(config, neg_const);
+    for loc in 0..MATH_CONSTS.len() {
+        let mut alloc = Allocation::default();
+        alloc.initial_location(VarSet::default().into(), math_const + loc as Location);
+        allocs.push(alloc);
+    }
+    let mut scratch = vec![0; func.insts.len()];
+    for (idx, inst) in func.insts.iter().enumerate() {
+        if let Some(steps) = inst_steps(config, inst) {
+            scratch[idx] = allocs.len();
+            allocs.resize(allocs.len() + steps.len() - 1, Allocation::default());
+        }
+    }
+
+    let span = tracing::info_span!(
+        "regalloc",
+        sink_loads = ?config.regalloc.sink_loads(config.registers.into()),
+        stack_slots = tracing::field::Empty,
+        evictions = tracing::field::Empty,
+        sunk_loads = tracing::field::Empty,
+    )
+    .entered();
+    let xy = VarSet::from(Var::X) | VarSet::from(Var::Y);
+    let pack = (config.pack_bits && func.vars == xy).then(|| (func.vars.into(), neg_const + 1));
+    let target = X86Target::new(
+        vectors.iter().copied(),
+        config.vector_axis,
+        config.stride.lanes(),
+        const_copies(config),
+        pack,
+    );
+    let mut regs = Registers::new(config.regalloc, allocs, config.registers.into(), target);
 
     for (idx, inst) in func.insts.iter().enumerate().rev() {
         let idx = idx.try_into().unwrap();
+        if let Inst::BinOp { op, args } = *inst
+            && let Some(arg) = winner(config, func, op, args)
+        {
+            let dst = regs.get_output_reg(idx);
+            let src = regs.get_reg(arg);
+            if src != dst {
+                regs.target.insts.push(X86Inst::XmmUnaryRmRVex {
+                    op: XmmUnaryRmRVexOpcode::Vmovaps,
+                    src: Xmm::from(src).into(),
+                    dst: dst.into(),
+                });
+            }
+            continue;
+        }
         match *inst {
             Inst::Const { .. } | Inst::Var { .. } => {
                 unimplemented!("{inst:?} not allowed in memoized functions")
             }
+            Inst::UnOp {
+                op: UnOp::Exp | UnOp::Ln | UnOp::Sin | UnOp::Cos | UnOp::Recip | UnOp::Rsqrt,
+                ..
+            }
+            | Inst::BinOp { op: BinOp::Mod, .. }
+            | Inst::TernOp { .. } => {
+                let steps = inst_steps(config, inst).unwrap();
+                let args = inst.args();
+                let operand = |operand| match operand {
+                    Operand::Arg => args[0],
+                    Operand::Arg2 => args[1],
+                    Operand::Arg3 => args[2],
+                    Operand::Step(step) => {
+                        (scratch[idx.idx()] + usize::from(step)).try_into().unwrap()
+                    }
+                    Operand::Const(c) => (math_alloc + c as usize).try_into().unwrap(),
+                };
+                // the last step computes the instruction's own value
+                for (step, &inst) in steps.iter().enumerate().rev() {
+                    let dst = if step == steps.len() - 1 {
+                        idx
+                    } else {
+                        operand(Operand::Step(step.try_into().unwrap()))
+                    };
+                    emit_step(&mut regs, inst, dst, operand);
+                }
+            }
             Inst::UnOp { op, arg } => {
                 let dst = regs.get_output_reg(idx).into();
+                let scalar = regs.target.scalar();
                 let inst = match op {
                     UnOp::Neg => {
-                        let sign = sink_load(&mut regs, neg_alloc);
-                        let arg = regs.get_reg(arg).into();
+                        // there's no scalar xor, so this always reads a full
+                        // vector from memory
+                        let arg = Xmm(regs.get_reg(arg));
+                        let sign = sink_load(&mut regs, neg_alloc, false, &[arg]);
                         X86Inst::XmmRmR {
                             op: XmmRmROpcode::Vxorps,
                             src1: arg,
@@ -137,82 +700,1001 @@ fn emit(
                     UnOp::Square => {
                         let arg = regs.get_reg(arg).into();
                         X86Inst::XmmRmR {
-                            op: XmmRmROpcode::Vmulps,
+                            op: XmmRmROpcode::binop(BinOp::Mul, scalar),
                             src1: arg,
                             src2: arg.into(),
                             dst,
                         }
                     }
+                    UnOp::Sqrt if scalar => {
+                        // The upper lanes are copied from src1, but we don't
+                        // care what they are, so avoid using another register.
+                        let arg = sink_load(&mut regs, arg, true, &[]);
+                        X86Inst::XmmRmR {
+                            op: XmmRmROpcode::Vsqrtss,
+                            src1: dst,
+                            src2: arg,
+                            dst,
+                        }
+                    }
                     UnOp::Sqrt => {
-                        let arg = sink_load(&mut regs, arg);
+                        let arg = sink_load(&mut regs, arg, false, &[]);
                         X86Inst::XmmUnaryRmRVex {
                             op: XmmUnaryRmRVexOpcode::Vsqrtps,
                             src: arg,
                             dst,
                         }
                     }
+                    UnOp::Abs => {
+                        // clearing the sign bit is `andps` with every other
+                        // bit set, but `andnps` inverts its first operand, so
+                        // it can share the mask that `neg` uses; as with
+                        // `neg`, there's no scalar form
+                        let sign = Xmm(regs.get_reg(neg_alloc));
+                        let arg = sink_load(&mut regs, arg, false, &[sign]);
+                        X86Inst::XmmRmR {
+                            op: XmmRmROpcode::Vandnps,
+                            src1: sign,
+                            src2: arg,
+                            dst,
+                        }
+                    }
+                    UnOp::Floor | UnOp::Ceil | UnOp::Round => {
+                        let imm = match op {
+                            UnOp::Floor => ROUND_FLOOR,
+                            UnOp::Ceil => ROUND_CEIL,
+                            _ => ROUND_NEAREST,
+                        };
+                        let src = sink_load(&mut regs, arg, scalar, &[]);
+                        X86Inst::XmmRound {
+                            imm,
+                            scalar,
+                            src,
+                            dst,
+                        }
+                    }
+                    UnOp::Exp | UnOp::Ln | UnOp::Sin | UnOp::Cos | UnOp::Recip | UnOp::Rsqrt => {
+                        unreachable!("lowered above")
+                    }
+                    UnOp::Barrier => unreachable!("barriers are removed during memoization"),
+                    UnOp::PowI(_) => unreachable!("powi is expanded during memoization"),
                 };
                 regs.target.insts.push(inst);
             }
             Inst::BinOp { op, args: [a, b] } => {
-                // can't call get_reg between sink_load and get_output_reg so we
-                // need to allocate operands in this order
+                // the output has to be allocated first and a sunk load last,
+                // so no reloads land between the sunk load and the instruction
                 let dst = regs.get_output_reg(idx).into();
-                let src2 = sink_load(&mut regs, b);
-                let src1 = regs.get_reg(a).into();
+                let scalar = regs.target.scalar();
+                let src1 = Xmm(regs.get_reg(a));
+                // the same value in both operands has to be in a register
+                // anyway, and sinking one load would leave it half-patched
+                let src2 = if a == b {
+                    src1.into()
+                } else {
+                    sink_load(&mut regs, b, scalar, &[src1])
+                };
                 regs.target.insts.push(X86Inst::XmmRmR {
-                    op: match op {
-                        BinOp::Add => XmmRmROpcode::Vaddps,
-                        BinOp::Sub => XmmRmROpcode::Vsubps,
-                        BinOp::Mul => XmmRmROpcode::Vmulps,
-                        BinOp::Min => XmmRmROpcode::Vminps,
-                        BinOp::Max => XmmRmROpcode::Vmaxps,
-                    },
+                    op: XmmRmROpcode::binop(op, scalar),
+                    src1,
+                    src2,
+                    dst,
+                });
+            }
+            Inst::Fma { args: [a, b, c] } => {
+                let dst = Xmm(regs.get_output_reg(idx));
+                let scalar = regs.target.scalar();
+                // One operand can come from memory, if it's used only once
+                // here: a factor if either can, or else the addend. That
+                // decides the form, along with which operand, if any, landed
+                // in `dst`, since every form overwrites one of its operands.
+                // If none did, one gets copied there first.
+                let sinkable = |regs: &Registers<X86Target>, arg: InstIdx| {
+                    [a, b, c].iter().filter(|&&other| other == arg).count() == 1
+                        && in_memory(regs, arg, scalar)
+                };
+                let [a, b] = if !sinkable(&regs, b) && sinkable(&regs, a) {
+                    [b, a]
+                } else {
+                    [a, b]
+                };
+                let addend_in_memory = !sinkable(&regs, b) && sinkable(&regs, c);
+                let (mem, others) = if addend_in_memory {
+                    (c, [a, b])
+                } else {
+                    (b, [a, c])
+                };
+                let [x, y] = others.map(|arg| Xmm(regs.get_reg(arg)));
+                // `dst` is read too, or written by the copy before the
+                // instruction reads `mem`
+                let mem = if sinkable(&regs, mem) {
+                    sink_load(&mut regs, mem, scalar, &[x, y, dst])
+                } else {
+                    Xmm(regs.get_reg(mem)).into()
+                };
+                let in_dst = |reg: Xmm| reg.0 == dst.0;
+                let mem_in_dst = matches!(mem, XmmMem::Xmm(reg) if in_dst(reg));
+                let (form, src1, src2, copy) = if addend_in_memory {
+                    // `x` and `y` are the factors
+                    match () {
+                        _ if in_dst(x) => (FmaForm::Mul213, y, mem, None),
+                        _ if in_dst(y) => (FmaForm::Mul213, x, mem, None),
+                        _ if mem_in_dst => (FmaForm::Add231, x, y.into(), None),
+                        _ => (FmaForm::Mul213, y, mem, Some(x)),
+                    }
+                } else {
+                    // `x` is the other factor and `y` is the addend
+                    match () {
+                        _ if in_dst(y) => (FmaForm::Add231, x, mem, None),
+                        _ if in_dst(x) => (FmaForm::Mul132, y, mem, None),
+                        _ if mem_in_dst => (FmaForm::Mul132, y, x.into(), None),
+                        _ => (FmaForm::Add231, x, mem, Some(y)),
+                    }
+                };
+                regs.target.insts.push(X86Inst::Fma {
+                    form,
+                    scalar,
                     src1,
                     src2,
                     dst,
                 });
+                if let Some(src) = copy {
+                    regs.target.insts.push(X86Inst::XmmUnaryRmRVex {
+                        op: XmmUnaryRmRVexOpcode::Vmovaps,
+                        src: src.into(),
+                        dst,
+                    });
+                }
             }
             Inst::Load { vars, loc } => regs.emit_load(idx, vars.into(), loc),
         }
     }
 
     regs.emit_load(neg_alloc, VarSet::default().into(), neg_const);
-    regs.finish()
+    for loc in 0..MATH_CONSTS.len() {
+        let alloc = (math_alloc + loc).try_into().unwrap();
+        regs.emit_load(
+            alloc,
+            VarSet::default().into(),
+            math_const + loc as Location,
+        );
+    }
+    let (target, stack_slots, stats) = regs.finish();
+    span.record("stack_slots", stack_slots);
+    span.record("evictions", stats.evictions);
+    span.record("sunk_loads", stats.sunk_loads);
+    (target, stack_slots, stats)
+}
+
+// If the operands' ranges don't overlap, `min` or `max` always returns the
+// same one. The ranges never include NaN, and the strict comparison means the
+// operands can't both be zeroes of different signs.
+fn winner(
+    config: X86Config,
+    func: &MemoizedFunc,
+    op: BinOp,
+    [a, b]: [InstIdx; 2],
+) -> Option<InstIdx> {
+    if !matches!(op, BinOp::Min | BinOp::Max) {
+        return None;
+    }
+    // exact for every value, so this doesn't depend on ranges; see
+    // `copy_loaded_outputs`
+    if a == b {
+        return Some(a);
+    }
+    if !config.use_ranges {
+        return None;
+    }
+    let [lo, hi] = if func.ranges[a.idx()].hi < func.ranges[b.idx()].lo {
+        [a, b]
+    } else if func.ranges[b.idx()].hi < func.ranges[a.idx()].lo {
+        [b, a]
+    } else {
+        return None;
+    };
+    match op {
+        BinOp::Min => Some(lo),
+        BinOp::Max => Some(hi),
+        _ => None,
+    }
+}
+
+// The ranges from memoization are computed with exact `f32` operations, but
+// the lowerings of `exp`, `ln`, `sin`, and `cos`, and of `recip` and `rsqrt`
+// with `--approx-recip`, are off by a few ulps, so their ranges don't bound
+// what the generated code computes, nor do the ranges of anything computed
+// from them, even in other functions. Widen all of those to everything, so
+// `winner` never picks between them.
+fn widen_approximate_ranges(config: X86Config, memoized: &Memoized) -> Memoized {
+    let mut memoized = memoized.clone();
+    // whether each function's outputs are approximate, for the loads in
+    // later functions; functions only load from subsets of their variables,
+    // which come first
+    let mut approximate_outputs: Vec<Vec<bool>> = Vec::new();
+    for func in memoized.funcs.iter_mut() {
+        let mut approximate = vec![false; func.insts.len()];
+        for (idx, inst) in func.insts.iter().enumerate() {
+            approximate[idx] = match *inst {
+                Inst::UnOp { op, .. } if is_approximate(config, op) => true,
+                // constants have no function, and a function's loads of its
+                // own outputs are the input coordinates
+                Inst::Load { vars, loc } => vars
+                    .idx()
+                    .checked_sub(1)
+                    .and_then(|from| approximate_outputs.get(from))
+                    .is_some_and(|outputs| outputs[usize::from(loc)]),
+                _ => inst.args().iter().any(|arg| approximate[arg.idx()]),
+            };
+            if approximate[idx] {
+                func.ranges[idx] = Interval::EVERYTHING;
+            }
+        }
+        approximate_outputs.push(
+            func.outputs
+                .iter()
+                .map(|out| out.is_some_and(|idx| approximate[idx.idx()]))
+                .collect(),
+        );
+    }
+    memoized
+}
+
+fn is_approximate(config: X86Config, op: UnOp) -> bool {
+    match op {
+        UnOp::Exp | UnOp::Ln | UnOp::Sin | UnOp::Cos => true,
+        UnOp::Recip | UnOp::Rsqrt => config.approx_recip,
+        _ => false,
+    }
+}
+
+fn is_load(func: &MemoizedFunc, idx: InstIdx) -> bool {
+    matches!(func.insts[idx.idx()], Inst::Load { .. })
+}
+
+// The register allocator gives each value at most one home in memory, so a
+// loaded value can't also be stored to an output, which happens when the
+// final result is a constant or depends on only one variable. Store a copy
+// instead, spelled `max(v, v)` so `winner` turns it into a move.
+fn copy_loaded_outputs(func: &MemoizedFunc) -> MemoizedFunc {
+    let mut func = func.clone();
+    for loc in 0..func.outputs.len() {
+        if let Some(def) = func.outputs[loc]
+            && is_load(&func, def)
+        {
+            let copy = func.insts.len().try_into().unwrap();
+            func.insts.push(Inst::BinOp {
+                op: BinOp::Max,
+                args: [def, def],
+            });
+            func.ranges.push(func.ranges[def.idx()]);
+            func.outputs[loc] = Some(copy);
+        }
+    }
+    func
 }
 
-fn sink_load(regs: &mut Registers<X86Target>, arg: InstIdx) -> XmmMem {
-    if let Some((mem, loc)) = regs.address_of(arg) {
-        if regs.target.vectors & (1 << mem.idx()) != 0 {
-            if regs.sink_load(arg, regs.target.insts.len()) {
-                regs.target.insts.push(X86Inst::Placeholder);
-                return Address(mem, loc, regs.target.stride).into();
+// Reassociation flushes `min` and `max` with negated operands as
+// `op(pos, neg(rest))`, and `rest` is often a difference or another tree of
+// `min` and `max`, so the `neg` can often be folded into the instructions
+// that compute its argument: `-(a - b)` is `b - a`, `-(-a)` is `a`, and
+// `-max(a, b)` is `min(-a, -b)` if both operands can be negated for free.
+// x86 has no negate instruction, so each fold saves an `xor` with the sign
+// mask. Only values with no other uses are rewritten, so nothing is computed
+// twice, and folding never adds instructions.
+fn fold_negations(func: &MemoizedFunc, sinkable: impl Fn(VarSet) -> bool) -> MemoizedFunc {
+    let mut uses = vec![0usize; func.insts.len()];
+    for arg in func
+        .insts
+        .iter()
+        .flat_map(Inst::args)
+        .chain(func.outputs.iter().flatten())
+    {
+        uses[arg.idx()] += 1;
+    }
+
+    // Which values could be computed negated at no extra cost. Only the
+    // second operand of an instruction can come straight from memory, so
+    // swapping a `sub` whose second operand is such a load would just trade
+    // the `xor` for a separate load.
+    let is_load =
+        |idx: InstIdx| matches!(func.insts[idx.idx()], Inst::Load { vars, .. } if sinkable(vars));
+    let mut free = vec![false; func.insts.len()];
+    for (idx, inst) in func.insts.iter().enumerate() {
+        free[idx] = uses[idx] == 1
+            && match *inst {
+                Inst::UnOp { op: UnOp::Neg, .. } => true,
+                Inst::BinOp {
+                    op: BinOp::Sub,
+                    args: [a, b],
+                } => is_load(a) || !is_load(b),
+                Inst::BinOp {
+                    op: BinOp::Min | BinOp::Max,
+                    args: [a, b],
+                } => free[a.idx()] && free[b.idx()],
+                _ => false,
+            };
+    }
+
+    // Decide which values to compute negated, from the end backward so each
+    // `neg` joins the outermost tree it can. A `neg` that's part of a larger
+    // tree just passes its argument through.
+    let mut negate = vec![false; func.insts.len()];
+    for (idx, inst) in func.insts.iter().enumerate().rev() {
+        if let Inst::UnOp { op: UnOp::Neg, arg } = *inst
+            && !negate[idx]
+            && free[arg.idx()]
+        {
+            let mut pending = vec![arg];
+            while let Some(arg) = pending.pop() {
+                negate[arg.idx()] = true;
+                if let Inst::BinOp {
+                    op: BinOp::Min | BinOp::Max,
+                    args,
+                } = func.insts[arg.idx()]
+                {
+                    pending.extend(args);
+                }
             }
         }
     }
+
+    // `map` holds each value's new index, or its negation's if it's in
+    // `negate`
+    let mut result = MemoizedFunc {
+        vars: func.vars,
+        ..MemoizedFunc::default()
+    };
+    let mut map: Vec<InstIdx> = Vec::with_capacity(func.insts.len());
+    for (idx, inst) in func.insts.iter().enumerate() {
+        let mut inst = match *inst {
+            Inst::UnOp { op: UnOp::Neg, arg } if negate[idx] || negate[arg.idx()] => {
+                map.push(map[arg.idx()]);
+                continue;
+            }
+            Inst::BinOp {
+                op: BinOp::Sub,
+                args: [a, b],
+            } if negate[idx] => Inst::BinOp {
+                op: BinOp::Sub,
+                args: [b, a],
+            },
+            Inst::BinOp { op, args } if negate[idx] => Inst::BinOp {
+                op: match op {
+                    BinOp::Min => BinOp::Max,
+                    BinOp::Max => BinOp::Min,
+                    _ => unreachable!(),
+                },
+                args,
+            },
+            ref inst => inst.clone(),
+        };
+        for arg in inst.args_mut() {
+            *arg = map[arg.idx()];
+        }
+        let range = if negate[idx] {
+            func.ranges[idx].unop(UnOp::Neg)
+        } else {
+            func.ranges[idx]
+        };
+        map.push(result.insts.len().try_into().unwrap());
+        result.insts.push(inst);
+        result.ranges.push(range);
+    }
+    result.outputs = func
+        .outputs
+        .iter()
+        .map(|out| out.map(|idx| map[idx.idx()]))
+        .collect();
+    result
+}
+
+// Scalar instructions only read one element from memory, so they can use any
+// address as an operand. Vector instructions can only use addresses in memory
+// spaces which have a separate element for each lane.
+//
+// This must be the last allocation before the instruction is pushed, because
+// patching the sunk load rewrites whatever comes right after its placeholder,
+// and allocating a register can emit a reload there. So `others` are the
+// registers the instruction already holds for its other operands, which the
+// sunk load mustn't be patched to use.
+fn sink_load(
+    regs: &mut Registers<X86Target>,
+    arg: InstIdx,
+    scalar_op: bool,
+    others: &[Xmm],
+) -> XmmMem {
+    if in_memory(regs, arg, scalar_op)
+        && let Some((mem, loc)) = regs.address_of(arg)
+        && regs.sink_load(arg, regs.target.insts.len())
+    {
+        regs.target.insts.push(X86Inst::Placeholder);
+        for other in others {
+            regs.mark_used(other.0);
+        }
+        return regs.target.address(mem, loc).into();
+    }
     Xmm(regs.get_reg(arg)).into()
 }
 
+// Whether `arg` has a home in memory that an instruction could read it from.
+fn in_memory(regs: &Registers<X86Target>, arg: InstIdx, scalar_op: bool) -> bool {
+    regs.address_of(arg)
+        .is_some_and(|(mem, _)| scalar_op || regs.target.vectors & (1 << mem.idx()) != 0)
+}
+
+// Where the constants for `exp`, `ln`, `sin`, and `cos` start in the pool: after the sign
+// bit, and after the zero for packed results if there is one.
+fn math_consts(config: X86Config, neg_const: Location) -> Location {
+    neg_const + 1 + Location::from(config.pack_bits)
+}
+
+// x86 has no instructions for `exp`, `ln`, `sin`, or `cos`, so they're
+// computed with polynomial approximations, within an ulp or so of the exact
+// results, in a sequence of steps, as are `recip` and `rsqrt`. Each step is a vector instruction whose operands are the
+// instruction's argument, an earlier step, or one of these constants, and
+// the last step is the result.
+#[derive(Clone, Copy)]
+enum MathConst {
+    ExpMax,
+    ExpMin,
+    Log2E,
+    // 1.5 * 2^23, plus the exponent bias: adding it rounds to an integer,
+    // which then sits in the low bits of the sum
+    Round,
+    Ln2Hi,
+    Ln2Lo,
+    One,
+    Exp0,
+    Exp1,
+    Exp2,
+    Exp3,
+    Exp4,
+    Exp5,
+    // the bits of √½
+    LnOffset,
+    // the sign and exponent bits
+    ExponentMask,
+    Ln0,
+    Ln1,
+    Ln2,
+    Ln3,
+    Ln4,
+    Ln5,
+    Ln6,
+    Ln7,
+    Ln8,
+    NegHalf,
+    InvPi,
+    // 1.5 * 2^23, and one more; the sum's low bit is then the parity of the
+    // integer it rounded to, or of one more than it
+    RoundSin,
+    RoundCos,
+    // π in three parts, the first two with few enough bits that multiplying
+    // them by the integer is exact
+    PiHi,
+    PiMid,
+    PiLo,
+    Sin0,
+    Sin1,
+    Sin2,
+    Sin3,
+    Sin4,
+    Sin5,
+    NegZero,
+}
+
+const MATH_CONSTS: [(u32, &str); 38] = [
+    (88.376f32.to_bits(), "88.376, exp's largest argument"),
+    ((-87.3365f32).to_bits(), "-87.3365, exp's smallest argument"),
+    (std::f32::consts::LOG2_E.to_bits(), "log2(e)"),
+    (12583039f32.to_bits(), "1.5 * 2^23 + 127"),
+    ((355f32 / 512.0).to_bits(), "ln(2), rounded to 9 bits"),
+    ((-2.1219444e-4f32).to_bits(), "ln(2) minus that"),
+    (1f32.to_bits(), "1"),
+    (1.9875691e-4f32.to_bits(), "exp polynomial"),
+    (1.3981999e-3f32.to_bits(), ""),
+    (8.333452e-3f32.to_bits(), ""),
+    (4.1665796e-2f32.to_bits(), ""),
+    (1.6666666e-1f32.to_bits(), ""),
+    (0.5f32.to_bits(), ""),
+    (0x3f3504f3, "bits of sqrt(0.5)"),
+    (0xff800000, "sign and exponent"),
+    (7.0376836e-2f32.to_bits(), "ln polynomial"),
+    ((-1.151461e-1f32).to_bits(), ""),
+    (1.16769984e-1f32.to_bits(), ""),
+    ((-1.2420141e-1f32).to_bits(), ""),
+    (1.4249323e-1f32.to_bits(), ""),
+    ((-1.6668057e-1f32).to_bits(), ""),
+    (2.0000714e-1f32.to_bits(), ""),
+    ((-2.4999994e-1f32).to_bits(), ""),
+    (3.333333e-1f32.to_bits(), ""),
+    ((-0.5f32).to_bits(), "-0.5"),
+    (std::f32::consts::FRAC_1_PI.to_bits(), "1/pi"),
+    (12582912f32.to_bits(), "1.5 * 2^23"),
+    (12582913f32.to_bits(), "1.5 * 2^23 + 1"),
+    (3.140625f32.to_bits(), "pi, rounded to 8 bits"),
+    (9.675026e-4f32.to_bits(), "the next 12 bits of pi"),
+    (1.509958e-7f32.to_bits(), "the rest of pi"),
+    ((1f32 / 6227020800.0).to_bits(), "sin polynomial"),
+    ((-1f32 / 39916800.0).to_bits(), ""),
+    ((1f32 / 362880.0).to_bits(), ""),
+    ((-1f32 / 5040.0).to_bits(), ""),
+    ((1f32 / 120.0).to_bits(), ""),
+    ((-1f32 / 6.0).to_bits(), ""),
+    ((-0f32).to_bits(), "-0"),
+];
+
+#[derive(Clone, Copy)]
+enum Operand {
+    Arg,
+    // the second argument, for binary instructions
+    Arg2,
+    // and the third, for `clamp` and `lerp`
+    Arg3,
+    Step(u8),
+    Const(MathConst),
+}
+
+#[derive(Clone, Copy)]
+enum Step {
+    /// `op` on floats, like the IR instruction
+    Float(BinOp, Operand, Operand),
+    /// a vector-only operation on the bits of each lane
+    Packed(XmmRmROpcode, Operand, Operand),
+    Shift(XmmShiftOpcode, u8, Operand),
+    IntToFloat(Operand),
+    Div(Operand, Operand),
+    Sqrt(Operand),
+    /// `vroundps`, rounding to an integer in the mode the immediate picks
+    RoundToInt(u8, Operand),
+    /// `vrcpps` or `vrsqrtps`, for `recip` or `rsqrt`: good to about 12
+    /// bits, or 14 with AVX-512
+    Estimate(UnOp, Operand),
+}
+
+// Immediates for `vroundps`: the rounding mode in the low two bits, and bit
+// 3 set to skip raising the precision exception, as compilers do for `floor`
+// and friends.
+const ROUND_NEAREST: u8 = 0x8;
+const ROUND_FLOOR: u8 = 0x9;
+const ROUND_CEIL: u8 = 0xa;
+
+// `a - b * floor(a / b)`, exactly as `ir::modulo` rounds it.
+const MOD_STEPS: [Step; 4] = {
+    use Operand::{Arg, Arg2, Step as S};
+    use Step::*;
+    [
+        Div(Arg, Arg2),
+        RoundToInt(ROUND_FLOOR, S(0)),
+        Float(BinOp::Mul, S(1), Arg2),
+        Float(BinOp::Sub, Arg, S(2)),
+    ]
+};
+
+// `clamp` and `lerp`, as the binary operations they expand to, but without
+// the values in between getting instructions of their own.
+const CLAMP_STEPS: [Step; 2] = {
+    use Operand::{Arg, Arg2, Arg3, Step as S};
+    use Step::*;
+    [Float(BinOp::Max, Arg, Arg2), Float(BinOp::Min, S(0), Arg3)]
+};
+const LERP_STEPS: [Step; 3] = {
+    use Operand::{Arg, Arg2, Arg3, Step as S};
+    use Step::*;
+    [
+        Float(BinOp::Sub, Arg2, Arg),
+        Float(BinOp::Mul, S(0), Arg3),
+        Float(BinOp::Add, Arg, S(1)),
+    ]
+};
+
+// Clamp the argument so the result is a normal number, and split it into
+// n * ln(2) + r, where n is an integer and |r| <= ln(2)/2. Then e^r comes
+// from a polynomial, and 2^n from shifting n into the exponent bits. NaN
+// passes through the clamps since it's their second operand.
+const EXP_STEPS: [Step; 25] = {
+    use MathConst::*;
+    use Operand::{Arg, Const as C, Step as S};
+    use Step::*;
+    [
+        Float(BinOp::Min, C(ExpMax), Arg),
+        Float(BinOp::Max, C(ExpMin), S(0)),
+        Float(BinOp::Mul, S(1), C(Log2E)),
+        Float(BinOp::Add, S(2), C(Round)),
+        Float(BinOp::Sub, S(3), C(Round)),
+        Float(BinOp::Mul, S(4), C(Ln2Hi)),
+        Float(BinOp::Sub, S(1), S(5)),
+        Float(BinOp::Mul, S(4), C(Ln2Lo)),
+        Float(BinOp::Sub, S(6), S(7)),
+        Float(BinOp::Mul, S(8), S(8)),
+        Float(BinOp::Mul, S(8), C(Exp0)),
+        Float(BinOp::Add, S(10), C(Exp1)),
+        Float(BinOp::Mul, S(11), S(8)),
+        Float(BinOp::Add, S(12), C(Exp2)),
+        Float(BinOp::Mul, S(13), S(8)),
+        Float(BinOp::Add, S(14), C(Exp3)),
+        Float(BinOp::Mul, S(15), S(8)),
+        Float(BinOp::Add, S(16), C(Exp4)),
+        Float(BinOp::Mul, S(17), S(8)),
+        Float(BinOp::Add, S(18), C(Exp5)),
+        Float(BinOp::Mul, S(19), S(9)),
+        Float(BinOp::Add, S(20), S(8)),
+        Float(BinOp::Add, S(21), C(One)),
+        Shift(XmmShiftOpcode::Vpslld, 23, S(3)),
+        Float(BinOp::Mul, S(22), S(23)),
+    ]
+};
+
+// Split the argument into 2^e * m, where e is an integer and m is between
+// √½ and √2, using integer arithmetic on its bits. Then ln(m) comes from a
+// polynomial in m - 1, and ln(2^e) is e * ln(2). Only positive normal
+// numbers get the right answer: zero and subnormals give about -88, and
+// negative numbers, infinity, and NaN give finite nonsense.
+const LN_STEPS: [Step; 32] = {
+    use MathConst::*;
+    use Operand::{Arg, Const as C, Step as S};
+    use Step::*;
+    [
+        Packed(XmmRmROpcode::Vpsubd, Arg, C(LnOffset)),
+        Shift(XmmShiftOpcode::Vpsrad, 23, S(0)),
+        Packed(XmmRmROpcode::Vandps, S(0), C(ExponentMask)),
+        Packed(XmmRmROpcode::Vpsubd, Arg, S(2)),
+        Float(BinOp::Sub, S(3), C(One)),
+        IntToFloat(S(1)),
+        Float(BinOp::Mul, S(4), S(4)),
+        Float(BinOp::Mul, S(4), C(Ln0)),
+        Float(BinOp::Add, S(7), C(Ln1)),
+        Float(BinOp::Mul, S(8), S(4)),
+        Float(BinOp::Add, S(9), C(Ln2)),
+        Float(BinOp::Mul, S(10), S(4)),
+        Float(BinOp::Add, S(11), C(Ln3)),
+        Float(BinOp::Mul, S(12), S(4)),
+        Float(BinOp::Add, S(13), C(Ln4)),
+        Float(BinOp::Mul, S(14), S(4)),
+        Float(BinOp::Add, S(15), C(Ln5)),
+        Float(BinOp::Mul, S(16), S(4)),
+        Float(BinOp::Add, S(17), C(Ln6)),
+        Float(BinOp::Mul, S(18), S(4)),
+        Float(BinOp::Add, S(19), C(Ln7)),
+        Float(BinOp::Mul, S(20), S(4)),
+        Float(BinOp::Add, S(21), C(Ln8)),
+        Float(BinOp::Mul, S(22), S(4)),
+        Float(BinOp::Mul, S(23), S(6)),
+        Float(BinOp::Mul, S(5), C(Ln2Lo)),
+        Float(BinOp::Add, S(24), S(25)),
+        Float(BinOp::Mul, S(6), C(NegHalf)),
+        Float(BinOp::Add, S(26), S(27)),
+        Float(BinOp::Add, S(4), S(28)),
+        Float(BinOp::Mul, S(5), C(Ln2Hi)),
+        Float(BinOp::Add, S(29), S(30)),
+    ]
+};
+
+// Split the argument into n * π + r, where n is an integer and |r| <= π/2,
+// then compute sin(r) from its Taylor series up to r^13, whose truncation
+// error is far below an ulp over that range, and flip its sign if n is odd. Rounding to an
+// integer by adding 1.5 * 2^23 only works while |n| < 2^22, and r loses bits
+// as n grows, so this is only accurate for arguments up to thousands.
+const SIN_STEPS: [Step; 25] = {
+    use MathConst::*;
+    use Operand::{Arg, Const as C, Step as S};
+    use Step::*;
+    [
+        Float(BinOp::Mul, Arg, C(InvPi)),
+        Float(BinOp::Add, S(0), C(RoundSin)),
+        Float(BinOp::Sub, S(1), C(RoundSin)),
+        Float(BinOp::Mul, S(2), C(PiHi)),
+        Float(BinOp::Sub, Arg, S(3)),
+        Float(BinOp::Mul, S(2), C(PiMid)),
+        Float(BinOp::Sub, S(4), S(5)),
+        Float(BinOp::Mul, S(2), C(PiLo)),
+        Float(BinOp::Sub, S(6), S(7)),
+        Float(BinOp::Mul, S(8), S(8)),
+        Float(BinOp::Mul, S(9), C(Sin0)),
+        Float(BinOp::Add, S(10), C(Sin1)),
+        Float(BinOp::Mul, S(11), S(9)),
+        Float(BinOp::Add, S(12), C(Sin2)),
+        Float(BinOp::Mul, S(13), S(9)),
+        Float(BinOp::Add, S(14), C(Sin3)),
+        Float(BinOp::Mul, S(15), S(9)),
+        Float(BinOp::Add, S(16), C(Sin4)),
+        Float(BinOp::Mul, S(17), S(9)),
+        Float(BinOp::Add, S(18), C(Sin5)),
+        Float(BinOp::Mul, S(19), S(9)),
+        Float(BinOp::Mul, S(20), S(8)),
+        Float(BinOp::Add, S(21), S(8)),
+        Shift(XmmShiftOpcode::Vpslld, 31, S(1)),
+        Packed(XmmRmROpcode::Vxorps, S(22), S(23)),
+    ]
+};
+
+// The same as `sin`, but with the argument split into (n + ½) * π + r, since
+// cos(x) is sin(r) there, negated if n is even.
+const COS_STEPS: [Step; 27] = {
+    use MathConst::*;
+    use Operand::{Arg, Const as C, Step as S};
+    use Step::*;
+    [
+        Float(BinOp::Mul, Arg, C(InvPi)),
+        Float(BinOp::Add, S(0), C(NegHalf)),
+        Float(BinOp::Add, S(1), C(RoundCos)),
+        Float(BinOp::Sub, S(2), C(RoundCos)),
+        Float(BinOp::Sub, S(3), C(NegHalf)),
+        Float(BinOp::Mul, S(4), C(PiHi)),
+        Float(BinOp::Sub, Arg, S(5)),
+        Float(BinOp::Mul, S(4), C(PiMid)),
+        Float(BinOp::Sub, S(6), S(7)),
+        Float(BinOp::Mul, S(4), C(PiLo)),
+        Float(BinOp::Sub, S(8), S(9)),
+        Float(BinOp::Mul, S(10), S(10)),
+        Float(BinOp::Mul, S(11), C(Sin0)),
+        Float(BinOp::Add, S(12), C(Sin1)),
+        Float(BinOp::Mul, S(13), S(11)),
+        Float(BinOp::Add, S(14), C(Sin2)),
+        Float(BinOp::Mul, S(15), S(11)),
+        Float(BinOp::Add, S(16), C(Sin3)),
+        Float(BinOp::Mul, S(17), S(11)),
+        Float(BinOp::Add, S(18), C(Sin4)),
+        Float(BinOp::Mul, S(19), S(11)),
+        Float(BinOp::Add, S(20), C(Sin5)),
+        Float(BinOp::Mul, S(21), S(11)),
+        Float(BinOp::Mul, S(22), S(10)),
+        Float(BinOp::Add, S(23), S(10)),
+        Shift(XmmShiftOpcode::Vpslld, 31, S(2)),
+        Packed(XmmRmROpcode::Vxorps, S(24), S(25)),
+    ]
+};
+
+// Exactly rounded, as `ir::rsqrt` is.
+const RECIP_STEPS: [Step; 1] = [Step::Div(Operand::Const(MathConst::One), Operand::Arg)];
+const RSQRT_STEPS: [Step; 2] = {
+    use MathConst::*;
+    use Operand::{Arg, Const as C, Step as S};
+    use Step::*;
+    [Sqrt(Arg), Div(C(One), S(0))]
+};
+
+// One Newton-Raphson step takes the estimate r to r + r * (1 - x * r), and
+// squares its relative error. Where the estimate is exact already, at zero
+// and infinity, `x * r` is NaN instead, so the correction is replaced by -0,
+// which is the second operand of the `max` and so wins there, and leaves r
+// alone when added.
+const APPROX_RECIP_STEPS: [Step; 7] = {
+    use MathConst::*;
+    use Operand::{Arg, Const as C, Step as S};
+    use Step::*;
+    [
+        Estimate(UnOp::Recip, Arg),
+        Float(BinOp::Mul, Arg, S(0)),
+        Float(BinOp::Sub, C(One), S(1)),
+        Float(BinOp::Mul, S(0), S(2)),
+        Float(BinOp::Max, S(3), C(NegZero)),
+        Float(BinOp::Min, S(3), S(4)),
+        Float(BinOp::Add, S(0), S(5)),
+    ]
+};
+
+// Likewise r + r/2 * (1 - x * r * r) for `rsqrt`.
+const APPROX_RSQRT_STEPS: [Step; 9] = {
+    use MathConst::*;
+    use Operand::{Arg, Const as C, Step as S};
+    use Step::*;
+    [
+        Estimate(UnOp::Rsqrt, Arg),
+        Float(BinOp::Mul, Arg, S(0)),
+        Float(BinOp::Mul, S(1), S(0)),
+        Float(BinOp::Sub, S(2), C(One)),
+        Float(BinOp::Mul, S(0), C(NegHalf)),
+        Float(BinOp::Mul, S(4), S(3)),
+        Float(BinOp::Max, S(5), C(NegZero)),
+        Float(BinOp::Min, S(5), S(6)),
+        Float(BinOp::Add, S(0), S(7)),
+    ]
+};
+
+// The steps to compute `op`, if it doesn't have an instruction of its own.
+fn math_steps(config: X86Config, op: UnOp) -> Option<&'static [Step]> {
+    match op {
+        UnOp::Exp => Some(&EXP_STEPS),
+        UnOp::Ln => Some(&LN_STEPS),
+        UnOp::Sin => Some(&SIN_STEPS),
+        UnOp::Cos => Some(&COS_STEPS),
+        UnOp::Recip if config.approx_recip => Some(&APPROX_RECIP_STEPS),
+        UnOp::Recip => Some(&RECIP_STEPS),
+        UnOp::Rsqrt if config.approx_recip => Some(&APPROX_RSQRT_STEPS),
+        UnOp::Rsqrt => Some(&RSQRT_STEPS),
+        _ => None,
+    }
+}
+
+// Likewise for any instruction.
+fn inst_steps(config: X86Config, inst: &Inst) -> Option<&'static [Step]> {
+    match *inst {
+        Inst::UnOp { op, .. } => math_steps(config, op),
+        Inst::BinOp { op: BinOp::Mod, .. } => Some(&MOD_STEPS),
+        Inst::TernOp {
+            op: TernOp::Clamp, ..
+        } => Some(&CLAMP_STEPS),
+        Inst::TernOp {
+            op: TernOp::Lerp, ..
+        } => Some(&LERP_STEPS),
+        _ => None,
+    }
+}
+
+// Allocate registers for one step and emit it, in the same order as for an
+// IR instruction. `operand` finds the allocation for each operand.
+fn emit_step(
+    regs: &mut Registers<X86Target>,
+    step: Step,
+    dst: InstIdx,
+    operand: impl Fn(Operand) -> InstIdx,
+) {
+    let dst = Xmm(regs.get_output_reg(dst));
+    let scalar = regs.target.scalar();
+    let binary = |regs: &mut Registers<X86Target>, op, a, b, scalar_op| {
+        let [a, b] = [a, b].map(&operand);
+        let src1 = Xmm(regs.get_reg(a));
+        // as for IR instructions, the same value twice has to be in a
+        // register
+        let src2 = if a == b {
+            src1.into()
+        } else {
+            sink_load(regs, b, scalar_op, &[src1])
+        };
+        X86Inst::XmmRmR {
+            op,
+            src1,
+            src2,
+            dst,
+        }
+    };
+    let inst = match step {
+        Step::Float(op, a, b) => binary(regs, XmmRmROpcode::binop(op, scalar), a, b, scalar),
+        Step::Packed(op, a, b) => binary(regs, op, a, b, false),
+        Step::Shift(op, imm, src) => X86Inst::XmmShiftImm {
+            op,
+            imm,
+            src: Xmm(regs.get_reg(operand(src))),
+            dst,
+        },
+        Step::IntToFloat(src) => X86Inst::XmmUnaryRmRVex {
+            op: XmmUnaryRmRVexOpcode::Vcvtdq2ps,
+            src: sink_load(regs, operand(src), false, &[]),
+            dst,
+        },
+        Step::Div(a, b) => {
+            let op = if scalar {
+                XmmRmROpcode::Vdivss
+            } else {
+                XmmRmROpcode::Vdivps
+            };
+            binary(regs, op, a, b, scalar)
+        }
+        // as for `sqrt` instructions, the scalar form copies the upper lanes
+        // from an operand we don't care about
+        Step::Sqrt(src) if scalar => X86Inst::XmmRmR {
+            op: XmmRmROpcode::Vsqrtss,
+            src1: dst,
+            src2: sink_load(regs, operand(src), true, &[]),
+            dst,
+        },
+        Step::Sqrt(src) => X86Inst::XmmUnaryRmRVex {
+            op: XmmUnaryRmRVexOpcode::Vsqrtps,
+            src: sink_load(regs, operand(src), false, &[]),
+            dst,
+        },
+        Step::RoundToInt(imm, src) => X86Inst::XmmRound {
+            imm,
+            scalar,
+            src: sink_load(regs, operand(src), scalar, &[]),
+            dst,
+        },
+        Step::Estimate(op, src) => X86Inst::XmmEstimate {
+            op,
+            scalar,
+            src: sink_load(regs, operand(src), scalar, &[]),
+            dst,
+        },
+    };
+    regs.target.insts.push(inst);
+}
+
+// Compile one function, trying each load-sinking policy if requested, in
+// which case the chosen policy and its cost are returned too.
+fn compile_func(
+    config: X86Config,
+    neg_const: Location,
+    func: &MemoizedFunc,
+    vectors: &[VarSet],
+) -> (X86Target, Location, Stats, Option<(usize, SinkLoads)>) {
+    let folded;
+    let func = if config.fold_negations {
+        // the same test `sink_load` makes for vector instructions
+        let sinkable = |vars: VarSet| {
+            vectors.is_empty()
+                || vectors.contains(&vars)
+                || (vars == VarSet::default() && const_copies(config) > 1)
+        };
+        folded = fold_negations(func, sinkable);
+        &folded
+    } else {
+        func
+    };
+    if !config.tune_sink_loads {
+        let (target, stack_slots, stats) = emit(config, neg_const, func, vectors);
+        return (target, stack_slots, stats, None);
+    }
+    let mut best: Option<(usize, SinkLoads, _, _, _)> = None;
+    for &sink_loads in SinkLoads::value_variants() {
+        let mut config = config;
+        config.regalloc.sink_loads = Some(sink_loads);
+        let (target, stack_slots, stats) = emit(config, neg_const, func, vectors);
+        let cost = target.cost(stack_slots);
+        if best
+            .as_ref()
+            .is_none_or(|&(best_cost, ..)| cost < best_cost)
+        {
+            best = Some((cost, sink_loads, target, stack_slots, stats));
+        }
+    }
+    let (cost, sink_loads, target, stack_slots, stats) = best.unwrap();
+    (target, stack_slots, stats, Some((cost, sink_loads)))
+}
+
 fn write_func(
     mut f: impl io::Write,
-    config: Config,
+    mut config: X86Config,
     neg_const: Location,
     func: &MemoizedFunc,
-    vectors: impl IntoIterator<Item = VarSet>,
+    vectors: &[VarSet],
+    name: &str,
+    tuning: &mut Tuning,
 ) -> io::Result<()> {
-    let (target, stack_slots) = emit(config, neg_const, func, vectors);
+    let _span =
+        tracing::info_span!("function", vars = ?func.vars, insts = func.insts.len()).entered();
+    let chosen = tuning.get(name);
+    if let Some(sink_loads) = chosen {
+        config.tune_sink_loads = false;
+        config.regalloc.sink_loads = Some(sink_loads);
+    }
+    let (target, stack_slots, stats, tuned) = compile_func(config, neg_const, func, vectors);
+    // the same comment whether the policy was just tuned or reused
+    let tuned = tuned.or_else(|| chosen.map(|sink_loads| (target.cost(stack_slots), sink_loads)));
+    if let Some((cost, sink_loads)) = tuned {
+        tuning.set(name, sink_loads);
+        let policy = sink_loads.to_possible_value().unwrap();
+        writeln!(f, "# sink-loads: {} (cost {cost})", policy.get_name())?;
+    }
 
     // prologue
-    let frame_size = usize::from(stack_slots) * usize::from(target.stride) * 4;
+    if func.vars == VarSet::ALL {
+        writeln!(f, "movq {},{}", ARGS[6], ARG_REGISTERS[6])?;
+    }
+    let vector_size = usize::from(target.stride) * 4;
+    let frame_size = target.frame_size(stack_slots);
     if frame_size > 0 {
         writeln!(f, "pushq %rbp")?;
         writeln!(f, "movq %rsp,%rbp")?;
         writeln!(f, "sub ${:#x},%rsp", frame_size)?;
+        // the calling convention only keeps the stack 16-byte aligned, which
+        // isn't enough for aligned moves of wider vectors
+        if vector_size > 16 {
+            writeln!(f, "and $-{:#x},%rsp", vector_size)?;
+        }
     }
 
-    for inst in target.insts.into_iter().rev() {
+    let live_ranges = config.live_ranges.then(|| live_ranges(&target.insts));
+    for (idx, inst) in target.insts.iter().enumerate().rev() {
         if !matches!(inst, X86Inst::Placeholder) {
-            writeln!(f, "{inst}")?;
+            let inst = inst.display(target.stride);
+            if let Some(live_ranges) = &live_ranges {
+                let inst = inst.to_string();
+                writeln!(f, "{inst:40} # {}", live_ranges[idx])?;
+            } else {
+                writeln!(f, "{inst}")?;
+            }
         }
     }
 
@@ -220,48 +1702,219 @@ fn write_func(
         writeln!(f, "movq %rbp,%rsp")?;
         writeln!(f, "pop %rbp")?;
     }
-    writeln!(f, "ret")
+    // avoid penalties in any SSE code the caller runs next; 128-bit VEX
+    // instructions clear the upper halves themselves
+    if config.vzeroupper && target.stride > 4 {
+        writeln!(f, "vzeroupper")?;
+    }
+    writeln!(f, "ret")?;
+
+    if config.stats {
+        write_stats(&mut f, &target, stack_slots, &stats)?;
+    }
+    Ok(())
+}
+
+// Draw one column per register, marking where each value is defined (`+`),
+// used (`*`), and used for the last time (`-`), and connecting those points
+// with `|` while the value is live. An instruction which overwrites one of its
+// operands just shows the definition. `insts` are in reverse order, just as
+// they're emitted, which is the natural order for liveness analysis.
+fn live_ranges(insts: &[X86Inst]) -> Vec<String> {
+    let mut live = [false; 16];
+    insts
+        .iter()
+        .map(|inst| {
+            let (def, uses) = inst.regs();
+            let mut column: Vec<char> = live.map(|live| if live { '|' } else { ' ' }).into();
+            if let Some(def) = def {
+                live[def.idx()] = false;
+            }
+            for reg in uses.into_iter().flatten() {
+                column[reg.idx()] = if live[reg.idx()] { '*' } else { '-' };
+                live[reg.idx()] = true;
+            }
+            if let Some(def) = def {
+                column[def.idx()] = '+';
+            }
+            column.into_iter().collect()
+        })
+        .collect()
+}
+
+// The numbers people ask about when looking at generated code: what it's made
+// of, how much it spilled, and what the cost model thinks of it.
+fn write_stats(
+    mut f: impl io::Write,
+    target: &X86Target,
+    stack_slots: Location,
+    stats: &Stats,
+) -> io::Result<()> {
+    // count the lines of each instruction's text, since some expand to
+    // several instructions
+    let mut opcodes: HashMap<String, usize> = HashMap::new();
+    for inst in target.insts.iter() {
+        for line in inst.display(target.stride).to_string().lines() {
+            if let Some(opcode) = line.split_whitespace().next() {
+                *opcodes.entry(opcode.to_string()).or_default() += 1;
+            }
+        }
+    }
+    let mut opcodes: Vec<(String, usize)> = opcodes.into_iter().collect();
+    opcodes.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    let total: usize = opcodes.iter().map(|&(_, count)| count).sum();
+    write!(
+        f,
+        "# instructions: {total} besides the prologue and epilogue"
+    )?;
+    for (idx, (opcode, count)) in opcodes.into_iter().enumerate() {
+        write!(f, "{} {count} {opcode}", if idx == 0 { ":" } else { "," })?;
+    }
+    writeln!(f)?;
+    writeln!(
+        f,
+        "# spills: {}, using {stack_slots} stack slots",
+        stats.spills
+    )?;
+    writeln!(f, "# frame: {} bytes", target.frame_size(stack_slots))?;
+    writeln!(
+        f,
+        "# estimated cycles: {} per call, by the static cost model",
+        target.cost(stack_slots)
+    )?;
+    writeln!(f, "# evictions: {}", stats.evictions)?;
+    writeln!(
+        f,
+        "# sunk loads: {} ({} patched, {} missed, {} expired)",
+        stats.sunk_loads, stats.sink_hits, stats.sink_misses, stats.sink_expired
+    )?;
+    if stats.sink_hits > 0 {
+        write!(f, "# patched by distance:")?;
+        for (distance, &hits) in stats.hits_by_distance.iter().enumerate() {
+            if hits > 0 {
+                write!(f, " {distance}:{hits}")?;
+            }
+        }
+        writeln!(f)?;
+    }
+    Ok(())
 }
 
 struct X86Target {
     vectors: u16,
+    // memory spaces laid out with `stride` elements per location
+    strided: u16,
     stride: u8,
+    const_stride: u8,
+    // stores to this memory space are packed into sign bits, using the
+    // zero constant at this location
+    pack: Option<(MemorySpace, Location)>,
     insts: Vec<X86Inst>,
 }
 
 impl X86Target {
-    fn new(vectors: impl IntoIterator<Item = VarSet>) -> X86Target {
-        let vectors = vectors.into_iter().fold(0, |set, vars| {
-            set | (1 << MemorySpace::from(vars).idx()) | 0b11
+    fn new(
+        vectors: impl IntoIterator<Item = VarSet>,
+        axis: VectorAxis,
+        lanes: u8,
+        const_copies: u8,
+        pack: Option<(MemorySpace, Location)>,
+    ) -> X86Target {
+        let consts = MemorySpace::from(VarSet::default());
+        let mut vectors = vectors.into_iter().fold(0, |set, vars| {
+            set | (1 << MemorySpace::from(vars).idx()) | (1 << MemorySpace::STACK.idx())
         });
+        let stride = if vectors != 0 { lanes } else { 1 };
+        // Along x or y, every function is vectorized, so even spaces this
+        // function only broadcasts from were written a whole vector at a
+        // time. Along z, the functions which don't depend on z are scalar.
+        let strided = match axis {
+            VectorAxis::X | VectorAxis::Y => u16::MAX,
+            VectorAxis::Z => vectors,
+        };
+        // Scalar functions still find each constant at its usual place in a
+        // pool which was laid out for vectors.
+        if vectors != 0 && const_copies > 1 {
+            vectors |= 1 << consts.idx();
+        }
         X86Target {
             vectors,
-            stride: if vectors != 0 { STRIDE } else { 1 },
+            strided,
+            stride,
+            const_stride: const_copies,
+            pack,
             insts: Vec::new(),
         }
     }
+
+    /// Estimate the cost of the function emitted so far, including its stack
+    /// frame setup if it needs any stack slots.
+    fn cost(&self, stack_slots: Location) -> usize {
+        let frame = if stack_slots > 0 { 5 } else { 0 };
+        frame + self.insts.iter().map(X86Inst::cost).sum::<usize>()
+    }
+
+    /// Bytes of stack needed for `stack_slots` spilled vectors.
+    fn frame_size(&self, stack_slots: Location) -> usize {
+        usize::from(stack_slots) * usize::from(self.stride) * 4
+    }
+
+    /// If no memory spaces are vectorized then we only need to compute one
+    /// lane, so we can use scalar instructions.
+    fn scalar(&self) -> bool {
+        self.vectors == 0
+    }
+
+    fn address(&self, mem: MemorySpace, loc: Location) -> Address {
+        if mem == MemorySpace::from(VarSet::default()) {
+            Address(mem, loc, self.const_stride)
+        } else if self.strided & (1 << mem.idx()) != 0 {
+            Address(mem, loc, self.stride)
+        } else {
+            Address(mem, loc, 1)
+        }
+    }
 }
 
 impl Target for X86Target {
     fn emit_load(&mut self, reg: Register, mem: MemorySpace, loc: Location) {
         let op = if self.vectors & (1 << mem.idx()) != 0 {
             XmmUnaryRmRVexOpcode::Vmovaps
+        } else if self.scalar() {
+            XmmUnaryRmRVexOpcode::Vmovss
         } else {
             XmmUnaryRmRVexOpcode::Vbroadcastss
         };
         let dst = reg.into();
-        let src = Address(mem, loc, self.stride).into();
+        let src = self.address(mem, loc).into();
         self.insts.push(X86Inst::XmmUnaryRmRVex { op, src, dst });
     }
 
     fn emit_store(&mut self, reg: Register, mem: MemorySpace, loc: Location) {
+        // scratch locations after the result hold whole values
+        if let Some((pack, zero)) = self.pack
+            && pack == mem
+            && loc == 0
+        {
+            let src = reg.into();
+            let zero = self.address(VarSet::default().into(), zero);
+            let dst = self.address(mem, loc);
+            let lanes = self.stride;
+            self.insts.push(X86Inst::PackSigns {
+                src,
+                zero,
+                dst,
+                lanes,
+            });
+            return;
+        }
         let op = if self.vectors & (1 << mem.idx()) != 0 {
             XmmMovRMVexOpcode::Vmovaps
         } else {
             XmmMovRMVexOpcode::Vmovd
         };
         let src = reg.into();
-        let dst = Address(mem, loc, self.stride).into();
+        let dst = self.address(mem, loc).into();
         self.insts.push(X86Inst::XmmMovRMVex { op, src, dst });
     }
 
@@ -279,9 +1932,20 @@ impl Target for X86Target {
         match &mut self.insts[patch_at + 1] {
             X86Inst::XmmRmR { src2, .. } => *src2 = Xmm(reg).into(),
             X86Inst::XmmUnaryRmRVex { src, .. } => *src = Xmm(reg).into(),
-            X86Inst::Placeholder | X86Inst::XmmMovRMVex { .. } => unreachable!(),
+            X86Inst::XmmRound { src, .. } => *src = Xmm(reg).into(),
+            X86Inst::XmmEstimate { src, .. } => *src = Xmm(reg).into(),
+            X86Inst::Fma { src2, .. } => *src2 = Xmm(reg).into(),
+            X86Inst::Placeholder
+            | X86Inst::XmmShiftImm { .. }
+            | X86Inst::XmmMovRMVex { .. }
+            | X86Inst::PackSigns { .. } => unreachable!(),
         }
     }
+
+    fn can_spill_to(&self, mem: MemorySpace) -> bool {
+        // packed stores only keep the sign bits
+        self.pack.is_none_or(|(pack, _)| pack != mem)
+    }
 }
 
 #[derive(Debug)]
@@ -298,16 +1962,124 @@ enum X86Inst {
         src: XmmMem,
         dst: Xmm,
     },
+    /// Shift each lane of `src`, as a 32-bit integer, by `imm` bits.
+    XmmShiftImm {
+        op: XmmShiftOpcode,
+        imm: u8,
+        src: Xmm,
+        dst: Xmm,
+    },
     XmmMovRMVex {
         op: XmmMovRMVexOpcode,
         src: Xmm,
         dst: XmmMem,
     },
+    /// Round each lane of `src` to an integer, in the mode `imm` picks. The
+    /// scalar form copies the upper lanes from `dst`, which we don't care
+    /// about, as with `vsqrtss`.
+    XmmRound {
+        imm: u8,
+        scalar: bool,
+        src: XmmMem,
+        dst: Xmm,
+    },
+    /// An estimate of `recip` or `rsqrt` of each lane of `src`. Like
+    /// `XmmRound`, the scalar form copies the upper lanes from `dst`.
+    XmmEstimate {
+        op: UnOp,
+        scalar: bool,
+        src: XmmMem,
+        dst: Xmm,
+    },
+    /// A fused multiply-add, which also reads `dst` as the operand `form`
+    /// says.
+    Fma {
+        form: FmaForm,
+        scalar: bool,
+        src1: Xmm,
+        src2: XmmMem,
+        dst: Xmm,
+    },
+    /// Store one bit per lane of `src`, set where the lane is
+    /// [inside](crate::image::is_inside) the shape, in the low bits of the
+    /// byte at `dst`. First adding `zero` turns -0.0 into +0.0 and leaves
+    /// every other value alone, so `src` still holds an equivalent value
+    /// afterward and the sign bits give the answer. This uses `%eax` as a
+    /// scratch register, which the calling convention lets us clobber.
+    PackSigns {
+        src: Xmm,
+        zero: Address,
+        dst: Address,
+        lanes: u8,
+    },
 }
 
-impl fmt::Display for X86Inst {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+impl X86Inst {
+    /// The register this instruction writes, if any, and the registers it
+    /// reads.
+    fn regs(&self) -> (Option<Register>, [Option<Register>; 3]) {
+        let reg = |operand: &XmmMem| match operand {
+            XmmMem::Xmm(Xmm(reg)) => Some(*reg),
+            XmmMem::Mem(_) => None,
+        };
+        match self {
+            X86Inst::Placeholder => (None, [None; 3]),
+            X86Inst::XmmRmR {
+                src1, src2, dst, ..
+            } => (Some(dst.0), [Some(src1.0), reg(src2), None]),
+            X86Inst::XmmUnaryRmRVex { src, dst, .. } => (Some(dst.0), [reg(src), None, None]),
+            X86Inst::XmmShiftImm { src, dst, .. } => (Some(dst.0), [Some(src.0), None, None]),
+            X86Inst::XmmMovRMVex { src, dst, .. } => (reg(dst), [Some(src.0), None, None]),
+            X86Inst::XmmRound { src, dst, .. } => (Some(dst.0), [reg(src), None, None]),
+            X86Inst::XmmEstimate { src, dst, .. } => (Some(dst.0), [reg(src), None, None]),
+            X86Inst::Fma {
+                src1, src2, dst, ..
+            } => (Some(dst.0), [Some(src1.0), reg(src2), Some(dst.0)]),
+            X86Inst::PackSigns { src, .. } => (None, [Some(src.0), None, None]),
+        }
+    }
+
+    /// A rough static cost model: one unit per instruction, one more per
+    /// memory access, and a few extra for square roots and division, which
+    /// have much higher latency than other arithmetic.
+    fn cost(&self) -> usize {
+        let mem = |operand: &XmmMem| usize::from(matches!(operand, XmmMem::Mem(_)));
         match self {
+            X86Inst::Placeholder => 0,
+            X86Inst::XmmRmR { op, src2, .. } => {
+                let sqrt = if matches!(
+                    op,
+                    XmmRmROpcode::Vsqrtss | XmmRmROpcode::Vdivps | XmmRmROpcode::Vdivss
+                ) {
+                    4
+                } else {
+                    0
+                };
+                1 + sqrt + mem(src2)
+            }
+            X86Inst::XmmUnaryRmRVex { op, src, .. } => {
+                let sqrt = if matches!(op, XmmUnaryRmRVexOpcode::Vsqrtps) {
+                    4
+                } else {
+                    0
+                };
+                1 + sqrt + mem(src)
+            }
+            X86Inst::XmmShiftImm { .. } => 1,
+            X86Inst::XmmMovRMVex { dst, .. } => 1 + mem(dst),
+            X86Inst::XmmRound { src, .. } => 1 + mem(src),
+            X86Inst::XmmEstimate { src, .. } => 1 + mem(src),
+            X86Inst::Fma { src2, .. } => 1 + mem(src2),
+            X86Inst::PackSigns { .. } => 5,
+        }
+    }
+}
+
+impl X86Inst {
+    /// Format this instruction for a function whose vector registers hold
+    /// `lanes` floats each.
+    fn display(&self, lanes: u8) -> impl fmt::Display + '_ {
+        fmt::from_fn(move |f| match self {
             X86Inst::Placeholder => Ok(()),
             X86Inst::XmmRmR {
                 op,
@@ -322,25 +2094,125 @@ impl fmt::Display for X86Inst {
                     XmmRmROpcode::Vminps => "vminps",
                     XmmRmROpcode::Vmaxps => "vmaxps",
                     XmmRmROpcode::Vxorps => "vxorps",
+                    XmmRmROpcode::Vandnps => "vandnps",
+                    XmmRmROpcode::Vandps => "vandps",
+                    XmmRmROpcode::Vpsubd => "vpsubd",
+                    XmmRmROpcode::Vaddss => "vaddss",
+                    XmmRmROpcode::Vsubss => "vsubss",
+                    XmmRmROpcode::Vmulss => "vmulss",
+                    XmmRmROpcode::Vminss => "vminss",
+                    XmmRmROpcode::Vmaxss => "vmaxss",
+                    XmmRmROpcode::Vsqrtss => "vsqrtss",
+                    XmmRmROpcode::Vdivps => "vdivps",
+                    XmmRmROpcode::Vdivss => "vdivss",
                 };
-                write!(f, "{opcode} {src2},{src1},{dst}")
+                let [src1, dst] = [src1, dst].map(|reg| reg.display(lanes));
+                write!(f, "{opcode} {},{src1},{dst}", src2.display(lanes))
             }
             X86Inst::XmmUnaryRmRVex { op, src, dst } => {
                 let opcode = match op {
                     XmmUnaryRmRVexOpcode::Vmovaps => "vmovaps",
                     XmmUnaryRmRVexOpcode::Vbroadcastss => "vbroadcastss",
                     XmmUnaryRmRVexOpcode::Vsqrtps => "vsqrtps",
+                    XmmUnaryRmRVexOpcode::Vmovss => "vmovss",
+                    XmmUnaryRmRVexOpcode::Vcvtdq2ps => "vcvtdq2ps",
                 };
-                write!(f, "{opcode} {src},{dst}")
+                write!(f, "{opcode} {},{}", src.display(lanes), dst.display(lanes))
+            }
+            X86Inst::XmmShiftImm { op, imm, src, dst } => {
+                let opcode = match op {
+                    XmmShiftOpcode::Vpslld => "vpslld",
+                    XmmShiftOpcode::Vpsrad => "vpsrad",
+                };
+                let [src, dst] = [src, dst].map(|reg| reg.display(lanes));
+                write!(f, "{opcode} ${imm},{src},{dst}")
             }
             X86Inst::XmmMovRMVex { op, src, dst } => {
+                // vmovd only takes the low lane, which is always an xmm
+                // register
+                let (opcode, lanes) = match op {
+                    XmmMovRMVexOpcode::Vmovaps => ("vmovaps", lanes),
+                    XmmMovRMVexOpcode::Vmovd => ("vmovd", 1),
+                };
+                write!(f, "{opcode} {},{}", src.display(lanes), dst.display(lanes))
+            }
+            X86Inst::XmmRound {
+                imm,
+                scalar,
+                src,
+                dst,
+            } => {
+                let (src, dst) = (src.display(lanes), dst.display(lanes));
+                match lanes {
+                    _ if *scalar => write!(f, "vroundss ${imm},{src},{dst},{dst}"),
+                    // AVX-512 generalized it, and its immediate's low bits
+                    // mean the same
+                    16 => write!(f, "vrndscaleps ${imm},{src},{dst}"),
+                    _ => write!(f, "vroundps ${imm},{src},{dst}"),
+                }
+            }
+            X86Inst::XmmEstimate {
+                op,
+                scalar,
+                src,
+                dst,
+            } => {
                 let opcode = match op {
-                    XmmMovRMVexOpcode::Vmovaps => "vmovaps",
-                    XmmMovRMVexOpcode::Vmovd => "vmovd",
+                    UnOp::Recip => "vrcp",
+                    UnOp::Rsqrt => "vrsqrt",
+                    _ => unreachable!("only recip and rsqrt have estimates"),
                 };
-                write!(f, "{opcode} {src},{dst}")
+                let (src, dst) = (src.display(lanes), dst.display(lanes));
+                match lanes {
+                    _ if *scalar => write!(f, "{opcode}ss {src},{dst},{dst}"),
+                    // AVX-512 replaced them with more precise versions
+                    16 => write!(f, "{opcode}14ps {src},{dst}"),
+                    _ => write!(f, "{opcode}ps {src},{dst}"),
+                }
             }
-        }
+            X86Inst::Fma {
+                form,
+                scalar,
+                src1,
+                src2,
+                dst,
+            } => {
+                let form = match form {
+                    FmaForm::Add231 => "231",
+                    FmaForm::Mul132 => "132",
+                    FmaForm::Mul213 => "213",
+                };
+                let suffix = if *scalar { "ss" } else { "ps" };
+                let [src1, dst] = [src1, dst].map(|reg| reg.display(lanes));
+                write!(
+                    f,
+                    "vfmadd{form}{suffix} {},{src1},{dst}",
+                    src2.display(lanes)
+                )
+            }
+            X86Inst::PackSigns {
+                src,
+                zero,
+                dst,
+                lanes,
+            } => {
+                let add = if *lanes == 1 { "vaddss" } else { "vaddps" };
+                let src = src.display(*lanes);
+                writeln!(f, "{add} {zero},{src},{src}")?;
+                // there's no vmovmskps for zmm registers, but AVX-512 can
+                // collect the sign bits into a mask register instead
+                let (mov, al) = if *lanes > 8 {
+                    writeln!(f, "vpmovd2m {src},%k1")?;
+                    writeln!(f, "kmovw %k1,%eax")?;
+                    ("movw", "%ax")
+                } else {
+                    writeln!(f, "vmovmskps {src},%eax")?;
+                    ("movb", "%al")
+                };
+                writeln!(f, "xor ${:#x},%eax", (1u32 << lanes) - 1)?;
+                write!(f, "{mov} {al},{dst}")
+            }
+        })
     }
 }
 
@@ -352,13 +2224,66 @@ enum XmmRmROpcode {
     Vminps,
     Vmaxps,
     Vxorps,
+    Vandnps,
+    Vandps,
+    // 32-bit integer subtraction
+    Vpsubd,
+    Vaddss,
+    Vsubss,
+    Vmulss,
+    Vminss,
+    Vmaxss,
+    Vsqrtss,
+    Vdivps,
+    Vdivss,
+}
+
+impl XmmRmROpcode {
+    fn binop(op: BinOp, scalar: bool) -> Self {
+        match (op, scalar) {
+            (BinOp::Add, false) => XmmRmROpcode::Vaddps,
+            (BinOp::Sub, false) => XmmRmROpcode::Vsubps,
+            (BinOp::Mul, false) => XmmRmROpcode::Vmulps,
+            (BinOp::Min, false) => XmmRmROpcode::Vminps,
+            (BinOp::Max, false) => XmmRmROpcode::Vmaxps,
+            (BinOp::Add, true) => XmmRmROpcode::Vaddss,
+            (BinOp::Sub, true) => XmmRmROpcode::Vsubss,
+            (BinOp::Mul, true) => XmmRmROpcode::Vmulss,
+            (BinOp::Min, true) => XmmRmROpcode::Vminss,
+            (BinOp::Max, true) => XmmRmROpcode::Vmaxss,
+            (BinOp::Mod, _) => unreachable!("mod takes several instructions"),
+        }
+    }
+}
+
+/// Which operands of `vfmadd` are multiplied, in Intel's numbering, where
+/// the destination is operand 1, `src1` is 2, and `src2` is 3.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum FmaForm {
+    /// `dst = src1 * src2 + dst`
+    Add231,
+    /// `dst = dst * src2 + src1`
+    Mul132,
+    /// `dst = src1 * dst + src2`
+    Mul213,
 }
 
 #[derive(Clone, Copy, Debug)]
 enum XmmUnaryRmRVexOpcode {
     Vbroadcastss,
     Vmovaps,
+    Vmovss,
     Vsqrtps,
+    // 32-bit integers to floats
+    Vcvtdq2ps,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum XmmShiftOpcode {
+    // left
+    Vpslld,
+    // right, copying the sign bit
+    Vpsrad,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -373,12 +2298,12 @@ enum XmmMem {
     Mem(Address),
 }
 
-impl fmt::Display for XmmMem {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            XmmMem::Xmm(xmm) => xmm.fmt(f),
-            XmmMem::Mem(address) => address.fmt(f),
-        }
+impl XmmMem {
+    fn display(&self, lanes: u8) -> impl fmt::Display + '_ {
+        fmt::from_fn(move |f| match self {
+            XmmMem::Xmm(xmm) => write!(f, "{}", xmm.display(lanes)),
+            XmmMem::Mem(address) => write!(f, "{address}"),
+        })
     }
 }
 
@@ -397,9 +2322,16 @@ impl From<Address> for XmmMem {
 #[derive(Clone, Copy, Debug)]
 struct Xmm(Register);
 
-impl fmt::Display for Xmm {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "%xmm{}", self.0.idx())
+impl Xmm {
+    /// Name the whole register which holds `lanes` floats, such as `%ymm3`
+    /// for eight.
+    fn display(self, lanes: u8) -> impl fmt::Display {
+        let prefix = match lanes {
+            16 => "z",
+            8 => "y",
+            _ => "x",
+        };
+        fmt::from_fn(move |f| write!(f, "%{prefix}mm{}", self.0.idx()))
     }
 }
 
@@ -414,20 +2346,601 @@ struct Address(MemorySpace, Location, u8);
 
 impl fmt::Display for Address {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let memory_space = [
-            "(%rsp)",
-            "+consts(%rip)",
-            "(%rdi)",
-            "(%rsi)",
-            "(%rdx)",
-            "(%rcx)",
-            "(%r8)",
-            "(%r9)",
-            "(%r10)",
-        ][self.0.idx()];
         if self.1 > 0 {
             write!(f, "{:#x}", usize::from(self.1) * usize::from(self.2) * 4)?;
         }
-        write!(f, "{}", memory_space)
+        match self.0.idx() {
+            0 => write!(f, "(%rsp)"),
+            1 => write!(f, "+consts(%rip)"),
+            idx => write!(f, "({})", ARG_REGISTERS[idx - 2]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::bytecode::{Input, Program};
+    use crate::ir::memoize::MemoBuilder;
+
+    #[test]
+    fn test_stats_trailer() {
+        // all 24 products are live at once, which is more than the 16
+        // registers can hold
+        let mut text = "x var-x\ny var-y\n".to_string();
+        for i in 0..24 {
+            text += &format!("c{i} const {}\nm{i} mul x c{i}\ns{i} add m{i} y\n", i + 2);
+        }
+        text += "r23 add s23 s0\n";
+        for i in (0..23).rev() {
+            text += &format!("r{i} mul r{} s{i}\n", i + 1);
+        }
+        let memoized = crate::ir::io::read(text.as_bytes(), MemoBuilder::new()).unwrap();
+        let config = X86Config {
+            stats: true,
+            ..X86Config::default()
+        };
+        let mut asm = Vec::new();
+        write(&mut asm, config, &memoized).unwrap();
+        let asm = String::from_utf8(asm).unwrap();
+
+        let xy = asm.split("\nxy:\n").nth(1).unwrap();
+        let stat = |name: &str| {
+            let line = xy.lines().find(|line| line.starts_with(name)).unwrap();
+            let numbers = line[name.len()..].split(|c: char| !c.is_ascii_digit());
+            let numbers = numbers.filter(|n| !n.is_empty());
+            numbers.map(|n| n.parse().unwrap()).collect::<Vec<usize>>()
+        };
+        // every instruction in the body is a vector instruction
+        let body = xy
+            .lines()
+            .filter(|line| line.starts_with('v') && *line != "vzeroupper");
+        assert_eq!(stat("# instructions: ")[0], body.count());
+        let spills = stat("# spills: ");
+        assert!(spills[0] >= 8, "{xy}");
+        // each stack slot holds a vector of four floats
+        assert_eq!(stat("# frame: ")[0], spills[1] * 16);
+    }
+
+    #[test]
+    fn test_vzeroupper() {
+        let memoized = crate::corpus::shape("circle")
+            .unwrap()
+            .read(MemoBuilder::new());
+        let count = |stride, vzeroupper| {
+            let config = X86Config {
+                stride,
+                vzeroupper,
+                ..X86Config::default()
+            };
+            let mut asm = Vec::new();
+            write(&mut asm, config, &memoized).unwrap();
+            let asm = String::from_utf8(asm).unwrap();
+            let rets = asm.lines().filter(|&line| line == "ret").count();
+            let lines = asm.lines().collect::<Vec<_>>();
+            // only ever right before returning
+            let before = lines
+                .windows(2)
+                .filter(|pair| pair == &["vzeroupper", "ret"]);
+            let total = asm.lines().filter(|&line| line == "vzeroupper").count();
+            assert_eq!(before.count(), total);
+            (total, rets)
+        };
+        let (total, rets) = count(Stride::Eight, true);
+        assert_eq!(total, rets);
+        assert_eq!(count(Stride::Sixteen, true).0, rets);
+        assert_eq!(count(Stride::Eight, false).0, 0);
+        assert_eq!(count(Stride::Four, true).0, 0);
+    }
+
+    #[test]
+    fn test_wide_strides() {
+        use crate::codegen::loops;
+        // wider vectors only change how many lanes each loop and buffer has
+        fn widen(steps: &mut [loops::Step], lanes: u16) {
+            for step in steps {
+                if let loops::Step::Loop(level, body) = step {
+                    if level.lanes == 4 {
+                        level.lanes = lanes;
+                    }
+                    widen(body, lanes);
+                }
+            }
+        }
+        let memoized = crate::corpus::shape("ring")
+            .unwrap()
+            .read(MemoBuilder::new());
+        let config = |stride| X86Config {
+            stride,
+            ..X86Config::default()
+        };
+        let four = config(Stride::Four);
+        for stride in [Stride::Eight, Stride::Sixteen] {
+            let lanes = u16::from(stride.lanes());
+            let mut expected = manifest(four, &memoized);
+            expected.stride = lanes;
+            assert_eq!(manifest(config(stride), &memoized), expected);
+
+            let mut expected = loop_nest(four, &memoized).unwrap();
+            widen(&mut expected.body, lanes);
+            for buffer in expected.buffers.iter_mut() {
+                let levels = buffer.levels.iter_mut().map(|level| &mut level.lanes);
+                for buffer_lanes in levels.chain([&mut buffer.lanes]) {
+                    if *buffer_lanes == 4 {
+                        *buffer_lanes = lanes;
+                    }
+                }
+            }
+            expected.stride = lanes;
+            assert_eq!(loop_nest(config(stride), &memoized).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_loaded_result() {
+        // a constant result is loaded into `xy`, which then has to store it
+        let text = "c const 0.25\n";
+        let memoized = crate::ir::io::read(text.as_bytes(), MemoBuilder::new()).unwrap();
+        let mut asm = Vec::new();
+        write(&mut asm, X86Config::default(), &memoized).unwrap();
+        let asm = String::from_utf8(asm).unwrap();
+        let xy = asm.split("\nxy:\n").nth(1).unwrap();
+        let body: Vec<&str> = xy.lines().take_while(|&line| line != "ret").collect();
+        assert_eq!(
+            body,
+            ["vmovaps +consts(%rip),%xmm15", "vmovaps %xmm15,(%rdx)"],
+            "{xy}"
+        );
+    }
+
+    #[test]
+    fn test_split_outputs() {
+        // `a` is both stored for xyz and used by every later part of xy,
+        // which has to load it back from where it's already stored
+        let text = "x var-x\ny var-y\nz var-z\na add x y\nb mul a z\nc sub a x\n\
+            d mul c y\ne add d a\nf mul e z\ng add f b\n";
+        let memoized = crate::ir::io::read(text.as_bytes(), MemoBuilder::new()).unwrap();
+        for max_insts in 1..=4 {
+            let config = X86Config {
+                max_insts: Some(max_insts),
+                ..X86Config::default()
+            };
+            let mut asm = Vec::new();
+            write(&mut asm, config, &memoized).unwrap();
+            let asm = String::from_utf8(asm).unwrap();
+            let xy = asm.split("\nxy:\n").nth(1).unwrap();
+            let xy = xy.split("\n.globl z\n").next().unwrap();
+            // each location is stored at most once along the chain
+            let mut stores: Vec<&str> = xy
+                .lines()
+                .filter_map(|line| line.strip_prefix("vmovaps %")?.split_once(','))
+                .map(|(_, address)| address)
+                .filter(|address| address.ends_with("(%rdx)"))
+                .collect();
+            let count = stores.len();
+            stores.sort_unstable();
+            stores.dedup();
+            assert_eq!(stores.len(), count, "{xy}");
+        }
+    }
+
+    #[test]
+    fn test_pack_bits_with_z() {
+        // functions of z read xy's values, so they can't be packed
+        let text = "_0 var-x\n_1 var-y\n_2 var-z\n_3 add _0 _1\n_4 mul _3 _3\n\
+            _5 sin _4\n_6 add _5 _2\n";
+        let memoized = crate::ir::io::read(text.as_bytes(), MemoBuilder::new()).unwrap();
+        let config = X86Config {
+            pack_bits: true,
+            ..X86Config::default()
+        };
+        let mut asm = Vec::new();
+        write(&mut asm, config, &memoized).unwrap();
+        let asm = String::from_utf8(asm).unwrap();
+        assert!(asm.contains("xy_packed: .byte 0\n"));
+        assert!(!asm.contains("movb"), "{asm}");
+        assert!(!manifest(config, &memoized).xy_packed);
+
+        // but a program of x and y alone still packs its result
+        let memoized = crate::corpus::shape("circle")
+            .unwrap()
+            .read(MemoBuilder::new());
+        assert!(manifest(config, &memoized).xy_packed);
+    }
+
+    #[test]
+    fn test_uses_z() {
+        // only xy is rendered, which here is just an intermediate value
+        let text = "x var-x\ny var-y\nz var-z\nxy add x y\nout mul xy z\n";
+        let memoized = crate::ir::io::read(text.as_bytes(), MemoBuilder::new()).unwrap();
+        let config = X86Config::default();
+        assert!(loop_nest(config, &memoized).is_err());
+        let nest = xy_loop_nest(config, &memoized);
+        let err = super::super::harness::write(Vec::new(), &memoized, &nest).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        // SAFETY: the manifest is rejected before anything is loaded
+        let loaded = unsafe { crate::runtime::dlopen("missing.so", &manifest(config, &memoized)) };
+        assert!(matches!(loaded, Err(crate::runtime::Error::UsesZ(_))));
+
+        let memoized = crate::corpus::shape("circle")
+            .unwrap()
+            .read(MemoBuilder::new());
+        assert!(loop_nest(config, &memoized).is_ok());
+    }
+
+    #[test]
+    fn test_seventh_argument() {
+        // xyz's own memory space is its seventh argument, which the calling
+        // convention passes on the stack
+        let text = "x var-x\ny var-y\nz var-z\nxy add x y\nout mul xy z\n";
+        let memoized = crate::ir::io::read(text.as_bytes(), MemoBuilder::new()).unwrap();
+        let mut asm = Vec::new();
+        write(&mut asm, X86Config::default(), &memoized).unwrap();
+        let asm = String::from_utf8(asm).unwrap();
+        let xyz = asm.split("\nxyz:\n").nth(1).unwrap();
+        assert_eq!(xyz.lines().next(), Some("movq 8(%rsp),%r10"), "{xyz}");
+        let manifest = manifest(X86Config::default(), &memoized);
+        let params = &manifest.function("xyz").unwrap().params;
+        assert_eq!(params[6].register.as_deref(), Some("8(%rsp)"));
+    }
+
+    #[test]
+    fn test_approximate_ranges() {
+        // `exp` is approximated, so neither it nor anything computed from
+        // it in `x` or `xy` can be trusted to stay in its range
+        let text = "x var-x\ny var-y\nc const 3\ne exp x\nm min e c\nt add y c\nout max m t\n";
+        let memoized = crate::ir::io::read(text.as_bytes(), MemoBuilder::new()).unwrap();
+        let widened = widen_approximate_ranges(X86Config::default(), &memoized);
+        let everything = |func: &MemoizedFunc| {
+            func.ranges
+                .iter()
+                .filter(|&&range| range == Interval::EVERYTHING)
+                .count()
+        };
+        let [x, y, xy] = [
+            Var::X.into(),
+            Var::Y.into(),
+            VarSet::from(Var::X) | Var::Y.into(),
+        ]
+        .map(|vars: VarSet| vars.idx() - 1);
+        assert!(everything(&memoized.funcs[x]) < everything(&widened.funcs[x]));
+        assert_eq!(
+            everything(&memoized.funcs[y]),
+            everything(&widened.funcs[y])
+        );
+        let xy = &widened.funcs[xy];
+        let out = xy.outputs[0].unwrap();
+        assert_eq!(xy.ranges[out.idx()], Interval::EVERYTHING);
+        assert!(xy.insts.iter().enumerate().all(|(idx, inst)| {
+            !matches!(inst, Inst::Load { vars, .. } if *vars == Var::X.into())
+                || xy.ranges[idx] == Interval::EVERYTHING
+        }));
+    }
+
+    #[test]
+    fn test_abs() {
+        // |x| clears the sign bit with the same mask `neg` flips it with
+        let text = "x var-x\ny var-y\na abs x\nout add a y\n";
+        let memoized = crate::ir::io::read(text.as_bytes(), MemoBuilder::new()).unwrap();
+        let mut asm = Vec::new();
+        write(&mut asm, X86Config::default(), &memoized).unwrap();
+        let asm = String::from_utf8(asm).unwrap();
+        let x = asm.split("\nx:\n").nth(1).unwrap();
+        let body: Vec<&str> = x.lines().take_while(|&line| line != "ret").collect();
+        assert_eq!(
+            body,
+            [
+                "vmovaps +consts(%rip),%xmm15",
+                "vandnps (%rdi),%xmm15,%xmm15",
+                "vmovaps %xmm15,0x10(%rdi)"
+            ],
+            "{x}"
+        );
+    }
+
+    #[test]
+    fn test_math_steps() {
+        // run the steps one lane at a time, the way the instructions would
+        fn run(steps: &[Step], args: &[f32]) -> f32 {
+            let mut results: Vec<u32> = Vec::new();
+            for &step in steps {
+                let get = |operand| match operand {
+                    Operand::Arg => args[0].to_bits(),
+                    Operand::Arg2 => args[1].to_bits(),
+                    Operand::Arg3 => args[2].to_bits(),
+                    Operand::Step(step) => results[usize::from(step)],
+                    Operand::Const(c) => MATH_CONSTS[c as usize].0,
+                };
+                let float = |operand| f32::from_bits(get(operand));
+                results.push(match step {
+                    // like minps and maxps, the second operand wins ties
+                    // and NaN
+                    Step::Float(BinOp::Min, a, b) if float(a) < float(b) => get(a),
+                    Step::Float(BinOp::Max, a, b) if float(a) > float(b) => get(a),
+                    Step::Float(BinOp::Min | BinOp::Max, _, b) => get(b),
+                    Step::Float(op, a, b) => {
+                        let [a, b] = [a, b].map(float);
+                        match op {
+                            BinOp::Add => a + b,
+                            BinOp::Sub => a - b,
+                            BinOp::Mul => a * b,
+                            BinOp::Min | BinOp::Max | BinOp::Mod => unreachable!(),
+                        }
+                        .to_bits()
+                    }
+                    Step::Packed(XmmRmROpcode::Vpsubd, a, b) => get(a).wrapping_sub(get(b)),
+                    Step::Packed(XmmRmROpcode::Vandps, a, b) => get(a) & get(b),
+                    Step::Packed(XmmRmROpcode::Vxorps, a, b) => get(a) ^ get(b),
+                    Step::Packed(..) => unreachable!(),
+                    Step::Shift(XmmShiftOpcode::Vpslld, imm, a) => get(a) << imm,
+                    Step::Shift(XmmShiftOpcode::Vpsrad, imm, a) => (get(a) as i32 >> imm) as u32,
+                    Step::IntToFloat(a) => (get(a) as i32 as f32).to_bits(),
+                    Step::Div(a, b) => (float(a) / float(b)).to_bits(),
+                    Step::Sqrt(a) => float(a).sqrt().to_bits(),
+                    Step::RoundToInt(imm, a) => match imm & 3 {
+                        0 => float(a).round_ties_even(),
+                        1 => float(a).floor(),
+                        2 => float(a).ceil(),
+                        _ => float(a).trunc(),
+                    }
+                    .to_bits(),
+                    // as bad as `vrcpps` is allowed to be: a relative
+                    // error up to 1.5 * 2^-12, here always too small
+                    Step::Estimate(op, a) => {
+                        let exact = match op {
+                            UnOp::Recip => 1.0 / float(a),
+                            _ => crate::ir::rsqrt(float(a)),
+                        };
+                        (exact * (1.0 - 1.5 / 4096.0)).to_bits()
+                    }
+                });
+            }
+            f32::from_bits(*results.last().unwrap())
+        }
+        let ulps = |value: f32, exact: f32| value.to_bits().abs_diff(exact.to_bits());
+        for idx in -870..=880 {
+            let x = idx as f32 / 10.0 + 0.037;
+            assert!(ulps(run(&EXP_STEPS, &[x]), x.exp()) <= 1, "exp({x})");
+            let x = x.exp();
+            assert!(ulps(run(&LN_STEPS, &[x]), x.ln()) <= 1, "ln({x})");
+        }
+        // out of range, results saturate, but NaN stays NaN
+        assert!(run(&EXP_STEPS, &[-1000.0]).is_normal());
+        assert!(run(&EXP_STEPS, &[1000.0]).is_normal());
+        assert!(run(&EXP_STEPS, &[f32::NAN]).is_nan());
+
+        // sin and cos lose a little more to rounding in the reduction
+        for idx in -10000..=10000 {
+            let x = idx as f32 / 100.0 + 0.0037;
+            let exact = f64::from(x).sin() as f32;
+            assert!(ulps(run(&SIN_STEPS, &[x]), exact) <= 2, "sin({x})");
+            let exact = f64::from(x).cos() as f32;
+            assert!(ulps(run(&COS_STEPS, &[x]), exact) <= 2, "cos({x})");
+        }
+        assert!(run(&SIN_STEPS, &[f32::INFINITY]).is_nan());
+
+        // One refinement is enough for all but the last few bits. At
+        // zero and infinity the estimates are exact, and stay that way.
+        let approx = |steps: &[Step], x: f32| run(steps, &[x]);
+        for idx in 1..=2000 {
+            let x = idx as f32 * 0.0173 - 17.3;
+            assert_eq!(run(&RECIP_STEPS, &[x]), 1.0 / x);
+            assert!(
+                ulps(approx(&APPROX_RECIP_STEPS, x), 1.0 / x) <= 3,
+                "recip({x})"
+            );
+            let x = x.abs();
+            assert_eq!(run(&RSQRT_STEPS, &[x]), crate::ir::rsqrt(x));
+            let exact = crate::ir::rsqrt(x);
+            assert!(
+                ulps(approx(&APPROX_RSQRT_STEPS, x), exact) <= 5,
+                "rsqrt({x})"
+            );
+        }
+        for x in [0.0, -0.0, f32::INFINITY, f32::NEG_INFINITY] {
+            assert_eq!(
+                approx(&APPROX_RECIP_STEPS, x).to_bits(),
+                (1.0 / x).to_bits()
+            );
+        }
+        for x in [0.0, -0.0, f32::INFINITY] {
+            let exact = crate::ir::rsqrt(x);
+            assert_eq!(approx(&APPROX_RSQRT_STEPS, x).to_bits(), exact.to_bits());
+        }
+        assert!(approx(&APPROX_RSQRT_STEPS, -1.0).is_nan());
+
+        // mod takes the same steps as the interpreter, so it's exact
+        for a in -50..=50 {
+            for b in [-2.5, -0.3, 0.7, 1.0, 3.0] {
+                let a = a as f32 / 4.0 + 0.01;
+                let expected = crate::ir::modulo(a, b);
+                assert_eq!(
+                    run(&MOD_STEPS, &[a, b]).to_bits(),
+                    expected.to_bits(),
+                    "mod({a}, {b})"
+                );
+            }
+        }
+
+        // and so do clamp and lerp, including NaN in clamp's first operand
+        for x in [-3.0, -0.5, 0.25, 0.999, 7.0, f32::NAN] {
+            for [a, b] in [[-1.0, 1.0], [0.5, 0.125], [0.3, 0.7]] {
+                for (steps, op, args) in [
+                    (&CLAMP_STEPS[..], TernOp::Clamp, [x, a, b]),
+                    (&LERP_STEPS[..], TernOp::Lerp, [a, b, x]),
+                ] {
+                    assert_eq!(
+                        run(steps, &args).to_bits(),
+                        op.eval(args).to_bits(),
+                        "{op:?}{args:?}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_fma() {
+        // each form overwrites a different operand, and when none of them is
+        // free to overwrite, the addend is copied into place first
+        let text = "x var-x\ny var-y\nk const 3\nc const 0.25\nf fma x k c\ng fma f f c\n\
+            h fma x y g\nout fma g y h\n";
+        let memoized = crate::ir::io::read(text.as_bytes(), MemoBuilder::new()).unwrap();
+        let mut asm = Vec::new();
+        write(&mut asm, X86Config::default(), &memoized).unwrap();
+        let asm = String::from_utf8(asm).unwrap();
+        let body = |name: &str| -> Vec<&str> {
+            let func = asm.split(&format!("\n{name}:\n")).nth(1).unwrap();
+            func.lines().take_while(|&line| line != "ret").collect()
+        };
+        assert_eq!(
+            body("x"),
+            [
+                "vmovaps (%rdi),%xmm15",
+                "vmovaps 0x10+consts(%rip),%xmm14",
+                "vfmadd132ps +consts(%rip),%xmm14,%xmm15",
+                "vfmadd213ps %xmm14,%xmm15,%xmm15",
+                "vmovaps %xmm15,0x10(%rdi)"
+            ]
+        );
+        assert_eq!(
+            body("xy"),
+            [
+                "vbroadcastss (%rsi),%xmm15",
+                "vmovaps 0x10(%rdi),%xmm13",
+                "vmovaps %xmm13,%xmm14",
+                "vfmadd231ps (%rdi),%xmm15,%xmm14",
+                "vfmadd132ps %xmm13,%xmm14,%xmm15",
+                "vmovaps %xmm15,(%rdx)"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_few_registers() {
+        // prospero.vm isn't distributed with this repository, so without it
+        // this only covers the embedded shapes
+        let mut texts: Vec<String> = crate::corpus::SHAPES
+            .iter()
+            .map(|shape| shape.text.to_string())
+            .collect();
+        texts.extend(crate::corpus::prospero());
+        for text in texts {
+            let memoized = crate::ir::io::read(text.as_bytes(), MemoBuilder::new()).unwrap();
+            for registers in [4, 8] {
+                let config = X86Config {
+                    registers,
+                    ..X86Config::default()
+                };
+                let mut asm = Vec::new();
+                write(&mut asm, config, &memoized).unwrap();
+                let asm = String::from_utf8(asm).unwrap();
+                let highest = asm
+                    .split("%xmm")
+                    .skip(1)
+                    .map(|rest| {
+                        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap();
+                        rest[..digits].parse::<u8>().unwrap()
+                    })
+                    .max();
+                assert!(highest.is_some_and(|reg| reg < registers), "{asm}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_fold_negations() {
+        let x = VarSet::from(Var::X);
+        let load = |loc| Inst::Load { vars: x, loc };
+        let idx = |idx: usize| InstIdx::try_from(idx).unwrap();
+        let binop = |op, a, b| Inst::BinOp {
+            op,
+            args: [idx(a), idx(b)],
+        };
+        let neg = |arg| Inst::UnOp {
+            op: UnOp::Neg,
+            arg: idx(arg),
+        };
+        // max(v8, -min(v1 - v2, -v4))
+        let insts = vec![
+            load(0),
+            Inst::UnOp {
+                op: UnOp::Square,
+                arg: idx(0),
+            },
+            load(1),
+            binop(BinOp::Sub, 1, 2),
+            load(2),
+            neg(4),
+            binop(BinOp::Min, 3, 5),
+            neg(6),
+            load(3),
+            binop(BinOp::Max, 8, 7),
+        ];
+        let func = MemoizedFunc {
+            vars: x,
+            ranges: vec![Interval::new(-1.0, 1.0); insts.len()],
+            insts,
+            outputs: vec![Some(idx(9))],
+        };
+
+        let folded = fold_negations(&func, |_| false);
+        assert_eq!(folded.insts.len(), 8);
+        let is_neg = |inst: &Inst| matches!(inst, Inst::UnOp { op: UnOp::Neg, .. });
+        assert!(!folded.insts.iter().any(is_neg));
+        // the loaded operand of `sub` stays second if it could be sunk
+        assert_eq!(fold_negations(&func, |_| true).insts, func.insts);
+
+        let memoized = Memoized::default();
+        for space in [[0.5, 0.25, -0.75, 0.0], [-0.5, 0.25, 0.125, -1.0]] {
+            let [expected, actual] = [&func, &folded].map(|func| {
+                let program = Program::compile_memoized(&memoized, func);
+                let mut regs = program.registers();
+                for (reg, input) in regs.iter_mut().zip(program.inputs()) {
+                    let &Input::Load(_, loc) = input else {
+                        unreachable!()
+                    };
+                    *reg = space[usize::from(loc)];
+                }
+                program.run(&mut regs);
+                program.outputs(&regs).next().unwrap()
+            });
+            assert_eq!(expected, actual);
+        }
+    }
+
+    #[test]
+    fn test_tuning() {
+        let memoized = crate::corpus::shape("circle")
+            .unwrap()
+            .read(MemoBuilder::new());
+        let tuned = X86Config {
+            tune_sink_loads: true,
+            ..X86Config::default()
+        };
+        let mut tuning = Tuning::default();
+        let mut expected = Vec::new();
+        write_tuned(&mut expected, tuned, &memoized, &mut tuning).unwrap();
+        assert!(tuning.get("xyz").is_some());
+
+        let mut text = Vec::new();
+        tuning.write(&mut text).unwrap();
+        let mut reread = Tuning::parse(std::str::from_utf8(&text).unwrap()).unwrap();
+        assert_eq!(reread, tuning);
+
+        // a stored choice is reused as-is, even with tuning turned on
+        let mut actual = Vec::new();
+        write_tuned(&mut actual, tuned, &memoized, &mut reread).unwrap();
+        assert_eq!(actual, expected);
+        assert_eq!(reread, tuning);
+
+        assert!(matches!(
+            Tuning::parse("xyz all\nxy\n"),
+            Err(TuningError::Syntax(2))
+        ));
+        assert!(matches!(
+            Tuning::parse("xyz sometimes\n"),
+            Err(TuningError::Policy(1, policy)) if policy == "sometimes"
+        ));
     }
 }
@@ -0,0 +1,848 @@
+//! Small programs with the images that every backend has to produce from
+//! them, so a backend can be checked against the same expectations as all
+//! the others rather than only against whichever one it was compared to
+//! while it was being written. Each case exercises a few operations, and
+//! together they cover every one, along with programs that depend on only
+//! one variable or on neither.
+//!
+//! The interpreters and renderers are checked by this module's tests. With
+//! the `all-backends` feature, so are the x86 backend at every stride this
+//! CPU supports and the C backend, which need `gcc` to build.
+
+use std::fmt::Write;
+use thiserror::Error;
+
+use crate::image::{Image, Tolerance, is_inside};
+use crate::ir::Insts;
+use crate::ir::io::read;
+
+/// The width and height of the images in [`CASES`]. Pixels are exactly a
+/// quarter apart, so every backend gets the same coordinates however it
+/// steps from one pixel to the next, and plenty of them land exactly on
+/// contours, where zero of either sign counts as inside. It isn't a multiple
+/// of any vector width, so backends have to get partial vectors right too.
+pub const SIZE: u16 = 9;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Case {
+    pub name: &'static str,
+    pub text: &'static str,
+    /// The expected image, a line per row starting from the top, with `#`
+    /// for pixels which are [inside](is_inside) and `.` for the rest.
+    pub image: &'static str,
+    /// The column, row, and value of pixels which have to be within
+    /// `tolerance` of the given value.
+    pub samples: &'static [(u16, u16, f32)],
+    pub tolerance: Tolerance,
+}
+
+/// How a backend's image differed from a [`Case`]'s expectations.
+#[derive(Clone, Debug, Error, PartialEq)]
+pub enum Failure {
+    #[error("image is {found}x{found} but should be {SIZE}x{SIZE}")]
+    Size { found: u16 },
+    #[error("{count} pixels on the wrong side of the contour:\n{image}")]
+    Image { count: usize, image: String },
+    #[error(
+        "pixel at column {col}, row {row} is {actual:?}, not within {tolerance} of {expected:?}"
+    )]
+    Sample {
+        col: u16,
+        row: u16,
+        expected: f32,
+        actual: f32,
+        tolerance: Tolerance,
+    },
+}
+
+/// Every case, each of which any backend has to pass.
+pub const CASES: &[Case] = &[
+    Case {
+        name: "circle",
+        text: include_str!("../corpus/circle.vm"),
+        image: "\
+            .........\n\
+            .........\n\
+            ....#....\n\
+            ...###...\n\
+            ..#####..\n\
+            ...###...\n\
+            ....#....\n\
+            .........\n\
+            .........\n",
+        samples: &[(1, 2, -0.4013878), (4, 4, 0.5)],
+        tolerance: Tolerance::Exact,
+    },
+    Case {
+        name: "ring",
+        text: include_str!("../corpus/ring.vm"),
+        image: "\
+            .........\n\
+            .........\n\
+            .........\n\
+            ...####..\n\
+            ..##..##.\n\
+            ..##..##.\n\
+            ..#####..\n\
+            ...####..\n\
+            .........\n",
+        samples: &[(1, 2, -0.40113574), (4, 4, -0.0763932)],
+        tolerance: Tolerance::Exact,
+    },
+    Case {
+        name: "fenced",
+        text: include_str!("../corpus/fenced.vm"),
+        image: "\
+            .........\n\
+            .........\n\
+            ....#....\n\
+            ...##....\n\
+            ..#####..\n\
+            ....##...\n\
+            ....#....\n\
+            .........\n\
+            .........\n",
+        samples: &[(1, 2, -0.4013878), (3, 3, 0.0625)],
+        tolerance: Tolerance::Exact,
+    },
+    Case {
+        name: "halfplanes",
+        text: include_str!("../corpus/conformance/halfplanes.vm"),
+        image: "\
+            ......###\n\
+            ......###\n\
+            ......###\n\
+            ......###\n\
+            ......###\n\
+            .........\n\
+            .........\n\
+            .........\n\
+            .........\n",
+        samples: &[(1, 2, -1.25), (6, 1, 0.0)],
+        tolerance: Tolerance::Exact,
+    },
+    Case {
+        name: "frame",
+        text: include_str!("../corpus/conformance/frame.vm"),
+        image: "\
+            .........\n\
+            .#######.\n\
+            .#######.\n\
+            .##...##.\n\
+            .##...##.\n\
+            .##...##.\n\
+            .#######.\n\
+            .#######.\n\
+            .........\n",
+        samples: &[(1, 2, 0.14999998), (4, 4, -0.4)],
+        tolerance: Tolerance::Exact,
+    },
+    Case {
+        name: "diamond",
+        text: include_str!("../corpus/conformance/diamond.vm"),
+        image: "\
+            ....#....\n\
+            ....#....\n\
+            ...###...\n\
+            ...###...\n\
+            ..#####..\n\
+            ...###...\n\
+            ...###...\n\
+            ....#....\n\
+            ....#....\n",
+        samples: &[(6, 1, -0.75), (4, 4, 1.0)],
+        tolerance: Tolerance::Exact,
+    },
+    Case {
+        name: "saddle",
+        text: include_str!("../corpus/conformance/saddle.vm"),
+        image: "\
+            ...######\n\
+            ...######\n\
+            ..#######\n\
+            #########\n\
+            #########\n\
+            #########\n\
+            #######..\n\
+            ######...\n\
+            ######...\n",
+        samples: &[(7, 7, -0.3125), (6, 1, 0.625)],
+        tolerance: Tolerance::Exact,
+    },
+    // Backends may approximate `exp`, `ln`, `sin`, and `cos`, as long as
+    // they're close.
+    Case {
+        name: "exp_ln",
+        text: include_str!("../corpus/conformance/exp_ln.vm"),
+        image: "\
+            #######..\n\
+            #######..\n\
+            #######..\n\
+            #######..\n\
+            #######..\n\
+            ######...\n\
+            ######...\n\
+            #####....\n\
+            #####....\n",
+        samples: &[(1, 2, 1.4439242), (6, 1, 0.36287975)],
+        tolerance: Tolerance::Ulps(4),
+    },
+    Case {
+        name: "waves",
+        text: include_str!("../corpus/conformance/waves.vm"),
+        image: "\
+            ......#..\n\
+            .........\n\
+            ......##.\n\
+            #...#####\n\
+            #..######\n\
+            #...#####\n\
+            ......##.\n\
+            .........\n\
+            ......#..\n",
+        samples: &[(1, 2, -1.49422), (6, 5, 1.2377973)],
+        tolerance: Tolerance::Ulps(4),
+    },
+    Case {
+        name: "tiles",
+        text: include_str!("../corpus/conformance/tiles.vm"),
+        image: "\
+            .........\n\
+            .#.#.#.#.\n\
+            .........\n\
+            .#.#.#.#.\n\
+            .........\n\
+            .#.#.#.#.\n\
+            .........\n\
+            .#.#.#.#.\n\
+            .........\n",
+        samples: &[(4, 4, -0.3), (3, 7, 0.19)],
+        tolerance: Tolerance::Exact,
+    },
+    Case {
+        name: "lens",
+        text: include_str!("../corpus/conformance/lens.vm"),
+        image: "\
+            .........\n\
+            .........\n\
+            ..#####..\n\
+            .#######.\n\
+            .#######.\n\
+            .#######.\n\
+            ..#####..\n\
+            ...###...\n\
+            .........\n",
+        samples: &[(4, 4, 0.375), (1, 4, 0.08333337), (4, 1, -0.17647052)],
+        tolerance: Tolerance::Exact,
+    },
+    Case {
+        name: "columns",
+        text: include_str!("../corpus/conformance/columns.vm"),
+        image: "\
+            ...###...\n\
+            ...###...\n\
+            ...###...\n\
+            ...###...\n\
+            ...###...\n\
+            ...###...\n\
+            ...###...\n\
+            ...###...\n\
+            ...###...\n",
+        samples: &[(6, 1, -0.19999999), (4, 4, 0.3)],
+        tolerance: Tolerance::Exact,
+    },
+    Case {
+        name: "capsule",
+        text: include_str!("../corpus/conformance/capsule.vm"),
+        image: "\
+            .........\n\
+            .........\n\
+            .........\n\
+            ....###..\n\
+            ..######.\n\
+            ....###..\n\
+            .........\n\
+            .........\n\
+            .........\n",
+        samples: &[(4, 4, 0.26), (0, 4, -0.34000003), (7, 3, -0.018553376)],
+        tolerance: Tolerance::Exact,
+    },
+    Case {
+        name: "powers",
+        text: include_str!("../corpus/conformance/powers.vm"),
+        image: "\
+            .........\n\
+            ..#####..\n\
+            .#######.\n\
+            .#######.\n\
+            ..######.\n\
+            ..######.\n\
+            ..######.\n\
+            ..#####..\n\
+            .........\n",
+        samples: &[(4, 4, 0.125), (1, 4, -0.055555552), (2, 6, 0.0)],
+        tolerance: Tolerance::Exact,
+    },
+    Case {
+        name: "pressure",
+        text: include_str!("../corpus/conformance/pressure.vm"),
+        image: "\
+            #........\n\
+            #........\n\
+            #........\n\
+            #........\n\
+            #.......#\n\
+            #......#.\n\
+            #.....#..\n\
+            ##...#...\n\
+            ###.#....\n",
+        samples: &[(4, 4, -1.0), (2, 6, -0.5), (6, 2, -1.5), (0, 8, 0.5)],
+        tolerance: Tolerance::Exact,
+    },
+    Case {
+        name: "constant",
+        text: include_str!("../corpus/conformance/constant.vm"),
+        image: "\
+            .........\n\
+            .........\n\
+            .........\n\
+            .........\n\
+            .........\n\
+            .........\n\
+            .........\n\
+            .........\n\
+            .........\n",
+        samples: &[(0, 0, -0.5), (8, 8, -0.5)],
+        tolerance: Tolerance::Exact,
+    },
+];
+
+impl Case {
+    /// The program as written, without any passes run on it.
+    pub fn insts(&self) -> Insts {
+        read(self.text.as_bytes(), Insts::default()).expect("conformance cases are valid")
+    }
+
+    /// Check a backend's render of this case at [`SIZE`] against the
+    /// expected image, and against the samples too if `values` is set. An
+    /// image with pixels on the wrong side is reported with those pixels
+    /// marked `X`.
+    pub fn check(&self, image: &Image, values: bool) -> Result<(), Failure> {
+        if image.size != SIZE {
+            return Err(Failure::Size { found: image.size });
+        }
+        let mut count = 0;
+        let mut marked = String::new();
+        for (row, expected) in image.rows().zip(self.image.lines()) {
+            for (&value, expected) in row.iter().zip(expected.chars()) {
+                let inside = is_inside(value);
+                if inside != (expected == '#') {
+                    count += 1;
+                    marked.push('X');
+                } else {
+                    marked.push(if inside { '#' } else { '.' });
+                }
+            }
+            marked.push('\n');
+        }
+        if count > 0 {
+            return Err(Failure::Image {
+                count,
+                image: marked,
+            });
+        }
+        if !values {
+            return Ok(());
+        }
+        for &(col, row, expected) in self.samples {
+            let actual = image.pixels[usize::from(row) * usize::from(SIZE) + usize::from(col)];
+            if !self.tolerance.accepts(expected, actual) {
+                return Err(Failure::Sample {
+                    col,
+                    row,
+                    expected,
+                    actual,
+                    tolerance: self.tolerance,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Run every case through a backend, given as a function which renders a
+/// program at [`SIZE`], and describe each case it failed, if any. Backends
+/// which only promise which side of the contour each pixel is on, like
+/// [`render_adaptive`](crate::render::render_adaptive), pass `values: false`
+/// to skip the samples.
+pub fn check_backend(values: bool, mut render: impl FnMut(&Insts) -> Image) -> Result<(), String> {
+    let mut failures = String::new();
+    for case in CASES {
+        if let Err(failure) = case.check(&render(&case.insts()), values) {
+            writeln!(failures, "{}: {failure}", case.name).unwrap();
+        }
+    }
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(failures)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::bytecode;
+    use crate::ir::interp::interp_image;
+    use crate::ir::memoize::MemoBuilder;
+    use crate::render::{self, Config};
+
+    type Backend<'a> = &'a dyn Fn(&Insts) -> Image;
+
+    #[test]
+    fn test_reference_backends() {
+        // tiles smaller than the image, so they're pieced together too
+        let config = Config {
+            tile_size: 5,
+            threads: 2,
+            memoize_min_size: 0,
+            ..Config::default()
+        };
+        let memoize = |insts: &Insts| insts.replay(MemoBuilder::new());
+        let backends: [(&str, bool, Backend); 6] = [
+            ("interp", true, &|insts| interp_image(insts, SIZE)),
+            ("bytecode", true, &|insts| {
+                bytecode::interp_image(insts, SIZE)
+            }),
+            ("memoized", true, &|insts| {
+                bytecode::interp_memoized(&memoize(insts), SIZE)
+            }),
+            ("render", true, &|insts| render::render(insts, SIZE, config)),
+            ("render_memoized", true, &|insts| {
+                render::render_memoized(&memoize(insts), SIZE, config)
+            }),
+            // fills tiles it can decide from intervals with a bound
+            ("render_adaptive", false, &|insts| {
+                render::render_adaptive(insts, SIZE, config)
+            }),
+        ];
+        for (name, values, backend) in backends {
+            if let Err(failures) = check_backend(values, backend) {
+                panic!("{name} failed:\n{failures}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_check_reports_pixels() {
+        let case = &CASES[0];
+        let mut image = interp_image(&case.insts(), SIZE);
+        image.pixels[usize::from(SIZE) + 2] = 1.0;
+        let Err(Failure::Image { count, image }) = case.check(&image, true) else {
+            panic!("wrong pixel not reported");
+        };
+        assert_eq!(count, 1);
+        assert_eq!(image.lines().nth(1), Some("..X......"));
+    }
+
+    #[cfg(feature = "all-backends")]
+    mod native {
+        use super::*;
+        use crate::codegen::abi::{Manifest, part_name};
+        use crate::codegen::c;
+        use crate::codegen::loops::{LoopNest, Step};
+        use crate::codegen::x86::{self, Stride, VectorAxis, X86Config};
+        use crate::ir::interp::eval_point;
+        use crate::ir::memoize::Memoized;
+        use crate::ir::{Var, VarSet};
+        use crate::runtime::{self, Library, cpu_supports};
+        use std::path::{Path, PathBuf};
+        use std::process::Command;
+
+        // Build generated source into a shared object with gcc and load it.
+        fn build(dir: &Path, name: &str, source: &[u8], manifest: &Manifest) -> Library {
+            let library = shared_object(dir, name, source);
+            // SAFETY: the source was just generated by this crate
+            unsafe { runtime::dlopen(&library, manifest).unwrap() }
+        }
+
+        // Build generated source into a shared object with gcc.
+        fn shared_object(dir: &Path, name: &str, source: &[u8]) -> PathBuf {
+            let source_path = dir.join(name);
+            std::fs::write(&source_path, source).unwrap();
+            let library = dir.join(format!("{name}.so"));
+            let status = Command::new("gcc")
+                .args(["-shared", "-fPIC", "-O2", "-Wl,-z,noexecstack", "-I"])
+                .arg(dir)
+                .arg("-o")
+                .arg(&library)
+                .arg(&source_path)
+                .arg("-lm")
+                .status()
+                .expect("gcc is needed for the native backends");
+            assert!(status.success(), "gcc failed on {}", source_path.display());
+            library
+        }
+
+        fn check_native(
+            backend: &str,
+            mut generate: impl FnMut(&Path, &Memoized) -> Library,
+        ) -> Result<(), String> {
+            let dir =
+                std::env::temp_dir().join(format!("conformance-{backend}-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let mut count = 0;
+            let result = check_backend(true, |insts| {
+                // each case gets its own files, since a shared object that's
+                // already loaded wouldn't be read again
+                count += 1;
+                let case_dir = dir.join(count.to_string());
+                std::fs::create_dir_all(&case_dir).unwrap();
+                generate(&case_dir, &insts.replay(MemoBuilder::new())).render(SIZE)
+            });
+            std::fs::remove_dir_all(&dir).unwrap();
+            result
+        }
+
+        #[test]
+        fn test_x86() {
+            let scalar = X86Config {
+                vectorize: false,
+                ..X86Config::default()
+            };
+            let strides = [Stride::Four, Stride::Eight, Stride::Sixteen];
+            let vector = strides.map(|stride| X86Config {
+                stride,
+                ..X86Config::default()
+            });
+            let columns = X86Config {
+                vector_axis: VectorAxis::Y,
+                ..X86Config::default()
+            };
+            let few = [4, 8].map(|registers| X86Config {
+                registers,
+                ..X86Config::default()
+            });
+            let split = X86Config {
+                max_insts: Some(4),
+                ..X86Config::default()
+            };
+            let configs = [scalar].into_iter().chain(vector).chain([columns]);
+            for config in configs.chain(few).chain([split]) {
+                let stride = if config.vectorize {
+                    u16::from(config.stride.lanes())
+                } else {
+                    1
+                };
+                if !cpu_supports(stride) {
+                    continue;
+                }
+                let mut backend = match config.vector_axis {
+                    VectorAxis::X => format!("x86-{stride}"),
+                    axis => format!("x86-{stride}-{}", axis.var().name()),
+                };
+                if config.registers < 16 {
+                    backend += &format!("-r{}", config.registers);
+                }
+                if let Some(max_insts) = config.max_insts {
+                    backend += &format!("-split{max_insts}");
+                }
+                let result = check_native(&backend, |dir, memoized| {
+                    let mut asm = Vec::new();
+                    x86::write(&mut asm, config, memoized).unwrap();
+                    build(dir, "shape.s", &asm, &x86::manifest(config, memoized))
+                });
+                if let Err(failures) = result {
+                    panic!("{backend} failed:\n{failures}");
+                }
+            }
+        }
+
+        #[test]
+        fn test_strides_agree() {
+            // the image is 9 pixels wide, so wider vectors also cover rows
+            // that end partway through one
+            let dir =
+                std::env::temp_dir().join(format!("conformance-strides-{}", std::process::id()));
+            let strides = [Stride::Four, Stride::Eight, Stride::Sixteen];
+            let strides = strides
+                .into_iter()
+                .filter(|stride| cpu_supports(stride.lanes().into()));
+            let strides: Vec<Stride> = strides.collect();
+            for case in CASES {
+                let memoized = case.insts().replay(MemoBuilder::new());
+                let images: Vec<Image> = strides
+                    .iter()
+                    .map(|&stride| {
+                        let config = X86Config {
+                            stride,
+                            ..X86Config::default()
+                        };
+                        let case_dir = dir.join(case.name).join(stride.lanes().to_string());
+                        std::fs::create_dir_all(&case_dir).unwrap();
+                        let mut asm = Vec::new();
+                        x86::write(&mut asm, config, &memoized).unwrap();
+                        let manifest = x86::manifest(config, &memoized);
+                        build(&case_dir, "shape.s", &asm, &manifest).render(SIZE)
+                    })
+                    .collect();
+                for (stride, image) in strides.iter().zip(&images).skip(1) {
+                    let same = image
+                        .pixels
+                        .iter()
+                        .zip(&images[0].pixels)
+                        .all(|(a, b)| a.to_bits() == b.to_bits());
+                    assert!(same, "{}: stride {} differs", case.name, stride.lanes());
+                }
+            }
+            if dir.exists() {
+                std::fs::remove_dir_all(&dir).unwrap();
+            }
+        }
+
+        // Call a generated function with a pointer to each space it takes.
+        unsafe fn call(library: &libloading::Library, name: &str, ptrs: &[*mut f32]) {
+            type P = *mut f32;
+            let name = name.as_bytes();
+            unsafe {
+                match *ptrs {
+                    [a] => library.get::<unsafe extern "C" fn(P)>(name).unwrap()(a),
+                    [a, b] => library.get::<unsafe extern "C" fn(P, P)>(name).unwrap()(a, b),
+                    [a, b, c] => {
+                        library.get::<unsafe extern "C" fn(P, P, P)>(name).unwrap()(a, b, c)
+                    }
+                    [a, b, c, d] => library
+                        .get::<unsafe extern "C" fn(P, P, P, P)>(name)
+                        .unwrap()(a, b, c, d),
+                    [a, b, c, d, e] => library
+                        .get::<unsafe extern "C" fn(P, P, P, P, P)>(name)
+                        .unwrap()(a, b, c, d, e),
+                    [a, b, c, d, e, f] => library
+                        .get::<unsafe extern "C" fn(P, P, P, P, P, P)>(name)
+                        .unwrap()(a, b, c, d, e, f),
+                    [a, b, c, d, e, f, g] => library
+                        .get::<unsafe extern "C" fn(P, P, P, P, P, P, P)>(name)
+                        .unwrap()(a, b, c, d, e, f, g),
+                    _ => unreachable!(),
+                }
+            }
+        }
+
+        // Vector loads and stores need buffers aligned to a whole stride.
+        #[repr(C, align(64))]
+        #[derive(Clone, Copy)]
+        struct Lanes([f32; 16]);
+
+        fn as_floats(lanes: &mut [Lanes]) -> &mut [f32] {
+            let len = lanes.len() * 16;
+            // a slice of repr(C) arrays of f32 is a slice of f32
+            unsafe { std::slice::from_raw_parts_mut(lanes.as_mut_ptr().cast(), len) }
+        }
+
+        // Everything needed to run a loop nest over x, y, and z, which
+        // `runtime` doesn't do, since it only renders images.
+        struct Volume<'a> {
+            library: &'a libloading::Library,
+            nest: &'a LoopNest,
+            outputs: Vec<usize>,
+            buffers: Vec<Vec<Lanes>>,
+            extent: [usize; 3],
+            pos: [usize; 3],
+            values: Vec<f32>,
+        }
+
+        impl Volume<'_> {
+            fn steps(&mut self, steps: &[Step], groups: [Option<u16>; 3]) {
+                for step in steps {
+                    match step {
+                        Step::Loop(level, body) => {
+                            let var = level.var as usize;
+                            let start = self.pos[var];
+                            let end = match groups[var] {
+                                Some(outer) => (start + usize::from(outer)).min(self.extent[var]),
+                                None => self.extent[var],
+                            };
+                            let mut groups = groups;
+                            groups[var] = Some(level.lanes);
+                            for pos in (start..end).step_by(level.lanes.into()) {
+                                self.pos[var] = pos;
+                                self.steps(body, groups);
+                            }
+                            self.pos[var] = start;
+                        }
+                        &Step::Call(func) => self.call(func),
+                    }
+                }
+            }
+
+            fn call(&mut self, func: VarSet) {
+                let nest = self.nest;
+                let offsets: Vec<Option<usize>> = nest
+                    .buffers
+                    .iter()
+                    .zip(&self.outputs)
+                    .map(|(buffer, &outputs)| {
+                        ((buffer.func | func) == func)
+                            .then(|| buffer.offset(self.pos, self.extent, outputs))
+                    })
+                    .collect();
+                let idx = nest.buffers.iter().position(|b| b.func == func).unwrap();
+                let buffer = &nest.buffers[idx];
+                let own = offsets[idx].unwrap();
+                let lanes = usize::from(buffer.lanes);
+                if func.count() == 1 {
+                    let var = buffer.lane_var as usize;
+                    let buf = &mut as_floats(&mut self.buffers[idx])[own..][..lanes];
+                    for (lane, value) in buf.iter_mut().enumerate() {
+                        *value =
+                            coordinate(buffer.lane_var, self.pos[var] + lane, self.extent[var]);
+                    }
+                }
+                let mut ptrs: Vec<*mut f32> = self
+                    .buffers
+                    .iter_mut()
+                    .zip(&offsets)
+                    .map(|(buf, offset)| {
+                        offset.map_or(std::ptr::null_mut(), |offset| {
+                            as_floats(buf)[offset..].as_mut_ptr()
+                        })
+                    })
+                    .collect();
+                ptrs.truncate(func.idx());
+                for part in 0..buffer.parts {
+                    // SAFETY: each space has room for every location the
+                    // function's manifest says it uses
+                    unsafe { call(self.library, &part_name(func, part), &ptrs) };
+                }
+
+                if func == nest.output {
+                    let out = &as_floats(&mut self.buffers[idx])[own..][..lanes];
+                    let lane_var = buffer.lane_var as usize;
+                    for (lane, &value) in out.iter().enumerate() {
+                        let mut pos = self.pos;
+                        pos[lane_var] += lane;
+                        let [col, row, slice] = pos;
+                        let [width, height, depth] = self.extent;
+                        if col < width && row < height && slice < depth {
+                            self.values[(slice * height + row) * width + col] = value;
+                        }
+                    }
+                }
+            }
+        }
+
+        // The same coordinates as `pixel_point`, with z running like x.
+        fn coordinate(var: Var, pos: usize, extent: usize) -> f32 {
+            let scale = 2.0 / (extent - 1) as f32;
+            match var {
+                Var::Y => (extent - 1 - pos) as f32 * scale - 1.0,
+                _ => pos as f32 * scale - 1.0,
+            }
+        }
+
+        #[test]
+        fn test_z_slices() {
+            // a sphere sheared by a plane, with values in every function of
+            // z, rendered as a few slices, which don't fill a whole vector
+            let text = "x var-x\ny var-y\nz var-z\nx2 square x\ny2 square y\nz2 square z\n\
+                r2 add x2 y2\ns add r2 z2\nr sqrt s\nhalf const 0.5\nball sub half r\n\
+                xz mul x z\nyz mul y z\nw add xz yz\nout min ball w\n";
+            let insts = read(text.as_bytes(), Insts::default()).unwrap();
+            let memoized = insts.replay(MemoBuilder::new());
+            let extent = [usize::from(SIZE), usize::from(SIZE), 5];
+            let mut regs = vec![0.0; insts.pool.len()];
+            let dir = std::env::temp_dir().join(format!("conformance-z-{}", std::process::id()));
+            // scalar code as well, where every function runs one point at a
+            // time
+            let configs = [
+                (false, Stride::Four),
+                (true, Stride::Four),
+                (true, Stride::Eight),
+                (true, Stride::Sixteen),
+            ];
+            for (vectorize, stride) in configs {
+                let lanes = if vectorize { stride.lanes().into() } else { 1 };
+                if !cpu_supports(lanes) {
+                    continue;
+                }
+                let config = X86Config {
+                    vectorize,
+                    stride,
+                    vector_axis: VectorAxis::Z,
+                    ..X86Config::default()
+                };
+                let stride_dir = dir.join(lanes.to_string());
+                std::fs::create_dir_all(&stride_dir).unwrap();
+                let mut asm = Vec::new();
+                x86::write(&mut asm, config, &memoized).unwrap();
+                let path = shared_object(&stride_dir, "shape.s", &asm);
+                // SAFETY: the source was just generated by this crate
+                let library = unsafe { libloading::Library::new(&path).unwrap() };
+
+                let manifest = x86::manifest(config, &memoized);
+                let nest = LoopNest::new(VarSet::ALL, lanes, Var::Z);
+                let outputs: Vec<usize> = nest
+                    .buffers
+                    .iter()
+                    .map(|buffer| {
+                        manifest
+                            .function(&format!("{:?}", buffer.func))
+                            .unwrap()
+                            .outputs
+                    })
+                    .collect();
+                let buffers = nest
+                    .buffers
+                    .iter()
+                    .zip(&outputs)
+                    .map(|(buffer, &outputs)| {
+                        vec![Lanes([0.0; 16]); buffer.len(extent, outputs).div_ceil(16)]
+                    })
+                    .collect();
+                let mut volume = Volume {
+                    library: &library,
+                    nest: &nest,
+                    outputs,
+                    buffers,
+                    extent,
+                    pos: [0; 3],
+                    values: vec![f32::NAN; extent.iter().product()],
+                };
+                volume.steps(&nest.body, [None; 3]);
+
+                for (idx, &value) in volume.values.iter().enumerate() {
+                    let [col, row, slice] = [
+                        idx % extent[0],
+                        idx / extent[0] % extent[1],
+                        idx / extent[0] / extent[1],
+                    ];
+                    let vars = [
+                        coordinate(Var::X, col, extent[0]),
+                        coordinate(Var::Y, row, extent[1]),
+                        coordinate(Var::Z, slice, extent[2]),
+                    ];
+                    let expected = eval_point(&insts, &mut regs, &vars);
+                    assert_eq!(value, expected, "{lanes} lanes at {vars:?}");
+                }
+            }
+            if dir.exists() {
+                std::fs::remove_dir_all(&dir).unwrap();
+            }
+        }
+
+        #[test]
+        fn test_c() {
+            let result = check_native("c", |dir, memoized| {
+                let mut header = Vec::new();
+                c::write_header(&mut header, memoized).unwrap();
+                std::fs::write(dir.join("shape.h"), header).unwrap();
+                let mut source = Vec::new();
+                c::write_source(&mut source, memoized, "shape.h").unwrap();
+                build(dir, "shape.c", &source, &c::manifest(memoized))
+            });
+            if let Err(failures) = result {
+                panic!("c failed:\n{failures}");
+            }
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use std::fmt::Write;
+use std::path::PathBuf;
+
+use crate::ir::io::read;
+use crate::ir::{InstSink, Insts};
+
+#[derive(Clone, Copy, Debug)]
+pub struct Shape {
+    pub name: &'static str,
+    pub text: &'static str,
+}
+
+/// Small programs embedded in the library for use in tests and benchmarks.
+pub const SHAPES: &[Shape] = &[
+    Shape {
+        name: "circle",
+        text: include_str!("../corpus/circle.vm"),
+    },
+    Shape {
+        name: "ring",
+        text: include_str!("../corpus/ring.vm"),
+    },
+    Shape {
+        name: "fenced",
+        text: include_str!("../corpus/fenced.vm"),
+    },
+];
+
+impl Shape {
+    pub fn insts(&self) -> Insts {
+        self.read(Insts::default())
+    }
+
+    /// Parse this shape into any sink, such as a pass or memoization.
+    pub fn read<S: InstSink>(&self, sink: S) -> S::Output {
+        read(self.text.as_bytes(), sink).expect("embedded shapes are valid")
+    }
+}
+
+/// Look up one of the embedded [`SHAPES`] by name.
+pub fn shape(name: &str) -> Option<&'static Shape> {
+    SHAPES.iter().find(|shape| shape.name == name)
+}
+
+/// The text of Matt Keeter's `prospero.vm`, which isn't distributed with this
+/// repository, if it can be found at the path named by the `PROSPERO_VM`
+/// environment variable, in the current directory, or at the root of this
+/// crate's source.
+pub fn prospero() -> Option<String> {
+    let candidates = [
+        std::env::var_os("PROSPERO_VM").map(PathBuf::from),
+        Some(PathBuf::from("prospero.vm")),
+        Some(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("prospero.vm")),
+    ];
+    candidates
+        .into_iter()
+        .flatten()
+        .find_map(|path| std::fs::read_to_string(path).ok())
+}
+
+/// A synthetic program: the union of `count` circles at pseudo-random
+/// positions, which gives a deep min tree with plenty of constants, like many
+/// real inputs. The same `count` always produces the same program.
+pub fn circles(count: usize) -> String {
+    let mut seed = 0x2545_f491_u32;
+    let mut random = || {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        seed as f32 / u32::MAX as f32 * 2.0 - 1.0
+    };
+
+    let mut text = "x var-x\ny var-y\n".to_string();
+    let mut union = "c_0".to_string();
+    for idx in 0..count {
+        let (cx, cy, r) = (random(), random(), random().abs() * 0.2);
+        writeln!(text, "cx_{idx} const {cx}").unwrap();
+        writeln!(text, "cy_{idx} const {cy}").unwrap();
+        writeln!(text, "r_{idx} const {r}").unwrap();
+        writeln!(text, "dx_{idx} sub x cx_{idx}").unwrap();
+        writeln!(text, "dy_{idx} sub y cy_{idx}").unwrap();
+        writeln!(text, "dx2_{idx} square dx_{idx}").unwrap();
+        writeln!(text, "dy2_{idx} square dy_{idx}").unwrap();
+        writeln!(text, "d2_{idx} add dx2_{idx} dy2_{idx}").unwrap();
+        writeln!(text, "d_{idx} sqrt d2_{idx}").unwrap();
+        writeln!(text, "c_{idx} sub d_{idx} r_{idx}").unwrap();
+        if idx > 0 {
+            writeln!(text, "u_{idx} min {union} c_{idx}").unwrap();
+            union = format!("u_{idx}");
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::memoize::MemoBuilder;
+
+    #[test]
+    fn test_shapes_parse() {
+        for shape in SHAPES {
+            assert!(!shape.insts().pool.is_empty(), "{}", shape.name);
+            shape.read(MemoBuilder::new());
+        }
+        let circles = read(circles(10).as_bytes(), Insts::default()).unwrap();
+        assert_eq!(circles.pool.len(), 2 + 10 * 10 + 9);
+    }
+}
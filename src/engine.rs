@@ -0,0 +1,324 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::cancel::{CancelToken, Cancelled};
+use crate::image::Image;
+use crate::ir::Insts;
+use crate::ir::bytecode::{MemoizedProgram, Program};
+use crate::ir::edit::Editor;
+use crate::ir::io::{self, read};
+use crate::ir::memoize::{MemoBuilder, Memoized};
+use crate::ir::reassociate::reassociate;
+use crate::ir::simplify::Simplify;
+use crate::render::{self, render_bytecode_cancellable, render_program_cancellable};
+
+// Everything the engine hands out is shared between rendering threads, so
+// check at compile time that none of it has grown any thread-unsafe state.
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Insts>();
+    assert_send_sync::<Memoized>();
+    assert_send_sync::<MemoizedProgram>();
+    assert_send_sync::<Compiled>();
+    assert_send_sync::<Engine>();
+};
+
+/// A shape's distance function, as returned by [`compile`].
+pub type ShapeFn = Box<dyn Fn(f32, f32, f32) -> f32 + Send + Sync>;
+
+/// Evaluates a shape at many points at once, as returned by
+/// [`compile_batch`]: given slices of x, y, and z coordinates, it stores the
+/// value at each point in the last slice, which must be the same length.
+pub type BatchFn = Box<dyn Fn(&[f32], &[f32], &[f32], &mut [f32]) + Send + Sync>;
+
+/// Parse program text in Matt Keeter's format, optimize it, and return a
+/// function which evaluates it at any point, for callers who don't need any
+/// of the lower-level layers.
+///
+/// Each call evaluates the program's bytecode from scratch, with no
+/// memoization, which suits probing a few points. To fill a grid, use
+/// [`Compiled`] instead, and for many scattered points, [`compile_batch`].
+pub fn compile(text: &str) -> io::Result<ShapeFn> {
+    let program = Program::compile(&optimize(text)?);
+    Ok(Box::new(move |x, y, z| {
+        program.eval(&mut program.registers(), &[x, y, z])
+    }))
+}
+
+/// Like [`compile`], but the function evaluates a whole batch of points,
+/// reusing one register file for all of them.
+pub fn compile_batch(text: &str) -> io::Result<BatchFn> {
+    let program = Program::compile(&optimize(text)?);
+    Ok(Box::new(move |xs, ys, zs, out| {
+        assert!(xs.len() == out.len() && ys.len() == out.len() && zs.len() == out.len());
+        let mut regs = program.registers();
+        for (idx, value) in out.iter_mut().enumerate() {
+            *value = program.eval(&mut regs, &[xs[idx], ys[idx], zs[idx]]);
+        }
+    }))
+}
+
+/// Parse program text and run the passes every entry point in this module
+/// runs before compiling: simplification, then reassociation.
+pub fn optimize(text: &str) -> io::Result<Insts> {
+    let insts = read(text.as_bytes(), Simplify::new(Insts::default()))?;
+    Ok(reassociate(&insts.pool, Insts::default()))
+}
+
+/// A program which has been parsed, optimized, memoized, and compiled to
+/// bytecode, ready to render at any size. It's immutable, so one copy can be
+/// used by any number of threads at once.
+pub struct Compiled {
+    memoized: Memoized,
+    program: MemoizedProgram,
+    // the same program without memoization, for images too small for
+    // memoization to pay off
+    unmemoized: Program,
+}
+
+impl Compiled {
+    /// Run the whole pipeline on program text in Matt Keeter's format.
+    pub fn new(text: &str) -> io::Result<Compiled> {
+        let insts = optimize(text)?;
+        Ok(Compiled::from_parts(
+            insts.replay(MemoBuilder::new()),
+            &insts,
+        ))
+    }
+
+    /// Compile the current state of an [`Editor`], reusing the work it has
+    /// already done. Edited programs aren't reassociated, so this may render
+    /// more slowly than [`Compiled::new`] would on the same text.
+    pub fn edited(editor: &mut Editor) -> Compiled {
+        Compiled::from_parts(editor.memoized(), &editor.insts())
+    }
+
+    fn from_parts(memoized: Memoized, insts: &Insts) -> Compiled {
+        let program = MemoizedProgram::compile(&memoized);
+        let unmemoized = Program::compile(insts);
+        Compiled {
+            memoized,
+            program,
+            unmemoized,
+        }
+    }
+
+    pub fn memoized(&self) -> &Memoized {
+        &self.memoized
+    }
+
+    /// The program without memoization, for evaluating individual points.
+    pub fn program(&self) -> &Program {
+        &self.unmemoized
+    }
+
+    /// Render the program, with memoization unless the image is smaller than
+    /// `config.memoize_min_size`.
+    pub fn render(
+        &self,
+        size: u16,
+        config: render::Config,
+        cancel: &CancelToken,
+    ) -> Result<Image, Cancelled> {
+        if size < config.memoize_min_size {
+            render_bytecode_cancellable(&self.unmemoized, size, config, cancel)
+        } else {
+            render_program_cancellable(&self.program, size, config, cancel)
+        }
+    }
+}
+
+/// A long-lived compilation context for hosts which render many requests,
+/// such as a server. Compiled programs are cached by their source text, so
+/// repeated requests for the same shape only pay for compilation once. The
+/// cache holds at most [`capacity`](Engine::capacity) programs, evicting the
+/// least recently used one to make room, so a host serving arbitrary
+/// programs doesn't grow without bound.
+///
+/// `Engine` is `Send + Sync`: share it between threads with a reference or an
+/// `Arc`. Compilation happens outside the cache lock, so a slow compile
+/// doesn't block lookups of other programs; if two threads compile the same
+/// text at once, both get the copy which was cached first.
+pub struct Engine {
+    config: render::Config,
+    capacity: usize,
+    programs: Mutex<Programs>,
+}
+
+/// How many programs [`Engine::new`] caches.
+pub const DEFAULT_CAPACITY: usize = 64;
+
+#[derive(Default)]
+struct Programs {
+    // each program with the tick it was last used at
+    entries: HashMap<String, (Arc<Compiled>, u64)>,
+    tick: u64,
+}
+
+impl Programs {
+    fn get(&mut self, text: &str) -> Option<Arc<Compiled>> {
+        self.tick += 1;
+        let (compiled, used) = self.entries.get_mut(text)?;
+        *used = self.tick;
+        Some(compiled.clone())
+    }
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Engine::new(render::Config::default())
+    }
+}
+
+impl Engine {
+    pub fn new(config: render::Config) -> Self {
+        Engine::with_capacity(config, DEFAULT_CAPACITY)
+    }
+
+    /// An engine which caches at most `capacity` programs, or none at all if
+    /// it's 0.
+    pub fn with_capacity(config: render::Config, capacity: usize) -> Self {
+        Engine {
+            config,
+            capacity,
+            programs: Mutex::default(),
+        }
+    }
+
+    /// The most programs this engine caches at once.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Get the compiled form of `text` if it's already cached.
+    pub fn cached(&self, text: &str) -> Option<Arc<Compiled>> {
+        self.programs.lock().unwrap().get(text)
+    }
+
+    /// Get the compiled form of `text`, compiling it if it isn't cached yet.
+    pub fn compile(&self, text: &str) -> io::Result<Arc<Compiled>> {
+        if let Some(compiled) = self.programs.lock().unwrap().get(text) {
+            tracing::debug!("compiled program cache hit");
+            return Ok(compiled);
+        }
+        tracing::debug!("compiled program cache miss");
+        let compiled = Arc::new(Compiled::new(text)?);
+        let mut programs = self.programs.lock().unwrap();
+        if let Some(cached) = programs.get(text) {
+            return Ok(cached);
+        }
+        if self.capacity == 0 {
+            return Ok(compiled);
+        }
+        if programs.entries.len() >= self.capacity {
+            let oldest = programs
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(text, _)| text.clone())
+                .unwrap();
+            tracing::debug!("evicting least recently used program");
+            programs.entries.remove(&oldest);
+        }
+        let tick = programs.tick;
+        programs
+            .entries
+            .insert(text.to_string(), (compiled.clone(), tick));
+        Ok(compiled)
+    }
+
+    /// Compile `text` if needed and render it with this engine's
+    /// configuration.
+    pub fn render(&self, text: &str, size: u16, cancel: &CancelToken) -> io::Result<Image> {
+        let compiled = self.compile(text)?;
+        Ok(compiled.render(size, self.config, cancel)?)
+    }
+
+    /// Number of programs currently cached.
+    pub fn len(&self) -> usize {
+        self.programs.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drop every cached program. Renders already in progress keep their
+    /// own references and aren't affected.
+    pub fn clear(&self) {
+        self.programs.lock().unwrap().entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corpus;
+    use crate::ir::interp::interp_image;
+    use std::thread;
+
+    #[test]
+    fn test_shared_between_threads() {
+        let engine = Engine::default();
+        let shape = corpus::shape("ring").unwrap();
+        let expected = interp_image(&shape.insts(), 32);
+
+        let compiled: Vec<_> = thread::scope(|scope| {
+            let threads: Vec<_> = (0..4)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let image = engine.render(shape.text, 32, &CancelToken::new()).unwrap();
+                        assert_eq!(image.pixels, expected.pixels);
+                        engine.compile(shape.text).unwrap()
+                    })
+                })
+                .collect();
+            threads.into_iter().map(|t| t.join().unwrap()).collect()
+        });
+
+        assert_eq!(engine.len(), 1);
+        assert!(compiled.iter().all(|c| Arc::ptr_eq(c, &compiled[0])));
+        // too small to memoize
+        let image = engine.render(shape.text, 3, &CancelToken::new()).unwrap();
+        assert_eq!(image, interp_image(&shape.insts(), 3));
+
+        engine.clear();
+        assert!(engine.is_empty());
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let engine = Engine::with_capacity(render::Config::default(), 2);
+        let [a, b, c] = ["circle", "ring", "fenced"].map(|name| corpus::shape(name).unwrap().text);
+        let first = engine.compile(a).unwrap();
+        engine.compile(b).unwrap();
+        // using `a` again makes `b` the one to go
+        assert!(Arc::ptr_eq(&engine.cached(a).unwrap(), &first));
+        engine.compile(c).unwrap();
+        assert_eq!(engine.len(), 2);
+        assert!(engine.cached(a).is_some());
+        assert!(engine.cached(b).is_none());
+        assert!(engine.cached(c).is_some());
+
+        let uncached = Engine::with_capacity(render::Config::default(), 0);
+        uncached.compile(a).unwrap();
+        assert!(uncached.is_empty());
+    }
+
+    #[test]
+    fn test_compile_closures() {
+        let shape = corpus::shape("ring").unwrap();
+        let expected = Program::compile(&shape.insts());
+        let f = compile(shape.text).unwrap();
+        let batch = compile_batch(shape.text).unwrap();
+        let xs = [-0.9, -0.3, 0.0, 0.45, 0.7];
+        let ys = [0.2, -0.6, 0.0, 0.45, -0.1];
+        let mut out = [0.0; 5];
+        batch(&xs, &ys, &[0.0; 5], &mut out);
+        for ((&x, &y), &batched) in xs.iter().zip(&ys).zip(&out) {
+            let want = expected.eval(&mut expected.registers(), &[x, y, 0.0]);
+            assert!((f(x, y, 0.0) - want).abs() < 1e-5);
+            assert_eq!(f(x, y, 0.0), batched);
+        }
+    }
+}
@@ -0,0 +1,343 @@
+//! A C interface for hosts written in other languages, declared in
+//! `include/live_long_and_prospero.h`. Handles are opaque pointers to boxed
+//! Rust values, and failures are reported as null pointers, NaN, or nonzero
+//! results, with a message from [`llp_last_error`]. That includes panics,
+//! which are caught rather than unwinding into the host, which would abort
+//! it.
+
+use std::cell::RefCell;
+use std::ffi::{CString, c_char, c_int};
+use std::fmt;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::sync::Arc;
+
+use crate::cancel::CancelToken;
+use crate::engine::{self, Compiled, Engine};
+use crate::ir::io::write;
+use crate::render::Config;
+
+/// Bump whenever a function's signature or meaning changes, along with
+/// `LLP_API_VERSION` in the header.
+pub const API_VERSION: u32 = 1;
+
+pub struct LlpContext {
+    engine: Engine,
+    config: Config,
+}
+
+pub struct LlpProgram {
+    compiled: Arc<Compiled>,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_error(err: impl fmt::Display) {
+    // messages come from our own errors, which never contain NUL
+    let message = CString::new(err.to_string()).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+fn clear_error() {
+    LAST_ERROR.with(|last| *last.borrow_mut() = None);
+}
+
+// Run `body`, or return `failure` if it panics, with the panic's message as
+// the last error.
+fn guard<T>(failure: T, body: impl FnOnce() -> T) -> T {
+    catch_unwind(AssertUnwindSafe(body)).unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown cause");
+        set_error(format_args!("internal error: {message}"));
+        failure
+    })
+}
+
+// Borrow a string the caller passed with its length.
+unsafe fn text<'a>(text: *const c_char, len: usize) -> Option<&'a str> {
+    let bytes = unsafe { std::slice::from_raw_parts(text.cast::<u8>(), len) };
+    match std::str::from_utf8(bytes) {
+        Ok(text) => Some(text),
+        Err(err) => {
+            set_error(err);
+            None
+        }
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn llp_api_version() -> u32 {
+    API_VERSION
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn llp_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(std::ptr::null(), |message| message.as_ptr())
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn llp_context_new(threads: usize) -> *mut LlpContext {
+    clear_error();
+    guard(std::ptr::null_mut(), || {
+        let config = Config {
+            threads,
+            ..Config::default()
+        };
+        let engine = Engine::new(config);
+        Box::into_raw(Box::new(LlpContext { engine, config }))
+    })
+}
+
+/// # Safety
+///
+/// `context` must be null or come from [`llp_context_new`], and not be used
+/// again afterward.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn llp_context_free(context: *mut LlpContext) {
+    if !context.is_null() {
+        guard((), || drop(unsafe { Box::from_raw(context) }));
+    }
+}
+
+/// # Safety
+///
+/// `context` must be a live context and `text` must point to `len` bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn llp_compile(
+    context: *mut LlpContext,
+    text: *const c_char,
+    len: usize,
+) -> *mut LlpProgram {
+    clear_error();
+    guard(std::ptr::null_mut(), || {
+        let context = unsafe { &*context };
+        let Some(text) = (unsafe { self::text(text, len) }) else {
+            return std::ptr::null_mut();
+        };
+        match context.engine.compile(text) {
+            Ok(compiled) => Box::into_raw(Box::new(LlpProgram { compiled })),
+            Err(err) => {
+                set_error(err);
+                std::ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// # Safety
+///
+/// `program` must be null or come from [`llp_compile`], and not be used
+/// again afterward.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn llp_program_free(program: *mut LlpProgram) {
+    if !program.is_null() {
+        guard((), || drop(unsafe { Box::from_raw(program) }));
+    }
+}
+
+/// # Safety
+///
+/// `program` must be a live program.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn llp_eval(program: *const LlpProgram, x: f32, y: f32, z: f32) -> f32 {
+    guard(f32::NAN, || {
+        let program = unsafe { &*program }.compiled.program();
+        program.eval(&mut program.registers(), &[x, y, z])
+    })
+}
+
+/// # Safety
+///
+/// `program` must be a live program, and unless `count` is zero, the other
+/// pointers must each point to `count` floats.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn llp_eval_batch(
+    program: *const LlpProgram,
+    xs: *const f32,
+    ys: *const f32,
+    zs: *const f32,
+    out: *mut f32,
+    count: usize,
+) {
+    if count == 0 {
+        return;
+    }
+    if out.is_null() {
+        set_error("null output pointer");
+        return;
+    }
+    let out = unsafe { std::slice::from_raw_parts_mut(out, count) };
+    if [xs, ys, zs].iter().any(|coords| coords.is_null()) {
+        set_error("null coordinate pointer");
+        out.fill(f32::NAN);
+        return;
+    }
+    let [xs, ys, zs] =
+        [xs, ys, zs].map(|coords| unsafe { std::slice::from_raw_parts(coords, count) });
+    let finished = guard(false, || {
+        let program = unsafe { &*program }.compiled.program();
+        let mut regs = program.registers();
+        for (idx, value) in out.iter_mut().enumerate() {
+            *value = program.eval(&mut regs, &[xs[idx], ys[idx], zs[idx]]);
+        }
+        true
+    });
+    if !finished {
+        out.fill(f32::NAN);
+    }
+}
+
+/// # Safety
+///
+/// `context` and `program` must be live, and `out` must point to
+/// `size * size` floats. Sizes under 2 are an error.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn llp_render(
+    context: *const LlpContext,
+    program: *const LlpProgram,
+    size: u16,
+    out: *mut f32,
+) -> c_int {
+    clear_error();
+    if size < 2 {
+        set_error(format_args!("image size {size} is under 2 pixels"));
+        return 1;
+    }
+    guard(1, || {
+        let (context, program) = unsafe { (&*context, &*program) };
+        let image = match program
+            .compiled
+            .render(size, context.config, &CancelToken::new())
+        {
+            Ok(image) => image,
+            Err(err) => {
+                set_error(err);
+                return 1;
+            }
+        };
+        let out = unsafe { std::slice::from_raw_parts_mut(out, image.pixels.len()) };
+        out.copy_from_slice(&image.pixels);
+        0
+    })
+}
+
+/// # Safety
+///
+/// `text` must point to `len` bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn llp_optimize(text: *const c_char, len: usize) -> *mut c_char {
+    clear_error();
+    guard(std::ptr::null_mut(), || {
+        let Some(text) = (unsafe { self::text(text, len) }) else {
+            return std::ptr::null_mut();
+        };
+        match engine::optimize(text) {
+            Ok(insts) => {
+                let mut out = Vec::new();
+                write(&mut out, insts.pool).unwrap();
+                CString::new(out).unwrap().into_raw()
+            }
+            Err(err) => {
+                set_error(err);
+                std::ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// # Safety
+///
+/// `string` must be null or come from this library, and not be used again
+/// afterward.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn llp_string_free(string: *mut c_char) {
+    if !string.is_null() {
+        guard((), || drop(unsafe { CString::from_raw(string) }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corpus::shape;
+    use std::ffi::CStr;
+
+    #[test]
+    fn test_round_trip() {
+        let text = shape("circle").unwrap().text;
+        unsafe {
+            let context = llp_context_new(1);
+            let program = llp_compile(context, text.as_ptr().cast(), text.len());
+            assert!(!program.is_null());
+            assert_eq!(llp_eval(program, 0.0, 0.0, 0.0), 0.5);
+
+            let mut pixels = vec![0.0; 9];
+            assert_eq!(llp_render(context, program, 3, pixels.as_mut_ptr()), 0);
+            assert_eq!(pixels[4], 0.5);
+            assert_eq!(llp_render(context, program, 1, pixels.as_mut_ptr()), 1);
+            assert_eq!(
+                CStr::from_ptr(llp_last_error()).to_str().unwrap(),
+                "image size 1 is under 2 pixels"
+            );
+
+            let null = std::ptr::null();
+            llp_eval_batch(program, null, null, null, std::ptr::null_mut(), 0);
+            let xs = [0.0, 1.0];
+            llp_eval_batch(
+                program,
+                xs.as_ptr(),
+                xs.as_ptr(),
+                null,
+                pixels.as_mut_ptr(),
+                2,
+            );
+            assert!(pixels[..2].iter().all(|value| value.is_nan()));
+            llp_eval_batch(
+                program,
+                xs.as_ptr(),
+                xs.as_ptr(),
+                xs.as_ptr(),
+                pixels.as_mut_ptr(),
+                2,
+            );
+            assert_eq!(pixels[..2], [0.5, llp_eval(program, 1.0, 1.0, 1.0)]);
+            llp_context_free(context);
+            llp_program_free(program);
+
+            let bad = "x frobnicate";
+            assert!(llp_optimize(bad.as_ptr().cast(), bad.len()).is_null());
+            let error = CStr::from_ptr(llp_last_error());
+            assert_eq!(
+                error.to_str().unwrap(),
+                "unknown instruction \"frobnicate\""
+            );
+        }
+    }
+
+    #[test]
+    fn test_panics_caught() {
+        let result = guard(std::ptr::null_mut::<LlpProgram>(), || panic!("oops {}", 1));
+        assert!(result.is_null());
+        let error = unsafe { CStr::from_ptr(llp_last_error()) };
+        assert_eq!(error.to_str().unwrap(), "internal error: oops 1");
+
+        // a constant program once panicked in memoization
+        let text = "a const 1\nb const 2\nc sub a b\n";
+        unsafe {
+            let context = llp_context_new(1);
+            let program = llp_compile(context, text.as_ptr().cast(), text.len());
+            assert!(!program.is_null());
+            assert_eq!(llp_eval(program, 0.0, 0.0, 0.0), -1.0);
+            llp_program_free(program);
+            llp_context_free(context);
+        }
+    }
+}
@@ -0,0 +1,140 @@
+//! Turning text into programs, built with the `fonts` feature. Glyph outlines
+//! come from any TrueType or OpenType font that [`ttf_parser`] can read, and
+//! their curves are flattened into polygons for
+//! [`polygons`](crate::ir::polygon::polygons), which reproduces the original
+//! Prospero challenge, a page of text, from whatever font you like:
+//!
+//! ```sh
+//! cargo run --features fonts --example text -- DejaVuSans.ttf "Hello" | cargo run --example simplify
+//! ```
+
+use ttf_parser::{Face, GlyphId, OutlineBuilder};
+
+/// How much of the square from -1 to 1 the laid out text fills, leaving a
+/// margin so the edges of the outermost glyphs aren't cut off.
+const FILL: f32 = 0.9;
+
+/// Lay out `text` in `face` and flatten each glyph's outline into polygons,
+/// scaled and centered so the text fills the view. Each line of `text` goes
+/// below the previous one, and characters the font doesn't have are drawn as
+/// its missing-glyph box. Every curve becomes `segments` straight edges, so
+/// more segments make smoother letters at the cost of a longer program. There
+/// is no kerning or shaping, so scripts which need them won't look right.
+pub fn outlines(face: &Face, text: &str, segments: u16) -> Vec<Vec<[f32; 2]>> {
+    let line_height = f32::from(face.height()) + f32::from(face.line_gap());
+    let mut flatten = Flatten {
+        contours: Vec::new(),
+        origin: [0.0; 2],
+        current: [0.0; 2],
+        segments: segments.max(1),
+    };
+    for (row, line) in text.lines().enumerate() {
+        flatten.origin = [0.0, -(row as f32) * line_height];
+        for c in line.chars() {
+            let glyph = face.glyph_index(c).unwrap_or(GlyphId(0));
+            face.outline_glyph(glyph, &mut flatten);
+            flatten.origin[0] += f32::from(face.glyph_hor_advance(glyph).unwrap_or(0));
+        }
+    }
+
+    let mut contours = flatten.contours;
+    let (mut lo, mut hi) = ([f32::INFINITY; 2], [f32::NEG_INFINITY; 2]);
+    for point in contours.iter().flatten() {
+        for axis in 0..2 {
+            lo[axis] = lo[axis].min(point[axis]);
+            hi[axis] = hi[axis].max(point[axis]);
+        }
+    }
+    let extent = (hi[0] - lo[0]).max(hi[1] - lo[1]);
+    if extent > 0.0 {
+        let scale = 2.0 * FILL / extent;
+        let center = [(lo[0] + hi[0]) / 2.0, (lo[1] + hi[1]) / 2.0];
+        for point in contours.iter_mut().flatten() {
+            *point = [0, 1].map(|axis| (point[axis] - center[axis]) * scale);
+        }
+    }
+    contours
+}
+
+// Collects a glyph's outline as polygons in font units, offset by where the
+// glyph goes in the text.
+struct Flatten {
+    contours: Vec<Vec<[f32; 2]>>,
+    origin: [f32; 2],
+    // the last point pushed, relative to the glyph's origin like the font's
+    // own coordinates
+    current: [f32; 2],
+    segments: u16,
+}
+
+impl Flatten {
+    fn push(&mut self, x: f32, y: f32) {
+        self.current = [x, y];
+        let point = [x + self.origin[0], y + self.origin[1]];
+        self.contours.last_mut().unwrap().push(point);
+    }
+
+    // Push points along a Bézier curve from the current point through
+    // `controls`.
+    fn curve(&mut self, controls: &[[f32; 2]]) {
+        let start = self.current;
+        for step in 1..=self.segments {
+            let t = f32::from(step) / f32::from(self.segments);
+            // de Casteljau's algorithm
+            let mut points = vec![start];
+            points.extend_from_slice(controls);
+            while points.len() > 1 {
+                for idx in 0..points.len() - 1 {
+                    let [a, b] = [points[idx], points[idx + 1]];
+                    points[idx] = [0, 1].map(|axis| a[axis] + (b[axis] - a[axis]) * t);
+                }
+                points.pop();
+            }
+            self.push(points[0][0], points[0][1]);
+        }
+    }
+}
+
+impl OutlineBuilder for Flatten {
+    fn move_to(&mut self, x: f32, y: f32) {
+        self.contours.push(Vec::new());
+        self.push(x, y);
+    }
+
+    fn line_to(&mut self, x: f32, y: f32) {
+        self.push(x, y);
+    }
+
+    fn quad_to(&mut self, x1: f32, y1: f32, x: f32, y: f32) {
+        self.curve(&[[x1, y1], [x, y]]);
+    }
+
+    fn curve_to(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, x: f32, y: f32) {
+        self.curve(&[[x1, y1], [x2, y2], [x, y]]);
+    }
+
+    fn close(&mut self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flatten_quad() {
+        let mut flatten = Flatten {
+            contours: Vec::new(),
+            origin: [10.0, 0.0],
+            current: [0.0; 2],
+            segments: 2,
+        };
+        flatten.move_to(0.0, 0.0);
+        flatten.quad_to(1.0, 2.0, 2.0, 0.0);
+        flatten.line_to(1.0, -1.0);
+        flatten.close();
+        assert_eq!(
+            flatten.contours,
+            [vec![[10.0, 0.0], [11.0, 1.0], [12.0, 0.0], [11.0, -1.0]]]
+        );
+    }
+}
@@ -0,0 +1,534 @@
+use clap::ValueEnum;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// The value of a program at each pixel of a square image.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Image {
+    pub size: u16,
+    /// Rows of pixels, starting from the top of the image.
+    pub pixels: Vec<f32>,
+}
+
+/// Which way the y axis points in an image. Programs are always evaluated in
+/// math convention, with y increasing upward, but some consumers expect rows
+/// in screen order instead.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum Orientation {
+    /// The first row is at y = 1, so shapes appear the right way up
+    #[default]
+    YUp,
+    /// The first row is at y = -1, as if y increased downward
+    YDown,
+}
+
+impl Orientation {
+    /// The y coordinate of `row` in a `size`×`size` image covering -1 to 1.
+    pub fn row_y(self, row: u16, size: u16) -> f32 {
+        let scale = 2.0 / f32::from(size - 1);
+        let idx = match self {
+            Orientation::YUp => size - 1 - row,
+            Orientation::YDown => row,
+        };
+        f32::from(idx) * scale - 1.0
+    }
+}
+
+/// A rectangle of pixels within a square image, counted from the image's
+/// top left corner, for rendering one huge image in pieces.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Tile {
+    pub left: u16,
+    pub top: u16,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl Tile {
+    /// The whole of a `size`×`size` image.
+    pub fn full(size: u16) -> Tile {
+        Tile {
+            left: 0,
+            top: 0,
+            width: size,
+            height: size,
+        }
+    }
+
+    /// Whether the tile has any pixels and lies entirely within a
+    /// `size`×`size` image.
+    pub fn fits(self, size: u16) -> bool {
+        let end = |start: u16, len: u16| u32::from(start) + u32::from(len);
+        self.width > 0
+            && self.height > 0
+            && end(self.left, self.width) <= size.into()
+            && end(self.top, self.height) <= size.into()
+    }
+}
+
+/// Whether a point with this value is inside the shape. Points on the
+/// contour count as inside, and the sign of a zero never matters: rewrites
+/// like turning `-(a - b)` into `b - a`, or `min` picking either of two equal
+/// zeros, can produce `-0.0` where the original program gave `+0.0`, and
+/// every backend has to classify the result the same way regardless. Any
+/// other value, including NaN, is inside if its sign bit is clear, which is
+/// the test that `movmskps` does.
+pub fn is_inside(value: f32) -> bool {
+    value == 0.0 || value.is_sign_positive()
+}
+
+/// How closely a render has to match a reference render of the same
+/// program, for checking that a pass or backend keeps its accuracy
+/// contract. Reassociation and fused multiply-adds legitimately change
+/// results by a little, so most pipelines can't promise exact bits.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Tolerance {
+    /// Every pixel has exactly the same bits, written `exact`.
+    Exact,
+    /// Every pixel is within this many representable values of the
+    /// reference, written like `ulps:4`. Zeros of either sign are equal,
+    /// and NaN only matches NaN.
+    Ulps(u32),
+    /// Every pixel farther than `margin` from the contour, going by the
+    /// reference's value, is on the same side of it, written like
+    /// `sign:0.001`. Pixels nearer the contour may go either way.
+    Sign { margin: f32 },
+}
+
+impl Tolerance {
+    /// Whether `actual` is close enough to the reference's `expected`.
+    pub fn accepts(self, expected: f32, actual: f32) -> bool {
+        match self {
+            Tolerance::Exact => expected.to_bits() == actual.to_bits(),
+            Tolerance::Ulps(ulps) => {
+                if expected.is_nan() || actual.is_nan() {
+                    return expected.is_nan() && actual.is_nan();
+                }
+                // map floats onto integers in the same order, with both
+                // zeros at 0, so the difference counts the values between
+                let ordered = |value: f32| {
+                    let magnitude = i64::from(value.to_bits() & 0x7fff_ffff);
+                    if value.is_sign_negative() {
+                        -magnitude
+                    } else {
+                        magnitude
+                    }
+                };
+                ordered(expected).abs_diff(ordered(actual)) <= u64::from(ulps)
+            }
+            Tolerance::Sign { margin } => {
+                expected.abs() <= margin || is_inside(expected) == is_inside(actual)
+            }
+        }
+    }
+}
+
+impl FromStr for Tolerance {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "exact" => Ok(Tolerance::Exact),
+            Some(("ulps", ulps)) => ulps.parse().map(Tolerance::Ulps).map_err(|e| e.to_string()),
+            Some(("sign", margin)) => match margin.parse() {
+                Ok(margin) if margin >= 0.0 => Ok(Tolerance::Sign { margin }),
+                Ok(_) => Err("margin can't be negative".to_string()),
+                Err(e) => Err(e.to_string()),
+            },
+            _ => Err("expected exact, ulps:N, or sign:MARGIN".to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Tolerance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Tolerance::Exact => write!(f, "exact"),
+            Tolerance::Ulps(ulps) => write!(f, "ulps:{ulps}"),
+            Tolerance::Sign { margin } => write!(f, "sign:{margin}"),
+        }
+    }
+}
+
+/// The pixels where a render broke its [`Tolerance`], from [`Image::check`].
+#[derive(Clone, Copy, Debug, Error, PartialEq)]
+#[error(
+    "{count} pixels outside {tolerance}, first at column {col}, row {row}: \
+    expected {expected:?}, got {actual:?}"
+)]
+pub struct Mismatch {
+    pub tolerance: Tolerance,
+    pub count: usize,
+    pub col: u16,
+    pub row: u16,
+    pub expected: f32,
+    pub actual: f32,
+}
+
+impl Image {
+    pub fn new(size: u16) -> Self {
+        let pixels = vec![0.0; usize::from(size) * usize::from(size)];
+        Image { size, pixels }
+    }
+
+    pub fn rows(&self) -> impl Iterator<Item = &[f32]> {
+        self.pixels.chunks(usize::from(self.size).max(1))
+    }
+
+    /// Rearrange rows, assuming they're currently in [`Orientation::YUp`]
+    /// order as produced by the interpreters and renderers.
+    pub fn orient(&mut self, orientation: Orientation) {
+        if orientation == Orientation::YDown {
+            let size = usize::from(self.size);
+            for row in 0..size / 2 {
+                let (top, bottom) = self.pixels.split_at_mut((size - 1 - row) * size);
+                top[row * size..(row + 1) * size].swap_with_slice(&mut bottom[..size]);
+            }
+        }
+    }
+
+    /// Check this render against a `reference` image of the same size,
+    /// reporting how many pixels are outside `tolerance` and where the
+    /// first one is.
+    pub fn check(&self, reference: &Image, tolerance: Tolerance) -> Result<(), Mismatch> {
+        assert_eq!(self.size, reference.size);
+        let mut bad = self
+            .pixels
+            .iter()
+            .zip(&reference.pixels)
+            .enumerate()
+            .filter(|&(_, (&actual, &expected))| !tolerance.accepts(expected, actual));
+        let Some((idx, (&actual, &expected))) = bad.next() else {
+            return Ok(());
+        };
+        let size = usize::from(self.size);
+        Err(Mismatch {
+            tolerance,
+            count: 1 + bad.count(),
+            col: (idx % size).try_into().unwrap(),
+            row: (idx / size).try_into().unwrap(),
+            expected,
+            actual,
+        })
+    }
+
+    /// The magnitude of the field's gradient at each pixel, estimated from
+    /// differences between neighboring pixels in coordinate units, so a true
+    /// distance field gives about 1 everywhere. Pixels use central differences
+    /// where they can, and differences with their one neighbor on the edges
+    /// of the image. Kinks in the field, or noise from lost precision, show up
+    /// as pixels that stand out from their neighbors.
+    pub fn gradient(&self) -> Image {
+        let size = usize::from(self.size);
+        if size < 2 {
+            return Image::new(self.size);
+        }
+        let spacing = 2.0 / (size - 1) as f32;
+        // the difference between the pixels on either side of `idx`, along
+        // whichever axis `pos` and `step` walk
+        let diff = |idx: usize, pos: usize, step: usize| {
+            let before = if pos > 0 { idx - step } else { idx };
+            let after = if pos < size - 1 { idx + step } else { idx };
+            let distance = (after - before) / step;
+            (self.pixels[after] - self.pixels[before]) / (distance as f32 * spacing)
+        };
+        let pixels = (0..self.pixels.len())
+            .map(|idx| {
+                let (row, col) = (idx / size, idx % size);
+                diff(idx, col, 1).hypot(diff(idx, row, size))
+            })
+            .collect();
+        Image {
+            size: self.size,
+            pixels,
+        }
+    }
+
+    /// Write a black-and-white image where the pixels which are
+    /// [inside](is_inside) the shape are set.
+    pub fn write_pbm(&self, f: impl io::Write) -> io::Result<()> {
+        write_pbm_rows(f, self.size, &self.pixels)
+    }
+
+    /// Write a color image of the field within `pixels` pixels' worth of
+    /// distance from the contour, as a PPM. The hue cycles once per pixel of
+    /// distance, at full brightness outside the shape and dimmer inside, so
+    /// values that differ by a few ULPs shift the color bands visibly where a
+    /// black-and-white image would usually be identical; diffing two of these
+    /// is a far more sensitive check of whether two backends agree. Farther
+    /// from the contour, the image is white outside and dark gray inside.
+    pub fn write_contour_ppm(&self, mut f: impl io::Write, pixels: f32) -> io::Result<()> {
+        // https://netpbm.sourceforge.net/doc/ppm.html
+        writeln!(f, "P6 {0} {0} 255", self.size)?;
+        let spacing = 2.0 / f32::from(self.size.max(2) - 1);
+        let mut rgb = Vec::with_capacity(self.pixels.len() * 3);
+        for &value in self.pixels.iter() {
+            let distance = value.abs() / spacing;
+            let inside = is_inside(value);
+            let color = if distance < pixels {
+                let brightness = if inside { 0.6 } else { 1.0 };
+                hue_rgb(distance.fract()).map(|c| c * brightness)
+            } else {
+                [if inside { 0.25 } else { 1.0 }; 3]
+            };
+            rgb.extend(color.map(|c| (c * 255.0).round() as u8));
+        }
+        f.write_all(&rgb)
+    }
+
+    /// Write an 8-bit grayscale image of coverage values between 0 and 1, such
+    /// as from [`interp_coverage`](crate::ir::interp::interp_coverage). Fully
+    /// covered pixels are black, matching [`Image::write_pbm`].
+    pub fn write_pgm(&self, mut f: impl io::Write) -> io::Result<()> {
+        // https://netpbm.sourceforge.net/doc/pgm.html
+        writeln!(f, "P5 {0} {0} 255", self.size)?;
+        let gray: Vec<u8> = self
+            .pixels
+            .iter()
+            .map(|coverage| ((1.0 - coverage.clamp(0.0, 1.0)) * 255.0).round() as u8)
+            .collect();
+        f.write_all(&gray)
+    }
+}
+
+// A fully saturated color, going from red through green and blue and back as
+// `hue` goes from 0 to 1.
+fn hue_rgb(hue: f32) -> [f32; 3] {
+    let h = hue * 6.0;
+    [
+        (h - 3.0).abs() - 1.0,
+        2.0 - (h - 2.0).abs(),
+        2.0 - (h - 4.0).abs(),
+    ]
+    .map(|c| c.clamp(0.0, 1.0))
+}
+
+/// Like [`Image::write_pbm`], for any rectangle of pixels given in rows
+/// `width` across, such as a [`Tile`].
+pub fn write_pbm_rows(f: impl io::Write, width: u16, pixels: &[f32]) -> io::Result<()> {
+    let width_usize = usize::from(width).max(1);
+    let height = pixels.len() / width_usize;
+    let mut writer = PbmWriter::new(f, width, height)?;
+    for row in pixels.chunks(width_usize) {
+        writer.write_row(row)?;
+    }
+    writer.flush()
+}
+
+// How many bytes of packed rows `PbmWriter` collects before writing them, so
+// small images take one write and large ones take a few per megabyte instead
+// of one per row.
+const PBM_BUFFER: usize = 64 * 1024;
+
+/// Writes a PBM one row at a time, for images which are written out as they
+/// are rendered rather than held in memory all at once. Rows are buffered
+/// and written several at a time; call [`PbmWriter::flush`] after the last
+/// row to see any error from writing the rest, since dropping the writer
+/// writes them but ignores errors.
+pub struct PbmWriter<W: io::Write> {
+    f: W,
+    buf: Vec<u8>,
+    row_bytes: usize,
+}
+
+impl<W: io::Write> PbmWriter<W> {
+    /// Write the header for an image `width` pixels across and `height` rows
+    /// tall. The caller must then write exactly `height` rows.
+    pub fn new(f: W, width: u16, height: usize) -> io::Result<Self> {
+        let row_bytes = usize::from(width).div_ceil(8);
+        let mut buf = Vec::with_capacity(PBM_BUFFER.max(row_bytes));
+        // https://netpbm.sourceforge.net/doc/pbm.html
+        io::Write::write_fmt(&mut buf, format_args!("P4 {width} {height}\n"))?;
+        Ok(PbmWriter { f, buf, row_bytes })
+    }
+
+    /// Write the next row, setting the pixels which are [inside](is_inside)
+    /// the shape.
+    pub fn write_row(&mut self, row: &[f32]) -> io::Result<()> {
+        if self.buf.len() + self.row_bytes > self.buf.capacity() {
+            self.f.write_all(&self.buf)?;
+            self.buf.clear();
+        }
+        let start = self.buf.len();
+        self.buf.resize(start + self.row_bytes, 0);
+        pack_row(row, &mut self.buf[start..]);
+        Ok(())
+    }
+
+    /// Write any buffered rows and flush the underlying writer.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.f.write_all(&self.buf)?;
+        self.buf.clear();
+        self.f.flush()
+    }
+}
+
+impl<W: io::Write> Drop for PbmWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+// Set one bit per pixel, most significant first, 64 pixels at a time. Each
+// pixel's bit is shifted into place independently of the others, with no
+// branches, which the compiler turns into vector compares and shifts, and
+// each word is then stored big-endian as 8 bytes of the row.
+fn pack_row(row: &[f32], packed: &mut [u8]) {
+    let pack = |pixels: &[f32]| {
+        let word = pixels.iter().enumerate().fold(0u64, |word, (idx, &value)| {
+            word | u64::from(is_inside(value)) << (63 - idx)
+        });
+        word.to_be_bytes()
+    };
+    let mut words = row.chunks_exact(64);
+    let mut bytes = packed.chunks_exact_mut(8);
+    for (pixels, bytes) in (&mut words).zip(&mut bytes) {
+        let pixels: &[f32; 64] = pixels.try_into().unwrap();
+        bytes.copy_from_slice(&pack(pixels));
+    }
+    let rest = words.remainder();
+    if !rest.is_empty() {
+        let bytes = bytes.into_remainder();
+        bytes.copy_from_slice(&pack(rest)[..bytes.len()]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_orient() {
+        let mut image = Image {
+            size: 3,
+            pixels: (0..9).map(|v| v as f32).collect(),
+        };
+        image.orient(Orientation::YDown);
+        assert_eq!(image.pixels, [6., 7., 8., 3., 4., 5., 0., 1., 2.]);
+        assert_eq!(Orientation::YUp.row_y(0, 3), 1.0);
+        assert_eq!(Orientation::YDown.row_y(0, 3), -1.0);
+    }
+
+    #[test]
+    fn test_signed_zero_is_inside() {
+        let image = Image {
+            size: 2,
+            pixels: vec![0.0, -0.0, -f32::MIN_POSITIVE, f32::MIN_POSITIVE],
+        };
+        let mut pbm = Vec::new();
+        image.write_pbm(&mut pbm).unwrap();
+        assert_eq!(pbm, b"P4 2 2\n\xc0\x40");
+    }
+
+    #[test]
+    fn test_pbm_packing() {
+        // rows which don't fill the last word, or the last byte, and enough
+        // of them to fill the buffer more than once
+        let width = 1000u16;
+        let pixels: Vec<f32> = (0..usize::from(width) * 600)
+            .map(|idx| match idx % 7 {
+                0 => -0.0,
+                1 | 4 => (idx as f32).sin(),
+                _ => -(idx as f32).cos(),
+            })
+            .collect();
+        let mut pbm = Vec::new();
+        write_pbm_rows(&mut pbm, width, &pixels).unwrap();
+
+        let mut expected = b"P4 1000 600\n".to_vec();
+        for row in pixels.chunks(width.into()) {
+            for byte in row.chunks(8) {
+                let bits = byte.iter().enumerate();
+                expected.push(bits.fold(0, |acc, (x, &v)| acc | u8::from(is_inside(v)) << (7 - x)));
+            }
+        }
+        assert!(pbm == expected);
+    }
+
+    #[test]
+    fn test_tolerance() {
+        let one_up = f32::from_bits(0.5f32.to_bits() + 1);
+        let exact = Tolerance::Exact;
+        let ulps = "ulps:1".parse::<Tolerance>().unwrap();
+        let sign = "sign:0.25".parse::<Tolerance>().unwrap();
+        assert!(exact.accepts(0.5, 0.5) && !exact.accepts(0.0, -0.0));
+        assert!(ulps.accepts(0.5, one_up) && !ulps.accepts(0.5, 0.5f32.next_up().next_up()));
+        // the smallest values on either side of zero are two apart
+        let tiny = f32::from_bits(1);
+        assert!(ulps.accepts(0.0, -0.0) && ulps.accepts(0.0, -tiny) && !ulps.accepts(tiny, -tiny));
+        assert!(ulps.accepts(f32::NAN, f32::NAN) && !ulps.accepts(f32::NAN, 0.0));
+        assert!(sign.accepts(0.2, -0.2) && sign.accepts(0.5, 100.0) && !sign.accepts(0.5, -0.1));
+        assert_eq!(sign.to_string(), "sign:0.25");
+        assert!("ulps:-1".parse::<Tolerance>().is_err());
+
+        let reference = Image {
+            size: 2,
+            pixels: vec![1.0, -1.0, 0.0, 0.5],
+        };
+        let render = Image {
+            size: 2,
+            pixels: vec![1.0, -1.0, 0.1, one_up],
+        };
+        assert_eq!(render.check(&reference, sign), Ok(()));
+        let mismatch = render.check(&reference, ulps).unwrap_err();
+        assert_eq!((mismatch.count, mismatch.col, mismatch.row), (1, 0, 1));
+        assert_eq!(render.check(&reference, exact).unwrap_err().count, 2);
+    }
+
+    #[test]
+    fn test_gradient() {
+        // 0.5 * x - y, sampled at -1, 0, and 1 on each axis
+        let image = Image {
+            size: 3,
+            pixels: vec![-1.5, -1.0, -0.5, -0.5, 0.0, 0.5, 0.5, 1.0, 1.5],
+        };
+        let expected = 0.5f32.hypot(1.0);
+        assert!(image.gradient().pixels.iter().all(|&g| g == expected));
+        assert_eq!(Image::new(1).gradient(), Image::new(1));
+    }
+
+    #[test]
+    fn test_contour_ppm() {
+        // pixels are a unit apart, so a value of 0.5 is half a pixel away
+        let image = Image {
+            size: 3,
+            pixels: vec![0.0, 0.5, -0.5, 1.0 / 6.0, 4.0, -4.0, f32::NAN, 3.0, -3.0],
+        };
+        let mut ppm = Vec::new();
+        image.write_contour_ppm(&mut ppm, 3.0).unwrap();
+        let (header, rgb) = ppm.split_at(11);
+        assert_eq!(header, b"P6 3 3 255\n");
+        assert_eq!(
+            rgb.chunks(3).collect::<Vec<_>>(),
+            [
+                [153, 0, 0],
+                [0, 153, 153],
+                [0, 255, 255],
+                [153, 153, 0],
+                [64, 64, 64],
+                [255, 255, 255],
+                [64, 64, 64],
+                [64, 64, 64],
+                [255, 255, 255],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tile_rows() {
+        let tile = Tile {
+            left: 1,
+            top: 0,
+            width: 3,
+            height: 1,
+        };
+        assert!(tile.fits(4));
+        assert!(!tile.fits(3));
+        assert!(Tile::full(3).fits(3));
+        let mut pbm = Vec::new();
+        write_pbm_rows(&mut pbm, tile.width, &[1.0, -1.0, 0.0]).unwrap();
+        assert_eq!(pbm, b"P4 3 1\n\xa0");
+    }
+}
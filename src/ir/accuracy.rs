@@ -0,0 +1,163 @@
+use thiserror::Error;
+
+use super::bounds::BoundingBox;
+use super::interval::{Interval, eval_interval};
+use super::{BinOp, Inst, InstIdx, Insts, UnOp};
+
+// Bits of precision in an `f32`, counting the implicit leading one.
+const MANTISSA_BITS: f32 = 24.0;
+
+/// Why an instruction might lose precision.
+#[derive(Clone, Copy, Debug, Error, PartialEq)]
+pub enum Hazard {
+    /// An `add` or `sub`, or the sum in an `fma`, whose operands can be much
+    /// larger than its result, so their rounding errors are large compared to
+    /// the result.
+    #[error("operands reach {operands:e} but the result stays within {result:e}")]
+    Cancellation { operands: f32, result: f32 },
+    /// A `sqrt` whose argument gets close to zero compared to its range,
+    /// where the square root magnifies any error in the argument. At most
+    /// half the bits can go this way: an error of one part in 2²⁴ of the
+    /// argument's range is still one part in 2¹² of the result's.
+    #[error("argument ranges from {lo:e} to {hi:e}")]
+    SqrtNearZero { lo: f32, hi: f32 },
+}
+
+/// An instruction which might lose precision somewhere in the audited
+/// region, along with the cell where it looked worst.
+#[derive(Clone, Copy, Debug, Error, PartialEq)]
+#[error(
+    "v{}: {hazard}, losing about {bits:.0} bits near x {:?}..{:?}, y {:?}..{:?}",
+    idx.idx(), cell.x.lo, cell.x.hi, cell.y.lo, cell.y.hi
+)]
+pub struct Finding {
+    pub idx: InstIdx,
+    pub hazard: Hazard,
+    /// Roughly how many of the result's 24 bits of precision rounding errors
+    /// in the operands could wipe out.
+    pub bits: f32,
+    pub cell: BoundingBox,
+}
+
+/// Look for instructions prone to catastrophic cancellation, which show up
+/// as shimmering or ragged contours. `region` is split into a grid of
+/// `cells`×`cells` cells, and interval arithmetic over each cell bounds
+/// every instruction's operands and result there. Any `add` or `sub` whose
+/// operands can be `2^min_bits` times larger than its result, or `sqrt` whose
+/// argument gets twice as many bits closer to zero than its largest value,
+/// is reported, worst first. Intervals are conservative, so these are
+/// candidates to look at rather than proof of a problem, and instructions
+/// whose operands are unbounded in some cell are skipped there.
+pub fn audit(insts: &Insts, region: BoundingBox, cells: u16, min_bits: f32) -> Vec<Finding> {
+    let _span = tracing::info_span!("audit", cells).entered();
+    let mut regs = vec![Interval::EVERYTHING; insts.pool.len()];
+    let mut worst: Vec<Option<Finding>> = vec![None; insts.pool.len()];
+    let step = |range: Interval, idx: u16| {
+        let width = (range.hi - range.lo) / f32::from(cells);
+        let lo = range.lo + width * f32::from(idx);
+        Interval::new(lo, lo + width)
+    };
+    for row in 0..cells {
+        for col in 0..cells {
+            let cell = BoundingBox {
+                x: step(region.x, col),
+                y: step(region.y, row),
+            };
+            eval_interval(insts, &mut regs, &[cell.x, cell.y, Interval::EVERYTHING]);
+            for (idx, inst) in insts.pool.iter().enumerate() {
+                let Some((hazard, bits)) = hazard(inst, &regs, regs[idx]) else {
+                    continue;
+                };
+                if bits >= min_bits && worst[idx].is_none_or(|worst| bits > worst.bits) {
+                    worst[idx] = Some(Finding {
+                        idx: idx.try_into().unwrap(),
+                        hazard,
+                        bits,
+                        cell,
+                    });
+                }
+            }
+        }
+    }
+    let mut findings: Vec<Finding> = worst.into_iter().flatten().collect();
+    findings.sort_by(|a, b| b.bits.total_cmp(&a.bits).then(a.idx.cmp(&b.idx)));
+    findings
+}
+
+fn hazard(inst: &Inst, regs: &[Interval], result: Interval) -> Option<(Hazard, f32)> {
+    match *inst {
+        Inst::BinOp {
+            op: op @ (BinOp::Add | BinOp::Sub),
+            args: [a, b],
+        } => {
+            let [a, b] = [a, b].map(|arg| regs[arg.idx()]);
+            let b = if op == BinOp::Sub {
+                b
+            } else {
+                Interval::new(-b.hi, -b.lo)
+            };
+            cancellation(a, b, result)
+        }
+        Inst::Fma { args: [a, b, c] } => {
+            let product = regs[a.idx()].binop(BinOp::Mul, regs[b.idx()]);
+            let c = regs[c.idx()];
+            cancellation(product, Interval::new(-c.hi, -c.lo), result)
+        }
+        Inst::UnOp {
+            op: UnOp::Sqrt,
+            arg,
+        } => {
+            let Interval { lo, hi } = regs[arg.idx()];
+            if !hi.is_finite() || hi <= 0.0 {
+                return None;
+            }
+            let hazard = Hazard::SqrtNearZero { lo, hi };
+            Some((hazard, lost(hi / lo.max(0.0)) / 2.0))
+        }
+        _ => None,
+    }
+}
+
+// The difference `a - b`, which only cancels if they have the same sign.
+fn cancellation(a: Interval, b: Interval, result: Interval) -> Option<(Hazard, f32)> {
+    let magnitude = |range: Interval| range.lo.abs().max(range.hi.abs());
+    let same_sign = a.hi >= 0.0 && b.hi >= 0.0 || a.lo <= 0.0 && b.lo <= 0.0;
+    let operands = magnitude(a).max(magnitude(b));
+    // a result that's the same everywhere in the cell can't shimmer
+    if !same_sign || !operands.is_finite() || result.lo == result.hi {
+        return None;
+    }
+    let result = magnitude(result);
+    let hazard = Hazard::Cancellation { operands, result };
+    Some((hazard, lost(operands / result)))
+}
+
+fn lost(ratio: f32) -> f32 {
+    ratio.log2().clamp(0.0, MANTISSA_BITS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::io::read;
+
+    #[test]
+    fn test_audit() {
+        // a circle of radius 0.5 written as r² - (x² + y²) with a large
+        // offset added to both sides, and the same circle as a distance
+        let text = "x var-x\ny var-y\nx2 square x\ny2 square y\nd2 add x2 y2\n\
+            big const 1000\nr2 const 0.25\nlhs add r2 big\nrhs add d2 big\n\
+            bad sub lhs rhs\nd sqrt d2\nr const 0.5\ngood sub r d\nboth min bad good\n";
+        let insts = read(text.as_bytes(), Insts::default()).unwrap();
+        let findings = audit(&insts, BoundingBox::square(1.0), 8, 12.0);
+        let flagged: Vec<usize> = findings.iter().map(|f| f.idx.idx()).collect();
+        // the big subtraction loses more than the sqrt at the center, and the
+        // distance's own subtraction is fine
+        assert_eq!(flagged, [9, 10], "{findings:?}");
+        assert!(matches!(
+            findings[0].hazard,
+            Hazard::Cancellation { operands, .. } if operands > 1000.0
+        ));
+        assert!(findings[0].to_string().starts_with("v9: operands reach"));
+    }
+}
@@ -0,0 +1,135 @@
+use super::interval::{Interval, eval_interval};
+use super::{BinOp, Const, Inst, InstSink, Insts, Var};
+
+/// An axis-aligned rectangle in the x/y plane.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BoundingBox {
+    pub x: Interval,
+    pub y: Interval,
+}
+
+impl BoundingBox {
+    /// The square from -`extent` to `extent` on both axes.
+    pub fn square(extent: f32) -> BoundingBox {
+        let range = Interval::new(-extent, extent);
+        BoundingBox { x: range, y: range }
+    }
+
+    fn union(self, other: BoundingBox) -> BoundingBox {
+        let union = |a: Interval, b: Interval| Interval::new(a.lo.min(b.lo), a.hi.max(b.hi));
+        BoundingBox {
+            x: union(self.x, other.x),
+            y: union(self.y, other.y),
+        }
+    }
+
+    fn quadrants(self) -> [BoundingBox; 4] {
+        let split = |range: Interval| {
+            let mid = (range.lo + range.hi) / 2.0;
+            [Interval::new(range.lo, mid), Interval::new(mid, range.hi)]
+        };
+        let [left, right] = split(self.x);
+        let [bottom, top] = split(self.y);
+        [
+            BoundingBox { x: left, y: bottom },
+            BoundingBox {
+                x: right,
+                y: bottom,
+            },
+            BoundingBox { x: left, y: top },
+            BoundingBox { x: right, y: top },
+        ]
+    }
+}
+
+/// Find a rectangle within `region` which contains every point where the
+/// program might be non-negative, which is to say every pixel that would be
+/// set in a rendered image. The region is split into quadrants up to `depth`
+/// times, discarding any which interval arithmetic proves are entirely
+/// outside the shape, so the result is conservative: it may be larger than
+/// the shape by up to one cell at that depth, but never smaller. Returns
+/// `None` if the shape is nowhere in the region.
+pub fn bounding_box(insts: &Insts, region: BoundingBox, depth: u32) -> Option<BoundingBox> {
+    let _span = tracing::info_span!("bounding_box", depth).entered();
+    let mut regs = vec![Interval::EVERYTHING; insts.pool.len()];
+    let mut cells = 0usize;
+    let mut search = |cell: BoundingBox| {
+        cells += 1;
+        eval_interval(insts, &mut regs, &[cell.x, cell.y, Interval::EVERYTHING])
+    };
+    let result = search_cell(&mut search, region, depth);
+    tracing::debug!(cells, "bounding box search finished");
+    result
+}
+
+fn search_cell(
+    eval: &mut impl FnMut(BoundingBox) -> Interval,
+    cell: BoundingBox,
+    depth: u32,
+) -> Option<BoundingBox> {
+    let bounds = eval(cell);
+    if bounds.is_negative() {
+        return None;
+    }
+    if bounds.is_positive() || depth == 0 {
+        return Some(cell);
+    }
+    cell.quadrants()
+        .into_iter()
+        .filter_map(|quadrant| search_cell(eval, quadrant, depth - 1))
+        .reduce(BoundingBox::union)
+}
+
+/// Rewrite the program so that the usual view from -1 to 1 on each axis
+/// shows `bounds` instead, centered and with its aspect ratio preserved.
+pub fn fit(insts: &Insts, bounds: BoundingBox) -> Insts {
+    let center = |range: Interval| (range.lo + range.hi) / 2.0;
+    let extent = (bounds.x.hi - bounds.x.lo).max(bounds.y.hi - bounds.y.lo);
+    let half = (extent / 2.0).max(f32::EPSILON);
+    let centers = [center(bounds.x), center(bounds.y)];
+
+    let mut out = Insts::default();
+    let mut map = Vec::with_capacity(insts.pool.len());
+    for inst in insts.pool.iter() {
+        let mut inst = inst.clone();
+        for arg in inst.args_mut() {
+            *arg = map[arg.idx()];
+        }
+        let idx = match inst {
+            Inst::Var { var } if var != Var::Z => {
+                let coord = out.push_var(var);
+                let half = out.push_const(Const::new(half));
+                let scaled = out.push_binop(BinOp::Mul, [coord, half]);
+                let center = out.push_const(Const::new(centers[var as usize]));
+                out.push_binop(BinOp::Add, [scaled, center])
+            }
+            inst => out.push(inst),
+        };
+        map.push(idx);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::io::read;
+
+    #[test]
+    fn test_offset_circle() {
+        // circle of radius 0.25 centered at (0.5, -0.25)
+        let text = "x var-x\ny var-y\ncx const 0.5\ncy const -0.25\nr const 0.25\n\
+            dx sub x cx\ndy sub y cy\ndx2 square dx\ndy2 square dy\nd2 add dx2 dy2\n\
+            d sqrt d2\nc sub r d\n";
+        let insts = read(text.as_bytes(), Insts::default()).unwrap();
+        let bounds = bounding_box(&insts, BoundingBox::square(1.0), 8).unwrap();
+        let cell = 2.0 / 256.0;
+        for (range, lo, hi) in [(bounds.x, 0.25, 0.75), (bounds.y, -0.5, 0.0)] {
+            assert!(range.lo <= lo && range.lo >= lo - cell, "{bounds:?}");
+            assert!(range.hi >= hi && range.hi <= hi + cell, "{bounds:?}");
+        }
+
+        let fitted = bounding_box(&fit(&insts, bounds), BoundingBox::square(1.0), 8).unwrap();
+        assert!(fitted.x.lo < -0.9 && fitted.x.hi > 0.9, "{fitted:?}");
+    }
+}
@@ -0,0 +1,103 @@
+use super::simplify::{Idx, Simplify};
+use super::{BinOp, Const, InstIdx, InstSink, Insts, UnOp};
+
+// As everywhere else in this crate, values are positive inside a shape.
+
+/// Everything inside either shape.
+pub fn union(a: &Insts, b: &Insts) -> Insts {
+    join(a, b, |sink, [a, b]| sink.push_binop(BinOp::Max, [a, b]))
+}
+
+/// Only what's inside both shapes.
+pub fn intersection(a: &Insts, b: &Insts) -> Insts {
+    join(a, b, |sink, [a, b]| sink.push_binop(BinOp::Min, [a, b]))
+}
+
+/// What's inside `a` but not inside `b`.
+pub fn difference(a: &Insts, b: &Insts) -> Insts {
+    join(a, b, |sink, [a, b]| {
+        let outside_b = sink.push_unop(UnOp::Neg, b);
+        sink.push_binop(BinOp::Min, [a, outside_b])
+    })
+}
+
+/// Grow the shape by `distance`, or shrink it if `distance` is negative. The
+/// result is only an exact offset if the program is a true distance field.
+pub fn offset(a: &Insts, distance: f32) -> Insts {
+    let mut sink = Simplify::new(Insts::default());
+    let a = a.replay_into(&mut sink);
+    let distance = sink.push_const(Const::new(distance));
+    let last = sink.push_binop(BinOp::Add, [a, distance]);
+    sink.finish(last)
+}
+
+/// A blend between two shapes, which is `a` when `t` is 0 and `b` when `t` is
+/// 1. Sweeping `t` morphs one shape into the other.
+pub fn lerp(a: &Insts, b: &Insts, t: f32) -> Insts {
+    join(a, b, |sink, [a, b]| {
+        // (1 - t) * a + t * b gives exactly `a` or `b` at the ends
+        let wa = sink.push_const(Const::new(1.0 - t));
+        let wb = sink.push_const(Const::new(t));
+        let a = sink.push_binop(BinOp::Mul, [a, wa]);
+        let b = sink.push_binop(BinOp::Mul, [b, wb]);
+        sink.push_binop(BinOp::Add, [a, b])
+    })
+}
+
+// Replay both programs through one simplify pass, so anything they compute in
+// common, such as the coordinates or a shared transform, is only computed once
+// in the result.
+fn join(
+    a: &Insts,
+    b: &Insts,
+    op: impl FnOnce(&mut Simplify<Insts>, [Idx<InstIdx>; 2]) -> Idx<InstIdx>,
+) -> Insts {
+    let mut sink = Simplify::new(Insts::default());
+    let a = a.replay_into(&mut sink);
+    let b = b.replay_into(&mut sink);
+    let last = op(&mut sink, [a, b]);
+    sink.finish(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corpus::shape;
+    use crate::ir::interp::eval_point;
+
+    #[test]
+    fn test_combine() {
+        let circle = shape("circle").unwrap().insts();
+        let ring = shape("ring").unwrap().insts();
+        let eval = |insts: &Insts, point: [f32; 2]| {
+            eval_point(insts, &mut vec![0.0; insts.pool.len()], &point)
+        };
+
+        let joined = [
+            (union(&circle, &ring), f32::max as fn(f32, f32) -> f32),
+            (intersection(&circle, &ring), f32::min),
+            (difference(&circle, &ring), |a, b| a.min(-b)),
+        ];
+        for (insts, op) in joined.iter() {
+            // both shapes start from x and y, and simplify merges them
+            assert!(insts.pool.len() < circle.pool.len() + ring.pool.len());
+            for point in [[0.0, 0.0], [0.3, 0.4], [0.6, 0.0], [0.9, 0.9]] {
+                let expected = op(eval(&circle, point), eval(&ring, point));
+                assert_eq!(eval(insts, point), expected, "{point:?}");
+            }
+        }
+
+        for point in [[0.0, 0.0], [0.6, 0.0]] {
+            let (a, b) = (eval(&circle, point), eval(&ring, point));
+            assert_eq!(eval(&lerp(&circle, &ring, 0.0), point), a);
+            assert_eq!(eval(&lerp(&circle, &ring, 1.0), point), b);
+            assert_eq!(
+                eval(&lerp(&circle, &ring, 0.25), point),
+                0.75 * a + 0.25 * b
+            );
+        }
+
+        let grown = offset(&circle, 0.25);
+        assert_eq!(eval(&grown, [0.6, 0.0]), eval(&circle, [0.6, 0.0]) + 0.25);
+    }
+}
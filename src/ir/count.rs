@@ -0,0 +1,113 @@
+use std::collections::BTreeMap;
+
+use super::{BinOp, Const, InstSink, Location, TernOp, UnOp, Var, VarSet};
+
+/// How many instructions of each kind a program has, and how many depend on
+/// each set of variables.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Counts {
+    pub consts: usize,
+    pub vars: usize,
+    pub loads: usize,
+    pub unops: BTreeMap<UnOp, usize>,
+    pub binops: BTreeMap<BinOp, usize>,
+    pub fmas: usize,
+    pub ternops: BTreeMap<TernOp, usize>,
+    /// Indexed by [`VarSet::idx`]. Constants count toward the empty set.
+    pub by_vars: [usize; 8],
+}
+
+impl Counts {
+    pub fn total(&self) -> usize {
+        self.by_vars.iter().sum()
+    }
+}
+
+/// A sink which only counts the instructions pushed into it, for a cheap dry
+/// run of a pipeline: to size buffers before running it for real, or to
+/// compare configurations without building the programs they'd produce.
+#[derive(Default)]
+pub struct Count {
+    counts: Counts,
+}
+
+/// Instructions don't exist anywhere, so an index is just a serial number,
+/// which keeps indexes distinct for adaptors like
+/// [`Simplify`](super::simplify::Simplify) which look them up, along with the
+/// variables the instruction depends on.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct CountIdx {
+    serial: usize,
+    vars: VarSet,
+}
+
+impl Count {
+    fn push(&mut self, vars: VarSet) -> CountIdx {
+        let serial = self.counts.total();
+        self.counts.by_vars[vars.idx()] += 1;
+        CountIdx { serial, vars }
+    }
+}
+
+impl InstSink for Count {
+    type Idx = CountIdx;
+    type Output = Counts;
+
+    fn push_const(&mut self, _value: Const) -> Self::Idx {
+        self.counts.consts += 1;
+        self.push(VarSet::default())
+    }
+
+    fn push_var(&mut self, var: Var) -> Self::Idx {
+        self.counts.vars += 1;
+        self.push(var.into())
+    }
+
+    fn push_unop(&mut self, op: UnOp, arg: Self::Idx) -> Self::Idx {
+        *self.counts.unops.entry(op).or_default() += 1;
+        self.push(arg.vars)
+    }
+
+    fn push_binop(&mut self, op: BinOp, [a, b]: [Self::Idx; 2]) -> Self::Idx {
+        *self.counts.binops.entry(op).or_default() += 1;
+        self.push(a.vars | b.vars)
+    }
+
+    fn push_fma(&mut self, [a, b, c]: [Self::Idx; 3]) -> Self::Idx {
+        self.counts.fmas += 1;
+        self.push(a.vars | b.vars | c.vars)
+    }
+
+    fn push_ternop(&mut self, op: TernOp, [a, b, c]: [Self::Idx; 3]) -> Self::Idx {
+        *self.counts.ternops.entry(op).or_default() += 1;
+        self.push(a.vars | b.vars | c.vars)
+    }
+
+    fn push_load(&mut self, vars: VarSet, _loc: Location) -> Self::Idx {
+        self.counts.loads += 1;
+        self.push(vars)
+    }
+
+    fn finish(self, _last: Self::Idx) -> Self::Output {
+        self.counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corpus::shape;
+    use crate::ir::Insts;
+    use crate::ir::simplify::Simplify;
+
+    #[test]
+    fn test_matches_insts() {
+        let shape = shape("circle").unwrap();
+        let counts = shape.read(Simplify::new(Count::default()));
+        let insts = shape.read(Simplify::new(Insts::with_capacity(counts.total())));
+        assert_eq!(counts.total(), insts.pool.len());
+        assert_eq!(counts.vars, 2);
+        let xy = (VarSet::from(Var::X) | Var::Y.into()).idx();
+        assert!(counts.by_vars[xy] > 0);
+    }
+}
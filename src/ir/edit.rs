@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+
+use super::io::{Error, Parsed, Result, parse_line};
+use super::memoize::{MemoBuilder, MemoIdx, Memoized};
+use super::simplify::{Idx, Simplify};
+use super::{Inst, InstIdx, InstSink, Insts};
+
+/// A program which can be changed a few lines at a time, for interactive
+/// editors where recompiling thousands of instructions after every small
+/// tweak would be too slow.
+///
+/// The editor keeps the program's text as statements, along with a
+/// [`Simplify`] pass and a [`MemoBuilder`] that both stay open between
+/// edits. An edit only pushes the statements it changes, and the later
+/// statements whose arguments changed as a result, so unaffected parts of the
+/// program are neither simplified nor memoized again, and an edit that
+/// recreates an existing expression finds it in the GVN table.
+///
+/// Reassociation needs the whole program at once, so edited programs skip
+/// it. Replaced statements leave their old instructions behind in the open
+/// passes; [`insts`](Editor::insts) and [`memoized`](Editor::memoized) drop
+/// them from what they return, but they still take up memory until the
+/// editor is rebuilt from text.
+pub struct Editor {
+    // each line of the program, with arguments referring to earlier
+    // statements by position
+    stmts: Vec<Parsed<usize>>,
+    names: HashMap<String, usize>,
+    // each statement's simplified value
+    values: Vec<Idx<InstIdx>>,
+    simplify: Simplify<Insts>,
+    memo: MemoBuilder,
+    // each simplified instruction's memoized value, for every instruction
+    // the memoizer has seen so far
+    memoized: Vec<MemoIdx>,
+}
+
+impl Editor {
+    /// Start editing a program in Matt Keeter's format.
+    pub fn new(text: &str) -> Result<Editor> {
+        let mut editor = Editor {
+            stmts: Vec::new(),
+            names: HashMap::new(),
+            values: Vec::new(),
+            simplify: Simplify::new(Insts::default()),
+            memo: MemoBuilder::new(),
+            memoized: Vec::new(),
+        };
+        editor.edit(text)?;
+        if editor.stmts.is_empty() {
+            return Err(Error::Empty);
+        }
+        Ok(editor)
+    }
+
+    /// Apply lines in the same format as the program. A line defining a new
+    /// name is appended to the program and becomes its result. A line
+    /// defining an existing name replaces that statement everywhere it's
+    /// used; it may only use names defined before the statement it replaces,
+    /// so the program stays in order. If any line is malformed, nothing
+    /// changes.
+    pub fn edit(&mut self, text: &str) -> Result<()> {
+        let mut added: HashMap<&str, usize> = HashMap::new();
+        let mut changes = Vec::new();
+        for line in text.lines() {
+            let lookup = |name: &str| added.get(name).or(self.names.get(name)).copied();
+            let Some((name, parsed)) = parse_line(line, lookup)? else {
+                continue;
+            };
+            let slot = match lookup(name) {
+                Some(slot) if parsed.args().iter().any(|&arg| arg >= slot) => {
+                    return Err(Error::ForwardReference(name.to_string()));
+                }
+                Some(slot) => slot,
+                None => {
+                    let slot = self.stmts.len() + added.len();
+                    added.insert(name, slot);
+                    slot
+                }
+            };
+            changes.push((slot, parsed));
+        }
+        let Some(first) = changes.iter().map(|&(slot, _)| slot).min() else {
+            return Ok(());
+        };
+
+        let len = self.stmts.len() + added.len();
+        let mut edited = vec![false; len];
+        for (slot, parsed) in changes {
+            // new statements got their slots in order
+            if slot < self.stmts.len() {
+                self.stmts[slot] = parsed;
+            } else {
+                self.stmts.push(parsed);
+            }
+            edited[slot] = true;
+        }
+        self.names.extend(
+            added
+                .into_iter()
+                .map(|(name, slot)| (name.to_string(), slot)),
+        );
+
+        // Walk forward from the first change, pushing only statements which
+        // were edited or have an argument whose value changed.
+        let mut changed = vec![false; len];
+        for slot in first..len {
+            let stmt = self.stmts[slot];
+            if !edited[slot] && !stmt.args().iter().any(|&arg| changed[arg]) {
+                continue;
+            }
+            let value = self.push(stmt);
+            if let Some(old) = self.values.get_mut(slot) {
+                changed[slot] = *old != value;
+                *old = value;
+            } else {
+                changed[slot] = true;
+                self.values.push(value);
+            }
+        }
+        tracing::debug!(
+            statements = len,
+            pushed = self.simplify.base().pool.len() - self.memoized.len(),
+            "edited program"
+        );
+        self.memoize_new();
+        Ok(())
+    }
+
+    /// The simplified program as it stands, without any instructions left
+    /// behind by replaced statements.
+    pub fn insts(&mut self) -> Insts {
+        let last = self.result();
+        let pool = &self.simplify.base().pool;
+        let mut live = vec![false; last.idx() + 1];
+        live[last.idx()] = true;
+        for idx in (0..live.len()).rev() {
+            if live[idx] {
+                for arg in pool[idx].args() {
+                    live[arg.idx()] = true;
+                }
+            }
+        }
+
+        let mut insts = Insts::default();
+        let mut map = Vec::with_capacity(live.len());
+        for (inst, live) in pool.iter().zip(live) {
+            let mut inst = inst.clone();
+            for arg in inst.args_mut() {
+                *arg = map[arg.idx()];
+            }
+            map.push(if live {
+                insts.push(inst)
+            } else {
+                // never read
+                last
+            });
+        }
+        insts
+    }
+
+    /// The program as it stands, memoized as [`MemoBuilder`] would have done
+    /// had it been given the whole simplified program at once.
+    pub fn memoized(&mut self) -> Memoized {
+        let last = self.result();
+        self.memo.snapshot(self.memoized[last.idx()])
+    }
+
+    fn push(&mut self, stmt: Parsed<usize>) -> Idx<InstIdx> {
+        let simplify = &mut self.simplify;
+        match stmt {
+            Parsed::Const(value) => simplify.push_const(value),
+            Parsed::Var(var) => simplify.push_var(var),
+            Parsed::UnOp(op, arg) => simplify.push_unop(op, self.values[arg]),
+            Parsed::BinOp(op, args) => simplify.push_binop(op, args.map(|arg| self.values[arg])),
+            Parsed::Fma(args) => simplify.push_fma(args.map(|arg| self.values[arg])),
+            Parsed::TernOp(op, args) => simplify.push_ternop(op, args.map(|arg| self.values[arg])),
+        }
+    }
+
+    // The last statement's value as an instruction.
+    fn result(&mut self) -> InstIdx {
+        let last = *self.values.last().unwrap();
+        let last = self.simplify.force_neg(last);
+        self.memoize_new();
+        last
+    }
+
+    // Feed instructions that simplification added since the last call to
+    // the memoizer.
+    fn memoize_new(&mut self) {
+        let pool = &self.simplify.base().pool;
+        for inst in &pool[self.memoized.len()..] {
+            let arg = |idx: InstIdx| self.memoized[idx.idx()];
+            let idx = match *inst {
+                Inst::Const { value } => self.memo.push_const(value),
+                Inst::Var { var } => self.memo.push_var(var),
+                Inst::UnOp { op, arg: a } => self.memo.push_unop(op, arg(a)),
+                Inst::BinOp { op, args } => self.memo.push_binop(op, args.map(arg)),
+                Inst::Fma { args } => self.memo.push_fma(args.map(arg)),
+                Inst::TernOp { op, args } => self.memo.push_ternop(op, args.map(arg)),
+                Inst::Load { .. } => unreachable!("programs in text don't load"),
+            };
+            self.memoized.push(idx);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corpus::shape;
+    use crate::ir::bytecode::interp_memoized;
+    use crate::ir::interp::interp_image;
+    use crate::ir::io::read;
+
+    #[test]
+    fn test_edits_match_full_rebuild() {
+        let text = shape("ring").unwrap().text;
+        let mut editor = Editor::new(text).unwrap();
+        let len = editor.simplify.base().pool.len();
+
+        // widen the ring and cut a bar through it
+        let edit = "outer const 0.9\nbar_y square y\nbar sub bar_y width\ncut max out bar\n";
+        assert!(matches!(
+            editor.edit(edit),
+            Err(Error::UndefinedName(name)) if name == "width"
+        ));
+        assert!(matches!(
+            editor.edit("cy const 0.5\ncx add x r"),
+            Err(Error::ForwardReference(name)) if name == "cx"
+        ));
+        assert_eq!(editor.simplify.base().pool.len(), len);
+        editor.edit(&format!("width const 0.01\n{edit}")).unwrap();
+
+        let text = text.replace("outer const 0.7", "outer const 0.9")
+            + "width const 0.01\nbar_y square y\nbar sub bar_y width\ncut max out bar\n";
+        let expected = read(text.as_bytes(), Simplify::new(Insts::default())).unwrap();
+        let expected = interp_image(&expected, 32);
+        assert_eq!(interp_image(&editor.insts(), 32), expected);
+        assert_eq!(interp_memoized(&editor.memoized(), 32), expected);
+    }
+}
@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+use std::mem;
+use std::ptr;
+use std::rc::Rc;
+
+use super::{BinOp, Const, InstSink, Location, TernOp, UnOp, Var, VarSet};
+
+/// A program as a tree of expressions, for algorithms that are easier to
+/// write recursively than over [`Insts`](super::Insts). A value used more than
+/// once is one node shared through `Rc`, so the tree is really a DAG the same
+/// size as the program it came from. Build one by feeding a program into an
+/// [`ExprBuilder`], and turn it back into instructions with [`replay`].
+#[derive(Clone, Debug)]
+pub enum Expr {
+    Const(Const),
+    Var(Var),
+    UnOp(UnOp, Rc<Expr>),
+    BinOp(BinOp, [Rc<Expr>; 2]),
+    Fma([Rc<Expr>; 3]),
+    TernOp(TernOp, [Rc<Expr>; 3]),
+    Load(VarSet, Location),
+}
+
+impl Expr {
+    pub fn args(&self) -> &[Rc<Expr>] {
+        match self {
+            Expr::Const(_) | Expr::Var(_) | Expr::Load(..) => &[],
+            Expr::UnOp(_, arg) => std::slice::from_ref(arg),
+            Expr::BinOp(_, args) => args,
+            Expr::Fma(args) => args,
+            Expr::TernOp(_, args) => args,
+        }
+    }
+
+    fn args_mut(&mut self) -> &mut [Rc<Expr>] {
+        match self {
+            Expr::Const(_) | Expr::Var(_) | Expr::Load(..) => &mut [],
+            Expr::UnOp(_, arg) => std::slice::from_mut(arg),
+            Expr::BinOp(_, args) => args,
+            Expr::Fma(args) => args,
+            Expr::TernOp(_, args) => args,
+        }
+    }
+}
+
+// Dropping the last reference to a deep tree would recurse once per level,
+// so this detaches each child it holds the last reference to, swapping in a
+// shared placeholder, and drops them one at a time instead.
+impl Drop for Expr {
+    fn drop(&mut self) {
+        if !self.args().iter().any(|arg| Rc::strong_count(arg) == 1) {
+            return;
+        }
+        let placeholder = Rc::new(Expr::Const(Const::default()));
+        let detach = |expr: &mut Expr, orphans: &mut Vec<Expr>| {
+            for arg in expr.args_mut() {
+                if Rc::strong_count(arg) == 1 {
+                    let arg = mem::replace(arg, placeholder.clone());
+                    orphans.extend(Rc::into_inner(arg));
+                }
+            }
+        };
+        let mut orphans = Vec::new();
+        detach(self, &mut orphans);
+        while let Some(mut expr) = orphans.pop() {
+            detach(&mut expr, &mut orphans);
+        }
+    }
+}
+
+/// A sink which builds an [`Expr`] for each instruction pushed into it, with
+/// every use of an instruction sharing its node, and finishes with the
+/// program's result.
+#[derive(Default)]
+pub struct ExprBuilder {
+    nodes: Vec<Rc<Expr>>,
+}
+
+impl ExprBuilder {
+    fn push(&mut self, expr: Expr) -> usize {
+        self.nodes.push(Rc::new(expr));
+        self.nodes.len() - 1
+    }
+
+    fn get<const N: usize>(&self, args: [usize; N]) -> [Rc<Expr>; N] {
+        args.map(|arg| self.nodes[arg].clone())
+    }
+}
+
+impl InstSink for ExprBuilder {
+    type Idx = usize;
+    type Output = Rc<Expr>;
+
+    fn push_const(&mut self, value: Const) -> Self::Idx {
+        self.push(Expr::Const(value))
+    }
+
+    fn push_var(&mut self, var: Var) -> Self::Idx {
+        self.push(Expr::Var(var))
+    }
+
+    fn push_unop(&mut self, op: UnOp, arg: Self::Idx) -> Self::Idx {
+        let [arg] = self.get([arg]);
+        self.push(Expr::UnOp(op, arg))
+    }
+
+    fn push_binop(&mut self, op: BinOp, args: [Self::Idx; 2]) -> Self::Idx {
+        self.push(Expr::BinOp(op, self.get(args)))
+    }
+
+    fn push_fma(&mut self, args: [Self::Idx; 3]) -> Self::Idx {
+        self.push(Expr::Fma(self.get(args)))
+    }
+
+    fn push_ternop(&mut self, op: TernOp, args: [Self::Idx; 3]) -> Self::Idx {
+        self.push(Expr::TernOp(op, self.get(args)))
+    }
+
+    fn push_load(&mut self, vars: VarSet, loc: Location) -> Self::Idx {
+        self.push(Expr::Load(vars, loc))
+    }
+
+    fn finish(mut self, last: Self::Idx) -> Self::Output {
+        self.nodes.swap_remove(last)
+    }
+}
+
+/// Feed `root` into another sink as instructions, pushing each node once
+/// however many times it's used. Nodes come out in the order the `reorder`
+/// pass puts instructions in, each just before its first use, so a program
+/// that went through [`ExprBuilder`] comes back the way `reorder` would
+/// leave it: with the same instructions, less any the result doesn't depend
+/// on. Deep trees are walked with an explicit stack rather than recursion.
+pub fn replay<S: InstSink>(root: &Expr, mut sink: S) -> S::Output {
+    let mut pushed: HashMap<*const Expr, S::Idx> = HashMap::new();
+    let mut stack = vec![root];
+    while let Some(&expr) = stack.last() {
+        if pushed.contains_key(&ptr::from_ref(expr)) {
+            stack.pop();
+            continue;
+        }
+        let mut waiting = false;
+        for arg in expr.args().iter().rev() {
+            if !pushed.contains_key(&Rc::as_ptr(arg)) {
+                stack.push(arg);
+                waiting = true;
+            }
+        }
+        if waiting {
+            continue;
+        }
+
+        let arg = |arg: &Rc<Expr>| pushed[&Rc::as_ptr(arg)];
+        let idx = match expr {
+            Expr::Const(value) => sink.push_const(*value),
+            Expr::Var(var) => sink.push_var(*var),
+            Expr::UnOp(op, a) => sink.push_unop(*op, arg(a)),
+            Expr::BinOp(op, args) => sink.push_binop(*op, args.each_ref().map(arg)),
+            Expr::Fma(args) => sink.push_fma(args.each_ref().map(arg)),
+            Expr::TernOp(op, args) => sink.push_ternop(*op, args.each_ref().map(arg)),
+            Expr::Load(vars, loc) => sink.push_load(*vars, *loc),
+        };
+        pushed.insert(ptr::from_ref(expr), idx);
+        stack.pop();
+    }
+    sink.finish(pushed[&ptr::from_ref(root)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corpus::SHAPES;
+    use crate::ir::count::Count;
+    use crate::ir::reorder::reorder;
+    use crate::ir::{Insts, io::read};
+
+    #[test]
+    fn test_round_trip() {
+        for shape in SHAPES {
+            let mut insts = shape.insts();
+            let expr = insts.replay(ExprBuilder::default());
+            let replayed = replay(&expr, Insts::default());
+            reorder(&mut insts);
+            assert_eq!(replayed.pool, insts.pool, "{}", shape.name);
+        }
+
+        // x is one node however many places use it
+        let text = "x var-x\na mul x x\nb add a x\nc sub b x\n";
+        let expr = read(text.as_bytes(), ExprBuilder::default()).unwrap();
+        let Expr::BinOp(BinOp::Sub, [b, x]) = &*expr else {
+            panic!("{expr:?}");
+        };
+        assert!(matches!(**x, Expr::Var(Var::X)));
+        assert_eq!(Rc::strong_count(x), 4);
+        assert_eq!(replay(b, Insts::default()).pool.len(), 3);
+
+        // far deeper than recursion would survive, to build, replay, or drop
+        let mut builder = ExprBuilder::default();
+        let mut last = builder.push_var(Var::X);
+        for _ in 0..100_000 {
+            last = builder.push_unop(UnOp::Neg, last);
+        }
+        let expr = builder.finish(last);
+        assert_eq!(replay(&expr, Count::default()).total(), 100_001);
+    }
+}
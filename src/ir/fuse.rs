@@ -0,0 +1,174 @@
+use super::reassociate::find_fenced;
+use super::{BinOp, Const, Inst, InstIdx, InstSink, VarSet};
+
+/// Fuse each `mul` whose only use is an `add` or `sub` into that instruction,
+/// as one `fma`, which does both with a single rounding. That changes the
+/// results slightly, so this only runs when asked for.
+///
+/// A subtraction can only be fused if whatever it negates is a constant,
+/// since otherwise it'd take a `neg` to save a `mul`. Products are also left
+/// alone if the other operand depends on variables they don't, where
+/// memoization would compute the `mul` once per row or column but the `fma`
+/// at every point. Where both operands could be fused, the first one is,
+/// like [`Precision::Fma`](super::interp::Precision::Fma). Nothing under a
+/// `barrier` is fused, since it's computed in the order it was written.
+pub fn fuse<S: InstSink>(insts: &[Inst], mut sink: S) -> S::Output {
+    let _span = tracing::info_span!("fuse", insts = insts.len()).entered();
+    let mut uses = vec![0u32; insts.len()];
+    let mut vars = vec![VarSet::default(); insts.len()];
+    for (idx, inst) in insts.iter().enumerate() {
+        vars[idx] = match *inst {
+            Inst::Var { var } => var.into(),
+            Inst::Load { vars, .. } => vars,
+            _ => VarSet::default(),
+        };
+        for arg in inst.args() {
+            uses[arg.idx()] += 1;
+            vars[idx] = vars[idx] | vars[arg.idx()];
+        }
+    }
+    let constant = |idx: usize| match insts[idx] {
+        Inst::Const { value } => Some(value),
+        _ => None,
+    };
+
+    let fenced = find_fenced(insts);
+
+    // For each instruction, the product it absorbs and the addend to go
+    // with it, each possibly negated.
+    let mut plans: Vec<Option<Plan>> = vec![None; insts.len()];
+    let mut fused = vec![false; insts.len()];
+    for (idx, inst) in insts.iter().enumerate() {
+        let Inst::BinOp {
+            op: op @ (BinOp::Add | BinOp::Sub),
+            args: [a, b],
+        } = *inst
+        else {
+            continue;
+        };
+        if fenced[idx] {
+            continue;
+        }
+        for (product, addend) in [(a, b), (b, a)] {
+            let Inst::BinOp {
+                op: BinOp::Mul,
+                args: [x, y],
+            } = insts[product.idx()]
+            else {
+                continue;
+            };
+            if uses[product.idx()] != 1 || vars[product.idx()] != vars[idx] {
+                continue;
+            }
+            let plan = match (op, product == a) {
+                (BinOp::Add, _) => Plan {
+                    factors: [x, y].map(|arg| (arg.idx(), None)),
+                    addend: (addend.idx(), None),
+                },
+                // a product minus a constant
+                (_, true) => {
+                    let Some(value) = constant(addend.idx()) else {
+                        continue;
+                    };
+                    Plan {
+                        factors: [x, y].map(|arg| (arg.idx(), None)),
+                        addend: (addend.idx(), Some(negate(value))),
+                    }
+                }
+                // something minus a product with a constant factor
+                (_, false) => {
+                    let (k, value, other) = match (constant(x.idx()), constant(y.idx())) {
+                        (Some(value), _) => (x, value, y),
+                        (None, Some(value)) => (y, value, x),
+                        (None, None) => continue,
+                    };
+                    Plan {
+                        factors: [(k.idx(), Some(negate(value))), (other.idx(), None)],
+                        addend: (addend.idx(), None),
+                    }
+                }
+            };
+            plans[idx] = Some(plan);
+            fused[product.idx()] = true;
+            break;
+        }
+    }
+
+    let mut map: Vec<Option<S::Idx>> = Vec::with_capacity(insts.len());
+    for (idx, inst) in insts.iter().enumerate() {
+        if fused[idx] {
+            map.push(None);
+            continue;
+        }
+        let arg = |map: &[Option<S::Idx>], sink: &mut S, (arg, value): (usize, Option<Const>)| {
+            match value {
+                Some(value) => sink.push_const(value),
+                None => map[arg].unwrap(),
+            }
+        };
+        let new = if let Some(Plan { factors, addend }) = plans[idx] {
+            let [x, y] = factors.map(|factor| arg(&map, &mut sink, factor));
+            let addend = arg(&map, &mut sink, addend);
+            sink.push_fma([x, y, addend])
+        } else {
+            let arg = |arg: InstIdx| map[arg.idx()].unwrap();
+            match *inst {
+                Inst::Const { value } => sink.push_const(value),
+                Inst::Var { var } => sink.push_var(var),
+                Inst::Load { vars, loc } => sink.push_load(vars, loc),
+                Inst::UnOp { op, arg: a } => sink.push_unop(op, arg(a)),
+                Inst::BinOp { op, args } => sink.push_binop(op, args.map(arg)),
+                Inst::Fma { args } => sink.push_fma(args.map(arg)),
+                Inst::TernOp { op, args } => sink.push_ternop(op, args.map(arg)),
+            }
+        };
+        map.push(Some(new));
+    }
+    let last = map.last().copied().flatten().unwrap();
+    sink.finish(last)
+}
+
+// An operand as an index into the original program, or a replacement
+// constant, which is the negation of what's there.
+#[derive(Clone, Copy)]
+struct Plan {
+    factors: [(usize, Option<Const>); 2],
+    addend: (usize, Option<Const>),
+}
+
+fn negate(value: Const) -> Const {
+    Const::new(-value.value())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::Insts;
+    use crate::ir::io::{read, write};
+
+    #[test]
+    fn test_fuse() {
+        let text = "x var-x\ny var-y\nk const 3\nc const 0.25\n\
+            m1 mul x y\ns1 add x m1\nm2 mul s1 k\ns2 sub m2 c\nm3 mul s2 k\ns3 sub s1 m3\n\
+            m4 mul x s3\ntwice add m4 m4\nm5 mul x k\nout add m5 twice\n";
+        let insts = read(text.as_bytes(), Insts::default()).unwrap();
+        let fused = fuse(&insts.pool, Insts::default());
+        let mut out = Vec::new();
+        write(&mut out, fused.pool.iter().cloned()).unwrap();
+        // `m4` has two uses, and `m5` only depends on x, so neither is fused
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "v0 var-x\nv1 var-y\nv2 const 3\nv3 const 0.25\nv4 fma v0 v1 v0\n\
+            v5 const -0.25\nv6 fma v4 v2 v5\nv7 const -3\nv8 fma v7 v6 v4\n\
+            v9 mul v0 v8\nv10 add v9 v9\nv11 mul v0 v2\nv12 add v11 v10\n"
+        );
+    }
+
+    #[test]
+    fn test_fenced() {
+        let text = "x var-x\ny var-y\nm mul x y\ns add m x\nout barrier s\n";
+        let insts = read(text.as_bytes(), Insts::default()).unwrap();
+        let fused = fuse(&insts.pool, Insts::default());
+        assert_eq!(fused.pool, insts.pool);
+    }
+}
@@ -1,56 +1,769 @@
+use clap::ValueEnum;
 use std::io;
 
-use super::{BinOp, Inst, Insts, UnOp};
+use crate::cancel::{CancelToken, Cancelled};
+use crate::image::{Image, Orientation, is_inside};
 
-pub fn interp(mut f: impl io::Write, insts: &Insts, size: u16) -> io::Result<()> {
-    // https://netpbm.sourceforge.net/doc/pbm.html
-    writeln!(f, "P4 {size} {size}")?;
+use super::io::{ConstFormat, write_inst};
+use super::{BinOp, Inst, InstIdx, Insts, TernOp, UnOp, Var};
 
-    let mut row = vec![0u8; (usize::from(size) + 7) / 8];
+pub fn interp(f: impl io::Write, insts: &Insts, size: u16) -> io::Result<()> {
+    interp_image(insts, size).write_pbm(f)
+}
+
+/// Evaluate the program at every pixel of a `size`×`size` image, covering
+/// coordinates from -1 to 1 on both axes.
+pub fn interp_image(insts: &Insts, size: u16) -> Image {
+    interp_image_cancellable(insts, size, &CancelToken::default()).unwrap()
+}
+
+/// Like [`interp_image`], but gives up between rows if `cancel` is triggered.
+pub fn interp_image_cancellable(
+    insts: &Insts,
+    size: u16,
+    cancel: &CancelToken,
+) -> Result<Image, Cancelled> {
+    let mut pixels = Vec::with_capacity(usize::from(size) * usize::from(size));
+    interp_rows(insts, size, Orientation::YUp, cancel, |row| {
+        pixels.extend_from_slice(row);
+        Ok::<_, Cancelled>(())
+    })?;
+    Ok(Image { size, pixels })
+}
+
+/// Like [`interp_image_cancellable`], but hands each row to `emit` as soon as
+/// it's finished instead of collecting them into an image, so that huge
+/// images can be written out without ever holding more than one row. Any
+/// error from `emit` stops the render and is returned.
+pub fn interp_rows<E: From<Cancelled>>(
+    insts: &Insts,
+    size: u16,
+    orientation: Orientation,
+    cancel: &CancelToken,
+    mut emit: impl FnMut(&[f32]) -> Result<(), E>,
+) -> Result<(), E> {
+    let _span = tracing::info_span!("interp", size).entered();
+    let mut row = Vec::with_capacity(usize::from(size));
+    let mut regs = vec![0f32; insts.pool.len()];
+    let schedule = RowSchedule::new(insts);
+
+    for_each_pixel(size, orientation, |x, vars| {
+        if x == 0 {
+            cancel.check()?;
+        }
+        for &idx in schedule.at(x) {
+            regs[idx] = eval(&insts.pool[idx], &regs, vars);
+        }
+        row.push(*regs.last().unwrap());
+        if x == size - 1 {
+            emit(&row)?;
+            row.clear();
+        }
+        Ok(())
+    })
+}
+
+/// Evaluate the program at `samples`×`samples` evenly spaced points within
+/// each pixel, and record the fraction of them which are inside the shape.
+/// This antialiases edges even for programs which aren't distance fields.
+pub fn interp_coverage(insts: &Insts, size: u16, samples: u16) -> Image {
+    let scale = 2.0 / f32::from(size - 1);
+    let offsets: Vec<f32> = (0..samples)
+        .map(|k| ((f32::from(k) + 0.5) / f32::from(samples) - 0.5) * scale)
+        .collect();
+    let total = f32::from(samples) * f32::from(samples);
+
+    let mut pixels = Vec::with_capacity(usize::from(size) * usize::from(size));
+    let mut regs = vec![0f32; insts.pool.len()];
+    for_each_pixel(size, Orientation::YUp, |_, vars| {
+        let mut inside = 0u32;
+        for dy in offsets.iter() {
+            for dx in offsets.iter() {
+                if is_inside(eval_point(insts, &mut regs, &[vars[0] + dx, vars[1] + dy])) {
+                    inside += 1;
+                }
+            }
+        }
+        pixels.push(inside as f32 / total);
+        Ok::<_, std::convert::Infallible>(())
+    })
+    .unwrap();
+
+    Image { size, pixels }
+}
+
+/// Which instructions influenced the result at any pixel of a
+/// `size`×`size` image, following only the operand each `min` and `max`
+/// picked at that pixel, as [`explain`] does with `winners_only`. The rest
+/// could be dropped without changing a render at this resolution, though
+/// possibly at others. This evaluates the program and walks back from the
+/// result at every pixel, so it takes a while on big programs and images.
+pub fn influence(insts: &Insts, size: u16) -> Vec<bool> {
+    let _span = tracing::info_span!("influence", size).entered();
+    let mut influenced = vec![false; insts.pool.len()];
+    let mut feeds = vec![false; insts.pool.len()];
+    let mut regs = vec![0f32; insts.pool.len()];
+    let schedule = RowSchedule::new(insts);
+    for_each_pixel(size, Orientation::YUp, |x, vars| {
+        for &idx in schedule.at(x) {
+            regs[idx] = eval(&insts.pool[idx], &regs, vars);
+        }
+        feeds.fill(false);
+        *feeds.last_mut().unwrap() = true;
+        for (idx, inst) in insts.pool.iter().enumerate().rev() {
+            if feeds[idx] {
+                influenced[idx] = true;
+                for arg in picked(inst, &regs) {
+                    feeds[arg.idx()] = true;
+                }
+            }
+        }
+        Ok::<_, std::convert::Infallible>(())
+    })
+    .unwrap();
+    influenced
+}
+
+// The operands of `inst` which its value came from: just the one a `min` or
+// `max` picked, breaking ties toward the first like `interp_ids`, and every
+// operand of anything else.
+fn picked<'a>(inst: &'a Inst, regs: &[f32]) -> &'a [InstIdx] {
+    match inst {
+        Inst::BinOp {
+            op: op @ (BinOp::Min | BinOp::Max),
+            args,
+        } => {
+            let [a, b] = args.map(|arg| regs[arg.idx()]);
+            let pick_b = if *op == BinOp::Min { b < a } else { b > a };
+            std::slice::from_ref(&args[usize::from(pick_b)])
+        }
+        Inst::TernOp {
+            op: TernOp::Clamp,
+            args,
+        } => std::slice::from_ref(&args[clamp_pick(args.map(|arg| regs[arg.idx()]))]),
+        _ => inst.args(),
+    }
+}
+
+// Which operand a `clamp` picked, as the `max` and then the `min` it stands
+// for would.
+fn clamp_pick([x, lo, hi]: [f32; 3]) -> usize {
+    let (max, picked) = if lo > x { (lo, 1) } else { (x, 0) };
+    if hi < max { 2 } else { picked }
+}
+
+/// How [`interp_precision`] rounds intermediate values, for telling apart
+/// differences caused by rounding from differences in which operations run.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum Precision {
+    /// Plain `f32` arithmetic, as [`interp_image`] does
+    #[default]
+    F32,
+    /// Compute each instruction in `f64` and round it to `f32` before it's
+    /// used. Arithmetic, `sqrt`, `min` and `max`, and the rounding
+    /// operations are correctly rounded, and `f64` has more than twice the
+    /// bits of `f32`, so for those this gives exactly the same results as
+    /// `f32`; any difference from another engine is down to the operations
+    /// it ran, not how it rounded them. `exp`, `ln`, `sin`, and `cos` aren't
+    /// correctly rounded in either precision, so they may differ by an ulp.
+    F64Rounded,
+    /// Compute everything in `f64` and only round the final result, to see
+    /// how far `f32` rounding alone moves the field
+    F64,
+    /// Round like `f32`, but fuse each `add` or `sub` of a `mul` into one
+    /// fused multiply-add with a single rounding, as compilers do when they
+    /// contract floating-point expressions. Where both operands are products,
+    /// the first one is fused.
+    Fma,
+}
+
+/// Like [`interp_image`], but rounding intermediate values according to
+/// `precision`. Every instruction runs at every pixel, so this is slower.
+pub fn interp_precision(insts: &Insts, size: u16, precision: Precision) -> Image {
+    let mut pixels = Vec::with_capacity(usize::from(size) * usize::from(size));
+    let mut regs = vec![0f64; insts.pool.len()];
+    for_each_pixel(size, Orientation::YUp, |_, vars| {
+        for (idx, inst) in insts.pool.iter().enumerate() {
+            regs[idx] = eval_precision(insts, inst, &regs, vars, precision);
+        }
+        pixels.push(*regs.last().unwrap() as f32);
+        Ok::<_, std::convert::Infallible>(())
+    })
+    .unwrap();
+    Image { size, pixels }
+}
+
+fn eval_precision(
+    insts: &Insts,
+    inst: &Inst,
+    regs: &[f64],
+    vars: &[f32],
+    precision: Precision,
+) -> f64 {
+    let round = |value: f64| match precision {
+        Precision::F64 => value,
+        _ => f64::from(value as f32),
+    };
+    // the operands of a product that `precision` fuses into its user
+    let product = |arg: InstIdx| match insts.pool[arg.idx()] {
+        Inst::BinOp {
+            op: BinOp::Mul,
+            args: [a, b],
+        } if precision == Precision::Fma => Some([a, b].map(|arg| regs[arg.idx()] as f32)),
+        _ => None,
+    };
+    match *inst {
+        Inst::Const { value } => value.value().into(),
+        Inst::Var { var } => vars[var as usize].into(),
+        Inst::UnOp { op, arg } => {
+            let arg = regs[arg.idx()];
+            round(match op {
+                UnOp::Neg => -arg,
+                UnOp::Square => arg * arg,
+                UnOp::Sqrt => arg.sqrt(),
+                UnOp::Abs => arg.abs(),
+                UnOp::Exp => arg.exp(),
+                UnOp::Ln => arg.ln(),
+                UnOp::Sin => arg.sin(),
+                UnOp::Cos => arg.cos(),
+                UnOp::Floor => arg.floor(),
+                UnOp::Ceil => arg.ceil(),
+                UnOp::Round => arg.round_ties_even(),
+                UnOp::Recip => 1.0 / arg,
+                UnOp::Rsqrt => match precision {
+                    Precision::F64 => 1.0 / arg.sqrt(),
+                    // rounded twice in `f32`, like `mod`
+                    _ => super::rsqrt(arg as f32).into(),
+                },
+                UnOp::PowI(n) => match precision {
+                    Precision::F64 => arg.powi(n),
+                    // a chain of roundings in `f32`, like `rsqrt`
+                    _ => super::powi(arg as f32, n).into(),
+                },
+                UnOp::Barrier => arg,
+            })
+        }
+        Inst::BinOp {
+            op: op @ (BinOp::Add | BinOp::Sub),
+            args: [a, b],
+        } if product(a).is_some() || product(b).is_some() => {
+            let sign = if op == BinOp::Sub { -1.0 } else { 1.0 };
+            let [va, vb] = [a, b].map(|arg| regs[arg.idx()] as f32);
+            let fused = match (product(a), product(b)) {
+                (Some([x, y]), _) => x.mul_add(y, sign * vb),
+                (None, Some([x, y])) => (sign * x).mul_add(y, va),
+                (None, None) => unreachable!(),
+            };
+            fused.into()
+        }
+        Inst::BinOp { op, args: [a, b] } => {
+            let a = regs[a.idx()];
+            let b = regs[b.idx()];
+            round(match op {
+                BinOp::Add => a + b,
+                BinOp::Sub => a - b,
+                BinOp::Mul => a * b,
+                BinOp::Min => a.min(b),
+                BinOp::Max => a.max(b),
+                BinOp::Mod => match precision {
+                    Precision::F64 => a - b * (a / b).floor(),
+                    // several roundings in `f32`, which one rounding of the
+                    // `f64` result wouldn't match
+                    _ => super::modulo(a as f32, b as f32).into(),
+                },
+            })
+        }
+        Inst::Fma { args } => {
+            let [a, b, c] = args.map(|arg| regs[arg.idx()]);
+            match precision {
+                Precision::F64 => a * b + c,
+                // already rounded once, which double rounding through f64
+                // wouldn't be
+                _ => (a as f32).mul_add(b as f32, c as f32).into(),
+            }
+        }
+        Inst::TernOp { op, args } => {
+            let [a, b, c] = args.map(|arg| regs[arg.idx()]);
+            match (op, precision) {
+                (TernOp::Clamp, _) => a.max(b).min(c),
+                (TernOp::Lerp, Precision::F64) => a + (b - a) * c,
+                // rounded at every step in `f32`, like `mod`
+                (TernOp::Lerp, _) => op.eval(args.map(|arg| regs[arg.idx()] as f32)).into(),
+            }
+        }
+        Inst::Load { .. } => unimplemented!("load instruction in interpreter"),
+    }
+}
+
+/// Render an image identifying which leaf of the min/max tree determined the
+/// result at each pixel. Every `min` and `max` instruction passes along the ID
+/// of whichever operand it picked, as do instructions like `neg` which don't
+/// change which branch is active; any other instruction is a leaf, identified
+/// by its index in `insts`. The IDs are written as a 16-bit PGM image.
+pub fn interp_ids(
+    mut f: impl io::Write,
+    insts: &Insts,
+    size: u16,
+    orientation: Orientation,
+) -> io::Result<()> {
+    // https://netpbm.sourceforge.net/doc/pgm.html
+    writeln!(f, "P5 {size} {size} {}", u16::MAX)?;
+
+    let mut row = Vec::with_capacity(usize::from(size) * 2);
     let mut regs = vec![0f32; insts.pool.len()];
+    let mut ids = vec![0u16; insts.pool.len()];
+    let schedule = RowSchedule::new(insts);
+
+    for_each_pixel(size, orientation, |x, vars| {
+        for &idx in schedule.at(x) {
+            let inst = &insts.pool[idx];
+            regs[idx] = eval(inst, &regs, vars);
+            ids[idx] = match *inst {
+                Inst::UnOp {
+                    op: UnOp::Neg | UnOp::Abs | UnOp::Barrier,
+                    arg,
+                } => ids[arg.idx()],
+                Inst::BinOp {
+                    op: op @ (BinOp::Min | BinOp::Max),
+                    args: [a, b],
+                } => {
+                    let (va, vb) = (regs[a.idx()], regs[b.idx()]);
+                    let pick_b = if op == BinOp::Min { vb < va } else { vb > va };
+                    ids[if pick_b { b } else { a }.idx()]
+                }
+                Inst::TernOp {
+                    op: TernOp::Clamp,
+                    args,
+                } => ids[args[clamp_pick(args.map(|arg| regs[arg.idx()]))].idx()],
+                _ => idx.try_into().unwrap(),
+            };
+        }
+
+        row.extend_from_slice(&ids.last().unwrap().to_be_bytes());
+
+        if x == size - 1 {
+            f.write_all(&row)?;
+            row.clear();
+        }
+        Ok(())
+    })
+}
+
+/// Which instructions to evaluate at each pixel of a row. Instructions which
+/// don't depend on `x` have the same value across the whole row, so after the
+/// first pixel, their values (and IDs) from earlier in the row are still valid.
+struct RowSchedule {
+    all: Vec<usize>,
+    varying: Vec<usize>,
+}
+
+impl RowSchedule {
+    fn new(insts: &Insts) -> Self {
+        let mut depends_on_x = Vec::with_capacity(insts.pool.len());
+        for inst in insts.pool.iter() {
+            depends_on_x.push(match *inst {
+                Inst::Var { var } => var == Var::X,
+                Inst::Load { vars, .. } => vars.contains(Var::X),
+                _ => inst.args().iter().any(|arg| depends_on_x[arg.idx()]),
+            });
+        }
+        RowSchedule {
+            all: (0..insts.pool.len()).collect(),
+            varying: (0..insts.pool.len())
+                .filter(|&idx| depends_on_x[idx])
+                .collect(),
+        }
+    }
+
+    fn at(&self, x: u16) -> &[usize] {
+        if x == 0 { &self.all } else { &self.varying }
+    }
+}
+
+fn for_each_pixel<E>(
+    size: u16,
+    orientation: Orientation,
+    mut pixel: impl FnMut(u16, &[f32]) -> Result<(), E>,
+) -> Result<(), E> {
     let mut vars = [0f32; 2];
     let scale = 2.0 / f32::from(size - 1);
 
-    for y in (0..size).rev() {
-        vars[1] = f32::from(y) * scale - 1.0;
+    for row in 0..size {
+        vars[1] = orientation.row_y(row, size);
         for x in 0..size {
             vars[0] = f32::from(x) * scale - 1.0;
+            pixel(x, &vars)?;
+        }
+    }
 
-            for (idx, inst) in insts.pool.iter().enumerate() {
-                regs[idx] = match *inst {
-                    Inst::Const { value } => value.value(),
-                    Inst::Var { var } => vars[var as usize],
-                    Inst::UnOp { op, arg } => {
-                        let arg = regs[arg.idx()];
-                        match op {
-                            UnOp::Neg => -arg,
-                            UnOp::Square => arg * arg,
-                            UnOp::Sqrt => arg.sqrt(),
-                        }
-                    }
-                    Inst::BinOp { op, args: [a, b] } => {
-                        let a = regs[a.idx()];
-                        let b = regs[b.idx()];
-                        match op {
-                            BinOp::Add => a + b,
-                            BinOp::Sub => a - b,
-                            BinOp::Mul => a * b,
-                            BinOp::Min => a.min(b),
-                            BinOp::Max => a.max(b),
-                        }
-                    }
-                    Inst::Load { .. } => unimplemented!("load instruction in interpreter"),
-                };
+    Ok(())
+}
+
+/// Evaluate the whole program at one point, returning the value of the last
+/// instruction. `regs` must have room for every instruction in `insts`.
+pub fn eval_point(insts: &Insts, regs: &mut [f32], vars: &[f32]) -> f32 {
+    for (idx, inst) in insts.pool.iter().enumerate() {
+        regs[idx] = eval(inst, regs, vars);
+    }
+    regs[insts.pool.len() - 1]
+}
+
+/// The coordinates [`interp_image`] and friends evaluate at for pixel `col`
+/// of row `row` in a `size`×`size` image.
+pub fn pixel_point(size: u16, orientation: Orientation, col: u16, row: u16) -> [f32; 2] {
+    let scale = 2.0 / f32::from(size - 1);
+    [f32::from(col) * scale - 1.0, orientation.row_y(row, size)]
+}
+
+/// Every intermediate value from evaluating a program at one point, for
+/// working out why a pixel came out the way it did.
+pub struct Explanation {
+    /// The value of each instruction, indexed like the program's `pool`.
+    pub values: Vec<f32>,
+    /// Which instructions feed the result, indexed the same way.
+    pub feeds: Vec<bool>,
+    /// Whether only the operand each `min` and `max` picked counts as
+    /// feeding it, as in [`interp_ids`], rather than both.
+    pub winners_only: bool,
+}
+
+/// Evaluate the program at `vars`, keeping the value of every instruction
+/// and noting which of them the result depends on.
+pub fn explain(insts: &Insts, vars: &[f32], winners_only: bool) -> Explanation {
+    let mut values = vec![0f32; insts.pool.len()];
+    eval_point(insts, &mut values, vars);
+    let mut explanation = Explanation {
+        values,
+        feeds: vec![false; insts.pool.len()],
+        winners_only,
+    };
+    if let Some(last) = explanation.feeds.last_mut() {
+        *last = true;
+    }
+    for (idx, inst) in insts.pool.iter().enumerate().rev() {
+        if explanation.feeds[idx] {
+            for arg in explanation.args(inst) {
+                explanation.feeds[arg.idx()] = true;
             }
+        }
+    }
+    explanation
+}
 
-            if regs.last().unwrap().is_sign_positive() {
-                row[usize::from(x >> 3)] |= 0x80 >> (x & 7);
+impl Explanation {
+    /// The operands of `inst` which feed it: all of them, or with
+    /// `winners_only`, just the one a `min` or `max` picked.
+    pub fn args<'a>(&self, inst: &'a Inst) -> &'a [InstIdx] {
+        if self.winners_only {
+            picked(inst, &self.values)
+        } else {
+            inst.args()
+        }
+    }
+
+    /// Write the tree of values feeding the result, one instruction per line
+    /// and indented under whatever uses it. An instruction reached again is
+    /// only named the second time, and with `winners_only`, the operand each
+    /// `min` or `max` passed over gets its value but not its own operands.
+    pub fn write(&self, mut f: impl io::Write, insts: &Insts) -> io::Result<()> {
+        let mut written = vec![false; insts.pool.len()];
+        // instruction, depth, and whether it was passed over
+        let mut stack: Vec<(usize, usize, bool)> = Vec::new();
+        if !insts.pool.is_empty() {
+            stack.push((insts.pool.len() - 1, 0, false));
+        }
+        while let Some((idx, depth, lost)) = stack.pop() {
+            let inst = &insts.pool[idx];
+            write!(f, "{:1$}", "", depth * 2)?;
+            let value = self.values[idx];
+            if lost {
+                writeln!(f, "v{idx} = {value} (not picked)")?;
+                continue;
+            }
+            if written[idx] {
+                writeln!(f, "v{idx} = {value} (above)")?;
+                continue;
+            }
+            written[idx] = true;
+            write_inst(&mut f, idx, inst, ConstFormat::default())?;
+            writeln!(f, " = {value}")?;
+            let args = self.args(inst);
+            for &arg in inst.args().iter().rev() {
+                stack.push((arg.idx(), depth + 1, !args.contains(&arg)));
             }
         }
+        Ok(())
+    }
 
-        f.write_all(&row)?;
-        row.fill(0);
+    /// Write every instruction's evaluation as one line of JSON, for
+    /// notebooks and other tools that animate how values flow through the
+    /// program. Each line holds the index `point` of this probe point among
+    /// however many the caller traces, its coordinates `at`, the
+    /// instruction's `id` and `op`, the `args` it reads and their `inputs`,
+    /// its `output`, and whether it `feeds` the result. Values which JSON
+    /// can't represent, infinities and NaN, are written as `null`.
+    pub fn write_trace(
+        &self,
+        mut f: impl io::Write,
+        insts: &Insts,
+        point: usize,
+        vars: &[f32],
+    ) -> io::Result<()> {
+        let json = |value: f32| {
+            if value.is_finite() {
+                value.to_string()
+            } else {
+                "null".to_string()
+            }
+        };
+        let list = |values: &mut dyn Iterator<Item = String>| values.collect::<Vec<_>>().join(",");
+        let at = list(&mut vars.iter().map(|&var| json(var)));
+        for (idx, inst) in insts.pool.iter().enumerate() {
+            let op = match *inst {
+                Inst::Const { .. } => "const".to_string(),
+                Inst::Var { var } => format!("var-{}", var.name()),
+                Inst::UnOp { op, .. } => op.name().to_string(),
+                Inst::BinOp { op, .. } => op.name().to_string(),
+                Inst::Fma { .. } => "fma".to_string(),
+                Inst::TernOp { op, .. } => op.name().to_string(),
+                Inst::Load { .. } => "load".to_string(),
+            };
+            let args = list(&mut inst.args().iter().map(|arg| arg.idx().to_string()));
+            let inputs = list(&mut inst.args().iter().map(|arg| json(self.values[arg.idx()])));
+            writeln!(
+                f,
+                r#"{{"point":{point},"at":[{at}],"id":{idx},"op":"{op}","args":[{args}],"inputs":[{inputs}],"output":{},"feeds":{}}}"#,
+                json(self.values[idx]),
+                self.feeds[idx],
+            )?;
+        }
+        Ok(())
     }
+}
 
-    Ok(())
+fn eval(inst: &Inst, regs: &[f32], vars: &[f32]) -> f32 {
+    match *inst {
+        Inst::Const { value } => value.value(),
+        Inst::Var { var } => vars[var as usize],
+        Inst::UnOp { op, arg } => {
+            let arg = regs[arg.idx()];
+            match op {
+                UnOp::Neg => -arg,
+                UnOp::Square => arg * arg,
+                UnOp::Sqrt => arg.sqrt(),
+                UnOp::Abs => arg.abs(),
+                UnOp::Exp => arg.exp(),
+                UnOp::Ln => arg.ln(),
+                UnOp::Sin => arg.sin(),
+                UnOp::Cos => arg.cos(),
+                UnOp::Floor => arg.floor(),
+                UnOp::Ceil => arg.ceil(),
+                UnOp::Round => arg.round_ties_even(),
+                UnOp::Recip => 1.0 / arg,
+                UnOp::Rsqrt => super::rsqrt(arg),
+                UnOp::PowI(n) => super::powi(arg, n),
+                UnOp::Barrier => arg,
+            }
+        }
+        Inst::BinOp { op, args: [a, b] } => {
+            let a = regs[a.idx()];
+            let b = regs[b.idx()];
+            match op {
+                BinOp::Add => a + b,
+                BinOp::Sub => a - b,
+                BinOp::Mul => a * b,
+                BinOp::Min => a.min(b),
+                BinOp::Max => a.max(b),
+                BinOp::Mod => super::modulo(a, b),
+            }
+        }
+        Inst::Fma { args: [a, b, c] } => regs[a.idx()].mul_add(regs[b.idx()], regs[c.idx()]),
+        Inst::TernOp { op, args } => op.eval(args.map(|arg| regs[arg.idx()])),
+        Inst::Load { .. } => unimplemented!("load instruction in interpreter"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corpus::shape;
+    use crate::ir::io::read;
+    use crate::ir::simplify::Simplify;
+    use crate::ir::tee::Tee;
+
+    #[test]
+    fn test_row_reuse() {
+        // values that don't depend on x are reused along each row, which
+        // has to match evaluating everything at every pixel, however wide
+        // the rows are
+        let insts = read(crate::corpus::circles(3).as_bytes(), Insts::default()).unwrap();
+        for insts in [shape("ring").unwrap().insts(), insts] {
+            let mut regs = vec![0.0; insts.pool.len()];
+            for size in [2, 17, 32] {
+                for orientation in [Orientation::YUp, Orientation::YDown] {
+                    let mut pixels = Vec::new();
+                    interp_rows(&insts, size, orientation, &CancelToken::default(), |row| {
+                        pixels.extend_from_slice(row);
+                        Ok::<_, Cancelled>(())
+                    })
+                    .unwrap();
+                    assert_eq!(pixels.len(), usize::from(size) * usize::from(size));
+                    let expected = (0..size).flat_map(|row| (0..size).map(move |col| (row, col)));
+                    for ((row, col), value) in expected.zip(pixels) {
+                        let vars = pixel_point(size, orientation, col, row);
+                        let expected = eval_point(&insts, &mut regs, &vars);
+                        assert_eq!(value.to_bits(), expected.to_bits(), "{size} {row} {col}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_ids() {
+        // max(min(x, -y), -0.5), where `neg` keeps y's ID and the constant
+        // is the leaf that wins wherever the min is below it
+        let text = "x var-x\ny var-y\nn neg y\nm min x n\nc const -0.5\nout max m c\n";
+        let insts = read(text.as_bytes(), Insts::default()).unwrap();
+        let mut pgm = Vec::new();
+        interp_ids(&mut pgm, &insts, 5, Orientation::YUp).unwrap();
+        let header = b"P5 5 5 65535\n";
+        assert_eq!(&pgm[..header.len()], header);
+        let ids: Vec<u16> = pgm[header.len()..]
+            .chunks_exact(2)
+            .map(|id| u16::from_be_bytes([id[0], id[1]]))
+            .collect();
+        // ties go to the first operand
+        #[rustfmt::skip]
+        assert_eq!(ids, [
+            4, 4, 4, 4, 4,
+            4, 0, 1, 1, 1,
+            4, 0, 0, 1, 1,
+            4, 0, 0, 0, 1,
+            4, 0, 0, 0, 0,
+        ]);
+    }
+
+    #[test]
+    fn test_coverage() {
+        let insts = shape("circle").unwrap().insts();
+        let image = interp_image(&insts, 33);
+        let single = interp_coverage(&insts, 33, 1);
+        for (&value, &coverage) in image.pixels.iter().zip(single.pixels.iter()) {
+            assert_eq!(coverage, if is_inside(value) { 1.0 } else { 0.0 });
+        }
+
+        let coverage = interp_coverage(&insts, 33, 4);
+        assert_eq!(coverage.pixels[16 * 33 + 16], 1.0);
+        assert_eq!(coverage.pixels[0], 0.0);
+        assert!(coverage.pixels.iter().any(|&c| c > 0.0 && c < 1.0));
+    }
+
+    #[test]
+    fn test_contour_pixels() {
+        // Where x = -y this is +0.0, except at the origin, but simplify
+        // rewrites it to -(x + y), which is -0.0 there.
+        let text = "_0 var-x\n_1 var-y\n_2 neg _0\n_3 neg _1\n_4 add _2 _3\n";
+        let sink = Tee::new(Insts::default(), Simplify::new(Insts::default()));
+        let (insts, simplified) = read(text.as_bytes(), sink).unwrap();
+        let image = interp_image(&insts, 5);
+        let simple = interp_image(&simplified, 5);
+        for idx in [0, 1, 3, 4] {
+            assert!(image.pixels[idx * 6].is_sign_positive());
+            assert!(simple.pixels[idx * 6].is_sign_negative());
+        }
+        assert!(
+            image
+                .pixels
+                .iter()
+                .zip(&simple.pixels)
+                .all(|(&a, &b)| is_inside(a) && is_inside(b) || !is_inside(a) && !is_inside(b))
+        );
+        assert_eq!(
+            interp_coverage(&insts, 5, 1),
+            interp_coverage(&simplified, 5, 1)
+        );
+    }
+
+    #[test]
+    fn test_explain() {
+        // min(x, y * 2) + x at (0.5, 0.1)
+        let text = "x var-x\ny var-y\nc const 2\nd mul y c\ne min x d\nf add e x\n";
+        let insts = read(text.as_bytes(), Insts::default()).unwrap();
+        assert_eq!(pixel_point(5, Orientation::YUp, 3, 1), [0.5, 0.5]);
+        assert_eq!(pixel_point(5, Orientation::YDown, 3, 1), [0.5, -0.5]);
+
+        let explanation = explain(&insts, &[0.5, 0.1], false);
+        assert!(explanation.feeds.iter().all(|&feeds| feeds));
+        let mut text = Vec::new();
+        explanation.write(&mut text, &insts).unwrap();
+        assert_eq!(
+            String::from_utf8(text).unwrap(),
+            "v5 add v4 v0 = 0.7\n  v4 min v0 v3 = 0.2\n    v0 var-x = 0.5\n    \
+             v3 mul v1 v2 = 0.2\n      v1 var-y = 0.1\n      v2 const 2 = 2\n  \
+             v0 = 0.5 (above)\n"
+        );
+
+        let explanation = explain(&insts, &[0.5, 0.1], true);
+        assert!(explanation.feeds.iter().all(|&feeds| feeds));
+        let explanation = explain(&insts, &[0.5, 0.3], true);
+        let mut text = Vec::new();
+        explanation.write(&mut text, &insts).unwrap();
+        assert_eq!(
+            String::from_utf8(text).unwrap(),
+            "v5 add v4 v0 = 1\n  v4 min v0 v3 = 0.5\n    v0 var-x = 0.5\n    \
+             v3 = 0.6 (not picked)\n  v0 = 0.5 (above)\n"
+        );
+        assert_eq!(explanation.feeds, [true, false, false, false, true, true]);
+
+        let mut trace = Vec::new();
+        explanation
+            .write_trace(&mut trace, &insts, 1, &[0.5, 0.3])
+            .unwrap();
+        let trace = String::from_utf8(trace).unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(
+            lines[4],
+            r#"{"point":1,"at":[0.5,0.3],"id":4,"op":"min","args":[0,3],"inputs":[0.5,0.6],"output":0.5,"feeds":true}"#
+        );
+        assert!(lines[2].contains(r#""op":"const","args":[],"inputs":[],"output":2,"#));
+    }
+
+    #[test]
+    fn test_precision() {
+        let insts = shape("circle").unwrap().insts();
+        let expected = interp_image(&insts, 33);
+        assert_eq!(interp_precision(&insts, 33, Precision::F32), expected);
+        assert_eq!(
+            interp_precision(&insts, 33, Precision::F64Rounded),
+            expected
+        );
+
+        // x * 0.1 + y rounds twice without FMA, and once with it
+        let text = "x var-x\ny var-y\nc const 0.1\nm mul x c\na add m y\n";
+        let insts = read(text.as_bytes(), Insts::default()).unwrap();
+        let expected = interp_image(&insts, 33);
+        for precision in [Precision::F64, Precision::Fma] {
+            let image = interp_precision(&insts, 33, precision);
+            let diffs = || image.pixels.iter().zip(&expected.pixels);
+            assert!(diffs().any(|(a, b)| a != b), "{precision:?}");
+            assert!(diffs().all(|(a, b)| (a - b).abs() < 1e-6), "{precision:?}");
+        }
+    }
+
+    #[test]
+    fn test_influence() {
+        // the second circle is entirely inside the first, so it never wins
+        // the max, and neither do the instructions only it uses
+        let text = "x var-x\ny var-y\nx2 square x\ny2 square y\nd2 add x2 y2\n\
+            d sqrt d2\nr const 0.5\nbig sub r d\ns const 0.25\nsmall sub s d\n\
+            both max big small\n";
+        let insts = read(text.as_bytes(), Insts::default()).unwrap();
+        let influenced = influence(&insts, 33);
+        let never: Vec<usize> = (0..insts.pool.len())
+            .filter(|&idx| !influenced[idx])
+            .collect();
+        assert_eq!(never, [8, 9]);
+    }
 }
@@ -0,0 +1,227 @@
+use core::f64::consts::{FRAC_PI_2, PI, TAU};
+
+use super::{BinOp, Inst, Insts, TernOp, UnOp};
+
+/// A range of values which an expression might take over a region of space.
+///
+/// Each bound is computed with the same `f32` operations as evaluating a
+/// single point, and rounding is monotonic, so the value computed at any
+/// point inside the region is within these bounds. Where an operation might
+/// produce NaN, the result is widened to the whole number line instead.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Interval {
+    pub lo: f32,
+    pub hi: f32,
+}
+
+impl Interval {
+    pub const EVERYTHING: Interval = Interval {
+        lo: f32::NEG_INFINITY,
+        hi: f32::INFINITY,
+    };
+
+    pub fn new(lo: f32, hi: f32) -> Interval {
+        if lo.is_nan() || hi.is_nan() {
+            Interval::EVERYTHING
+        } else {
+            Interval { lo, hi }
+        }
+    }
+
+    pub fn point(value: f32) -> Interval {
+        Interval::new(value, value)
+    }
+
+    /// Every value in the interval is strictly positive, so every point in
+    /// the region is inside the shape.
+    pub fn is_positive(self) -> bool {
+        self.lo > 0.0
+    }
+
+    /// Every value in the interval is strictly negative, so every point in
+    /// the region is outside the shape.
+    pub fn is_negative(self) -> bool {
+        self.hi < 0.0
+    }
+
+    pub fn unop(self, op: UnOp) -> Interval {
+        let Interval { lo, hi } = self;
+        match op {
+            UnOp::Neg => Interval::new(-hi, -lo),
+            UnOp::Square if lo >= 0.0 => Interval::new(lo * lo, hi * hi),
+            UnOp::Square if hi <= 0.0 => Interval::new(hi * hi, lo * lo),
+            UnOp::Square => Interval::new(0.0, (lo * lo).max(hi * hi)),
+            UnOp::Sqrt if lo >= 0.0 => Interval::new(super::sqrt(lo), super::sqrt(hi)),
+            UnOp::Sqrt => Interval::EVERYTHING,
+            UnOp::Abs if lo >= 0.0 => self,
+            UnOp::Abs if hi <= 0.0 => Interval::new(-hi, -lo),
+            UnOp::Abs => Interval::new(0.0, hi.max(-lo)),
+            UnOp::Exp => Interval::new(super::exp(lo), super::exp(hi)),
+            UnOp::Ln if lo >= 0.0 => Interval::new(super::ln(lo), super::ln(hi)),
+            UnOp::Ln => Interval::EVERYTHING,
+            UnOp::Sin => self.periodic(super::sin, FRAC_PI_2),
+            UnOp::Cos => self.periodic(super::cos, 0.0),
+            UnOp::Floor => Interval::new(super::floor(lo), super::floor(hi)),
+            UnOp::Ceil => Interval::new(super::ceil(lo), super::ceil(hi)),
+            UnOp::Round => Interval::new(super::round(lo), super::round(hi)),
+            // either side of zero, where -0 would give -infinity
+            UnOp::Recip if lo > 0.0 || hi < 0.0 => Interval::new(1.0 / hi, 1.0 / lo),
+            UnOp::Recip => Interval::EVERYTHING,
+            UnOp::Rsqrt if lo > 0.0 => Interval::new(super::rsqrt(hi), super::rsqrt(lo)),
+            UnOp::Rsqrt => Interval::EVERYTHING,
+            UnOp::PowI(n) => self.powi(n),
+            UnOp::Barrier => self,
+        }
+    }
+
+    // Bounding each step of the chain `super::powi` rounds along bounds the
+    // whole. Each product treats its operands as unrelated, so the result
+    // may be looser than the true range, but never tighter.
+    fn powi(self, n: i32) -> Interval {
+        let power = n.unsigned_abs();
+        if power == 0 {
+            return Interval::point(1.0);
+        }
+        let mut result = self;
+        for bit in (0..power.ilog2()).rev() {
+            result = result.unop(UnOp::Square);
+            if power >> bit & 1 != 0 {
+                result = result.binop(BinOp::Mul, self);
+            }
+        }
+        if n < 0 {
+            result = result.unop(UnOp::Recip);
+        }
+        result
+    }
+
+    // `sin` and `cos` aren't monotonic, but they peak at 1 at `peak` plus
+    // every multiple of 2π, and bottom out at -1 halfway between, so between
+    // those they're between their values at the bounds. Whether a bound is
+    // past a peak is worked out in `f64`, where the bounds and the multiples
+    // of 2π near them are exact enough not to miss one.
+    fn periodic(self, f: fn(f32) -> f32, peak: f64) -> Interval {
+        let Interval { lo, hi } = self;
+        if !lo.is_finite() || !hi.is_finite() {
+            return Interval::EVERYTHING;
+        }
+        if lo == hi {
+            return Interval::point(f(lo));
+        }
+        let (lo64, hi64) = (f64::from(lo), f64::from(hi));
+        if hi64 - lo64 >= TAU {
+            return Interval::new(-1.0, 1.0);
+        }
+        let reaches = |offset: f64| {
+            let turns = (lo64 - offset) / TAU;
+            // round up, without `f64::ceil`, which `core` doesn't have
+            let whole = turns as i64 as f64;
+            let whole = if whole < turns { whole + 1.0 } else { whole };
+            offset + whole * TAU <= hi64
+        };
+        let (a, b) = (f(lo), f(hi));
+        Interval::new(
+            if reaches(peak + PI) { -1.0 } else { a.min(b) },
+            if reaches(peak) { 1.0 } else { a.max(b) },
+        )
+    }
+
+    pub fn binop(self, op: BinOp, other: Interval) -> Interval {
+        let (a, b) = (self, other);
+        match op {
+            BinOp::Add => Interval::new(a.lo + b.lo, a.hi + b.hi),
+            BinOp::Sub => Interval::new(a.lo - b.hi, a.hi - b.lo),
+            BinOp::Mul => {
+                let products = [a.lo * b.lo, a.lo * b.hi, a.hi * b.lo, a.hi * b.hi];
+                if products.iter().any(|p| p.is_nan()) {
+                    return Interval::EVERYTHING;
+                }
+                let lo = products.iter().copied().fold(f32::INFINITY, f32::min);
+                let hi = products.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+                Interval::new(lo, hi)
+            }
+            BinOp::Min => Interval::new(a.lo.min(b.lo), a.hi.min(b.hi)),
+            BinOp::Max => Interval::new(a.lo.max(b.lo), a.hi.max(b.hi)),
+            BinOp::Mod => a.modulo(b),
+        }
+    }
+
+    // Following the same steps as `super::modulo` bounds each one, which is
+    // tight while `a / b` stays between two integers. Past that, the
+    // operands are treated as unrelated, so the result is also clamped to
+    // where `modulo` always lands: from 0 to `b`, give or take the error of
+    // rounding `a / b` and `b * floor(a / b)`.
+    fn modulo(self, b: Interval) -> Interval {
+        let a = self;
+        if b.lo <= 0.0 && b.hi >= 0.0 {
+            return Interval::EVERYTHING;
+        }
+        let quotients = [a.lo / b.lo, a.lo / b.hi, a.hi / b.lo, a.hi / b.hi];
+        if quotients.iter().any(|q| !q.is_finite()) {
+            return Interval::EVERYTHING;
+        }
+        let lo = quotients.iter().copied().fold(f32::INFINITY, f32::min);
+        let hi = quotients.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let floors = Interval::new(lo, hi).unop(UnOp::Floor);
+        let steps = a.binop(BinOp::Sub, floors.binop(BinOp::Mul, b));
+        let error = (2.0 * a.lo.abs().max(a.hi.abs()) + b.lo.abs().max(b.hi.abs())) * f32::EPSILON;
+        Interval::new(
+            steps.lo.max(b.lo.min(0.0) - error),
+            steps.hi.min(b.hi.max(0.0) + error),
+        )
+    }
+
+    /// Bounds on `op` of `self`, `b`, and `c`, through the binary operations
+    /// it [expands](TernOp::expand) to.
+    pub fn ternop(self, op: TernOp, b: Interval, c: Interval) -> Interval {
+        match op {
+            TernOp::Clamp => self.binop(BinOp::Max, b).binop(BinOp::Min, c),
+            TernOp::Lerp => {
+                let step = b.binop(BinOp::Sub, self).binop(BinOp::Mul, c);
+                self.binop(BinOp::Add, step)
+            }
+        }
+    }
+
+    /// Bounds on `self * mul + add` computed with a single rounding. Rounding
+    /// the product first, as [`binop`](Self::binop) would, could land on the
+    /// wrong side of a value the fused operation rounds toward, so each
+    /// corner of the product is fused with the matching end of `add`.
+    pub fn fma(self, mul: Interval, add: Interval) -> Interval {
+        let corners = [
+            (self.lo, mul.lo),
+            (self.lo, mul.hi),
+            (self.hi, mul.lo),
+            (self.hi, mul.hi),
+        ];
+        let lo = corners.map(|(a, b)| super::mul_add(a, b, add.lo));
+        let hi = corners.map(|(a, b)| super::mul_add(a, b, add.hi));
+        if lo.iter().chain(&hi).any(|v| v.is_nan()) {
+            return Interval::EVERYTHING;
+        }
+        let lo = lo.into_iter().fold(f32::INFINITY, f32::min);
+        let hi = hi.into_iter().fold(f32::NEG_INFINITY, f32::max);
+        Interval::new(lo, hi)
+    }
+}
+
+/// Evaluate the whole program over a region, returning bounds on the value of
+/// the last instruction anywhere in that region. `regs` must have room for
+/// every instruction in `insts`.
+pub fn eval_interval(insts: &Insts, regs: &mut [Interval], vars: &[Interval]) -> Interval {
+    for (idx, inst) in insts.pool.iter().enumerate() {
+        regs[idx] = match *inst {
+            Inst::Const { value } => Interval::point(value.value()),
+            Inst::Var { var } => vars[var as usize],
+            Inst::UnOp { op, arg } => regs[arg.idx()].unop(op),
+            Inst::BinOp { op, args: [a, b] } => regs[a.idx()].binop(op, regs[b.idx()]),
+            Inst::Fma { args: [a, b, c] } => regs[a.idx()].fma(regs[b.idx()], regs[c.idx()]),
+            Inst::TernOp {
+                op,
+                args: [a, b, c],
+            } => regs[a.idx()].ternop(op, regs[b.idx()], regs[c.idx()]),
+            Inst::Load { .. } => unimplemented!("load instruction in interval evaluation"),
+        };
+    }
+    regs[insts.pool.len() - 1]
+}
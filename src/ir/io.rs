@@ -1,39 +1,326 @@
+use clap::ValueEnum;
 use std::collections::{HashMap, hash_map::Entry};
+use std::fmt;
+use std::fs::File;
 use std::io;
-use std::num::ParseFloatError;
+use std::num::{ParseFloatError, ParseIntError};
+use std::path::{Path, PathBuf};
 use thiserror::Error;
 
+use crate::cancel::{CancelToken, Cancelled};
+
+use super::interval::Interval;
 use super::memoize::Memoized;
-use super::{BinOp, Const, Inst, InstSink, UnOp, Var};
+use super::{BinOp, Const, Inst, InstIdx, InstSink, Location, TernOp, UnOp, Var, VarSet};
 
-pub fn write(mut f: impl io::Write, insts: impl IntoIterator<Item = Inst>) -> io::Result<()> {
-    for (idx, inst) in insts.into_iter().enumerate() {
-        write!(f, "v{} ", idx)?;
-        match inst {
-            Inst::Const { value } => writeln!(f, "const {value}")?,
-            Inst::Var { var } => writeln!(f, "var-{}", var.name())?,
-            Inst::UnOp { op, arg } => writeln!(f, "{} v{arg}", op.name())?,
-            Inst::BinOp { op, args: [a, b] } => writeln!(f, "{} v{a} v{b}", op.name())?,
-            Inst::Load { vars, loc } => writeln!(f, "load {vars:?} {loc}")?,
+/// How constants are spelled in textual output. Every format reads back as
+/// exactly the same value.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum ConstFormat {
+    /// Shortest decimal which round-trips, without an exponent
+    #[default]
+    Decimal,
+    /// Shortest decimal which round-trips, in scientific notation
+    Exponent,
+    /// Hexadecimal IEEE-754 bit pattern, such as `0x3f800000` for 1
+    Bits,
+}
+
+impl ConstFormat {
+    pub fn display(self, value: Const) -> impl fmt::Display {
+        fmt::from_fn(move |f| match self {
+            ConstFormat::Decimal => write!(f, "{}", value.value()),
+            ConstFormat::Exponent => write!(f, "{:e}", value.value()),
+            ConstFormat::Bits => write!(f, "{:#010x}", value.bits()),
+        })
+    }
+}
+
+/// Key/value annotations carried in `#!key value` comment lines, such as the
+/// generator which produced a program or the units it's measured in. Other
+/// tools ignore them as ordinary comments. Keys keep the order they were
+/// first set in.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Metadata {
+    entries: Vec<(String, String)>,
+}
+
+impl Metadata {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Add an entry, replacing any existing value for `key`. Keys can't be
+    /// empty or contain whitespace, and values can't contain line breaks.
+    pub fn set(&mut self, key: impl Into<String>, value: impl Into<String>) {
+        let (key, value) = (key.into(), value.into());
+        assert!(!key.is_empty() && !key.contains(char::is_whitespace));
+        assert!(!value.contains(['\n', '\r']));
+        match self.entries.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, old)) => *old = value,
+            None => self.entries.push((key, value)),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Recognize a `#!key value` line, ignoring anything else. Line reading
+    // only strips `\n` and `\r\n`, so a stray carriage return is dropped
+    // rather than kept in the value.
+    fn parse_line(&mut self, line: &str) {
+        let Some(rest) = line.trim_start().strip_prefix("#!") else {
+            return;
         };
+        let (key, value) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        if !key.is_empty() {
+            self.set(key, value.trim().replace('\r', ""));
+        }
+    }
+
+    pub fn write(&self, mut f: impl io::Write) -> io::Result<()> {
+        for (key, value) in self.iter() {
+            writeln!(f, "#!{key} {value}")?;
+        }
+        Ok(())
+    }
+}
+
+pub fn write(f: impl io::Write, insts: impl IntoIterator<Item = Inst>) -> io::Result<()> {
+    write_with_format(f, insts, ConstFormat::default())
+}
+
+/// Like [`write`], but with constants spelled according to `format`.
+pub fn write_with_format(
+    mut f: impl io::Write,
+    insts: impl IntoIterator<Item = Inst>,
+    format: ConstFormat,
+) -> io::Result<()> {
+    for (idx, inst) in insts.into_iter().enumerate() {
+        write_inst(&mut f, idx, &inst, format)?;
+        writeln!(f)?;
     }
     Ok(())
 }
 
-pub fn write_memoized(mut f: impl io::Write, memoized: &Memoized) -> io::Result<()> {
+/// Like [`write`], followed by an `output` line for each of `outputs`, which
+/// [`read_with_outputs`] reads back. The first output is the program's
+/// result, which readers that only want one expect to be the last
+/// instruction.
+pub fn write_with_outputs(
+    mut f: impl io::Write,
+    insts: impl IntoIterator<Item = Inst>,
+    outputs: &[(String, InstIdx)],
+) -> io::Result<()> {
+    write(&mut f, insts)?;
+    for (name, idx) in outputs {
+        writeln!(f, "output {name} v{idx}")?;
+    }
+    Ok(())
+}
+
+/// Write one instruction the way [`write_with_format`] does, named `v{idx}`,
+/// without a newline.
+pub fn write_inst(
+    f: impl io::Write,
+    idx: usize,
+    inst: &Inst,
+    format: ConstFormat,
+) -> io::Result<()> {
+    let name = |idx: usize| fmt::from_fn(move |f| write!(f, "v{idx}"));
+    write_named(f, idx, inst, name, |value| format.display(value))
+}
+
+// Write one instruction with each value called whatever `name` gives for its
+// index, and constants spelled by `constant`, without a newline.
+fn write_named<N: fmt::Display, C: fmt::Display>(
+    mut f: impl io::Write,
+    idx: usize,
+    inst: &Inst,
+    name: impl Fn(usize) -> N,
+    constant: impl Fn(Const) -> C,
+) -> io::Result<()> {
+    let arg = |arg: InstIdx| name(arg.idx());
+    write!(f, "{} ", name(idx))?;
+    match *inst {
+        Inst::Const { value } => write!(f, "const {}", constant(value)),
+        Inst::Var { var } => write!(f, "var-{}", var.name()),
+        Inst::UnOp {
+            op: UnOp::PowI(n),
+            arg: a,
+        } => write!(f, "powi {} {n}", arg(a)),
+        Inst::UnOp { op, arg: a } => write!(f, "{} {}", op.name(), arg(a)),
+        Inst::BinOp { op, args: [a, b] } => write!(f, "{} {} {}", op.name(), arg(a), arg(b)),
+        Inst::Fma { args: [a, b, c] } => write!(f, "fma {} {} {}", arg(a), arg(b), arg(c)),
+        Inst::TernOp {
+            op,
+            args: [a, b, c],
+        } => write!(f, "{} {} {} {}", op.name(), arg(a), arg(b), arg(c)),
+        Inst::Load { vars, loc } => write!(f, "load {vars:?} {loc}"),
+    }
+}
+
+/// Write the program in as few bytes as it reads back from exactly: each
+/// value gets the shortest name left, with the most used values first, and
+/// each constant the shortest spelling that round-trips. There are no
+/// comments or metadata.
+pub fn write_minified(mut f: impl io::Write, insts: &[Inst]) -> io::Result<()> {
+    let mut uses = vec![0usize; insts.len()];
+    for inst in insts {
+        for arg in inst.args() {
+            uses[arg.idx()] += 1;
+        }
+    }
+    let mut order: Vec<usize> = (0..insts.len()).collect();
+    order.sort_by_key(|&idx| std::cmp::Reverse(uses[idx]));
+    let mut names = vec![String::new(); insts.len()];
+    for (rank, idx) in order.into_iter().enumerate() {
+        names[idx] = short_name(rank);
+    }
+    for (idx, inst) in insts.iter().enumerate() {
+        write_named(&mut f, idx, inst, |idx| &names[idx], short_const)?;
+        writeln!(f)?;
+    }
+    Ok(())
+}
+
+const NAME_CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+
+// The `rank`th name in order of length: every single character first, then
+// every pair, and so on.
+fn short_name(mut rank: usize) -> String {
+    let base = NAME_CHARS.len();
+    let mut name = Vec::new();
+    loop {
+        name.push(NAME_CHARS[rank % base]);
+        rank /= base;
+        if rank == 0 {
+            break;
+        }
+        rank -= 1;
+    }
+    name.reverse();
+    String::from_utf8(name).unwrap()
+}
+
+// Whichever of the decimal or scientific spellings is shorter, without the
+// leading zero of a fraction, which the parser doesn't need.
+fn short_const(value: Const) -> String {
+    let decimal = ConstFormat::Decimal.display(value).to_string();
+    let exponent = ConstFormat::Exponent.display(value).to_string();
+    let text = if exponent.len() < decimal.len() {
+        exponent
+    } else {
+        decimal
+    };
+    if let Some(fraction) = text.strip_prefix("0.") {
+        format!(".{fraction}")
+    } else if let Some(fraction) = text.strip_prefix("-0.") {
+        format!("-.{fraction}")
+    } else {
+        text
+    }
+}
+
+pub fn write_memoized(f: impl io::Write, memoized: &Memoized) -> io::Result<()> {
+    write_memoized_with_format(f, memoized, ConstFormat::default())
+}
+
+/// Like [`write_memoized`], but with constants spelled according to `format`.
+pub fn write_memoized_with_format(
+    f: impl io::Write,
+    memoized: &Memoized,
+    format: ConstFormat,
+) -> io::Result<()> {
+    write_memoized_listing(f, memoized, format, false)
+}
+
+/// Like [`write_memoized_with_format`], but with cross-references in
+/// comments: each load says which instruction, constant, or input coordinate
+/// it reads, and each store lists every load of it in other functions, so a
+/// value can be traced across functions without matching up locations by
+/// hand.
+pub fn write_memoized_annotated(
+    f: impl io::Write,
+    memoized: &Memoized,
+    format: ConstFormat,
+) -> io::Result<()> {
+    write_memoized_listing(f, memoized, format, true)
+}
+
+fn write_memoized_listing(
+    mut f: impl io::Write,
+    memoized: &Memoized,
+    format: ConstFormat,
+    annotate: bool,
+) -> io::Result<()> {
+    // every load of each stored location, by the function it's stored from
+    let mut loaded_by: HashMap<(VarSet, Location), Vec<(VarSet, usize)>> = HashMap::new();
+    if annotate {
+        for func in memoized.funcs.iter() {
+            for (idx, inst) in func.insts.iter().enumerate() {
+                if let &Inst::Load { vars, loc } = inst {
+                    loaded_by
+                        .entry((vars, loc))
+                        .or_default()
+                        .push((func.vars, idx));
+                }
+            }
+        }
+    }
+
     writeln!(f, "# consts: {}", memoized.consts.len())?;
-    for (idx, value) in memoized.consts.iter().enumerate() {
-        writeln!(f, "v{idx} const {value}")?;
+    for (idx, &value) in memoized.consts.iter().enumerate() {
+        writeln!(f, "v{idx} const {}", format.display(value))?;
     }
 
     for func in memoized.funcs.iter() {
         if !func.insts.is_empty() {
             writeln!(f)?;
             writeln!(f, "# func {:?}: {} outputs", func.vars, func.outputs.len())?;
-            write(&mut f, func.insts.iter().cloned())?;
+            for (idx, inst) in func.insts.iter().enumerate() {
+                write_inst(&mut f, idx, inst, format)?;
+                if annotate && let &Inst::Load { vars, loc } = inst {
+                    write!(f, "  # ")?;
+                    let loc = usize::from(loc);
+                    if vars == VarSet::default() {
+                        write!(f, "const {}", format.display(memoized.consts[loc]))?;
+                    } else {
+                        match memoized.funcs[vars.idx() - 1].outputs.get(loc) {
+                            Some(Some(def)) => write!(f, "{vars:?} v{def}")?,
+                            Some(None) => write!(f, "input {vars:?}")?,
+                            None => write!(f, "nothing stored")?,
+                        }
+                    }
+                }
+                writeln!(f)?;
+            }
             for (loc, &reg) in func.outputs.iter().enumerate() {
                 if let Some(reg) = reg {
-                    writeln!(f, "# store v{reg} {:?}:{loc}", func.vars)?;
+                    write!(f, "# store v{reg} {:?}:{loc}", func.vars)?;
+                    if annotate {
+                        let loc = loc.try_into().unwrap();
+                        match loaded_by.get(&(func.vars, loc)) {
+                            Some(loads) => {
+                                write!(f, " -> loaded by")?;
+                                for (i, (vars, idx)) in loads.iter().enumerate() {
+                                    let sep = if i == 0 { " " } else { ", " };
+                                    write!(f, "{sep}{vars:?} v{idx}")?;
+                                }
+                            }
+                            // memoization drops every other unused store
+                            None => write!(f, " -> final result")?,
+                        }
+                    }
+                    writeln!(f)?;
                 }
             }
         }
@@ -41,6 +328,164 @@ pub fn write_memoized(mut f: impl io::Write, memoized: &Memoized) -> io::Result<
     Ok(())
 }
 
+/// Write `memoized` so that [`read_checkpoint`] gets back exactly the same
+/// program, ranges and all, for tools that want to start from a memoized
+/// program without reading and memoizing a large input every time. Unlike
+/// [`write_memoized`]'s listing, nothing is left in comments: `const` lines
+/// give the constant pool, as bits, and then every function follows a
+/// `func` line, even if it's empty. Each instruction ends with its `range`,
+/// and after them, each location the function stores is an `output` line
+/// naming the instruction stored there, or `input` for an input coordinate.
+pub fn write_checkpoint(mut f: impl io::Write, memoized: &Memoized) -> io::Result<()> {
+    for &value in memoized.consts.iter() {
+        writeln!(f, "const {}", ConstFormat::Bits.display(value))?;
+    }
+    for func in memoized.funcs.iter() {
+        writeln!(f, "func {:?}", func.vars)?;
+        for (idx, (inst, range)) in func.insts.iter().zip(&func.ranges).enumerate() {
+            write_inst(&mut f, idx, inst, ConstFormat::Bits)?;
+            writeln!(f, " range {} {}", range.lo, range.hi)?;
+        }
+        for output in func.outputs.iter() {
+            match output {
+                Some(idx) => writeln!(f, "output v{idx}")?,
+                None => writeln!(f, "input")?,
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Read a program written by [`write_checkpoint`]. Comments are ignored, so
+/// [`Metadata`] can come along, but is not returned. Every load has to read
+/// a constant or a location that's stored.
+pub fn read_checkpoint(f: impl io::BufRead) -> Result<Memoized> {
+    let mut memoized = Memoized::default();
+    for func in memoized.funcs.iter_mut() {
+        func.outputs.clear();
+    }
+    let mut current = None;
+    for (line_number, line) in f.lines().enumerate() {
+        read_checkpoint_line(&line?, &mut memoized, &mut current).map_err(|error| {
+            Error::AtLine {
+                line: line_number + 1,
+                error: Box::new(error),
+            }
+        })?;
+    }
+    let next = current.map_or(0, |idx| idx + 1);
+    if let Some(func) = memoized.funcs.get(next) {
+        return Err(Error::MissingFunc(format!("{:?}", func.vars)));
+    }
+
+    for func in memoized.funcs.iter() {
+        for inst in func.insts.iter() {
+            if let &Inst::Load { vars, loc } = inst {
+                let stored = match vars.idx().checked_sub(1) {
+                    None => memoized.consts.len(),
+                    Some(from) => memoized.funcs[from].outputs.len(),
+                };
+                // only the function's own inputs, or the outputs of one with
+                // fewer variables
+                let reachable = vars | func.vars == func.vars;
+                if !reachable || usize::from(loc) >= stored {
+                    return Err(Error::UnstoredLoad(format!("{vars:?}"), loc));
+                }
+            }
+        }
+    }
+    Ok(memoized)
+}
+
+// Parse one line of a checkpoint into `memoized`, where `current` is the
+// index of the function that the lines so far have reached.
+fn read_checkpoint_line(
+    line: &str,
+    memoized: &mut Memoized,
+    current: &mut Option<usize>,
+) -> Result<()> {
+    let tokens: Vec<&str> = line
+        .split_ascii_whitespace()
+        .take_while(|token| !token.starts_with('#'))
+        .collect();
+    if tokens.is_empty() {
+        return Ok(());
+    }
+    let malformed = || Error::MalformedCheckpoint(line.trim().to_string());
+    if let ["const", value] = tokens[..]
+        && current.is_none()
+    {
+        memoized.consts.push(parse_const(value)?);
+        return Ok(());
+    }
+    if let ["func", name] = tokens[..] {
+        let next = current.map_or(0, |idx| idx + 1);
+        match memoized.funcs.get(next) {
+            Some(func) if format!("{:?}", func.vars) == name => {
+                *current = Some(next);
+                return Ok(());
+            }
+            _ => return Err(malformed()),
+        }
+    }
+    let Some(func) = current.map(|idx| &mut memoized.funcs[idx]) else {
+        return Err(malformed());
+    };
+    let len = func.insts.len();
+    let lookup = |name: &str| {
+        name.strip_prefix('v')?
+            .parse::<usize>()
+            .ok()
+            .filter(|&idx| idx < len)
+            .and_then(|idx| InstIdx::try_from(idx).ok())
+    };
+    match tokens[..] {
+        ["input"] => func.outputs.push(None),
+        ["output", name] => {
+            let idx = lookup(name).ok_or_else(|| Error::UndefinedName(name.to_string()))?;
+            func.outputs.push(Some(idx));
+        }
+        // instructions come before any outputs
+        [name, ref inst @ .., "range", lo, hi] if func.outputs.is_empty() => {
+            let bound = |token: &str| token.parse::<f32>().map_err(|_| Error::InvalidRange);
+            let (lo, hi) = (bound(lo)?, bound(hi)?);
+            if lo.is_nan() || hi.is_nan() || lo > hi {
+                return Err(Error::InvalidRange);
+            }
+            if name != format!("v{len}") {
+                return Err(malformed());
+            }
+            let inst = match *inst {
+                ["load", vars, loc] => {
+                    let vars = (0..=VarSet::ALL.0)
+                        .map(VarSet)
+                        .find(|set| format!("{set:?}") == vars)
+                        .ok_or_else(|| Error::UndefinedName(vars.to_string()))?;
+                    Inst::Load {
+                        vars,
+                        loc: loc.parse().map_err(|_| malformed())?,
+                    }
+                }
+                _ => match parse_line(&tokens[..tokens.len() - 3].join(" "), lookup)?
+                    .unwrap()
+                    .1
+                {
+                    Parsed::Const(value) => Inst::Const { value },
+                    Parsed::Var(var) => Inst::Var { var },
+                    Parsed::UnOp(op, arg) => Inst::UnOp { op, arg },
+                    Parsed::BinOp(op, args) => Inst::BinOp { op, args },
+                    Parsed::Fma(args) => Inst::Fma { args },
+                    Parsed::TernOp(op, args) => Inst::TernOp { op, args },
+                },
+            };
+            func.insts.push(inst);
+            func.ranges.push(Interval::new(lo, hi));
+        }
+        _ => return Err(malformed()),
+    }
+    Ok(())
+}
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("read failed")]
@@ -49,6 +494,12 @@ pub enum Error {
     Empty,
     #[error("invalid constant")]
     InvalidConst(#[from] ParseFloatError),
+    #[error("invalid constant bits")]
+    InvalidConstBits(#[from] ParseIntError),
+    #[error("constant is not finite")]
+    NonFiniteConst,
+    #[error("invalid power {0:?}")]
+    InvalidPower(String),
     #[error("missing token")]
     MissingToken,
     #[error("unexpected token {0:?}")]
@@ -57,87 +508,592 @@ pub enum Error {
     UndefinedName(String),
     #[error("instruction redefines existing name {0:?}")]
     RedefinedName(String),
+    #[error("redefinition of {0:?} uses a name defined after it")]
+    ForwardReference(String),
     #[error("unknown instruction {0:?}")]
     UnknownOp(String),
+    #[error("output {0:?} is already defined")]
+    RedefinedOutput(String),
+    #[error("view of {0} is already declared")]
+    RedefinedView(char),
+    #[error("view of {0} comes after a var-{0} instruction")]
+    LateView(char),
+    #[error("view must span a nonempty range")]
+    EmptyView,
+    #[error("include is only allowed when reading a file with read_file")]
+    IncludeNotAllowed,
+    #[error("include of {0:?} reaches outside the top-level file's directory")]
+    IncludeOutsideRoot(PathBuf),
+    #[error("{0:?} includes itself")]
+    IncludeCycle(PathBuf),
+    #[error("{}:{line}: {error}", path.display())]
+    InFile {
+        path: PathBuf,
+        line: usize,
+        error: Box<Error>,
+    },
+    #[error("malformed checkpoint line {0:?}")]
+    MalformedCheckpoint(String),
+    #[error("checkpoint ends before function {0}")]
+    MissingFunc(String),
+    #[error("invalid range")]
+    InvalidRange,
+    #[error("load of {0} location {1}, where nothing is stored")]
+    UnstoredLoad(String, Location),
+    #[error("line {line}: {error}")]
+    AtLine { line: usize, error: Box<Error> },
+    #[error(transparent)]
+    Cancelled(#[from] Cancelled),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
-pub fn read<S: InstSink>(f: impl io::BufRead, mut sink: S) -> Result<S::Output> {
+/// A program's named outputs, in the order they're declared; see
+/// [`read_with_outputs`].
+pub type Outputs<I> = Vec<(String, I)>;
+
+pub fn read<S: InstSink>(f: impl io::BufRead, sink: S) -> Result<S::Output> {
+    read_cancellable(f, sink, &CancelToken::default())
+}
+
+/// Like [`read`], but periodically checks whether `cancel` has been
+/// triggered, since inputs can be very large.
+pub fn read_cancellable<S: InstSink>(
+    f: impl io::BufRead,
+    sink: S,
+    cancel: &CancelToken,
+) -> Result<S::Output> {
+    Ok(read_lines(f, sink, cancel, None)?.0)
+}
+
+/// Like [`read`], but also returns the program's named outputs, from lines
+/// like `output distance v42`, in the order they're declared. A program with
+/// no `output` lines has no named outputs, and its result is its last value
+/// as always. Otherwise, the result which every reader returns is the first
+/// output, which is guaranteed to be the last instruction pushed: if it
+/// isn't already, it's pushed again as a `barrier` of itself, and the first
+/// output names that copy. The returned indexes are only useful for sinks
+/// whose output they still refer to, like [`Insts`](super::Insts).
+pub fn read_with_outputs<S: InstSink>(
+    f: impl io::BufRead,
+    sink: S,
+) -> Result<(S::Output, Outputs<S::Idx>)> {
+    let (output, _, outputs) = read_lines(f, sink, &CancelToken::default(), None)?;
+    Ok((output, outputs))
+}
+
+/// Like [`read`], but also returns any [`Metadata`] found in the input.
+pub fn read_with_metadata<S: InstSink>(
+    f: impl io::BufRead,
+    sink: S,
+) -> Result<(S::Output, Metadata)> {
+    let (output, metadata, _) = read_lines(f, sink, &CancelToken::default(), None)?;
+    Ok((output, metadata))
+}
+
+/// A line which [`read_lenient`] skipped, and why.
+#[derive(Debug, Error)]
+#[error("line {line}: {error}")]
+pub struct Diagnostic {
+    pub line: usize,
+    pub error: Error,
+}
+
+/// Like [`read`], but malformed lines are skipped and reported instead of
+/// stopping at the first one, for machine-generated inputs with occasional
+/// garbage. A skipped line leaves nothing behind in `sink`, but later lines
+/// which use its name are skipped too. I/O errors and inputs with no valid
+/// lines at all are still fatal. Any [`Metadata`] is returned as well.
+pub fn read_lenient<S: InstSink>(
+    f: impl io::BufRead,
+    sink: S,
+) -> Result<(S::Output, Metadata, Vec<Diagnostic>)> {
+    let mut diagnostics = Vec::new();
+    let (output, metadata, _) =
+        read_lines(f, sink, &CancelToken::default(), Some(&mut diagnostics))?;
+    Ok((output, metadata, diagnostics))
+}
+
+fn read_lines<S: InstSink>(
+    f: impl io::BufRead,
+    mut sink: S,
+    cancel: &CancelToken,
+    mut diagnostics: Option<&mut Vec<Diagnostic>>,
+) -> Result<(S::Output, Metadata, Outputs<S::Idx>)> {
+    let span = tracing::info_span!("read", lines = tracing::field::Empty).entered();
     let mut names = HashMap::new();
+    let mut metadata = Metadata::default();
+    let mut outputs = Vec::new();
+    let mut views = Views::default();
     let mut last = None;
+    let mut lines = 0;
 
-    for line in f.lines() {
+    for (line_number, line) in f.lines().enumerate() {
+        lines = line_number + 1;
+        if line_number % 1024 == 0 {
+            cancel.check()?;
+        }
         let line = line?;
+        metadata.parse_line(&line);
 
-        let mut tokens = Tokens {
-            names: &names,
-            tokens: line
-                .split_ascii_whitespace()
-                .take_while(|token| !token.starts_with('#')),
-        };
+        match read_line(&line, &mut names, &mut outputs, &mut views, &mut sink) {
+            Ok(None) => {}
+            Ok(Some(idx)) => last = Some(idx),
+            Err(error) => {
+                let Some(diagnostics) = diagnostics.as_deref_mut() else {
+                    return Err(error);
+                };
+                tracing::debug!(line = lines, %error, "skipping malformed line");
+                diagnostics.push(Diagnostic { line: lines, error });
+            }
+        }
+    }
 
-        let Ok(out) = tokens.next() else { continue };
+    span.record("lines", lines);
+    views.record(&mut metadata);
+    let result = result(&mut sink, &mut outputs, last)?;
+    Ok((sink.finish(result), metadata, outputs))
+}
 
-        let idx = match tokens.next()? {
-            "const" => sink.push_const(Const::new(tokens.next()?.parse()?)),
-            "var-x" => sink.push_var(Var::X),
-            "var-y" => sink.push_var(Var::Y),
-            "var-z" => sink.push_var(Var::Z),
+// The program's result, which is its first output if it has any, or else
+// its last value. Sinks like `Insts` take the last instruction to be the
+// result, so if the first output isn't the last value, it's pushed again as
+// a barrier, which changes nothing else, and the output renamed to that.
+fn result<S: InstSink>(
+    sink: &mut S,
+    outputs: &mut [(String, S::Idx)],
+    last: Option<S::Idx>,
+) -> Result<S::Idx> {
+    let last = last.ok_or(Error::Empty)?;
+    match outputs.first_mut() {
+        Some((_, output)) if *output != last => {
+            *output = sink.push_unop(UnOp::Barrier, *output);
+            Ok(*output)
+        }
+        _ => Ok(last),
+    }
+}
 
-            "neg" => tokens.unop(UnOp::Neg, &mut sink)?,
-            "square" => tokens.unop(UnOp::Square, &mut sink)?,
-            "sqrt" => tokens.unop(UnOp::Sqrt, &mut sink)?,
+/// Like [`read_with_metadata`], but reads the program from the file at
+/// `path`, which may split a large scene across several files with lines
+/// like `wheel include "parts/wheel.vm"`. Each included file is read with
+/// names of its own, starting from nothing, so it can't see or clash with
+/// the including file's names. Afterward, `wheel` names the included file's
+/// result, its first output or else its last value, and `wheel.hub` names
+/// its value `hub`, and so on for files it
+/// includes in turn. Include paths are relative to the including file, and
+/// can't reach outside the top-level file's directory. Errors say which file
+/// and line they came from. Only metadata in the top-level file is returned,
+/// along with views from any file.
+pub fn read_file<S: InstSink>(path: &Path, mut sink: S) -> Result<(S::Output, Metadata)> {
+    let root = path.canonicalize()?;
+    let mut includes = Includes {
+        root: root.parent().unwrap().to_path_buf(),
+        stack: Vec::new(),
+        metadata: Metadata::default(),
+        views: Views::default(),
+    };
+    let mut outputs = Vec::new();
+    let last = includes.read(path, &mut HashMap::new(), &mut outputs, &mut sink)?;
+    includes.views.record(&mut includes.metadata);
+    let result = result(&mut sink, &mut outputs, last)?;
+    Ok((sink.finish(result), includes.metadata))
+}
 
-            "add" => tokens.binop(BinOp::Add, &mut sink)?,
-            "sub" => tokens.binop(BinOp::Sub, &mut sink)?,
-            "mul" => tokens.binop(BinOp::Mul, &mut sink)?,
-            "min" => tokens.binop(BinOp::Min, &mut sink)?,
-            "max" => tokens.binop(BinOp::Max, &mut sink)?,
+// The state of `read_file` while it follows includes.
+struct Includes {
+    root: PathBuf,
+    // canonical paths of the files being read, outermost first
+    stack: Vec<PathBuf>,
+    metadata: Metadata,
+    // shared by every file, since they all describe one image
+    views: Views,
+}
 
-            op => return Err(Error::UnknownOp(op.to_string())),
+impl Includes {
+    fn read<S: InstSink>(
+        &mut self,
+        path: &Path,
+        names: &mut HashMap<String, S::Idx>,
+        outputs: &mut Outputs<S::Idx>,
+        sink: &mut S,
+    ) -> Result<Option<S::Idx>> {
+        let in_file = |line, error| match error {
+            // already says where it came from
+            Error::InFile { .. } => error,
+            error => Error::InFile {
+                path: path.to_path_buf(),
+                line,
+                error: Box::new(error),
+            },
         };
+        // failing to open the file is reported at the line including it
+        let canonical = path.canonicalize()?;
+        if self.stack.contains(&canonical) {
+            return Err(Error::IncludeCycle(path.to_path_buf()));
+        }
+        self.stack.push(canonical);
+        let dir = path.parent().unwrap_or(Path::new("."));
+        let f = io::BufReader::new(File::open(path)?);
 
-        tokens.empty()?;
+        let mut last = None;
+        for (line_number, line) in io::BufRead::lines(f).enumerate() {
+            let line = line.map_err(|e| in_file(line_number + 1, e.into()))?;
+            if self.stack.len() == 1 {
+                self.metadata.parse_line(&line);
+            }
+            let result = match parse_include(&line) {
+                Some(Ok((ns, file))) => self.include(&dir.join(file), ns, names, sink),
+                Some(Err(error)) => Err(error),
+                None => read_line(&line, names, outputs, &mut self.views, sink),
+            };
+            match result {
+                Ok(None) => {}
+                Ok(Some(idx)) => last = Some(idx),
+                Err(error) => return Err(in_file(line_number + 1, error)),
+            }
+        }
+        self.stack.pop();
+        Ok(last)
+    }
 
-        match names.entry(out.to_string()) {
-            Entry::Vacant(e) => {
-                e.insert(idx);
+    // Read an included file, then bring its names into `names` under `ns`.
+    fn include<S: InstSink>(
+        &mut self,
+        path: &Path,
+        ns: &str,
+        names: &mut HashMap<String, S::Idx>,
+        sink: &mut S,
+    ) -> Result<Option<S::Idx>> {
+        if names.contains_key(ns) {
+            return Err(Error::RedefinedName(ns.to_string()));
+        }
+        match path.canonicalize() {
+            Ok(canonical) if !canonical.starts_with(&self.root) => {
+                return Err(Error::IncludeOutsideRoot(path.to_path_buf()));
             }
-            Entry::Occupied(e) => {
-                return Err(Error::RedefinedName(e.remove_entry().0));
+            // a missing file is reported by trying to read it
+            _ => {}
+        }
+        let mut included = HashMap::new();
+        let mut outputs = Vec::new();
+        let last = self.read(path, &mut included, &mut outputs, sink)?;
+        // an included file's outputs are just names for its values, apart
+        // from the first, which stands in for its result
+        let last = outputs.first().map(|&(_, idx)| idx).or(last);
+        let last = last.ok_or(Error::Empty)?;
+        let included: Vec<(String, S::Idx)> = included
+            .into_iter()
+            .map(|(name, idx)| (format!("{ns}.{name}"), idx))
+            .collect();
+        if let Some((name, _)) = included.iter().find(|(name, _)| names.contains_key(name)) {
+            return Err(Error::RedefinedName(name.clone()));
+        }
+        names.extend(included);
+        names.insert(ns.to_string(), last);
+        Ok(Some(last))
+    }
+}
+
+// The namespace and path of an `include` line, with the path's quotes
+// removed, or `None` for any other line.
+fn parse_include(line: &str) -> Option<Result<(&str, &str)>> {
+    let mut tokens = line
+        .split_ascii_whitespace()
+        .take_while(|token| !token.starts_with('#'));
+    let ns = tokens.next()?;
+    if tokens.next()? != "include" {
+        return None;
+    }
+    let Some(file) = tokens.next() else {
+        return Some(Err(Error::MissingToken));
+    };
+    if let Some(extra) = tokens.next() {
+        return Some(Err(Error::ExtraToken(extra.to_string())));
+    }
+    let file = file
+        .strip_prefix('"')
+        .and_then(|file| file.strip_suffix('"'))
+        .unwrap_or(file);
+    Some(Ok((ns, file)))
+}
+
+// The name and value of an `output` line, or `None` for any other line.
+fn parse_output(line: &str) -> Option<(&str, &str)> {
+    let mut tokens = line
+        .split_ascii_whitespace()
+        .take_while(|token| !token.starts_with('#'));
+    match [tokens.next(), tokens.next(), tokens.next(), tokens.next()] {
+        [Some("output"), Some(name), Some(value), None] => Some((name, value)),
+        _ => None,
+    }
+}
+
+// The name and bounds of a `view` line, or `None` for any other line.
+fn parse_view(line: &str) -> Option<Result<(Var, Const, Const)>> {
+    let mut tokens = line
+        .split_ascii_whitespace()
+        .take_while(|token| !token.starts_with('#'));
+    if tokens.next()? != "view" {
+        return None;
+    }
+    let var = match tokens.next()? {
+        "x" => Var::X,
+        "y" => Var::Y,
+        "z" => Var::Z,
+        _ => return None,
+    };
+    let lo = tokens.next();
+    let hi = tokens.next();
+    let extra = tokens.next();
+    Some((|| {
+        let lo = parse_const(lo.ok_or(Error::MissingToken)?)?;
+        let hi = parse_const(hi.ok_or(Error::MissingToken)?)?;
+        if let Some(extra) = extra {
+            return Err(Error::ExtraToken(extra.to_string()));
+        }
+        Ok((var, lo, hi))
+    })())
+}
+
+// The range of each variable across the rendered image, in the units the
+// program works in, from its `view` lines. Renderers still run each
+// variable from -1 to 1, so `var-` instructions are lowered to scale and
+// offset that into the declared range, which costs a `mul` and an `add` per
+// variable. The lowered program no longer needs its views, so they're kept
+// as metadata, which writers copy out but readers don't apply again.
+#[derive(Default)]
+struct Views {
+    declared: [Option<(f32, f32)>; 3],
+    used: [bool; 3],
+}
+
+impl Views {
+    fn declare(&mut self, var: Var, lo: Const, hi: Const) -> Result<()> {
+        let (lo, hi) = (lo.value(), hi.value());
+        if lo == hi {
+            return Err(Error::EmptyView);
+        }
+        let view = &mut self.declared[var as usize];
+        if view.is_some() {
+            return Err(Error::RedefinedView(var.name()));
+        }
+        // already pushed unscaled, so it's too late to change
+        if self.used[var as usize] {
+            return Err(Error::LateView(var.name()));
+        }
+        *view = Some((lo, hi));
+        Ok(())
+    }
+
+    fn push_var<S: InstSink>(&mut self, var: Var, sink: &mut S) -> S::Idx {
+        self.used[var as usize] = true;
+        let mut idx = sink.push_var(var);
+        let Some((lo, hi)) = self.declared[var as usize] else {
+            return idx;
+        };
+        // a scale of 1 or an offset of 0 would change nothing, so only
+        // what's needed is pushed
+        let scale = ((f64::from(hi) - f64::from(lo)) / 2.0) as f32;
+        let offset = ((f64::from(hi) + f64::from(lo)) / 2.0) as f32;
+        if scale != 1.0 {
+            let scale = sink.push_const(Const::new(scale));
+            idx = sink.push_binop(BinOp::Mul, [idx, scale]);
+        }
+        if offset != 0.0 {
+            let offset = sink.push_const(Const::new(offset));
+            idx = sink.push_binop(BinOp::Add, [idx, offset]);
+        }
+        idx
+    }
+
+    // Record each view as a `#!view-x lo hi` metadata line.
+    fn record(&self, metadata: &mut Metadata) {
+        for (var, view) in [Var::X, Var::Y, Var::Z].into_iter().zip(self.declared) {
+            if let Some((lo, hi)) = view {
+                metadata.set(format!("view-{}", var.name()), format!("{lo} {hi}"));
             }
         }
-        last = Some(idx);
     }
+}
+
+// Parse one line and push its instruction into `sink`, returning `None` for
+// blank lines, comments, outputs, which are added to `outputs` instead, and
+// views, which are added to `views`. Everything is checked before anything
+// is pushed, so an error leaves `sink`, `names`, `outputs`, and `views`
+// unchanged.
+fn read_line<S: InstSink>(
+    line: &str,
+    names: &mut HashMap<String, S::Idx>,
+    outputs: &mut Outputs<S::Idx>,
+    views: &mut Views,
+    sink: &mut S,
+) -> Result<Option<S::Idx>> {
+    let parsed = parse_line(line, |name| names.get(name).copied());
+    if let Err(Error::UnknownOp(_)) = parsed
+        && let Some(view) = parse_view(line)
+    {
+        let (var, lo, hi) = view?;
+        views.declare(var, lo, hi)?;
+        return Ok(None);
+    }
+    // a value named `output` takes precedence, for programs written before
+    // outputs existed, so only lines which aren't instructions are outputs
+    if let Err(Error::UnknownOp(_)) = parsed
+        && let Some((name, value)) = parse_output(line)
+    {
+        if outputs.iter().any(|(existing, _)| existing == name) {
+            return Err(Error::RedefinedOutput(name.to_string()));
+        }
+        let idx = *names
+            .get(value)
+            .ok_or_else(|| Error::UndefinedName(value.to_string()))?;
+        outputs.push((name.to_string(), idx));
+        return Ok(None);
+    }
+    let Some((out, inst)) = parsed? else {
+        return Ok(None);
+    };
+    let entry = match names.entry(out.to_string()) {
+        Entry::Vacant(entry) => entry,
+        Entry::Occupied(entry) => return Err(Error::RedefinedName(entry.key().clone())),
+    };
+    let idx = match inst {
+        Parsed::Const(value) => sink.push_const(value),
+        Parsed::Var(var) => views.push_var(var, sink),
+        Parsed::UnOp(op, arg) => sink.push_unop(op, arg),
+        Parsed::BinOp(op, args) => sink.push_binop(op, args),
+        Parsed::Fma(args) => sink.push_fma(args),
+        Parsed::TernOp(op, args) => sink.push_ternop(op, args),
+    };
+    entry.insert(idx);
+    Ok(Some(idx))
+}
+
+/// Parse one line of a program without pushing it anywhere, for callers
+/// which keep track of names themselves. Returns the name the line defines
+/// and its instruction, with each argument resolved by `lookup`, or `None`
+/// for blank lines and comments.
+pub fn parse_line<I>(
+    line: &str,
+    lookup: impl Fn(&str) -> Option<I>,
+) -> Result<Option<(&str, Parsed<I>)>> {
+    let mut tokens = Tokens {
+        lookup,
+        tokens: line
+            .split_ascii_whitespace()
+            .take_while(|token| !token.starts_with('#')),
+    };
+
+    let Ok(out) = tokens.next() else {
+        return Ok(None);
+    };
 
-    Ok(sink.finish(last.ok_or(Error::Empty)?))
+    let inst = match tokens.next()? {
+        "const" => Parsed::Const(parse_const(tokens.next()?)?),
+        "var-x" => Parsed::Var(Var::X),
+        "var-y" => Parsed::Var(Var::Y),
+        "var-z" => Parsed::Var(Var::Z),
+
+        "neg" => Parsed::UnOp(UnOp::Neg, tokens.arg()?),
+        "square" => Parsed::UnOp(UnOp::Square, tokens.arg()?),
+        "sqrt" => Parsed::UnOp(UnOp::Sqrt, tokens.arg()?),
+        "abs" => Parsed::UnOp(UnOp::Abs, tokens.arg()?),
+        "exp" => Parsed::UnOp(UnOp::Exp, tokens.arg()?),
+        "ln" => Parsed::UnOp(UnOp::Ln, tokens.arg()?),
+        "sin" => Parsed::UnOp(UnOp::Sin, tokens.arg()?),
+        "cos" => Parsed::UnOp(UnOp::Cos, tokens.arg()?),
+        "floor" => Parsed::UnOp(UnOp::Floor, tokens.arg()?),
+        "ceil" => Parsed::UnOp(UnOp::Ceil, tokens.arg()?),
+        "round" => Parsed::UnOp(UnOp::Round, tokens.arg()?),
+        "recip" => Parsed::UnOp(UnOp::Recip, tokens.arg()?),
+        "rsqrt" => Parsed::UnOp(UnOp::Rsqrt, tokens.arg()?),
+        "powi" => {
+            let arg = tokens.arg()?;
+            let power = tokens.next()?;
+            let power = power
+                .parse()
+                .map_err(|_| Error::InvalidPower(power.to_string()))?;
+            Parsed::UnOp(UnOp::PowI(power), arg)
+        }
+        "barrier" => Parsed::UnOp(UnOp::Barrier, tokens.arg()?),
+
+        "add" => tokens.binop(BinOp::Add)?,
+        "sub" => tokens.binop(BinOp::Sub)?,
+        "mul" => tokens.binop(BinOp::Mul)?,
+        "min" => tokens.binop(BinOp::Min)?,
+        "max" => tokens.binop(BinOp::Max)?,
+        "mod" => tokens.binop(BinOp::Mod)?,
+
+        "fma" => Parsed::Fma([tokens.arg()?, tokens.arg()?, tokens.arg()?]),
+        "clamp" => tokens.ternop(TernOp::Clamp)?,
+        "lerp" => tokens.ternop(TernOp::Lerp)?,
+
+        "include" => return Err(Error::IncludeNotAllowed),
+        op => return Err(Error::UnknownOp(op.to_string())),
+    };
+
+    tokens.empty()?;
+    Ok(Some((out, inst)))
 }
 
-struct Tokens<'a, I, S: InstSink> {
-    names: &'a HashMap<String, S::Idx>,
-    tokens: I,
+/// An instruction as written in program text, before it's pushed into a
+/// sink, with arguments of whatever type the caller's names resolve to.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Parsed<I> {
+    Const(Const),
+    Var(Var),
+    UnOp(UnOp, I),
+    BinOp(BinOp, [I; 2]),
+    Fma([I; 3]),
+    TernOp(TernOp, [I; 3]),
 }
 
-impl<'a, I: Iterator<Item = &'a str>, S: InstSink> Tokens<'a, I, S> {
+impl<I> Parsed<I> {
+    pub fn args(&self) -> &[I] {
+        match self {
+            Parsed::Const(_) | Parsed::Var(_) => &[],
+            Parsed::UnOp(_, arg) => std::slice::from_ref(arg),
+            Parsed::BinOp(_, args) => args,
+            Parsed::Fma(args) => args,
+            Parsed::TernOp(_, args) => args,
+        }
+    }
+}
+
+fn parse_const(token: &str) -> Result<Const> {
+    let value = if let Some(bits) = token.strip_prefix("0x") {
+        f32::from_bits(u32::from_str_radix(bits, 16)?)
+    } else {
+        token.parse()?
+    };
+    if !value.is_finite() {
+        return Err(Error::NonFiniteConst);
+    }
+    Ok(Const::new(value))
+}
+
+struct Tokens<T, F> {
+    lookup: F,
+    tokens: T,
+}
+
+impl<'a, T: Iterator<Item = &'a str>, I, F: Fn(&str) -> Option<I>> Tokens<T, F> {
     fn next(&mut self) -> Result<&'a str> {
         self.tokens.next().ok_or(Error::MissingToken)
     }
 
-    fn arg(&mut self) -> Result<S::Idx> {
+    fn arg(&mut self) -> Result<I> {
         let name = self.next()?;
-        self.names
-            .get(name)
-            .ok_or_else(|| Error::UndefinedName(name.to_string()))
-            .copied()
+        (self.lookup)(name).ok_or_else(|| Error::UndefinedName(name.to_string()))
     }
 
-    fn unop(&mut self, op: UnOp, sink: &mut S) -> Result<S::Idx> {
-        Ok(sink.push_unop(op, self.arg()?))
+    fn binop(&mut self, op: BinOp) -> Result<Parsed<I>> {
+        Ok(Parsed::BinOp(op, [self.arg()?, self.arg()?]))
     }
 
-    fn binop(&mut self, op: BinOp, sink: &mut S) -> Result<S::Idx> {
-        Ok(sink.push_binop(op, [self.arg()?, self.arg()?]))
+    fn ternop(&mut self, op: TernOp) -> Result<Parsed<I>> {
+        Ok(Parsed::TernOp(op, [self.arg()?, self.arg()?, self.arg()?]))
     }
 
     fn empty(mut self) -> Result<()> {
@@ -148,3 +1104,250 @@ impl<'a, I: Iterator<Item = &'a str>, S: InstSink> Tokens<'a, I, S> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::Insts;
+    use crate::ir::memoize::MemoBuilder;
+
+    #[test]
+    fn test_const_formats_round_trip() {
+        let values = [0.1, -0.0, 1e-30, f32::MIN_POSITIVE / 3.0, f32::MAX];
+        for format in ConstFormat::value_variants() {
+            let mut text = Vec::new();
+            let insts = values.iter().map(|&v| Inst::Const {
+                value: Const::new(v),
+            });
+            write_with_format(&mut text, insts, *format).unwrap();
+            let parsed = read(&text[..], Insts::default()).unwrap();
+            let parsed: Vec<u32> = parsed
+                .pool
+                .iter()
+                .map(|inst| match inst {
+                    Inst::Const { value } => value.bits(),
+                    _ => unreachable!(),
+                })
+                .collect();
+            let expected: Vec<u32> = values.iter().map(|v| v.to_bits()).collect();
+            assert_eq!(parsed, expected, "{format:?}");
+        }
+    }
+
+    #[test]
+    fn test_minified() {
+        let text = "#!generator test\nfirst var-x # x\nsecond const 0.25\nthird const 1e-20\n\
+                    fourth mul first first\nfifth add fourth second\nsixth add fifth first\n\
+                    seventh max sixth third\n";
+        let insts = read(text.as_bytes(), Insts::default()).unwrap();
+        let mut out = Vec::new();
+        write_minified(&mut out, &insts.pool).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(
+            out,
+            "a var-x\nb const .25\nc const 1e-20\nd mul a a\ne add d b\nf add e a\ng max f c\n"
+        );
+        let reread = read(out.as_bytes(), Insts::default()).unwrap();
+        assert_eq!(reread.pool, insts.pool);
+
+        let names: Vec<String> = [0, 61, 62, 62 + 62 * 62].map(short_name).into();
+        assert_eq!(names, ["a", "9", "aa", "aaa"]);
+    }
+
+    #[test]
+    fn test_lenient() {
+        let text =
+            "x var-x\nbad frobnicate x\ny add x x x\nz mul x bad\nx neg x\nc const 2\nm mul x c\n";
+        assert!(matches!(
+            read(text.as_bytes(), Insts::default()),
+            Err(Error::UnknownOp(_))
+        ));
+        let (insts, _, diagnostics) = read_lenient(text.as_bytes(), Insts::default()).unwrap();
+        let lines: Vec<usize> = diagnostics.iter().map(|d| d.line).collect();
+        assert_eq!(lines, [2, 3, 4, 5]);
+        assert_eq!(insts.pool.len(), 3);
+    }
+
+    #[test]
+    fn test_metadata_round_trip() {
+        let text = "#!generator test 1.0\n# not metadata\nx var-x #!ignored\n#!units mm\n\
+            #!note a\rb\r\n";
+        let (insts, metadata) = read_with_metadata(text.as_bytes(), Insts::default()).unwrap();
+        assert_eq!(metadata.get("generator"), Some("test 1.0"));
+        assert_eq!(metadata.get("units"), Some("mm"));
+        assert_eq!(metadata.get("note"), Some("ab"));
+        assert_eq!(metadata.iter().count(), 3);
+
+        let mut out = Vec::new();
+        metadata.write(&mut out).unwrap();
+        write(&mut out, insts.pool).unwrap();
+        let (_, reread) = read_with_metadata(&out[..], Insts::default()).unwrap();
+        assert_eq!(reread, metadata);
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = std::env::temp_dir().join(format!("include-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("parts")).unwrap();
+        let write = |name: &str, text: &str| std::fs::write(dir.join(name), text).unwrap();
+        write(
+            "parts/disc.vm",
+            "x var-x\ny var-y\nsq include square.vm\nr2 add sq.x2 sq.y2\nr sqrt r2\n",
+        );
+        write(
+            "parts/square.vm",
+            "x var-x\ny var-y\nx2 square x\ny2 square y\n",
+        );
+        write(
+            "scene.vm",
+            "#!units mm\n# two names from the same file don't clash\n\
+             a include \"parts/disc.vm\"\nb include parts/disc.vm\n\
+             one const 1\nout sub one a\nboth add out b.sq.x2\n",
+        );
+        let (insts, metadata) = read_file(&dir.join("scene.vm"), Insts::default()).unwrap();
+        assert_eq!(metadata.get("units"), Some("mm"));
+        let flat = "x var-x\ny var-y\nx2 square x\ny2 square y\nr2 add x2 y2\nr sqrt r2\n\
+            one const 1\nout sub one r\nboth add out x2\n";
+        let flat = read(flat.as_bytes(), Insts::default()).unwrap();
+        let eval = |insts: &Insts| crate::ir::interp::interp_image(insts, 5).pixels;
+        assert_eq!(eval(&insts), eval(&flat));
+
+        // errors say where they are, and includes only work from files
+        let error = |name: &str, text: &str| {
+            write(name, text);
+            read_file(&dir.join(name), Insts::default())
+                .err()
+                .unwrap()
+                .to_string()
+        };
+        assert!(
+            error("bad.vm", "a include parts/disc.vm\nb neg a.x2\n")
+                .ends_with("bad.vm:2: argument uses undefined name \"a.x2\"")
+        );
+        assert!(error("bad.vm", "me include bad.vm\n").contains("includes itself"));
+        assert!(error("parts/bad.vm", "up include ../scene.vm\n").contains("outside"));
+        assert!(matches!(
+            read(&b"a include parts/disc.vm\n"[..], Insts::default()),
+            Err(Error::IncludeNotAllowed)
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_outputs() {
+        let text = "x var-x\ny var-y\nx2 square x\ny2 square y\nr2 add x2 y2\n\
+            r sqrt r2\nhalf const 0.5\ndisc sub half r\nband sub r2 half\n\
+            output disc disc\noutput band band # commented\n";
+        let (insts, outputs) = read_with_outputs(text.as_bytes(), Insts::default()).unwrap();
+        let names: Vec<&str> = outputs.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["disc", "band"]);
+        // the first output is copied to the end as the result
+        assert_eq!(insts.pool.len(), 10);
+        assert_eq!(outputs[0].1.idx(), 9);
+        assert_eq!(outputs[1].1.idx(), 8);
+        let disc = read(
+            &text.as_bytes()[..text.find("band").unwrap()],
+            Insts::default(),
+        );
+        let eval = |insts: &Insts| crate::ir::interp::interp_image(insts, 5).pixels;
+        assert_eq!(eval(&insts), eval(&disc.unwrap()));
+
+        // writing and reading back doesn't add another copy
+        let mut out = Vec::new();
+        write_with_outputs(&mut out, insts.pool.iter().cloned(), &outputs).unwrap();
+        let (reread, reread_outputs) = read_with_outputs(&out[..], Insts::default()).unwrap();
+        assert_eq!(reread.pool, insts.pool);
+        assert_eq!(reread_outputs, outputs);
+
+        // programs from before outputs existed can still name a value that
+        let (insts, outputs) =
+            read_with_outputs(&b"x var-x\noutput neg x\n"[..], Insts::default()).unwrap();
+        assert!(outputs.is_empty() && insts.pool.len() == 2);
+        assert!(matches!(
+            read(&b"x var-x\noutput a x\noutput a x\n"[..], Insts::default()),
+            Err(Error::RedefinedOutput(_))
+        ));
+    }
+
+    #[test]
+    fn test_views() {
+        let text = "view x 0 100 # mm\nview y -5 5\nx var-x\ny var-y\nout sub y x\n";
+        let insts = read(text.as_bytes(), Insts::default()).unwrap();
+        let plain = "x var-x\nhalf const 50\nxs mul x half\nmid const 50\nxm add xs mid\n\
+            y var-y\nfive const 5\nym mul y five\nout sub ym xm\n";
+        let plain = read(plain.as_bytes(), Insts::default()).unwrap();
+        // each view costs a `mul` and an `add`, except that y is centered,
+        // so it's only scaled
+        assert_eq!(insts.pool, plain.pool);
+
+        // views are already applied, so they're written back as metadata,
+        // which reads back without scaling anything again
+        let (insts, metadata) = read_with_metadata(text.as_bytes(), Insts::default()).unwrap();
+        assert_eq!(metadata.get("view-x"), Some("0 100"));
+        assert_eq!(metadata.get("view-y"), Some("-5 5"));
+        let mut out = Vec::new();
+        metadata.write(&mut out).unwrap();
+        write(&mut out, insts.pool.iter().cloned()).unwrap();
+        let (reread, remetadata) = read_with_metadata(&out[..], Insts::default()).unwrap();
+        assert_eq!(reread.pool, insts.pool);
+        assert_eq!(remetadata, metadata);
+
+        let unit = read(&b"view z -1 1\nz var-z\n"[..], Insts::default()).unwrap();
+        assert_eq!(unit.pool.len(), 1);
+        let err = |text: &str| read(text.as_bytes(), Insts::default()).err().unwrap();
+        assert!(matches!(err("x var-x\nview x 0 1\n"), Error::LateView('x')));
+        assert!(matches!(
+            err("view y 0 1\nview y 0 2\ny var-y\n"),
+            Error::RedefinedView('y')
+        ));
+        assert!(matches!(err("view x 1 1\nx var-x\n"), Error::EmptyView));
+    }
+
+    #[test]
+    fn test_annotated_cross_references() {
+        let text = "x var-x\ny var-y\na square x\nb add a y\n";
+        let memoized = read(text.as_bytes(), MemoBuilder::new()).unwrap();
+        let mut out = Vec::new();
+        write_memoized_annotated(&mut out, &memoized, ConstFormat::default()).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("v0 load x 0  # input x\n"));
+        assert!(out.contains("# store v1 x:1 -> loaded by xy v0\n"));
+        assert!(out.contains("v0 load x 1  # x v1\n"));
+        assert!(out.contains("-> final result\n"));
+    }
+
+    #[test]
+    fn test_checkpoint_round_trip() {
+        let memoized = crate::corpus::shape("fenced")
+            .unwrap()
+            .read(MemoBuilder::new());
+        let mut text = Vec::new();
+        write_checkpoint(&mut text, &memoized).unwrap();
+        let reread = read_checkpoint(&text[..]).unwrap();
+        assert_eq!(reread.consts, memoized.consts);
+        for (func, expected) in reread.funcs.iter().zip(&memoized.funcs) {
+            assert_eq!(func.vars, expected.vars);
+            assert_eq!(func.insts, expected.insts);
+            assert_eq!(func.ranges, expected.ranges);
+            assert_eq!(func.outputs, expected.outputs);
+        }
+
+        // every function has to be there, and every load has to find a value
+        let text = String::from_utf8(text).unwrap();
+        let cut = text.find("func xyz").unwrap();
+        assert!(matches!(
+            read_checkpoint(&text.as_bytes()[..cut]),
+            Err(Error::MissingFunc(name)) if name == "xyz"
+        ));
+        let text = text.replace("load const 0", "load const 99");
+        assert!(matches!(
+            read_checkpoint(text.as_bytes()),
+            Err(Error::UnstoredLoad(_, 99))
+        ));
+        let error = read_checkpoint(&b"const 1\nfunc y\n"[..]).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "line 2: malformed checkpoint line \"func y\""
+        );
+    }
+}
@@ -1,7 +1,33 @@
+#[cfg(not(feature = "std"))]
+use alloc::{collections::BTreeMap as HashMap, vec, vec::Vec};
+#[cfg(feature = "std")]
 use std::collections::HashMap;
 
-use super::{BinOp, Const, Inst, InstIdx, InstSink, Location, UnOp, Var, VarSet};
+use super::interval::Interval;
+use super::{BinOp, Const, Inst, InstIdx, InstSink, Location, TernOp, UnOp, Var, VarSet};
 
+// The view runs from -1 to 1, but supersampling reaches up to half a pixel
+// past its edges, which is a whole unit for a two-pixel image.
+const VIEW_EXTENT: f32 = 2.0;
+
+/// Ranges of x, y, and z everywhere this crate evaluates programs: x and y
+/// cover the render view from -1 to 1 with room to spare, and z is always
+/// unconstrained.
+pub const VIEW: [Interval; 3] = [
+    Interval {
+        lo: -VIEW_EXTENT,
+        hi: VIEW_EXTENT,
+    },
+    Interval {
+        lo: -VIEW_EXTENT,
+        hi: VIEW_EXTENT,
+    },
+    Interval::EVERYTHING,
+];
+
+/// A program split into one function per set of variables. Like
+/// [`Insts`](super::Insts), it has no interior mutability and is `Send + Sync`.
+#[derive(Clone)]
 pub struct Memoized {
     pub consts: Vec<Const>,
     pub funcs: [MemoizedFunc; VarSet::ALL.idx()],
@@ -21,17 +47,168 @@ impl Default for Memoized {
     }
 }
 
-#[derive(Default)]
+impl Memoized {
+    // Drop every output that no function loads, except the final result in
+    // `result`, along with any instructions that only fed those outputs. A
+    // function is only loaded from by functions of strictly more variables,
+    // which come after it in `funcs`, so walking backward sees every surviving
+    // load of a function's outputs before deciding which of them to keep.
+    fn eliminate_dead_stores(&mut self, result: (VarSet, Location)) {
+        let mut relocated: [Vec<Option<Location>>; VarSet::ALL.idx()] = Default::default();
+        for func_idx in (0..self.funcs.len()).rev() {
+            let (func, consumers) = self.funcs[func_idx..].split_first_mut().unwrap();
+            // empty outputs are input coordinates, filled in by the caller
+            let mut live: Vec<bool> = func.outputs.iter().map(Option::is_none).collect();
+            if result.0 == func.vars {
+                live[usize::from(result.1)] = true;
+            }
+            for inst in consumers.iter().flat_map(|consumer| &consumer.insts) {
+                if let &Inst::Load { vars, loc } = inst
+                    && vars == func.vars
+                {
+                    live[usize::from(loc)] = true;
+                }
+            }
+            relocated[func_idx] = func.retain_outputs(&live);
+        }
+
+        for func in self.funcs.iter_mut() {
+            for inst in func.insts.iter_mut() {
+                if let Inst::Load { vars, loc } = inst
+                    && let Some(from) = vars.idx().checked_sub(1)
+                {
+                    *loc = relocated[from][usize::from(*loc)].unwrap();
+                }
+            }
+        }
+    }
+
+    /// Sort the constant pool by value, dropping duplicates, and each
+    /// function's outputs after location 0 by a hash of the expression that
+    /// computes them. Neither order depends on the order that passes happened
+    /// to push instructions in, so code generated afterward only changes
+    /// where the program itself does. Hash collisions just leave outputs in
+    /// their original order.
+    pub fn canonicalize(&mut self) {
+        let mut order: Vec<usize> = (0..self.consts.len()).collect();
+        order.sort_by_key(|&loc| self.consts[loc].bits());
+        let mut consts: Vec<Const> = Vec::with_capacity(order.len());
+        let mut const_locs: Vec<Location> = vec![0; order.len()];
+        for loc in order {
+            let value = self.consts[loc];
+            if consts.last() != Some(&value) {
+                consts.push(value);
+            }
+            const_locs[loc] = (consts.len() - 1).try_into().unwrap();
+        }
+        self.consts = consts;
+
+        // each function's new location for each old one, and the hash of
+        // what's stored at each new location
+        let mut relocated: [Vec<Location>; VarSet::ALL.idx()] = Default::default();
+        let mut output_hashes: [Vec<u64>; VarSet::ALL.idx()] = Default::default();
+        for func_idx in 0..self.funcs.len() {
+            let func = &mut self.funcs[func_idx];
+            let mut hashes: Vec<u64> = Vec::with_capacity(func.insts.len());
+            for inst in func.insts.iter_mut() {
+                let hash = match inst {
+                    Inst::Load { vars, loc } => {
+                        let hash = match vars.idx().checked_sub(1) {
+                            None => {
+                                *loc = const_locs[usize::from(*loc)];
+                                self.consts[usize::from(*loc)].bits().into()
+                            }
+                            // the function's own input coordinate, which
+                            // stays in location 0
+                            Some(from) if from == func_idx => u64::from(*loc),
+                            Some(from) => {
+                                *loc = relocated[from][usize::from(*loc)];
+                                output_hashes[from][usize::from(*loc)]
+                            }
+                        };
+                        mix(&[0, vars.idx() as u64, hash])
+                    }
+                    Inst::Var { var } => mix(&[1, *var as u64]),
+                    Inst::Const { value } => mix(&[2, value.bits().into()]),
+                    Inst::UnOp { op, arg } => mix(&[3, unop_word(*op), hashes[arg.idx()]]),
+                    Inst::BinOp { op, args } => {
+                        let [a, b] = args.map(|arg| hashes[arg.idx()]);
+                        mix(&[4, *op as u64, a, b])
+                    }
+                    Inst::Fma { args } => {
+                        let [a, b, c] = args.map(|arg| hashes[arg.idx()]);
+                        mix(&[5, a, b, c])
+                    }
+                    Inst::TernOp { op, args } => {
+                        let [a, b, c] = args.map(|arg| hashes[arg.idx()]);
+                        mix(&[6, *op as u64, a, b, c])
+                    }
+                };
+                hashes.push(hash);
+            }
+
+            let hash = |output: Option<InstIdx>| output.map_or(0, |def| hashes[def.idx()]);
+            let mut order: Vec<usize> = (0..func.outputs.len()).collect();
+            if let Some(rest) = order.get_mut(1..) {
+                rest.sort_by_key(|&loc| hash(func.outputs[loc]));
+            }
+            relocated[func_idx] = vec![0; order.len()];
+            for (new, &old) in order.iter().enumerate() {
+                relocated[func_idx][old] = new.try_into().unwrap();
+            }
+            func.outputs = order.iter().map(|&old| func.outputs[old]).collect();
+            output_hashes[func_idx] = func.outputs.iter().map(|&output| hash(output)).collect();
+        }
+    }
+}
+
+// Each `UnOp` by its position in the declaration, as its discriminant was
+// before `powi` came along with a power to hash too, so hashes of programs
+// without it don't change.
+fn unop_word(op: UnOp) -> u64 {
+    match op {
+        UnOp::Neg => 0,
+        UnOp::Square => 1,
+        UnOp::Sqrt => 2,
+        UnOp::Barrier => 3,
+        UnOp::Abs => 4,
+        UnOp::Exp => 5,
+        UnOp::Ln => 6,
+        UnOp::Sin => 7,
+        UnOp::Cos => 8,
+        UnOp::Floor => 9,
+        UnOp::Ceil => 10,
+        UnOp::Round => 11,
+        UnOp::Recip => 12,
+        UnOp::Rsqrt => 13,
+        UnOp::PowI(n) => 14 | (u64::from(n as u32) << 32),
+    }
+}
+
+// FNV-1a, a word at a time, which unlike the standard library's hashers is
+// guaranteed to give the same results from one build to the next.
+fn mix(words: &[u64]) -> u64 {
+    words.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &word| {
+        (hash ^ word).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[derive(Clone, Default)]
 pub struct MemoizedFunc {
     pub vars: VarSet,
     pub insts: Vec<Inst>,
+    /// Bounds on the value of each instruction in `insts` over the variable
+    /// ranges the builder was given, which backends may use to pick cheaper
+    /// code; see [`VIEW`].
+    pub ranges: Vec<Interval>,
     pub outputs: Vec<Option<InstIdx>>,
 }
 
 impl MemoizedFunc {
-    fn push(&mut self, inst: Inst) -> InstIdx {
+    fn push(&mut self, inst: Inst, range: Interval) -> InstIdx {
         let idx = InstIdx::try_from(self.insts.len()).unwrap();
         self.insts.push(inst);
+        self.ranges.push(range);
         idx
     }
 
@@ -40,13 +217,69 @@ impl MemoizedFunc {
         self.outputs.push(Some(def));
         idx
     }
+
+    // Keep only the outputs whose entry in `live` is set, renumbering them in
+    // order, and then only the instructions those outputs depend on. Returns
+    // each old location's new location.
+    fn retain_outputs(&mut self, live: &[bool]) -> Vec<Option<Location>> {
+        let mut relocated = vec![None; self.outputs.len()];
+        let mut outputs = Vec::new();
+        for (loc, &output) in self.outputs.iter().enumerate() {
+            if live[loc] {
+                relocated[loc] = Some(outputs.len().try_into().unwrap());
+                outputs.push(output);
+            }
+        }
+
+        let mut needed = vec![false; self.insts.len()];
+        for def in outputs.iter().flatten() {
+            needed[def.idx()] = true;
+        }
+        for idx in (0..self.insts.len()).rev() {
+            if needed[idx] {
+                for arg in self.insts[idx].args() {
+                    needed[arg.idx()] = true;
+                }
+            }
+        }
+
+        let insts = core::mem::take(&mut self.insts);
+        let ranges = core::mem::take(&mut self.ranges);
+        let mut map = vec![None; insts.len()];
+        for (idx, (mut inst, range)) in insts.into_iter().zip(ranges).enumerate() {
+            if needed[idx] {
+                for arg in inst.args_mut() {
+                    *arg = map[arg.idx()].unwrap();
+                }
+                map[idx] = Some(self.push(inst, range));
+            }
+        }
+        self.outputs = outputs
+            .into_iter()
+            .map(|output| output.map(|def| map[def.idx()].unwrap()))
+            .collect();
+        relocated
+    }
 }
 
-#[derive(Default)]
 pub struct MemoBuilder {
     result: Memoized,
+    var_ranges: [Interval; 3],
+    inline_cost: u16,
     load: [HashMap<MemoIdx, InstIdx>; VarSet::ALL.idx()],
     store: [Vec<Location>; VarSet::ALL.idx()],
+    recipes: [Vec<Option<(Recipe, u16)>>; VarSet::ALL.idx()],
+}
+
+// How to compute an instruction again in another function, from the values
+// it was originally built from. Alongside each one, `MemoBuilder` keeps its
+// cost: the number of instructions in its function that would need copying.
+#[derive(Clone, Copy)]
+enum Recipe {
+    UnOp(UnOp, MemoIdx),
+    BinOp(BinOp, [MemoIdx; 2]),
+    Fma([MemoIdx; 3]),
+    TernOp(TernOp, [MemoIdx; 3]),
 }
 
 #[derive(Clone, Copy, Eq, Hash, Ord, PartialEq, PartialOrd)]
@@ -76,24 +309,75 @@ impl InstSink for MemoBuilder {
     }
 
     fn push_unop(&mut self, op: UnOp, arg: Self::Idx) -> Self::Idx {
-        let vars = arg.vars;
+        // Barriers only constrain the optimization passes that run before
+        // memoization, so there's no need to keep them any longer, and the
+        // backends only see the squares and products a `powi` stands for.
+        match op {
+            UnOp::Barrier => return arg,
+            UnOp::PowI(n) => return UnOp::expand_powi(self, arg, n),
+            _ => {}
+        }
+        if let Some(folded) = self.fold([arg], |[a]| op.eval(a)) {
+            return folded;
+        }
+        let vars = compute_vars(arg.vars);
+        let range = self.range(arg).unop(op);
+        let recipe = Recipe::UnOp(op, arg);
         let arg = self.ensure_load(vars, arg);
-        self.push(vars, Inst::UnOp { op, arg })
+        self.push(vars, Inst::UnOp { op, arg }, range, Some(recipe))
     }
 
     fn push_binop(&mut self, op: BinOp, [a, b]: [Self::Idx; 2]) -> Self::Idx {
-        let vars = a.vars | b.vars;
+        // the same canonical form as `Simplify` uses, for programs that
+        // skipped it, which also gives the result a non-negative range
+        if op == BinOp::Mul && a == b {
+            return self.push_unop(UnOp::Square, a);
+        }
+        if let Some(folded) = self.fold([a, b], |args| op.eval(args)) {
+            return folded;
+        }
+        let vars = compute_vars(a.vars | b.vars);
+        let range = self.range(a).binop(op, self.range(b));
+        let recipe = Recipe::BinOp(op, [a, b]);
         let args = [a, b].map(|arg| self.ensure_load(vars, arg));
-        self.push(vars, Inst::BinOp { op, args })
+        self.push(vars, Inst::BinOp { op, args }, range, Some(recipe))
+    }
+
+    fn push_fma(&mut self, [a, b, c]: [Self::Idx; 3]) -> Self::Idx {
+        if let Some(folded) = self.fold([a, b, c], |[a, b, c]| super::mul_add(a, b, c)) {
+            return folded;
+        }
+        let vars = compute_vars(a.vars | b.vars | c.vars);
+        let range = self.range(a).fma(self.range(b), self.range(c));
+        let recipe = Recipe::Fma([a, b, c]);
+        let args = [a, b, c].map(|arg| self.ensure_load(vars, arg));
+        self.push(vars, Inst::Fma { args }, range, Some(recipe))
+    }
+
+    fn push_ternop(&mut self, op: TernOp, [a, b, c]: [Self::Idx; 3]) -> Self::Idx {
+        if let Some(folded) = self.fold([a, b, c], |args| op.eval(args)) {
+            return folded;
+        }
+        let vars = compute_vars(a.vars | b.vars | c.vars);
+        let range = self.range(a).ternop(op, self.range(b), self.range(c));
+        let recipe = Recipe::TernOp(op, [a, b, c]);
+        let args = [a, b, c].map(|arg| self.ensure_load(vars, arg));
+        self.push(vars, Inst::TernOp { op, args }, range, Some(recipe))
     }
 
     fn push_load(&mut self, _vars: VarSet, _loc: Location) -> Self::Idx {
         unimplemented!()
     }
 
-    fn finish(mut self, last: Self::Idx) -> Self::Output {
-        self.result.funcs[func_for(last.vars)].add_output(last.idx.unwrap());
-        self.result
+    fn finish(self, last: Self::Idx) -> Self::Output {
+        let range = self.range(last);
+        complete(self.result, last, range)
+    }
+}
+
+impl Default for MemoBuilder {
+    fn default() -> Self {
+        Self::with_var_ranges(VIEW)
     }
 }
 
@@ -102,50 +386,222 @@ impl MemoBuilder {
         Self::default()
     }
 
-    fn ensure_load(&mut self, vars: VarSet, arg: MemoIdx) -> InstIdx {
-        let loc = if let Some(idx) = arg.idx {
-            if arg.vars == vars {
-                return idx;
-            }
-            if let Some(arg_func) = arg.vars.idx().checked_sub(1) {
-                let location = &mut self.store[arg_func][idx.idx()];
-                if *location == Location::MAX {
-                    *location = self.result.funcs[arg_func].add_output(idx);
-                }
-                *location
-            } else {
-                idx.idx().try_into().unwrap()
+    /// Memoize a program which will be evaluated with each variable only
+    /// taking values in the given range, in `Var` order.
+    pub fn with_var_ranges(var_ranges: [Interval; 3]) -> Self {
+        MemoBuilder {
+            result: Memoized::default(),
+            var_ranges,
+            inline_cost: 0,
+            load: Default::default(),
+            store: Default::default(),
+            recipes: Default::default(),
+        }
+    }
+
+    /// Compute values again in each function that uses them, instead of
+    /// storing them in the function that computed them and loading them back,
+    /// when that takes at most `cost` instructions. Storing a cheap value can
+    /// cost more in buffer traffic than recomputing it at every pixel. A value
+    /// that every function recomputes is never stored at all, and each
+    /// function computes it at most once. The default of 0 never recomputes.
+    pub fn with_inline_cost(mut self, cost: u16) -> Self {
+        self.inline_cost = cost;
+        self
+    }
+
+    /// The program memoized so far, as [`finish`](InstSink::finish) would
+    /// return it with `last` as the result, while leaving this builder able
+    /// to accept more instructions.
+    pub fn snapshot(&self, last: MemoIdx) -> Memoized {
+        complete(self.result.clone(), last, self.range(last))
+    }
+
+    fn range(&self, arg: MemoIdx) -> Interval {
+        match (arg.idx, arg.vars.idx().checked_sub(1)) {
+            (None, _) => {
+                let var = arg.vars.into_iter().next().unwrap();
+                self.var_ranges[var as usize]
             }
+            (Some(idx), None) => Interval::point(self.result.consts[idx.idx()].value()),
+            (Some(idx), Some(func_idx)) => self.result.funcs[func_idx].ranges[idx.idx()],
+        }
+    }
+
+    // No function computes operations on constants alone, so they're done
+    // here instead, into the constant pool. This rounds as every backend
+    // does, unlike `Simplify`, which leaves constants alone.
+    fn fold<const N: usize>(
+        &mut self,
+        args: [MemoIdx; N],
+        eval: impl FnOnce([f32; N]) -> f32,
+    ) -> Option<MemoIdx> {
+        if args.iter().any(|arg| arg.vars != VarSet::default()) {
+            return None;
+        }
+        let value = eval(args.map(|arg| self.result.consts[arg.idx.unwrap().idx()].value()));
+        // results no `Const` can hold are left to `compute_vars`
+        value
+            .is_finite()
+            .then(|| self.push_const(Const::new(value)))
+    }
+
+    fn ensure_load(&mut self, vars: VarSet, arg: MemoIdx) -> InstIdx {
+        if let Some(idx) = arg.idx
+            && arg.vars == vars
+        {
+            return idx;
+        }
+        let func_idx = func_for(vars);
+        if let Some(&idx) = self.load[func_idx].get(&arg) {
+            return idx;
+        }
+        let range = self.range(arg);
+        let idx = if let Some(recipe) = self.inlinable(arg) {
+            let inst = match recipe {
+                Recipe::UnOp(op, arg) => Inst::UnOp {
+                    op,
+                    arg: self.ensure_load(vars, arg),
+                },
+                Recipe::BinOp(op, args) => Inst::BinOp {
+                    op,
+                    args: args.map(|arg| self.ensure_load(vars, arg)),
+                },
+                Recipe::Fma(args) => Inst::Fma {
+                    args: args.map(|arg| self.ensure_load(vars, arg)),
+                },
+                Recipe::TernOp(op, args) => Inst::TernOp {
+                    op,
+                    args: args.map(|arg| self.ensure_load(vars, arg)),
+                },
+            };
+            self.push(vars, inst, range, None).idx.unwrap()
         } else {
-            0
+            let loc = self.store_location(arg);
+            let load = Inst::Load {
+                vars: arg.vars,
+                loc,
+            };
+            self.push(vars, load, range, None).idx.unwrap()
         };
-        let func_idx = func_for(vars);
-        *self.load[func_idx].entry(arg).or_insert_with(|| {
-            let vars = arg.vars;
-            self.store[func_idx].push(Location::MAX);
-            self.result.funcs[func_idx].push(Inst::Load { vars, loc })
-        })
+        self.load[func_idx].insert(arg, idx);
+        idx
     }
 
-    fn push(&mut self, vars: VarSet, inst: Inst) -> MemoIdx {
+    // Where a function loads `arg` from in the function, or constant pool,
+    // that has it, storing it there first if it isn't yet.
+    fn store_location(&mut self, arg: MemoIdx) -> Location {
+        let Some(idx) = arg.idx else {
+            return 0;
+        };
+        let Some(arg_func) = arg.vars.idx().checked_sub(1) else {
+            return idx.idx().try_into().unwrap();
+        };
+        let location = &mut self.store[arg_func][idx.idx()];
+        if *location == Location::MAX {
+            *location = self.result.funcs[arg_func].add_output(idx);
+        }
+        *location
+    }
+
+    fn inlinable(&self, arg: MemoIdx) -> Option<Recipe> {
+        let func_idx = arg.vars.idx().checked_sub(1)?;
+        let (recipe, cost) = self.recipes[func_idx][arg.idx?.idx()]?;
+        (cost <= self.inline_cost).then_some(recipe)
+    }
+
+    fn push(
+        &mut self,
+        vars: VarSet,
+        inst: Inst,
+        range: Interval,
+        recipe: Option<Recipe>,
+    ) -> MemoIdx {
         let func_idx = func_for(vars);
+        // copying an instruction also means copying its arguments from the
+        // same function, but loads of other functions' values are free
+        let cost = |arg: MemoIdx| match arg.idx {
+            Some(idx) if arg.vars == vars => self.recipes[func_idx][idx.idx()].map_or(0, |r| r.1),
+            _ => 0,
+        };
+        let cost = match recipe {
+            Some(Recipe::UnOp(_, arg)) => cost(arg).saturating_add(1),
+            Some(Recipe::BinOp(_, [a, b])) => cost(a).saturating_add(cost(b)).saturating_add(1),
+            Some(Recipe::Fma(args) | Recipe::TernOp(_, args)) => args
+                .into_iter()
+                .fold(1, |total: u16, arg| total.saturating_add(cost(arg))),
+            None => 0,
+        };
+        self.recipes[func_idx].push(recipe.map(|recipe| (recipe, cost)));
         self.store[func_idx].push(Location::MAX);
-        let idx = Some(self.result.funcs[func_idx].push(inst));
+        let idx = Some(self.result.funcs[func_idx].push(inst, range));
         MemoIdx { vars, idx }
     }
 }
 
-fn func_for(vars: VarSet) -> usize {
-    if let Some(func_idx) = vars.idx().checked_sub(1) {
-        func_idx
+// Store the final result and clean up after everything else. `range` is the
+// range of `last`.
+fn complete(mut result: Memoized, last: MemoIdx, range: Interval) -> Memoized {
+    // Renderers call a function of both x and y at every pixel and take its
+    // result from there, so a result which is constant, or depends on only
+    // one of them, gets loaded into that function from wherever it is.
+    let vars = last.vars | Var::X.into() | Var::Y.into();
+    let def = match last.idx {
+        Some(idx) if last.vars == vars => idx,
+        _ => {
+            let loc = match (last.idx, last.vars.idx().checked_sub(1)) {
+                (None, _) => 0,
+                (Some(idx), None) => idx.idx().try_into().unwrap(),
+                (Some(idx), Some(func_idx)) => {
+                    let func = &mut result.funcs[func_idx];
+                    match func.outputs.iter().position(|&def| def == Some(idx)) {
+                        Some(loc) => loc.try_into().unwrap(),
+                        None => func.add_output(idx),
+                    }
+                }
+            };
+            let load = Inst::Load {
+                vars: last.vars,
+                loc,
+            };
+            result.funcs[func_for(vars)].push(load, range)
+        }
+    };
+    let loc = result.funcs[func_for(vars)].add_output(def);
+    // Dead code in the input still gets memoized as it arrives, storing
+    // values for functions whose results are never used.
+    result.eliminate_dead_stores((vars, loc));
+    for func in result.funcs.iter() {
+        tracing::debug!(
+            vars = ?func.vars,
+            insts = func.insts.len(),
+            outputs = func.outputs.len(),
+            "memoized function"
+        );
+    }
+    result
+}
+
+// The variables of the function which computes an operation on `vars`. An
+// operation on constants alone that `MemoBuilder::fold` couldn't fold goes in
+// the function of x and y, which renderers call at every pixel anyway.
+fn compute_vars(vars: VarSet) -> VarSet {
+    if vars == VarSet::default() {
+        VarSet::from(Var::X) | Var::Y.into()
     } else {
-        todo!("constant folding");
+        vars
     }
 }
 
+fn func_for(vars: VarSet) -> usize {
+    vars.idx()
+        .checked_sub(1)
+        .expect("constants aren't computed by any function")
+}
+
 #[derive(Default)]
 pub struct UnmemoBuilder {
-    insts: Vec<Inst>,
+    func: MemoizedFunc,
     consts: Vec<Const>,
     vars: VarSet,
 }
@@ -158,21 +614,45 @@ impl InstSink for UnmemoBuilder {
         let loc = self.consts.len().try_into().unwrap();
         self.consts.push(value);
         let vars = VarSet::default();
-        self.push(Inst::Load { vars, loc })
+        self.func
+            .push(Inst::Load { vars, loc }, Interval::point(value.value()))
     }
 
     fn push_var(&mut self, var: Var) -> Self::Idx {
         let vars = var.into();
         self.vars = self.vars | vars;
-        self.push(Inst::Load { vars, loc: 0 })
+        self.func
+            .push(Inst::Load { vars, loc: 0 }, VIEW[var as usize])
     }
 
     fn push_unop(&mut self, op: UnOp, arg: Self::Idx) -> Self::Idx {
-        self.push(Inst::UnOp { op, arg })
+        match op {
+            UnOp::Barrier => return arg,
+            UnOp::PowI(n) => return UnOp::expand_powi(self, arg, n),
+            _ => {}
+        }
+        let range = self.func.ranges[arg.idx()].unop(op);
+        self.func.push(Inst::UnOp { op, arg }, range)
+    }
+
+    fn push_binop(&mut self, op: BinOp, [a, b]: [Self::Idx; 2]) -> Self::Idx {
+        // canonical, as in `MemoBuilder`
+        if op == BinOp::Mul && a == b {
+            return self.push_unop(UnOp::Square, a);
+        }
+        let range = self.func.ranges[a.idx()].binop(op, self.func.ranges[b.idx()]);
+        self.func.push(Inst::BinOp { op, args: [a, b] }, range)
+    }
+
+    fn push_fma(&mut self, args: [Self::Idx; 3]) -> Self::Idx {
+        let [a, b, c] = args.map(|arg| self.func.ranges[arg.idx()]);
+        self.func.push(Inst::Fma { args }, a.fma(b, c))
     }
 
-    fn push_binop(&mut self, op: BinOp, args: [Self::Idx; 2]) -> Self::Idx {
-        self.push(Inst::BinOp { op, args })
+    fn push_ternop(&mut self, op: TernOp, args: [Self::Idx; 3]) -> Self::Idx {
+        let [a, b, c] = args.map(|arg| self.func.ranges[arg.idx()]);
+        self.func
+            .push(Inst::TernOp { op, args }, a.ternop(op, b, c))
     }
 
     fn push_load(&mut self, _vars: VarSet, _loc: Location) -> Self::Idx {
@@ -180,19 +660,171 @@ impl InstSink for UnmemoBuilder {
     }
 
     fn finish(self, last: Self::Idx) -> Self::Output {
-        let mut memoized = Memoized::default();
-        memoized.consts = self.consts;
-        let func = func_for(self.vars);
-        memoized.funcs[func].insts = self.insts;
-        memoized.funcs[func].add_output(last);
+        let mut memoized = Memoized {
+            consts: self.consts,
+            ..Default::default()
+        };
+        // like `MemoBuilder`, put the result where renderers look for it
+        let vars = self.vars | Var::X.into() | Var::Y.into();
+        let func = &mut memoized.funcs[func_for(vars)];
+        func.insts = self.func.insts;
+        func.ranges = self.func.ranges;
+        func.add_output(last);
         memoized
     }
 }
 
-impl UnmemoBuilder {
-    fn push(&mut self, inst: Inst) -> InstIdx {
-        let idx = self.insts.len().try_into().unwrap();
-        self.insts.push(inst);
-        idx
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::ir::bytecode::{MemoizedProgram, interp_memoized};
+    use crate::ir::{Insts, interp, io};
+
+    #[test]
+    fn test_dead_stores_removed() {
+        let mut builder = MemoBuilder::new();
+        let x = builder.push_var(Var::X);
+        let y = builder.push_var(Var::Y);
+        let z = builder.push_var(Var::Z);
+        let xy = builder.push_binop(BinOp::Mul, [x, y]);
+        let x2 = builder.push_unop(UnOp::Square, x);
+        // nothing uses this, but it stores `xy` and `x2` for `xyz`
+        let dead = builder.push_binop(BinOp::Add, [xy, z]);
+        builder.push_binop(BinOp::Add, [dead, x2]);
+        let last = builder.push_binop(BinOp::Add, [xy, x2]);
+        let memoized = builder.finish(last);
+
+        let [x, _, xy, _, _, _, xyz] = &memoized.funcs;
+        assert!(xyz.insts.is_empty() && xyz.outputs.is_empty());
+        // x keeps its input coordinate and the square `xy` still loads
+        assert_eq!(x.outputs.len(), 2);
+        assert_eq!(x.insts.len(), 2);
+        assert_eq!(xy.outputs.len(), 1);
+        assert!(xy.insts.contains(&Inst::Load {
+            vars: Var::X.into(),
+            loc: 1,
+        }));
+    }
+
+    #[test]
+    fn test_inline_cost() {
+        let build = |cost: u16| {
+            let mut builder = MemoBuilder::new().with_inline_cost(cost);
+            let x = builder.push_var(Var::X);
+            let y = builder.push_var(Var::Y);
+            let half = builder.push_const(Const::new(0.5));
+            // costs 1, then 2, to compute again in `xy`
+            let x2 = builder.push_unop(UnOp::Square, x);
+            let shifted = builder.push_binop(BinOp::Sub, [x2, half]);
+            let a = builder.push_binop(BinOp::Mul, [shifted, y]);
+            let last = builder.push_binop(BinOp::Add, [a, x2]);
+            builder.finish(last)
+        };
+
+        let expected = interp_memoized(&build(0), 9);
+        let stored = |memoized: &Memoized| memoized.funcs[0].outputs.len() - 1;
+        assert_eq!(stored(&build(0)), 2);
+        // `x2` is recomputed, once, but `shifted` is still stored
+        let partial = build(1);
+        assert_eq!(stored(&partial), 1);
+        let squares = partial.funcs[2].insts.iter();
+        let squares = squares
+            .filter(|inst| matches!(inst, Inst::UnOp { .. }))
+            .count();
+        assert_eq!(squares, 1);
+        assert_eq!(interp_memoized(&partial, 9), expected);
+        // and then nothing is
+        let inlined = build(2);
+        assert_eq!(stored(&inlined), 0);
+        assert_eq!(interp_memoized(&inlined, 9), expected);
+    }
+
+    #[test]
+    fn test_degenerate_results() {
+        for text in [
+            "c const -0.25\n",
+            "x var-x\n",
+            "x var-x\nh const 0.5\nout sub h x\n",
+            "y var-y\nout neg y\n",
+            "a const 1\nb const 2\nc sub a b\n",
+            "a const 0\nb ln a\nx var-x\nout add x b\n",
+            "a const 2\nb const 3\nc const 4\nd fma a b c\ne clamp d a b\n",
+        ] {
+            let insts = io::read(text.as_bytes(), Insts::default()).unwrap();
+            let expected = interp::interp_image(&insts, 9);
+            for memoized in [
+                io::read(text.as_bytes(), MemoBuilder::new()).unwrap(),
+                io::read(text.as_bytes(), UnmemoBuilder::default()).unwrap(),
+            ] {
+                // the result is always where renderers look for it
+                let xy = &memoized.funcs[2];
+                assert_eq!(xy.outputs.len(), 1, "{text}");
+                assert_eq!(interp_memoized(&memoized, 9), expected, "{text}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_square_products() {
+        let text = "x var-x\ny var-y\nxx mul x x\nyy mul y y\nout add xx yy\n";
+        let is_square = |inst: &Inst| {
+            matches!(
+                inst,
+                Inst::UnOp {
+                    op: UnOp::Square,
+                    ..
+                }
+            )
+        };
+        let memoized = io::read(text.as_bytes(), MemoBuilder::new()).unwrap();
+        for func in &memoized.funcs[..2] {
+            let square = func.insts.iter().position(is_square).unwrap();
+            // where a product of [-1, 1] with itself would go down to -1
+            assert_eq!(func.ranges[square].lo, 0.0);
+        }
+        let unmemoized = io::read(text.as_bytes(), UnmemoBuilder::default()).unwrap();
+        assert_eq!(
+            unmemoized.funcs[2]
+                .insts
+                .iter()
+                .filter(|i| is_square(i))
+                .count(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_canonical_order() {
+        // the same program, but computing and storing `x * 2` and `x * 3`
+        // in either order
+        let build = |swap: bool| {
+            let mut builder = MemoBuilder::new();
+            let x = builder.push_var(Var::X);
+            let y = builder.push_var(Var::Y);
+            let mut scaled = |factor| {
+                let factor = builder.push_const(Const::new(factor));
+                builder.push_binop(BinOp::Mul, [x, factor])
+            };
+            let (two, three) = if swap {
+                let three = scaled(3.0);
+                (scaled(2.0), three)
+            } else {
+                let two = scaled(2.0);
+                (two, scaled(3.0))
+            };
+            let product = builder.push_binop(BinOp::Mul, [two, y]);
+            let last = builder.push_binop(BinOp::Add, [product, three]);
+            builder.finish(last)
+        };
+
+        let expected = interp_memoized(&build(false), 9);
+        let [mut a, mut b] = [false, true].map(build);
+        assert_ne!(a.consts, b.consts);
+        a.canonicalize();
+        b.canonicalize();
+        assert_eq!(a.consts, b.consts);
+        let column = |memoized: &Memoized| MemoizedProgram::compile(memoized).column(0.5);
+        assert_eq!(column(&a), column(&b));
+        assert_eq!(interp_memoized(&b, 9), expected);
     }
 }
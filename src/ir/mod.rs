@@ -1,14 +1,44 @@
-use std::fmt;
-use std::hash::Hash;
-use std::num::{NonZeroU16, TryFromIntError};
-use std::ops::BitOr;
-
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+use core::fmt;
+use core::hash::Hash;
+use core::num::{NonZeroU16, TryFromIntError};
+use core::ops::BitOr;
+
+#[cfg(feature = "std")]
+pub mod accuracy;
+#[cfg(feature = "std")]
+pub mod bounds;
+pub mod bytecode;
+#[cfg(feature = "std")]
+pub mod combine;
+#[cfg(feature = "std")]
+pub mod count;
+#[cfg(feature = "std")]
+pub mod edit;
+#[cfg(feature = "std")]
+pub mod expr;
+#[cfg(feature = "std")]
+pub mod fuse;
+#[cfg(feature = "std")]
 pub mod interp;
+pub mod interval;
+#[cfg(feature = "std")]
 pub mod io;
 pub mod memoize;
+#[cfg(feature = "std")]
+pub mod polygon;
+pub mod powi;
+#[cfg(feature = "std")]
 pub mod reassociate;
+#[cfg(feature = "std")]
 pub mod reorder;
+#[cfg(feature = "std")]
 pub mod simplify;
+#[cfg(feature = "std")]
+pub mod split;
+#[cfg(feature = "std")]
+pub mod tee;
 
 #[derive(Clone, Copy, Default, Eq, Hash, PartialEq)]
 pub struct Const(u32);
@@ -45,6 +75,19 @@ pub enum UnOp {
     Neg,
     Square,
     Sqrt,
+    Barrier,
+    Abs,
+    Exp,
+    Ln,
+    Sin,
+    Cos,
+    Floor,
+    Ceil,
+    Round,
+    Recip,
+    Rsqrt,
+    /// Raise to a whole power, as [`powi`] does.
+    PowI(i32),
 }
 
 impl UnOp {
@@ -53,8 +96,64 @@ impl UnOp {
             UnOp::Neg => "neg",
             UnOp::Square => "square",
             UnOp::Sqrt => "sqrt",
+            UnOp::Barrier => "barrier",
+            UnOp::Abs => "abs",
+            UnOp::Exp => "exp",
+            UnOp::Ln => "ln",
+            UnOp::Sin => "sin",
+            UnOp::Cos => "cos",
+            UnOp::Floor => "floor",
+            UnOp::Ceil => "ceil",
+            UnOp::Round => "round",
+            UnOp::Recip => "recip",
+            UnOp::Rsqrt => "rsqrt",
+            UnOp::PowI(_) => "powi",
         }
     }
+
+    /// The value every backend computes for this operator, unless it's asked
+    /// to approximate.
+    pub fn eval(self, a: f32) -> f32 {
+        match self {
+            UnOp::Neg => -a,
+            UnOp::Square => a * a,
+            UnOp::Sqrt => sqrt(a),
+            UnOp::Barrier => a,
+            UnOp::Abs => a.abs(),
+            UnOp::Exp => exp(a),
+            UnOp::Ln => ln(a),
+            UnOp::Sin => sin(a),
+            UnOp::Cos => cos(a),
+            UnOp::Floor => floor(a),
+            UnOp::Ceil => ceil(a),
+            UnOp::Round => round(a),
+            UnOp::Recip => 1.0 / a,
+            UnOp::Rsqrt => rsqrt(a),
+            UnOp::PowI(n) => powi(a, n),
+        }
+    }
+
+    /// Push the `square` and `mul` instructions, and the `recip` for a
+    /// negative power, which [`powi`] rounds after, for a sink or backend
+    /// with no instruction of its own for it. Other powers that share a
+    /// prefix of the chain share those instructions too.
+    pub fn expand_powi<S: InstSink>(sink: &mut S, arg: S::Idx, n: i32) -> S::Idx {
+        let power = n.unsigned_abs();
+        if power == 0 {
+            return sink.push_const(Const::new(1.0));
+        }
+        let mut result = arg;
+        for bit in (0..power.ilog2()).rev() {
+            result = sink.push_unop(UnOp::Square, result);
+            if power >> bit & 1 != 0 {
+                result = sink.push_binop(BinOp::Mul, [result, arg]);
+            }
+        }
+        if n < 0 {
+            result = sink.push_unop(UnOp::Recip, result);
+        }
+        result
+    }
 }
 
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
@@ -64,6 +163,7 @@ pub enum BinOp {
     Mul,
     Min,
     Max,
+    Mod,
 }
 
 impl BinOp {
@@ -74,6 +174,7 @@ impl BinOp {
             BinOp::Mul => "mul",
             BinOp::Min => "min",
             BinOp::Max => "max",
+            BinOp::Mod => "mod",
         }
     }
 
@@ -84,6 +185,57 @@ impl BinOp {
             BinOp::Mul => true,
             BinOp::Min => true,
             BinOp::Max => true,
+            BinOp::Mod => false,
+        }
+    }
+
+    pub fn eval(self, [a, b]: [f32; 2]) -> f32 {
+        match self {
+            BinOp::Add => a + b,
+            BinOp::Sub => a - b,
+            BinOp::Mul => a * b,
+            BinOp::Min => a.min(b),
+            BinOp::Max => a.max(b),
+            BinOp::Mod => modulo(a, b),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum TernOp {
+    Clamp,
+    Lerp,
+}
+
+impl TernOp {
+    pub fn name(self) -> &'static str {
+        match self {
+            TernOp::Clamp => "clamp",
+            TernOp::Lerp => "lerp",
+        }
+    }
+
+    pub fn eval(self, [a, b, c]: [f32; 3]) -> f32 {
+        match self {
+            TernOp::Clamp => clamp(a, b, c),
+            TernOp::Lerp => lerp(a, b, c),
+        }
+    }
+
+    /// Push the binary operations this stands for, rounding exactly as
+    /// [`eval`](Self::eval) does, for a sink or backend with no instruction
+    /// of its own for it.
+    pub fn expand<S: InstSink>(self, sink: &mut S, [a, b, c]: [S::Idx; 3]) -> S::Idx {
+        match self {
+            TernOp::Clamp => {
+                let max = sink.push_binop(BinOp::Max, [a, b]);
+                sink.push_binop(BinOp::Min, [max, c])
+            }
+            TernOp::Lerp => {
+                let diff = sink.push_binop(BinOp::Sub, [b, a]);
+                let step = sink.push_binop(BinOp::Mul, [diff, c]);
+                sink.push_binop(BinOp::Add, [a, step])
+            }
         }
     }
 }
@@ -130,35 +282,64 @@ pub type Location = u16;
 
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub enum Inst {
-    Const { value: Const },
-    Var { var: Var },
-    UnOp { op: UnOp, arg: InstIdx },
-    BinOp { op: BinOp, args: [InstIdx; 2] },
-    Load { vars: VarSet, loc: Location },
+    Const {
+        value: Const,
+    },
+    Var {
+        var: Var,
+    },
+    UnOp {
+        op: UnOp,
+        arg: InstIdx,
+    },
+    BinOp {
+        op: BinOp,
+        args: [InstIdx; 2],
+    },
+    /// `args[0] * args[1] + args[2]`, rounded once, as a fused multiply-add
+    /// instruction computes it.
+    Fma {
+        args: [InstIdx; 3],
+    },
+    /// An operation that could be written as several binary ones, and
+    /// rounds exactly as they would, but which backends can compute more
+    /// directly.
+    TernOp {
+        op: TernOp,
+        args: [InstIdx; 3],
+    },
+    Load {
+        vars: VarSet,
+        loc: Location,
+    },
 }
 
 impl Inst {
     pub fn args(&self) -> &[InstIdx] {
         match self {
             Inst::Const { .. } | Inst::Var { .. } | Inst::Load { .. } => &[],
-            Inst::UnOp { arg, .. } => std::slice::from_ref(arg),
+            Inst::UnOp { arg, .. } => core::slice::from_ref(arg),
             Inst::BinOp { args, .. } => args,
+            Inst::Fma { args } => args,
+            Inst::TernOp { args, .. } => args,
         }
     }
 
     pub fn args_mut(&mut self) -> &mut [InstIdx] {
         match self {
             Inst::Const { .. } | Inst::Var { .. } | Inst::Load { .. } => &mut [],
-            Inst::UnOp { arg, .. } => std::slice::from_mut(arg),
+            Inst::UnOp { arg, .. } => core::slice::from_mut(arg),
             Inst::BinOp { args, .. } => args,
+            Inst::Fma { args } => args,
+            Inst::TernOp { args, .. } => args,
         }
     }
 
     pub fn is_binop_mut(&mut self, expected: BinOp) -> Option<&mut [InstIdx; 2]> {
-        if let Inst::BinOp { op, args } = self {
-            if *op == expected {
-                return Some(args);
-            }
+        if let Inst::BinOp { op, args } = self
+            && *op == expected
+        {
+            return Some(args);
         }
         None
     }
@@ -185,6 +366,10 @@ impl VarSet {
     pub const fn idx(self) -> usize {
         self.0 as usize
     }
+
+    pub fn contains(self, var: Var) -> bool {
+        self.0 & VarSet::from(var).0 != 0
+    }
 }
 
 impl From<Var> for VarSet {
@@ -239,11 +424,16 @@ pub trait InstSink {
     fn push_var(&mut self, var: Var) -> Self::Idx;
     fn push_unop(&mut self, op: UnOp, arg: Self::Idx) -> Self::Idx;
     fn push_binop(&mut self, op: BinOp, args: [Self::Idx; 2]) -> Self::Idx;
+    fn push_fma(&mut self, args: [Self::Idx; 3]) -> Self::Idx;
+    fn push_ternop(&mut self, op: TernOp, args: [Self::Idx; 3]) -> Self::Idx;
     fn push_load(&mut self, vars: VarSet, loc: Location) -> Self::Idx;
     fn finish(self, last: Self::Idx) -> Self::Output;
 }
 
-#[derive(Default)]
+/// A program in SSA form, where each instruction may only refer to earlier
+/// ones. It's plain data, so it's `Send + Sync` and can be read from many
+/// threads at once.
+#[derive(Clone, Default)]
 pub struct Insts {
     pub pool: Vec<Inst>,
 }
@@ -268,6 +458,14 @@ impl InstSink for Insts {
         self.push(Inst::BinOp { op, args })
     }
 
+    fn push_fma(&mut self, args: [Self::Idx; 3]) -> Self::Idx {
+        self.push(Inst::Fma { args })
+    }
+
+    fn push_ternop(&mut self, op: TernOp, args: [Self::Idx; 3]) -> Self::Idx {
+        self.push(Inst::TernOp { op, args })
+    }
+
     fn push_load(&mut self, vars: VarSet, loc: Location) -> Self::Idx {
         self.push(Inst::Load { vars, loc })
     }
@@ -278,9 +476,186 @@ impl InstSink for Insts {
 }
 
 impl Insts {
+    /// An empty program with room for `len` instructions, such as the
+    /// [`total`](count::Counts::total) from a dry run.
+    pub fn with_capacity(len: usize) -> Self {
+        Insts {
+            pool: Vec::with_capacity(len),
+        }
+    }
+
+    /// Feed every instruction into another sink, such as a pass or
+    /// memoization, as if it had been parsed again.
+    pub fn replay<S: InstSink>(&self, mut sink: S) -> S::Output {
+        let last = self.replay_into(&mut sink);
+        sink.finish(last)
+    }
+
+    /// Like [`replay`](Insts::replay), but leaves the sink open for more
+    /// instructions, returning its index for this program's result.
+    pub fn replay_into<S: InstSink>(&self, sink: &mut S) -> S::Idx {
+        let mut map: Vec<S::Idx> = Vec::with_capacity(self.pool.len());
+        for inst in self.pool.iter() {
+            let idx = match *inst {
+                Inst::Const { value } => sink.push_const(value),
+                Inst::Var { var } => sink.push_var(var),
+                Inst::UnOp { op, arg } => sink.push_unop(op, map[arg.idx()]),
+                Inst::BinOp { op, args } => sink.push_binop(op, args.map(|arg| map[arg.idx()])),
+                Inst::Fma { args } => sink.push_fma(args.map(|arg| map[arg.idx()])),
+                Inst::TernOp { op, args } => sink.push_ternop(op, args.map(|arg| map[arg.idx()])),
+                Inst::Load { vars, loc } => sink.push_load(vars, loc),
+            };
+            map.push(idx);
+        }
+        *map.last().unwrap()
+    }
+
     fn push(&mut self, inst: Inst) -> InstIdx {
         let idx = self.pool.len().try_into().unwrap();
         self.pool.push(inst.clone());
         idx
     }
 }
+
+// `f32::sqrt` needs std, so without it the bytecode VM and interval
+// arithmetic use libm's, which is just as exact.
+#[cfg(feature = "std")]
+fn sqrt(value: f32) -> f32 {
+    value.sqrt()
+}
+
+#[cfg(not(feature = "std"))]
+fn sqrt(value: f32) -> f32 {
+    libm::sqrtf(value)
+}
+
+// Likewise `f32::mul_add`, for `Inst::Fma`.
+#[cfg(feature = "std")]
+fn mul_add(a: f32, b: f32, c: f32) -> f32 {
+    a.mul_add(b, c)
+}
+
+#[cfg(not(feature = "std"))]
+fn mul_add(a: f32, b: f32, c: f32) -> f32 {
+    libm::fmaf(a, b, c)
+}
+
+// And `f32::exp` and `f32::ln`, for `UnOp::Exp` and `UnOp::Ln`.
+#[cfg(feature = "std")]
+fn exp(value: f32) -> f32 {
+    value.exp()
+}
+
+#[cfg(not(feature = "std"))]
+fn exp(value: f32) -> f32 {
+    libm::expf(value)
+}
+
+#[cfg(feature = "std")]
+fn ln(value: f32) -> f32 {
+    value.ln()
+}
+
+#[cfg(not(feature = "std"))]
+fn ln(value: f32) -> f32 {
+    libm::logf(value)
+}
+
+// And `f32::sin` and `f32::cos`, for `UnOp::Sin` and `UnOp::Cos`.
+#[cfg(feature = "std")]
+fn sin(value: f32) -> f32 {
+    value.sin()
+}
+
+#[cfg(not(feature = "std"))]
+fn sin(value: f32) -> f32 {
+    libm::sinf(value)
+}
+
+#[cfg(feature = "std")]
+fn cos(value: f32) -> f32 {
+    value.cos()
+}
+
+#[cfg(not(feature = "std"))]
+fn cos(value: f32) -> f32 {
+    libm::cosf(value)
+}
+
+// And `f32::floor`, `f32::ceil`, and `f32::round_ties_even`, for `UnOp::Floor`,
+// `UnOp::Ceil`, and `UnOp::Round`. Rounding ties to even is what `vroundps`
+// and C's `rintf` do, unlike `f32::round`.
+#[cfg(feature = "std")]
+fn floor(value: f32) -> f32 {
+    value.floor()
+}
+
+#[cfg(not(feature = "std"))]
+fn floor(value: f32) -> f32 {
+    libm::floorf(value)
+}
+
+#[cfg(feature = "std")]
+fn ceil(value: f32) -> f32 {
+    value.ceil()
+}
+
+#[cfg(not(feature = "std"))]
+fn ceil(value: f32) -> f32 {
+    libm::ceilf(value)
+}
+
+#[cfg(feature = "std")]
+fn round(value: f32) -> f32 {
+    value.round_ties_even()
+}
+
+#[cfg(not(feature = "std"))]
+fn round(value: f32) -> f32 {
+    libm::rintf(value)
+}
+
+/// `BinOp::Mod`: `a - b * floor(a / b)`, rounding after each step, which
+/// every backend computes exactly this way. Like GLSL's `mod`, the result
+/// has the sign of `b`, so repeating a shape every `b` units works the same
+/// on both sides of the origin.
+pub fn modulo(a: f32, b: f32) -> f32 {
+    a - b * floor(a / b)
+}
+
+/// `UnOp::Rsqrt`: `1 / sqrt(x)`, rounding the square root before dividing,
+/// which is what every backend computes unless it's asked to approximate.
+pub fn rsqrt(value: f32) -> f32 {
+    1.0 / sqrt(value)
+}
+
+/// `UnOp::PowI`: `x` to the power `n` by square-and-multiply, working from
+/// the highest bit of `n` down and rounding after each step, then `1 / x^-n`
+/// for a negative `n`. That's the shortest chain of squares and products for
+/// every power below 15. `x^0` is 1, even for NaN.
+pub fn powi(x: f32, n: i32) -> f32 {
+    let power = n.unsigned_abs();
+    if power == 0 {
+        return 1.0;
+    }
+    let mut result = x;
+    for bit in (0..power.ilog2()).rev() {
+        result *= result;
+        if power >> bit & 1 != 0 {
+            result *= x;
+        }
+    }
+    if n < 0 { 1.0 / result } else { result }
+}
+
+/// `TernOp::Clamp`: `min(max(x, lo), hi)`, so NaN in `x` becomes `lo`, and
+/// `hi` wins if the bounds are the wrong way around.
+pub fn clamp(x: f32, lo: f32, hi: f32) -> f32 {
+    x.max(lo).min(hi)
+}
+
+/// `TernOp::Lerp`: `a + (b - a) * t`, rounding after each step, which is
+/// exact at `t = 0` but not necessarily at `t = 1`.
+pub fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
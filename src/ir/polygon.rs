@@ -0,0 +1,235 @@
+use thiserror::Error;
+
+use super::{BinOp, Const, InstSink, UnOp};
+
+#[derive(Debug, Error, PartialEq)]
+pub enum Error {
+    #[error("a polygon needs at least three distinct points, but the largest has {0}")]
+    TooFewPoints(usize),
+    #[error("point {1} of polygon {0} has a coordinate which is not finite")]
+    NonFinitePoint(usize, usize),
+}
+
+/// Push the exact signed distance to the polygon with corners `points`, in
+/// the form of Inigo Quilez's `sdPolygon`, into any sink, such as a program
+/// under construction or a [`Simplify`](super::simplify::Simplify) pass. The
+/// result is positive inside, like every other shape in this crate, and is
+/// correct for any simple polygon in either winding order.
+///
+/// `sdPolygon` counts which edges a ray from the point crosses to find out
+/// whether it's inside, and the IR has no comparisons, so each test becomes a
+/// step function: multiplying a difference by `f32::MAX` and clamping it to
+/// ±1 gives exactly ±1 unless the difference is subnormal. Consecutive
+/// repeated points are skipped, as are edges that close the polygon if the
+/// last point repeats the first.
+pub fn polygon<S: InstSink>(
+    sink: &mut S,
+    vars: [S::Idx; 2],
+    points: &[[f32; 2]],
+) -> Result<S::Idx, Error> {
+    polygons(sink, vars, &[points])
+}
+
+/// Like [`polygon`], for a shape made of several polygons, such as a glyph
+/// with holes in it. Points inside an odd number of the polygons are inside
+/// the shape. Polygons with fewer than three distinct points enclose nothing
+/// and are skipped, but there must be at least one which doesn't.
+pub fn polygons<S: InstSink>(
+    sink: &mut S,
+    [x, y]: [S::Idx; 2],
+    polygons: &[impl AsRef<[[f32; 2]]>],
+) -> Result<S::Idx, Error> {
+    let mut contours = Vec::with_capacity(polygons.len());
+    let mut largest = 0;
+    for (idx, points) in polygons.iter().enumerate() {
+        let points = points.as_ref();
+        if let Some(point) = points.iter().position(|p| !p.iter().all(|c| c.is_finite())) {
+            return Err(Error::NonFinitePoint(idx, point));
+        }
+        let mut corners: Vec<[f32; 2]> = points.to_vec();
+        corners.dedup();
+        if corners.len() > 1 && corners.first() == corners.last() {
+            corners.pop();
+        }
+        largest = largest.max(corners.len());
+        if corners.len() >= 3 {
+            contours.push(corners);
+        }
+    }
+    if contours.is_empty() {
+        return Err(Error::TooFewPoints(largest));
+    }
+
+    let konst = |sink: &mut S, value: f32| sink.push_const(Const::new(value));
+    let zero = konst(sink, 0.0);
+    let one = konst(sink, 1.0);
+    let neg_one = konst(sink, -1.0);
+    let step = konst(sink, f32::MAX);
+    let clamp = |sink: &mut S, value: S::Idx, offset: S::Idx| {
+        let scaled = sink.push_binop(BinOp::Mul, [value, step]);
+        let shifted = sink.push_binop(BinOp::Add, [scaled, offset]);
+        let lo = sink.push_binop(BinOp::Max, [shifted, neg_one]);
+        sink.push_binop(BinOp::Min, [lo, one])
+    };
+
+    let mut distance = None;
+    let mut sign = None;
+    for corners in contours.iter() {
+        // the offset from each corner to the point
+        let offsets: Vec<[S::Idx; 2]> = corners
+            .iter()
+            .map(|&[cx, cy]| {
+                let cx = konst(sink, cx);
+                let cy = konst(sink, cy);
+                [
+                    sink.push_binop(BinOp::Sub, [x, cx]),
+                    sink.push_binop(BinOp::Sub, [y, cy]),
+                ]
+            })
+            .collect();
+
+        for (idx, &[ax, ay]) in corners.iter().enumerate() {
+            let prev = (idx + corners.len() - 1) % corners.len();
+            let [bx, by] = corners[prev];
+            let [wx, wy] = offsets[idx];
+            let (ex, ey) = (bx - ax, by - ay);
+
+            // squared distance to the edge from `a` to `b`, clamping the
+            // projection onto the edge to its ends
+            let (cex, cey) = (konst(sink, ex), konst(sink, ey));
+            let along_x = sink.push_binop(BinOp::Mul, [wx, cex]);
+            let along_y = sink.push_binop(BinOp::Mul, [wy, cey]);
+            let along = sink.push_binop(BinOp::Add, [along_x, along_y]);
+            let scale = konst(sink, 1.0 / (ex * ex + ey * ey));
+            let t = sink.push_binop(BinOp::Mul, [along, scale]);
+            let t = sink.push_binop(BinOp::Max, [t, zero]);
+            let t = sink.push_binop(BinOp::Min, [t, one]);
+            let perp = |sink: &mut S, w, e| {
+                let proj = sink.push_binop(BinOp::Mul, [e, t]);
+                let perp = sink.push_binop(BinOp::Sub, [w, proj]);
+                sink.push_unop(UnOp::Square, perp)
+            };
+            let dx2 = perp(sink, wx, cex);
+            let dy2 = perp(sink, wy, cey);
+            let edge = sink.push_binop(BinOp::Add, [dx2, dy2]);
+            distance = Some(match distance {
+                Some(distance) => sink.push_binop(BinOp::Min, [distance, edge]),
+                None => edge,
+            });
+
+            // As ±1 steps: whether the point is at or above `a`, whether it's
+            // below `b`, and which side of the edge it's on. The ray crosses this
+            // edge if all three agree, in which case (a + b + c)² is 9 rather
+            // than 1, so 1.25 - (a + b + c)² / 4 is -1 rather than 1.
+            let above = clamp(sink, wy, one);
+            let below_b = sink.push_unop(UnOp::Neg, offsets[prev][1]);
+            let below = clamp(sink, below_b, neg_one);
+            let cross_y = sink.push_binop(BinOp::Mul, [wy, cex]);
+            let cross_x = sink.push_binop(BinOp::Mul, [wx, cey]);
+            let cross = sink.push_binop(BinOp::Sub, [cross_y, cross_x]);
+            let side = clamp(sink, cross, neg_one);
+            let votes = sink.push_binop(BinOp::Add, [above, below]);
+            let votes = sink.push_binop(BinOp::Add, [votes, side]);
+            let votes = sink.push_unop(UnOp::Square, votes);
+            let quarter = konst(sink, -0.25);
+            let votes = sink.push_binop(BinOp::Mul, [votes, quarter]);
+            let bias = konst(sink, 1.25);
+            let flip = sink.push_binop(BinOp::Add, [votes, bias]);
+            sign = Some(match sign {
+                Some(sign) => sink.push_binop(BinOp::Mul, [sign, flip]),
+                None => flip,
+            });
+        }
+    }
+
+    // an odd number of crossings flips the sign to -1 inside, and the
+    // result should be positive there
+    let distance = sink.push_unop(UnOp::Sqrt, distance.unwrap());
+    let distance = sink.push_binop(BinOp::Mul, [distance, sign.unwrap()]);
+    Ok(sink.push_unop(UnOp::Neg, distance))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::is_inside;
+    use crate::ir::interp::eval_point;
+    use crate::ir::{Insts, Var};
+
+    // Quilez's version, with real comparisons.
+    fn reference(points: &[[f32; 2]], [px, py]: [f32; 2]) -> f32 {
+        let mut distance = f32::INFINITY;
+        let mut sign = 1.0;
+        for (idx, &[ax, ay]) in points.iter().enumerate() {
+            let [bx, by] = points[(idx + points.len() - 1) % points.len()];
+            let (ex, ey, wx, wy) = (bx - ax, by - ay, px - ax, py - ay);
+            let t = ((wx * ex + wy * ey) / (ex * ex + ey * ey)).clamp(0.0, 1.0);
+            distance = distance.min((wx - ex * t).powi(2) + (wy - ey * t).powi(2));
+            let tests = [py >= ay, py < by, ex * wy > ey * wx];
+            if tests.iter().all(|&c| c) || tests.iter().all(|&c| !c) {
+                sign = -sign;
+            }
+        }
+        -sign * distance.sqrt()
+    }
+
+    #[test]
+    fn test_polygon_matches_reference() {
+        // an L shape
+        let corners = [
+            [-0.5, -0.5],
+            [0.5, -0.5],
+            [0.5, 0.0],
+            [0.0, 0.0],
+            [0.0, 0.5],
+            [-0.5, 0.5],
+        ];
+        // repeating a corner and closing the loop explicitly changes nothing
+        let mut points = corners.to_vec();
+        points.insert(1, corners[1]);
+        points.push(corners[0]);
+        let mut insts = Insts::default();
+        let vars = [insts.push_var(Var::X), insts.push_var(Var::Y)];
+        polygon(&mut insts, vars, &points).unwrap();
+
+        let mut regs = vec![0.0; insts.pool.len()];
+        for row in 0..=16 {
+            for col in 0..=16 {
+                let point = [col as f32 / 8.0 - 1.0, row as f32 / 8.0 - 1.0];
+                let expected = reference(&corners, point);
+                let actual = eval_point(&insts, &mut regs, &point);
+                assert!(
+                    (actual - expected).abs() < 1e-6,
+                    "{point:?}: {actual} != {expected}"
+                );
+                assert_eq!(is_inside(actual), is_inside(expected), "{point:?}");
+            }
+        }
+
+        let mut insts = Insts::default();
+        let vars = [insts.push_var(Var::X), insts.push_var(Var::Y)];
+        assert_eq!(
+            polygon(&mut insts, vars, &[[0.0, 0.0], [1.0, 0.0], [0.0, 0.0]]),
+            Err(Error::TooFewPoints(2))
+        );
+        assert_eq!(
+            polygon(&mut insts, vars, &[[0.0, 0.0], [f32::NAN, 0.0]]),
+            Err(Error::NonFinitePoint(0, 1))
+        );
+
+        // a square with a square hole, and a stray line which is ignored
+        let outer = [[-0.5, -0.5], [0.5, -0.5], [0.5, 0.5], [-0.5, 0.5]];
+        let inner = outer.map(|[x, y]| [x * 0.5, y * 0.5]);
+        let mut insts = Insts::default();
+        let vars = [insts.push_var(Var::X), insts.push_var(Var::Y)];
+        polygons(&mut insts, vars, &[&outer[..], &inner, &outer[..2]]).unwrap();
+        let mut regs = vec![0.0; insts.pool.len()];
+        for (point, expected) in [
+            ([0.0, 0.0], -0.25),
+            ([0.375, 0.0], 0.125),
+            ([1.0, 0.0], -0.5),
+        ] {
+            assert_eq!(eval_point(&insts, &mut regs, &point), expected, "{point:?}");
+        }
+    }
+}
@@ -0,0 +1,77 @@
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+use super::{Inst, InstSink, UnOp};
+
+/// Replace each `powi` with the `square` and `mul` instructions, and the
+/// `recip` for a negative power, that [`UnOp::expand_powi`] pushes for it.
+/// Passed through [`Simplify`](super::simplify::Simplify), powers of the same
+/// value then share the squares they have in common, and the result suits
+/// passes and backends that have no `powi` of their own.
+pub fn expand<S: InstSink>(insts: &[Inst], mut sink: S) -> S::Output {
+    let _span = tracing::info_span!("expand_powi", insts = insts.len()).entered();
+    let mut map: Vec<S::Idx> = Vec::with_capacity(insts.len());
+    for inst in insts {
+        let idx = match *inst {
+            Inst::Const { value } => sink.push_const(value),
+            Inst::Var { var } => sink.push_var(var),
+            Inst::UnOp {
+                op: UnOp::PowI(n),
+                arg,
+            } => UnOp::expand_powi(&mut sink, map[arg.idx()], n),
+            Inst::UnOp { op, arg } => sink.push_unop(op, map[arg.idx()]),
+            Inst::BinOp { op, args } => sink.push_binop(op, args.map(|arg| map[arg.idx()])),
+            Inst::Fma { args } => sink.push_fma(args.map(|arg| map[arg.idx()])),
+            Inst::TernOp { op, args } => sink.push_ternop(op, args.map(|arg| map[arg.idx()])),
+            Inst::Load { vars, loc } => sink.push_load(vars, loc),
+        };
+        map.push(idx);
+    }
+    sink.finish(*map.last().unwrap())
+}
+
+/// Whether any instruction is a `powi`, for consumers which only handle
+/// programs that have been through [`expand`].
+pub fn contains(insts: &[Inst]) -> bool {
+    insts.iter().any(|inst| {
+        matches!(
+            inst,
+            Inst::UnOp {
+                op: UnOp::PowI(_),
+                ..
+            }
+        )
+    })
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::ir::count::Count;
+    use crate::ir::interp::eval_point;
+    use crate::ir::io::read;
+    use crate::ir::simplify::Simplify;
+    use crate::ir::{BinOp, Insts, powi};
+
+    #[test]
+    fn test_expand() {
+        // x^8 squares x^2 and then x^4, x^6 squares x^3, and x^-3 shares
+        // both the square and the product with x^6
+        let text = "x var-x\na powi x 8\nb powi x 6\nc powi x -3\nd add a b\nout add d c\n";
+        let insts = read(text.as_bytes(), Insts::default()).unwrap();
+        let counts = expand(&insts.pool, Simplify::new(Count::default()));
+        assert_eq!(counts.unops.get(&UnOp::PowI(8)), None);
+        assert_eq!(counts.unops[&UnOp::Square], 4);
+        assert_eq!(counts.binops[&BinOp::Mul], 1);
+        assert_eq!(counts.unops[&UnOp::Recip], 1);
+
+        for (n, x) in [(0, f32::NAN), (1, -1.5), (5, 1.1), (-2, 3.0), (13, 0.9)] {
+            let text = format!("x var-x\nout powi x {n}\n");
+            let insts = read(text.as_bytes(), Insts::default()).unwrap();
+            let expanded = expand(&insts.pool, Insts::default());
+            let mut regs = vec![0.0; expanded.pool.len()];
+            let value = eval_point(&expanded, &mut regs, &[x, 0.0, 0.0]);
+            assert_eq!(value.to_bits(), powi(x, n).to_bits(), "x^{n}");
+        }
+    }
+}
@@ -1,13 +1,59 @@
 use std::mem::swap;
 use std::num::Saturating;
 
+use clap::{Args, ValueEnum};
+
 use super::{BinOp, Inst, InstSink, UnOp, VarSet};
 
-pub fn reassociate<S: InstSink>(insts: &[Inst], mut sink: S) -> S::Output {
+#[derive(Args, Clone, Copy, Debug)]
+#[group(id = "ReassociateConfig")]
+pub struct Config {
+    /// Whether to rewrite the product of a constant and a sum, `c * (a + b)`,
+    /// as a sum of products, `c * a + c * b`
+    #[arg(long, default_value_t = Distribute::Never, value_enum)]
+    pub distribute: Distribute,
+
+    /// Pixels across the images that `--distribute cost` should plan for
+    #[arg(long, default_value_t = 1024)]
+    pub distribute_size: u16,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            distribute: Distribute::Never,
+            distribute_size: 1024,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum Distribute {
+    /// Multiply the sum as a whole, in the function of every variable its
+    /// terms depend on, which rounds just as the program was written
+    #[default]
+    Never,
+    /// Multiply each term of the sum in the function of only the variables
+    /// it depends on, which takes one `mul` per term instead of one in all
+    Always,
+    /// Distribute when that runs fewer `mul`s over a whole image, counting
+    /// an instruction in the function of a set of variables as running once
+    /// per combination of their values
+    Cost,
+}
+
+pub fn reassociate<S: InstSink>(insts: &[Inst], sink: S) -> S::Output {
+    reassociate_with(Config::default(), insts, sink)
+}
+
+/// Like [`reassociate`], with control over the choices it makes.
+pub fn reassociate_with<S: InstSink>(config: Config, insts: &[Inst], mut sink: S) -> S::Output {
+    let _span = tracing::info_span!("reassociate", insts = insts.len()).entered();
     let uses = count_uses(insts);
+    let fenced = find_fenced(insts);
     let mut data: Vec<InstData<S::Idx>> = Vec::with_capacity(insts.len());
 
-    for (inst, uses) in insts.iter().zip(uses) {
+    for ((inst, uses), fenced) in insts.iter().zip(uses).zip(fenced) {
         let mut new = match *inst {
             Inst::Const { value } => InstData::new(VarSet::default(), sink.push_const(value)),
             Inst::Var { var } => InstData::new(var.into(), sink.push_var(var)),
@@ -22,26 +68,67 @@ pub fn reassociate<S: InstSink>(insts: &[Inst], mut sink: S) -> S::Output {
                     InstData::new(vars, sink.push_unop(op, idx))
                 }
             }
+            // `mod` doesn't associate with anything, so it's kept whole
+            Inst::BinOp {
+                op: BinOp::Mod,
+                args,
+            } => {
+                let mut vars = VarSet::default();
+                let args = args.map(|arg| {
+                    let (arg_vars, idx) = data[arg.idx()].clone().flush_neg(&mut sink);
+                    vars = vars | arg_vars;
+                    idx
+                });
+                InstData::new(vars, sink.push_binop(BinOp::Mod, args))
+            }
             Inst::BinOp { mut op, args } => {
                 let [mut a, mut b] = args.map(|arg| data[arg.idx()].clone());
                 if op == BinOp::Sub {
                     op = BinOp::Add;
                     b.negate();
                 }
-                if a.op != Some(op) {
-                    a.flush(&mut sink);
-                }
-                if b.op != Some(op) {
-                    b.flush(&mut sink);
-                }
-                for (subtree_a, subtree_b) in a.subtrees.iter_mut().zip(&b.subtrees) {
-                    subtree_a.merge(subtree_b, op, &mut sink);
+                let distributed = if op == BinOp::Mul && !fenced {
+                    distribute(config, &a, &b, &mut sink)
+                        .or_else(|| distribute(config, &b, &a, &mut sink))
+                } else {
+                    None
+                };
+                if let Some(sum) = distributed {
+                    sum
+                } else {
+                    // Operands of a fenced instruction must be fully computed
+                    // on their own, so the most we'll do is swap their order.
+                    if a.op != Some(op) || fenced {
+                        a.flush(&mut sink);
+                    }
+                    if b.op != Some(op) || fenced {
+                        b.flush(&mut sink);
+                    }
+                    for (subtree_a, subtree_b) in a.subtrees.iter_mut().zip(&b.subtrees) {
+                        subtree_a.merge(subtree_b, op, &mut sink);
+                    }
+                    a.op = Some(op);
+                    a
                 }
-                a.op = Some(op);
-                a
+            }
+            // an explicit fused multiply-add is kept whole, since splitting
+            // it would round the product separately, and so are `clamp` and
+            // `lerp`, which were asked for by name
+            Inst::Fma { args } | Inst::TernOp { args, .. } => {
+                let mut vars = VarSet::default();
+                let args = args.map(|arg| {
+                    let (arg_vars, idx) = data[arg.idx()].clone().flush_neg(&mut sink);
+                    vars = vars | arg_vars;
+                    idx
+                });
+                let idx = match *inst {
+                    Inst::TernOp { op, .. } => sink.push_ternop(op, args),
+                    _ => sink.push_fma(args),
+                };
+                InstData::new(vars, idx)
             }
         };
-        if uses.0 > 1 {
+        if uses.0 > 1 || fenced {
             new.flush(&mut sink);
         }
         data.push(new);
@@ -114,8 +201,16 @@ impl<I: Copy> InstData<I> {
     }
 
     fn negate(&mut self) {
-        for vars in self.subtrees.iter_mut() {
-            vars.negate();
+        let subtrees = self
+            .subtrees
+            .iter_mut()
+            .filter(|subtree| !subtree.is_empty());
+        if self.op == Some(BinOp::Mul) {
+            // negating one factor negates the product; negating them all
+            // would cancel out when there's an even number of them
+            subtrees.take(1).for_each(Subtree::negate);
+        } else {
+            subtrees.for_each(Subtree::negate);
         }
 
         match self.op {
@@ -149,7 +244,7 @@ impl<I: Copy> Subtree<I> {
     fn flush(&mut self, op: BinOp, sink: &mut impl InstSink<Idx = I>) {
         if let (Some(pos), Some(neg)) = (self.pos, self.neg) {
             let pos = match op {
-                BinOp::Sub | BinOp::Mul => unreachable!(),
+                BinOp::Sub | BinOp::Mul | BinOp::Mod => unreachable!(),
                 BinOp::Add => sink.push_binop(BinOp::Sub, [pos, neg]),
                 BinOp::Min | BinOp::Max => {
                     let neg = sink.push_unop(UnOp::Neg, neg);
@@ -167,7 +262,7 @@ impl<I: Copy> Subtree<I> {
 
     fn merge(&mut self, other: &Self, op: BinOp, sink: &mut impl InstSink<Idx = I>) {
         match op {
-            BinOp::Sub => unreachable!(),
+            BinOp::Sub | BinOp::Mod => unreachable!(),
             BinOp::Mul => {
                 debug_assert!(self.pos.is_none() || self.neg.is_none());
                 debug_assert!(other.pos.is_none() || other.neg.is_none());
@@ -197,6 +292,69 @@ impl<I: Copy> Subtree<I> {
     }
 }
 
+// If `factor` is a constant and `sum` is a sum whose terms depend on
+// different variables, and `config` says to, multiply each term by `factor`
+// and return the resulting sum, which further sums can still merge into.
+fn distribute<S: InstSink>(
+    config: Config,
+    factor: &InstData<S::Idx>,
+    sum: &InstData<S::Idx>,
+    sink: &mut S,
+) -> Option<InstData<S::Idx>> {
+    if sum.op != Some(BinOp::Add) || factor.subtrees[1..].iter().any(|t| !t.is_empty()) {
+        return None;
+    }
+    let terms = sum.subtrees.iter().enumerate();
+    let terms: Vec<VarSet> = terms
+        .filter(|(_, subtree)| !subtree.is_empty())
+        .map(|(vars, _)| VarSet(vars.try_into().unwrap()))
+        .collect();
+    if terms.len() < 2 {
+        return None;
+    }
+    let all = terms
+        .iter()
+        .fold(VarSet::default(), |all, &vars| all | vars);
+    match config.distribute {
+        Distribute::Never => return None,
+        Distribute::Always => {}
+        Distribute::Cost => {
+            // each term's `mul` runs once per combination of its variables
+            let runs = |vars: VarSet| {
+                u64::from(config.distribute_size).pow(vars.into_iter().count() as u32)
+            };
+            if terms.iter().map(|&vars| runs(vars)).sum::<u64>() >= runs(all) {
+                return None;
+            }
+        }
+    }
+
+    let mut factor = factor.clone();
+    factor.flush(sink);
+    let (factor, negated) = match factor.subtrees[0] {
+        Subtree {
+            pos: Some(pos),
+            neg: None,
+        } => (pos, false),
+        Subtree {
+            pos: None,
+            neg: Some(neg),
+        } => (neg, true),
+        _ => unreachable!(),
+    };
+    let mut sum = sum.clone();
+    for subtree in sum.subtrees.iter_mut() {
+        subtree.flush(BinOp::Add, sink);
+        for term in [&mut subtree.pos, &mut subtree.neg].into_iter().flatten() {
+            *term = sink.push_binop(BinOp::Mul, [factor, *term]);
+        }
+    }
+    if negated {
+        sum.negate();
+    }
+    Some(sum)
+}
+
 fn merge<S: InstSink>(this: &mut Option<S::Idx>, other: Option<S::Idx>, op: BinOp, sink: &mut S) {
     if let Some(b) = other {
         if let Some(a) = *this {
@@ -221,3 +379,98 @@ fn count_uses(insts: &[Inst]) -> Vec<Saturating<u8>> {
     }
     uses
 }
+
+// Everything that feeds into a `barrier` instruction is computed in exactly the
+// order it was written, so mark the whole expression tree under each barrier.
+pub(super) fn find_fenced(insts: &[Inst]) -> Vec<bool> {
+    let mut fenced = vec![false; insts.len()];
+    for (idx, inst) in insts.iter().enumerate().rev() {
+        if fenced[idx]
+            || matches!(
+                inst,
+                Inst::UnOp {
+                    op: UnOp::Barrier,
+                    ..
+                }
+            )
+        {
+            for &arg in inst.args() {
+                fenced[arg.idx()] = true;
+            }
+        }
+    }
+    fenced
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::interp::interp_image;
+    use crate::ir::io::{read, write};
+    use crate::ir::memoize::MemoBuilder;
+    use crate::ir::simplify::Simplify;
+    use crate::ir::{Insts, Var};
+
+    fn run(config: Config, text: &str) -> Insts {
+        let insts = read(text.as_bytes(), Insts::default()).unwrap();
+        let reassociated = reassociate_with(config, &insts.pool, Insts::default());
+        assert_eq!(interp_image(&reassociated, 17), interp_image(&insts, 17));
+        reassociated
+    }
+
+    #[test]
+    fn test_distribute() {
+        let text = "x var-x\ny var-y\nx2 square x\ny2 square y\nr2 add x2 y2\n\
+            half const -0.5\nscaled mul half r2\nquarter const 0.25\nout add scaled quarter\n";
+        let xy_insts = |distribute| {
+            let config = Config {
+                distribute,
+                ..Config::default()
+            };
+            let memoized = run(config, text).replay(MemoBuilder::new());
+            let xy = VarSet::from(Var::X) | VarSet::from(Var::Y);
+            let xy = &memoized.funcs[xy.idx() - 1].insts;
+            xy.iter()
+                .filter(|inst| !matches!(inst, Inst::Load { .. }))
+                .count()
+        };
+        // the sum and its product, or only the sum of the products, which
+        // changes rounding, so it's only done when asked for
+        assert_eq!(Config::default().distribute, Distribute::Never);
+        assert_eq!(xy_insts(Distribute::Never), 3);
+        assert_eq!(xy_insts(Distribute::Cost), 2);
+    }
+
+    #[test]
+    fn test_barrier() {
+        // reassociating would regroup the sum and multiply the constants
+        // together first, but under a barrier, everything stays as written
+        let text = "x var-x\ny var-y\nc const 2\na add y x\nb sub a y\nm mul b c\nn mul m c\n";
+        let pipeline = |text: &str| {
+            let simplified = read(text.as_bytes(), Simplify::new(Insts::default())).unwrap();
+            let insts = reassociate(&simplified.pool, Insts::default());
+            let mut out = Vec::new();
+            write(&mut out, insts.pool.iter().cloned()).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let fenced = pipeline(&format!("{text}out barrier n\n"));
+        assert_eq!(
+            fenced,
+            "v0 var-x\nv1 var-y\nv2 const 2\nv3 add v1 v0\nv4 sub v3 v1\nv5 mul v4 v2\n\
+             v6 mul v5 v2\nv7 barrier v6\n"
+        );
+        assert_ne!(
+            pipeline(text),
+            fenced.strip_suffix("v7 barrier v6\n").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_negated_product() {
+        // an even number of factors in a negated product
+        run(
+            Config::default(),
+            "x var-x\ny var-y\nc const -0.2\nm max y c\np mul c m\nout sub x p\n",
+        );
+    }
+}
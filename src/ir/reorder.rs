@@ -1,6 +1,7 @@
 use super::{Const, InstIdx, Insts};
 
 pub fn reorder(insts: &mut Insts) {
+    let _span = tracing::info_span!("reorder", insts = insts.pool.len()).entered();
     let Some(root) = insts.pool.len().checked_sub(1) else {
         return;
     };
@@ -10,10 +11,10 @@ pub fn reorder(insts: &mut Insts) {
     let mut stack = vec![InstIdx::try_from(root).unwrap()];
     while let Some(&idx) = stack.last() {
         let idx = idx.idx();
-        if remap[idx] == None {
+        if remap[idx].is_none() {
             let mut changed = false;
             for &arg in insts.pool[idx].args().iter().rev() {
-                if remap[arg.idx()] == None {
+                if remap[arg.idx()].is_none() {
                     stack.push(arg);
                     changed = true;
                 }
@@ -1,16 +1,147 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasher, RandomState};
 
-use super::{BinOp, Const, InstSink, Location, UnOp, Var, VarSet};
+use super::{BinOp, Const, Inst, InstIdx, InstSink, Insts, Location, TernOp, UnOp, Var, VarSet};
 
 pub struct Simplify<S: InstSink> {
     base: S,
-    gvn: HashMap<Key<S::Idx>, S::Idx>,
+    gvn: Gvn<S>,
+    // results of `square`, `sqrt`, `abs`, and `exp`, whose `abs` is
+    // themselves
+    nonnegative: HashSet<S::Idx>,
+    // results of `exp` and `ln`, with the operator and argument of each, so
+    // the other one can undo it
+    inverses: HashMap<S::Idx, (UnOp, S::Idx)>,
+    // results of `max`, with their arguments, if a `min` of one should be
+    // fused into a `clamp`
+    maxes: Option<HashMap<S::Idx, [S::Idx; 2]>>,
+}
+
+// The table for global value numbering. Normally every key is kept whole,
+// which for `Insts` takes 12 bytes per entry with its index, plus the map's
+// overhead. A sink that can say what each of its indexes computes can keep
+// only the indexes instead, in an open-addressed table, and rebuild keys from
+// them to compare.
+enum Gvn<S: InstSink> {
+    Keys(HashMap<Key<S::Idx>, S::Idx>),
+    Interned {
+        slots: Vec<Option<S::Idx>>,
+        len: usize,
+        hasher: RandomState,
+        key: fn(&S, S::Idx) -> Key<S::Idx>,
+    },
 }
 
 impl<S: InstSink> Simplify<S> {
     pub fn new(base: S) -> Self {
-        let gvn = HashMap::new();
-        Self { base, gvn }
+        let gvn = Gvn::Keys(HashMap::new());
+        Self {
+            base,
+            gvn,
+            nonnegative: HashSet::new(),
+            inverses: HashMap::new(),
+            maxes: None,
+        }
+    }
+
+    /// Also turn each `min` of a `max` pushed from now on into one `clamp`,
+    /// which computes exactly the same thing. That's off by default, since
+    /// passes that look for trees of `min` and `max` wouldn't recognize it.
+    pub fn fuse_clamps(mut self) -> Self {
+        self.maxes = Some(HashMap::new());
+        self
+    }
+
+    // The arguments of a `clamp` which computes `op` of `args`, if it's a
+    // `min` of a `max` and clamps are being fused.
+    fn clamp_of(&self, op: BinOp, [a, b]: [S::Idx; 2]) -> Option<[S::Idx; 3]> {
+        let maxes = self.maxes.as_ref().filter(|_| op == BinOp::Min)?;
+        let (&[x, lo], hi) = maxes
+            .get(&a)
+            .map(|max| (max, b))
+            .or_else(|| Some((maxes.get(&b)?, a)))?;
+        Some([x, lo, hi])
+    }
+
+    // The index for `key`, if anything computes it already.
+    fn lookup(&self, key: &Key<S::Idx>) -> Option<S::Idx> {
+        match &self.gvn {
+            Gvn::Keys(map) => map.get(key).copied(),
+            Gvn::Interned {
+                slots,
+                hasher,
+                key: key_of,
+                ..
+            } => {
+                if slots.is_empty() {
+                    return None;
+                }
+                let mask = slots.len() - 1;
+                let mut slot = hasher.hash_one(key) as usize & mask;
+                while let Some(idx) = slots[slot] {
+                    if key_of(&self.base, idx) == *key {
+                        return Some(idx);
+                    }
+                    slot = (slot + 1) & mask;
+                }
+                None
+            }
+        }
+    }
+
+    // The index for `key`, calling `push` to compute it if nothing does yet.
+    fn intern(&mut self, key: Key<S::Idx>, push: impl FnOnce(&mut S) -> S::Idx) -> S::Idx {
+        if let Gvn::Keys(map) = &mut self.gvn {
+            return *map.entry(key).or_insert_with(|| push(&mut self.base));
+        }
+        if let Some(idx) = self.lookup(&key) {
+            return idx;
+        }
+        let idx = push(&mut self.base);
+        self.insert(key, idx);
+        idx
+    }
+
+    // Record that `idx` computes `key`, which nothing else does yet.
+    fn insert(&mut self, key: Key<S::Idx>, idx: S::Idx) {
+        let (slots, len, hasher, key_of) = match &mut self.gvn {
+            Gvn::Keys(map) => {
+                map.insert(key, idx);
+                return;
+            }
+            Gvn::Interned {
+                slots,
+                len,
+                hasher,
+                key,
+            } => (slots, len, &*hasher, *key),
+        };
+        let probe = |slots: &[Option<S::Idx>], key: &Key<S::Idx>| {
+            let mask = slots.len() - 1;
+            let mut slot = hasher.hash_one(key) as usize & mask;
+            while slots[slot].is_some() {
+                slot = (slot + 1) & mask;
+            }
+            slot
+        };
+        // keep the table at most 3/4 full so probe sequences stay short
+        if (*len + 1) * 4 > slots.len() * 3 {
+            let old = std::mem::replace(slots, vec![None; (slots.len() * 2).max(16)]);
+            for idx in old.into_iter().flatten() {
+                let slot = probe(slots, &key_of(&self.base, idx));
+                slots[slot] = Some(idx);
+            }
+        }
+        let slot = probe(slots, &key);
+        slots[slot] = Some(idx);
+        *len += 1;
+    }
+
+    fn len(&self) -> usize {
+        match &self.gvn {
+            Gvn::Keys(map) => map.len(),
+            &Gvn::Interned { len, .. } => len,
+        }
     }
 
     fn gvn_binop(&mut self, op: BinOp, mut args: [S::Idx; 2]) -> Idx<S::Idx> {
@@ -23,27 +154,139 @@ impl<S: InstSink> Simplify<S> {
             // result.
             BinOp::Sub => {
                 let [a, b] = args;
-                let reversed = Key::BinOp(op, [b, a]);
-                if let Some(&idx) = self.gvn.get(&reversed) {
+                if let Some(idx) = self.lookup(&Key::BinOp(op, [b, a])) {
                     return Idx::Neg(idx);
                 }
             }
+
+            BinOp::Mod => {}
         }
 
-        self.gvn
-            .entry(Key::BinOp(op, args))
-            .or_insert_with(|| self.base.push_binop(op, args))
-            .into()
+        let idx = self.intern(Key::BinOp(op, args), |base| base.push_binop(op, args));
+        if op == BinOp::Max
+            && let Some(maxes) = &mut self.maxes
+        {
+            maxes.insert(idx, args);
+        }
+        Idx::Pos(idx)
+    }
+
+    fn gvn_ternop(&mut self, op: TernOp, mut args: [S::Idx; 3]) -> S::Idx {
+        // `max` is commutative, so `clamp` is in its first two arguments
+        if op == TernOp::Clamp {
+            args[..2].sort_unstable();
+        }
+        self.intern(Key::TernOp(op, args), |base| base.push_ternop(op, args))
     }
 
     fn gvn_unop(&mut self, op: UnOp, arg: S::Idx) -> S::Idx {
-        *self
-            .gvn
-            .entry(Key::UnOp(op, arg))
-            .or_insert_with(|| self.base.push_unop(op, arg))
+        let idx = self.intern(Key::UnOp(op, arg), |base| base.push_unop(op, arg));
+        if is_nonnegative(op) {
+            self.nonnegative.insert(idx);
+        }
+        if matches!(op, UnOp::Exp | UnOp::Ln) {
+            self.inverses.insert(idx, (op, arg));
+        }
+        idx
+    }
+
+    // The argument of `arg`, if applying `op` to it would undo it, as `exp`
+    // and `ln` undo each other. That's not exact at the edges: `ln(exp(x))`
+    // overflows for large `x`, and `exp(ln(x))` is NaN for negative `x`. But
+    // like `abs` of a square root, a program that depends on either has no
+    // meaningful value there anyway.
+    fn undo(&self, op: UnOp, arg: S::Idx) -> Option<S::Idx> {
+        let &(inner, x) = self.inverses.get(&arg)?;
+        (inner != op && matches!(op, UnOp::Exp | UnOp::Ln)).then_some(x)
+    }
+
+    /// Find the index [`push_const`](InstSink::push_const) would return for
+    /// this constant, if it has already been pushed.
+    pub fn get_const(&self, value: Const) -> Option<Idx<S::Idx>> {
+        self.get(Key::Const(value))
     }
 
-    fn force_neg(&mut self, arg: Idx<S::Idx>) -> S::Idx {
+    pub fn get_var(&self, var: Var) -> Option<Idx<S::Idx>> {
+        self.get(Key::Var(var))
+    }
+
+    pub fn get_load(&self, vars: VarSet, loc: Location) -> Option<Idx<S::Idx>> {
+        self.get(Key::Load(vars, loc))
+    }
+
+    /// Find the index [`push_unop`](InstSink::push_unop) would return, if it
+    /// wouldn't need to push any new instructions. Like the indexes pushing
+    /// returns, the result may be [`Idx::Neg`], which means the expression is
+    /// the negation of an existing instruction.
+    pub fn get_unop(&self, op: UnOp, arg: Idx<S::Idx>) -> Option<Idx<S::Idx>> {
+        if let Idx::Pos(x) = arg
+            && let Some(inner) = self.undo(op, x)
+        {
+            return Some(Idx::Pos(inner));
+        }
+        let arg = match (op, arg) {
+            (UnOp::Neg, arg) => return Some(arg.negate()),
+            (UnOp::Square | UnOp::Cos, Idx::Pos(x) | Idx::Neg(x)) => x,
+            (UnOp::Sin | UnOp::Floor | UnOp::Ceil | UnOp::Round | UnOp::Recip, Idx::Neg(x)) => {
+                return self.get(Key::UnOp(reflect(op), x)).map(Idx::negate);
+            }
+            (UnOp::Abs, Idx::Pos(x) | Idx::Neg(x)) if self.nonnegative.contains(&x) => {
+                return Some(Idx::Pos(x));
+            }
+            (UnOp::Abs, Idx::Pos(x) | Idx::Neg(x)) => x,
+            (UnOp::PowI(n), Idx::Neg(x)) if n % 2 != 0 => {
+                return self.get(Key::UnOp(op, x)).map(Idx::negate);
+            }
+            (UnOp::PowI(_), Idx::Pos(x) | Idx::Neg(x)) => x,
+            (_, Idx::Pos(x)) => x,
+            (_, Idx::Neg(x)) => self.get_neg(x)?,
+        };
+        self.get(Key::UnOp(op, arg))
+    }
+
+    /// Find the index [`push_binop`](InstSink::push_binop) would return, if
+    /// it wouldn't need to push any new instructions.
+    pub fn get_binop(&self, op: BinOp, args: [Idx<S::Idx>; 2]) -> Option<Idx<S::Idx>> {
+        if let Some((x, negated)) = square_of(op, args) {
+            let square = self.get(Key::UnOp(UnOp::Square, x))?;
+            return Some(if negated { square.negate() } else { square });
+        }
+        let (op, mut args, negated) = normalize(op, args, |x| self.get_neg(x))?;
+        if let Some(mut args) = self.clamp_of(op, args) {
+            args[..2].sort_unstable();
+            let idx = self.get(Key::TernOp(TernOp::Clamp, args))?;
+            return Some(if negated { idx.negate() } else { idx });
+        }
+        if op == BinOp::Sub {
+            let [a, b] = args;
+            if let Some(idx) = self.get(Key::BinOp(op, [b, a])) {
+                return Some(if negated { idx } else { idx.negate() });
+            }
+        } else if op.is_commutative() {
+            args.sort_unstable();
+        }
+        let idx = self.get(Key::BinOp(op, args))?;
+        Some(if negated { idx.negate() } else { idx })
+    }
+
+    fn get(&self, key: Key<S::Idx>) -> Option<Idx<S::Idx>> {
+        self.lookup(&key).map(Idx::Pos)
+    }
+
+    // an existing `neg` instruction whose argument is `arg`
+    fn get_neg(&self, arg: S::Idx) -> Option<S::Idx> {
+        self.lookup(&Key::UnOp(UnOp::Neg, arg))
+    }
+
+    /// The sink this pass feeds, holding everything pushed so far.
+    pub fn base(&self) -> &S {
+        &self.base
+    }
+
+    /// Turn an index which may be negated into an instruction of the
+    /// underlying sink, pushing a `neg` if needed, as `finish` does for the
+    /// final result.
+    pub fn force_neg(&mut self, arg: Idx<S::Idx>) -> S::Idx {
         match arg {
             Idx::Neg(arg) => self.gvn_unop(UnOp::Neg, arg),
             Idx::Pos(arg) => arg,
@@ -51,12 +294,171 @@ impl<S: InstSink> Simplify<S> {
     }
 }
 
+// Multiplying a value by itself is the same as squaring it, which is the
+// form every later pass and backend expects; each backend lowers `square`
+// however suits it. A product of `x` and `-x` is the negated square.
+fn square_of<I: Eq>(op: BinOp, args: [Idx<I>; 2]) -> Option<(I, bool)> {
+    let [(x, a_neg), (y, b_neg)] = args.map(|arg| match arg {
+        Idx::Pos(x) => (x, false),
+        Idx::Neg(x) => (x, true),
+    });
+    (op == BinOp::Mul && x == y).then_some((x, a_neg != b_neg))
+}
+
+// Rewrite a binary operation on possibly-negated operands as an operation
+// on plain indexes, and whether its result is negated. Operands that can't
+// be folded into the operation need a `neg` instruction, which `neg` returns
+// if it can.
+fn normalize<I>(
+    op: BinOp,
+    args: [Idx<I>; 2],
+    mut neg: impl FnMut(I) -> Option<I>,
+) -> Option<(BinOp, [I; 2], bool)> {
+    Some(match (op, args) {
+        (op, [Idx::Pos(a), Idx::Pos(b)]) => (op, [a, b], false),
+
+        // (-x) + (-y) = -(x + y)
+        (BinOp::Add, [Idx::Neg(a), Idx::Neg(b)]) => (BinOp::Add, [a, b], true),
+        // x + (-y) = x - y
+        (BinOp::Add, [Idx::Pos(a), Idx::Neg(b)]) => (BinOp::Sub, [a, b], false),
+        // (-x) + y = y - x
+        (BinOp::Add, [Idx::Neg(a), Idx::Pos(b)]) => (BinOp::Sub, [b, a], false),
+
+        // (-x) - (-y) = y - x
+        (BinOp::Sub, [Idx::Neg(a), Idx::Neg(b)]) => (BinOp::Sub, [b, a], false),
+        // x - (-y) = x + y
+        (BinOp::Sub, [Idx::Pos(a), Idx::Neg(b)]) => (BinOp::Add, [a, b], false),
+        // (-x) - y = -(x + y)
+        (BinOp::Sub, [Idx::Neg(a), Idx::Pos(b)]) => (BinOp::Add, [a, b], true),
+
+        // (-x) * (-y) = x * y
+        (BinOp::Mul, [Idx::Neg(a), Idx::Neg(b)]) => (BinOp::Mul, [a, b], false),
+        // x * (-y) = -(x * y)
+        (BinOp::Mul, [Idx::Pos(a), Idx::Neg(b)]) => (BinOp::Mul, [a, b], true),
+        // (-x) * y = -(x * y)
+        (BinOp::Mul, [Idx::Neg(a), Idx::Pos(b)]) => (BinOp::Mul, [a, b], true),
+
+        // min(-x, -y) = -max(x, y)
+        (BinOp::Min, [Idx::Neg(a), Idx::Neg(b)]) => (BinOp::Max, [a, b], true),
+        // max(-x, -y) = -min(x, y)
+        (BinOp::Max, [Idx::Neg(a), Idx::Neg(b)]) => (BinOp::Min, [a, b], true),
+
+        // mod(-x, -y) = -mod(x, y), since (-x)/(-y) is exactly x/y
+        (BinOp::Mod, [Idx::Neg(a), Idx::Neg(b)]) => (BinOp::Mod, [a, b], true),
+
+        (op, [Idx::Pos(a), Idx::Neg(b)]) => (op, [a, neg(b)?], false),
+        (op, [Idx::Neg(a), Idx::Pos(b)]) => (op, [neg(a)?, b], false),
+    })
+}
+
+impl Simplify<Insts> {
+    /// Pick up building a program where an earlier pass through `Simplify`
+    /// left off, such as to append edits to a program that was already read.
+    /// Every existing instruction is indexed, so pushing or looking up an
+    /// expression the program already computes finds the existing copy.
+    pub fn resume(insts: Insts) -> Self {
+        let gvn = Gvn::Keys(HashMap::with_capacity(insts.pool.len()));
+        Self::index(insts, gvn)
+    }
+
+    /// Like [`resume`](Self::resume), but the table that finds existing
+    /// copies of an expression only holds 2-byte instruction indexes, and
+    /// looks at the instructions themselves to compare them. For inputs big
+    /// enough that its memory matters, that's much smaller than a map of
+    /// whole keys, but every probe has to read the instruction pool too.
+    pub fn interned(insts: Insts) -> Self {
+        let gvn = Gvn::Interned {
+            slots: Vec::new(),
+            len: 0,
+            hasher: RandomState::new(),
+            key: |insts: &Insts, idx| key(&insts.pool[idx.idx()]),
+        };
+        Self::index(insts, gvn)
+    }
+
+    fn index(insts: Insts, gvn: Gvn<Insts>) -> Self {
+        let mut simplify = Simplify {
+            base: insts,
+            gvn,
+            nonnegative: HashSet::new(),
+            inverses: HashMap::new(),
+            maxes: None,
+        };
+        for idx in 0..simplify.base.pool.len() {
+            let key = key(&simplify.base.pool[idx]);
+            let inst = InstIdx::try_from(idx).unwrap();
+            if let Key::UnOp(op, arg) = key {
+                if is_nonnegative(op) {
+                    simplify.nonnegative.insert(inst);
+                }
+                if matches!(op, UnOp::Exp | UnOp::Ln) {
+                    simplify.inverses.insert(inst, (op, arg));
+                }
+            }
+            if simplify.lookup(&key).is_none() {
+                simplify.insert(key, inst);
+            }
+        }
+        simplify
+    }
+}
+
+// Whether `op` never returns a negative number, so `abs` of it changes
+// nothing. That's not quite true of NaN, whose sign bit `abs` clears, but a
+// program that makes NaN, such as by taking the square root of a negative
+// number, has no meaningful value there anyway.
+fn is_nonnegative(op: UnOp) -> bool {
+    match op {
+        UnOp::Square | UnOp::Sqrt | UnOp::Abs | UnOp::Exp => true,
+        UnOp::PowI(n) => n % 2 == 0,
+        _ => false,
+    }
+}
+
+// The operation `f` for which `op(-x)` is `-f(x)`, for the operations that
+// negation passes through.
+fn reflect(op: UnOp) -> UnOp {
+    match op {
+        UnOp::Floor => UnOp::Ceil,
+        UnOp::Ceil => UnOp::Floor,
+        op => op,
+    }
+}
+
+// The key an instruction would have been pushed with.
+fn key(inst: &Inst) -> Key<InstIdx> {
+    match *inst {
+        Inst::Const { value } => Key::Const(value),
+        Inst::Var { var } => Key::Var(var),
+        Inst::UnOp { op, arg } => Key::UnOp(op, arg),
+        Inst::BinOp { op, mut args } => {
+            if op.is_commutative() {
+                args.sort_unstable();
+            }
+            Key::BinOp(op, args)
+        }
+        Inst::Fma { mut args } => {
+            args[..2].sort_unstable();
+            Key::Fma(args)
+        }
+        Inst::TernOp { op, mut args } => {
+            if op == TernOp::Clamp {
+                args[..2].sort_unstable();
+            }
+            Key::TernOp(op, args)
+        }
+        Inst::Load { vars, loc } => Key::Load(vars, loc),
+    }
+}
+
 #[derive(Debug, Eq, Hash, PartialEq)]
 enum Key<I> {
     Const(Const),
     Var(Var),
     UnOp(UnOp, I),
     BinOp(BinOp, [I; 2]),
+    Fma([I; 3]),
+    TernOp(TernOp, [I; 3]),
     Load(VarSet, Location),
 }
 
@@ -86,17 +488,11 @@ impl<S: InstSink> InstSink for Simplify<S> {
     type Output = S::Output;
 
     fn push_const(&mut self, value: Const) -> Self::Idx {
-        self.gvn
-            .entry(Key::Const(value))
-            .or_insert_with(|| self.base.push_const(value))
-            .into()
+        Idx::Pos(self.intern(Key::Const(value), |base| base.push_const(value)))
     }
 
     fn push_var(&mut self, var: Var) -> Self::Idx {
-        self.gvn
-            .entry(Key::Var(var))
-            .or_insert_with(|| self.base.push_var(var))
-            .into()
+        Idx::Pos(self.intern(Key::Var(var), |base| base.push_var(var)))
     }
 
     fn push_unop(&mut self, op: UnOp, arg: Self::Idx) -> Self::Idx {
@@ -104,11 +500,42 @@ impl<S: InstSink> InstSink for Simplify<S> {
             // Delay creating Neg instructions in case we can simplify them away.
             UnOp::Neg => return arg.negate(),
 
-            // Squaring -x is the same as squaring x, so ignore negation.
-            UnOp::Square => match arg {
+            // Squaring -x is the same as squaring x, so ignore negation, and
+            // likewise for cos.
+            UnOp::Square | UnOp::Cos => match arg {
+                Idx::Pos(x) | Idx::Neg(x) => x,
+            },
+
+            // But sin is odd, so sin(-x) is -sin(x), whose negation may yet
+            // simplify away too, and so is recip. Rounding is nearly so:
+            // floor(-x) is -ceil(x), and the other way around.
+            UnOp::Sin | UnOp::Floor | UnOp::Ceil | UnOp::Round | UnOp::Recip => match arg {
+                Idx::Pos(x) => x,
+                Idx::Neg(x) => return Idx::Neg(self.gvn_unop(reflect(op), x)),
+            },
+
+            // Likewise for abs, which also leaves non-negative values alone.
+            UnOp::Abs => match arg {
+                Idx::Pos(x) | Idx::Neg(x) if self.nonnegative.contains(&x) => return Idx::Pos(x),
+                Idx::Pos(x) | Idx::Neg(x) => x,
+            },
+
+            // Whole powers are even or odd as the power is, and each step
+            // rounds the same either way, so (-x)^n is exactly ±x^n.
+            UnOp::PowI(n) => match arg {
+                Idx::Neg(x) if n % 2 != 0 => return Idx::Neg(self.gvn_unop(op, x)),
                 Idx::Pos(x) | Idx::Neg(x) => x,
             },
 
+            // `exp` and `ln` undo each other.
+            UnOp::Exp | UnOp::Ln => {
+                let arg = self.force_neg(arg);
+                if let Some(inner) = self.undo(op, arg) {
+                    return Idx::Pos(inner);
+                }
+                arg
+            }
+
             // For other operators, emit a Neg first if necessary.
             _ => self.force_neg(arg),
         };
@@ -116,52 +543,305 @@ impl<S: InstSink> InstSink for Simplify<S> {
     }
 
     fn push_binop(&mut self, op: BinOp, args: [Self::Idx; 2]) -> Self::Idx {
-        let (op, args, negated) = match (op, args) {
-            (op, [Idx::Pos(a), Idx::Pos(b)]) => (op, [a, b], false),
-
-            // (-x) + (-y) = -(x + y)
-            (BinOp::Add, [Idx::Neg(a), Idx::Neg(b)]) => (BinOp::Add, [a, b], true),
-            // x + (-y) = x - y
-            (BinOp::Add, [Idx::Pos(a), Idx::Neg(b)]) => (BinOp::Sub, [a, b], false),
-            // (-x) + y = y - x
-            (BinOp::Add, [Idx::Neg(a), Idx::Pos(b)]) => (BinOp::Sub, [b, a], false),
-
-            // (-x) - (-y) = y - x
-            (BinOp::Sub, [Idx::Neg(a), Idx::Neg(b)]) => (BinOp::Sub, [b, a], false),
-            // x - (-y) = x + y
-            (BinOp::Sub, [Idx::Pos(a), Idx::Neg(b)]) => (BinOp::Add, [a, b], false),
-            // (-x) - y = -(x + y)
-            (BinOp::Sub, [Idx::Neg(a), Idx::Pos(b)]) => (BinOp::Add, [a, b], true),
-
-            // (-x) * (-y) = x * y
-            (BinOp::Mul, [Idx::Neg(a), Idx::Neg(b)]) => (BinOp::Mul, [a, b], false),
-            // x * (-y) = -(x * y)
-            (BinOp::Mul, [Idx::Pos(a), Idx::Neg(b)]) => (BinOp::Mul, [a, b], true),
-            // (-x) * y = -(x * y)
-            (BinOp::Mul, [Idx::Neg(a), Idx::Pos(b)]) => (BinOp::Mul, [a, b], true),
-
-            // min(-x, -y) = -max(x, y)
-            (BinOp::Min, [Idx::Neg(a), Idx::Neg(b)]) => (BinOp::Max, [a, b], true),
-            // max(-x, -y) = -min(x, y)
-            (BinOp::Max, [Idx::Neg(a), Idx::Neg(b)]) => (BinOp::Min, [a, b], true),
-
-            (op, [Idx::Pos(a), Idx::Neg(b)]) => (op, [a, self.gvn_unop(UnOp::Neg, b)], false),
-            (op, [Idx::Neg(a), Idx::Pos(b)]) => (op, [self.gvn_unop(UnOp::Neg, a), b], false),
+        if let Some((x, negated)) = square_of(op, args) {
+            let square = Idx::Pos(self.gvn_unop(UnOp::Square, x));
+            return if negated { square.negate() } else { square };
+        }
+        let (op, args, negated) =
+            normalize(op, args, |x| Some(self.gvn_unop(UnOp::Neg, x))).unwrap();
+        let idx = match self.clamp_of(op, args) {
+            Some(args) => Idx::Pos(self.gvn_ternop(TernOp::Clamp, args)),
+            None => self.gvn_binop(op, args),
         };
+        if negated { idx.negate() } else { idx }
+    }
 
-        let idx = self.gvn_binop(op, args);
+    fn push_fma(&mut self, args: [Self::Idx; 3]) -> Self::Idx {
+        let [(a, a_neg), (b, b_neg), (c, c_neg)] = args.map(|arg| match arg {
+            Idx::Pos(x) => (x, false),
+            Idx::Neg(x) => (x, true),
+        });
+        // (-a) * b + c = -(a * b - c), so the sign of the product decides
+        // the sign of the result, and the addend needs a `neg` only if its
+        // sign disagrees
+        let negated = a_neg != b_neg;
+        let c = if c_neg != negated {
+            self.force_neg(Idx::Neg(c))
+        } else {
+            c
+        };
+        let mut args = [a, b, c];
+        args[..2].sort_unstable();
+        let idx = Idx::Pos(self.intern(Key::Fma(args), |base| base.push_fma(args)));
         if negated { idx.negate() } else { idx }
     }
 
+    fn push_ternop(&mut self, op: TernOp, args: [Self::Idx; 3]) -> Self::Idx {
+        // lerp(-a, -b, t) = -lerp(a, b, t), since every step just flips sign
+        if let (TernOp::Lerp, [Idx::Neg(a), Idx::Neg(b), t]) = (op, args) {
+            let t = self.force_neg(t);
+            return Idx::Neg(self.gvn_ternop(op, [a, b, t]));
+        }
+        let args = args.map(|arg| self.force_neg(arg));
+        Idx::Pos(self.gvn_ternop(op, args))
+    }
+
     fn push_load(&mut self, vars: VarSet, loc: Location) -> Self::Idx {
-        self.gvn
-            .entry(Key::Load(vars, loc))
-            .or_insert_with(|| self.base.push_load(vars, loc))
-            .into()
+        Idx::Pos(self.intern(Key::Load(vars, loc), |base| base.push_load(vars, loc)))
     }
 
     fn finish(mut self, last: Self::Idx) -> Self::Output {
+        tracing::debug!(gvn_entries = self.len(), "simplify finished");
         let last = self.force_neg(last);
         self.base.finish(last)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corpus::shape;
+    use crate::ir::io::read;
+
+    #[test]
+    fn test_resume_lookups() {
+        let insts = shape("circle")
+            .unwrap()
+            .read(Simplify::new(Insts::default()));
+        let len = insts.pool.len();
+        let mut simplify = Simplify::resume(insts);
+        let [x, y] = [Var::X, Var::Y].map(|var| simplify.get_var(var).unwrap());
+        let [x2, y2] = [x, y].map(|arg| simplify.get_unop(UnOp::Square, arg).unwrap());
+
+        // addition is commutative, so the operands' order doesn't matter
+        let sum = simplify.get_binop(BinOp::Add, [x2, y2]).unwrap();
+        assert_eq!(simplify.push_binop(BinOp::Add, [y2, x2]), sum);
+        assert_eq!(simplify.get_unop(UnOp::Neg, sum), Some(sum.negate()));
+        assert_eq!(simplify.get_binop(BinOp::Sub, [x2, y2]), None);
+
+        let diff = simplify.push_binop(BinOp::Sub, [x2, y2]);
+        let Idx::Pos(diff_idx) = diff else {
+            panic!("expected a new instruction")
+        };
+        assert_eq!(diff_idx.idx(), len);
+        assert_eq!(
+            simplify.get_binop(BinOp::Sub, [y2, x2]),
+            Some(Idx::Neg(diff_idx))
+        );
+        assert_eq!(simplify.get_const(Const::new(1.5)), None);
+    }
+
+    #[test]
+    fn test_abs() {
+        // |-x| is |x|, and |x²|, |√y|, and ||x|| are what's inside them
+        let text = "x var-x\ny var-y\nn neg x\na abs n\nb abs x\ns square x\nc abs s\n\
+            r sqrt y\nd abs r\ne abs a\nf add b c\ng add d e\nh add f g\n";
+        let insts = read(text.as_bytes(), Simplify::new(Insts::default())).unwrap();
+        let is_abs = |inst: &Inst| matches!(inst, Inst::UnOp { op: UnOp::Abs, .. });
+        assert_eq!(insts.pool.iter().filter(|inst| is_abs(inst)).count(), 1);
+
+        // resuming finds the squares and square roots already there
+        let len = insts.pool.len();
+        let mut simplify = Simplify::resume(insts);
+        let x = simplify.get_var(Var::X).unwrap();
+        let square = simplify.get_unop(UnOp::Square, x).unwrap();
+        assert_eq!(simplify.push_unop(UnOp::Abs, square.negate()), square);
+        assert_eq!(simplify.base().pool.len(), len);
+    }
+
+    #[test]
+    fn test_exp_ln() {
+        // exp(ln y) and ln(exp x) are their arguments, but exp(-ln y) isn't
+        let text = "x var-x\ny var-y\nl ln y\ne exp l\nf exp x\ng ln f\nn neg l\nh exp n\n\
+            a add e g\nb add a h\n";
+        let insts = read(text.as_bytes(), Simplify::new(Insts::default())).unwrap();
+        let mut out = Vec::new();
+        crate::ir::io::write(&mut out, insts.pool.iter().cloned()).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "v0 var-x\nv1 var-y\nv2 ln v1\nv3 exp v0\nv4 neg v2\nv5 exp v4\n\
+            v6 add v0 v1\nv7 add v5 v6\n"
+        );
+
+        // resuming remembers which is which, and that exp isn't negative
+        let mut simplify = Simplify::resume(insts);
+        let x = simplify.get_var(Var::X).unwrap();
+        let exp = simplify.get_unop(UnOp::Exp, x).unwrap();
+        assert_eq!(simplify.get_unop(UnOp::Ln, exp), Some(x));
+        assert_eq!(simplify.push_unop(UnOp::Abs, exp), exp);
+    }
+
+    #[test]
+    fn test_sin_cos() {
+        // cos(-x) is cos(x), and sin(-x) is -sin(x), which the sub absorbs
+        let text = "x var-x\ny var-y\nn neg x\ns sin n\nc cos n\nk cos x\nt sin x\n\
+            a add s y\nb mul a c\nd mul b k\nout add d t\n";
+        let insts = read(text.as_bytes(), Simplify::new(Insts::default())).unwrap();
+        let mut out = Vec::new();
+        crate::ir::io::write(&mut out, insts.pool.iter().cloned()).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "v0 var-x\nv1 var-y\nv2 sin v0\nv3 cos v0\nv4 sub v1 v2\nv5 mul v3 v4\n\
+            v6 mul v3 v5\nv7 add v2 v6\n"
+        );
+
+        let simplify = Simplify::resume(insts);
+        let x = simplify.get_var(Var::X).unwrap();
+        let sin = simplify.get_unop(UnOp::Sin, x).unwrap();
+        assert_eq!(simplify.get_unop(UnOp::Sin, x.negate()), Some(sin.negate()));
+        let cos = simplify.get_unop(UnOp::Cos, x).unwrap();
+        assert_eq!(simplify.get_unop(UnOp::Cos, x.negate()), Some(cos));
+    }
+
+    #[test]
+    fn test_rounding() {
+        let mut simplify = Simplify::new(Insts::default());
+        let x = simplify.push_var(Var::X);
+        let y = simplify.push_var(Var::Y);
+        // floor(-x) is -ceil(x), and round is odd
+        let ceil = simplify.push_unop(UnOp::Ceil, x);
+        assert_eq!(simplify.push_unop(UnOp::Floor, x.negate()), ceil.negate());
+        assert_eq!(
+            simplify.get_unop(UnOp::Floor, x.negate()),
+            Some(ceil.negate())
+        );
+        let floor = simplify.push_unop(UnOp::Floor, x);
+        assert_eq!(
+            simplify.get_unop(UnOp::Ceil, x.negate()),
+            Some(floor.negate())
+        );
+        let round = simplify.push_unop(UnOp::Round, x);
+        assert_eq!(simplify.push_unop(UnOp::Round, x.negate()), round.negate());
+
+        // mod(-x, -y) is -mod(x, y), but the operands don't commute
+        let rem = simplify.push_binop(BinOp::Mod, [x, y]);
+        assert_eq!(
+            simplify.push_binop(BinOp::Mod, [x.negate(), y.negate()]),
+            rem.negate()
+        );
+        assert_eq!(simplify.get_binop(BinOp::Mod, [y, x]), None);
+        assert_ne!(simplify.push_binop(BinOp::Mod, [y, x]), rem);
+    }
+
+    #[test]
+    fn test_recip() {
+        let mut simplify = Simplify::new(Insts::default());
+        let x = simplify.push_var(Var::X);
+        // 1/(-x) is -(1/x), but 1/sqrt(-x) is something else entirely
+        let recip = simplify.push_unop(UnOp::Recip, x);
+        assert_eq!(simplify.push_unop(UnOp::Recip, x.negate()), recip.negate());
+        assert_eq!(
+            simplify.get_unop(UnOp::Recip, x.negate()),
+            Some(recip.negate())
+        );
+        let rsqrt = simplify.push_unop(UnOp::Rsqrt, x);
+        assert_eq!(simplify.get_unop(UnOp::Rsqrt, x.negate()), None);
+        assert_ne!(simplify.push_unop(UnOp::Rsqrt, x.negate()), rsqrt);
+    }
+
+    #[test]
+    fn test_square_products() {
+        let mut simplify = Simplify::new(Insts::default());
+        let x = simplify.push_var(Var::X);
+        let square = simplify.push_binop(BinOp::Mul, [x, x]);
+        assert_eq!(simplify.get_unop(UnOp::Square, x), Some(square));
+        assert_eq!(
+            simplify.push_binop(BinOp::Mul, [x.negate(), x]),
+            square.negate()
+        );
+        assert_eq!(
+            simplify.get_binop(BinOp::Mul, [x, x.negate()]),
+            Some(square.negate())
+        );
+        assert_eq!(
+            simplify.base().pool,
+            [
+                Inst::Var { var: Var::X },
+                Inst::UnOp {
+                    op: UnOp::Square,
+                    arg: InstIdx::try_from(0).unwrap()
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fma_signs() {
+        let mut simplify = Simplify::new(Insts::default());
+        let [x, y, z] = [Var::X, Var::Y, Var::Z].map(|var| simplify.push_var(var));
+        let fma = simplify.push_fma([x, y, z]);
+        // the product's sign moves outside, and the factors commute
+        assert_eq!(simplify.push_fma([y.negate(), x, z.negate()]), fma.negate());
+        assert_eq!(simplify.base().pool.len(), 4);
+        // (-x) * y + z = -(x * y - z), which needs a `neg` of z
+        let mixed = simplify.push_fma([x.negate(), y, z]);
+        let Idx::Neg(mixed) = mixed else {
+            panic!("expected a negated fma")
+        };
+        assert_eq!(
+            simplify.base().pool[mixed.idx()],
+            Inst::Fma {
+                args: [0, 1, 4].map(|idx| InstIdx::try_from(idx).unwrap())
+            }
+        );
+        assert_eq!(
+            simplify.base().pool[4],
+            Inst::UnOp {
+                op: UnOp::Neg,
+                arg: InstIdx::try_from(2).unwrap()
+            }
+        );
+    }
+
+    #[test]
+    fn test_fuse_clamps() {
+        let mut simplify = Simplify::new(Insts::default()).fuse_clamps();
+        let [x, y, z] = [Var::X, Var::Y, Var::Z].map(|var| simplify.push_var(var));
+        let max = simplify.push_binop(BinOp::Max, [x, y]);
+        let clamp = simplify.push_binop(BinOp::Min, [z, max]);
+        // `max` commutes, and so does the `min` of it
+        assert_eq!(simplify.push_ternop(TernOp::Clamp, [y, x, z]), clamp);
+        assert_eq!(simplify.get_binop(BinOp::Min, [max, z]), Some(clamp));
+        // max(-a, -b) = -min(a, b), so this is a negated clamp
+        let neg_max = simplify.push_binop(BinOp::Max, [z.negate(), max.negate()]);
+        assert_eq!(neg_max, clamp.negate());
+        let Idx::Pos(clamp) = clamp else {
+            panic!("expected a clamp")
+        };
+        assert_eq!(
+            simplify.base().pool[clamp.idx()],
+            Inst::TernOp {
+                op: TernOp::Clamp,
+                args: [0, 1, 2].map(|idx| InstIdx::try_from(idx).unwrap())
+            }
+        );
+
+        // without asking for it, `min` and `max` stay as they are
+        let insts = read(
+            "x var-x\ny var-y\nm max x y\nout min m x\n".as_bytes(),
+            Insts::default(),
+        );
+        let plain = insts.unwrap().replay(Simplify::new(Insts::default()));
+        assert!(matches!(plain.pool[3], Inst::BinOp { op: BinOp::Min, .. }));
+    }
+
+    #[test]
+    fn test_interned_keys() {
+        let shape = shape("fenced").unwrap();
+        let expected = shape.read(Simplify::new(Insts::default()));
+        let insts = shape.read(Simplify::interned(Insts::default()));
+        assert_eq!(insts.pool, expected.pool);
+
+        // resuming finds every instruction again, including through a
+        // reversed subtraction
+        let mut simplify = Simplify::interned(insts);
+        let [x, y] = [Var::X, Var::Y].map(|var| simplify.get_var(var).unwrap());
+        let diff = simplify.push_binop(BinOp::Sub, [x, y]);
+        let len = simplify.base().pool.len();
+        assert_eq!(simplify.push_binop(BinOp::Sub, [y, x]), diff.negate());
+        for (idx, inst) in expected.pool.iter().enumerate() {
+            assert_eq!(simplify.lookup(&key(inst)).unwrap().idx(), idx, "{inst:?}");
+        }
+        assert_eq!(simplify.base().pool.len(), len);
+    }
+}
@@ -0,0 +1,161 @@
+use super::memoize::MemoizedFunc;
+use super::{Inst, InstIdx, Location};
+
+/// Split `func` into a chain of functions of roughly `max_insts`
+/// instructions each, for when one function's code would be too big to stay
+/// in the instruction cache. Every part has the same variables and
+/// parameters as `func`, and calling them in order has the same effect as
+/// calling `func` once.
+///
+/// A value computed in one part and used in a later one is stored in a
+/// scratch location in `func`'s own memory space, after its outputs, and
+/// loaded back where it's needed. A value which is already one of `func`'s
+/// outputs is loaded back from there instead, since storing it twice would
+/// leave two locations to keep in step. Loads are repeated in each part instead,
+/// since they'd cost a load either way. Each part's outputs cover the
+/// scratch locations too, so any part's `outputs.len()` is the size of the
+/// whole chain's buffer.
+pub fn split(func: &MemoizedFunc, max_insts: usize) -> Vec<MemoizedFunc> {
+    let len = func.insts.len();
+    if len <= max_insts.max(1) {
+        return vec![func.clone()];
+    }
+    let count = len.div_ceil(max_insts.max(1));
+    let chunk = len.div_ceil(count);
+    let part_of = |idx: InstIdx| idx.idx() / chunk;
+
+    // assign scratch locations to values which cross from one part to
+    // another, starting with the outputs that already have one
+    let mut scratch: Vec<Option<Location>> = vec![None; len];
+    for (loc, output) in func.outputs.iter().enumerate() {
+        if let &Some(def) = output {
+            scratch[def.idx()].get_or_insert(loc.try_into().unwrap());
+        }
+    }
+    let mut locations = func.outputs.len();
+    for (idx, inst) in func.insts.iter().enumerate() {
+        for &arg in inst.args() {
+            if part_of(arg) < idx / chunk
+                && !matches!(func.insts[arg.idx()], Inst::Load { .. })
+                && scratch[arg.idx()].is_none()
+            {
+                scratch[arg.idx()] = Some(locations.try_into().unwrap());
+                locations += 1;
+            }
+        }
+    }
+
+    (0..count)
+        .map(|part| {
+            let mut result = MemoizedFunc {
+                vars: func.vars,
+                outputs: vec![None; locations],
+                ..MemoizedFunc::default()
+            };
+            let mut map: Vec<Option<InstIdx>> = vec![None; len];
+            let start = part * chunk;
+            for idx in start..len.min(start + chunk) {
+                let mut inst = func.insts[idx].clone();
+                for arg in inst.args_mut() {
+                    *arg = match map[arg.idx()] {
+                        Some(mapped) => mapped,
+                        None => {
+                            // defined in an earlier part
+                            let load = match scratch[arg.idx()] {
+                                Some(loc) => Inst::Load {
+                                    vars: func.vars,
+                                    loc,
+                                },
+                                None => func.insts[arg.idx()].clone(),
+                            };
+                            let mapped = push(&mut result, load, func, *arg);
+                            map[arg.idx()] = Some(mapped);
+                            mapped
+                        }
+                    };
+                }
+                let def = InstIdx::try_from(idx).unwrap();
+                map[idx] = Some(push(&mut result, inst, func, def));
+                if let Some(loc) = scratch[idx] {
+                    result.outputs[usize::from(loc)] = map[idx];
+                }
+            }
+            for (loc, output) in func.outputs.iter().enumerate() {
+                if let &Some(def) = output
+                    && part_of(def) == part
+                {
+                    result.outputs[loc] = map[def.idx()];
+                }
+            }
+            result
+        })
+        .collect()
+}
+
+// Add a copy of `func`'s instruction `orig`, or a load of it, keeping its
+// range.
+fn push(result: &mut MemoizedFunc, inst: Inst, func: &MemoizedFunc, orig: InstIdx) -> InstIdx {
+    let idx = result.insts.len().try_into().unwrap();
+    result.insts.push(inst);
+    result.ranges.push(func.ranges[orig.idx()]);
+    idx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corpus::shape;
+    use crate::ir::bytecode::{Input, MemoizedProgram, Program};
+    use crate::ir::memoize::{MemoBuilder, Memoized};
+    use crate::ir::{Var, VarSet};
+
+    // Run functions of x and y one after another on the same memory space.
+    fn run(memoized: &Memoized, funcs: &[MemoizedFunc], spaces: [&[f32]; 2]) -> Vec<f32> {
+        let mut space = vec![0.0; funcs[0].outputs.len()];
+        for func in funcs {
+            let program = Program::compile_memoized(memoized, func);
+            let mut regs = program.registers();
+            for (reg, input) in regs.iter_mut().zip(program.inputs()) {
+                let &Input::Load(vars, loc) = input else {
+                    unreachable!()
+                };
+                let loc = usize::from(loc);
+                *reg = if vars == VarSet::from(Var::X) {
+                    spaces[0][loc]
+                } else if vars == VarSet::from(Var::Y) {
+                    spaces[1][loc]
+                } else {
+                    space[loc]
+                };
+            }
+            program.run(&mut regs);
+            for (loc, value) in program.outputs(&regs).enumerate() {
+                if let Some(value) = value {
+                    space[loc] = value;
+                }
+            }
+        }
+        space
+    }
+
+    #[test]
+    fn test_chain_matches() {
+        let memoized = shape("fenced").unwrap().read(MemoBuilder::new());
+        let program = MemoizedProgram::compile(&memoized);
+        let xy = &memoized.funcs[2];
+        let parts = split(xy, 3);
+        assert!(parts.len() >= 3);
+        assert!(
+            parts
+                .iter()
+                .all(|part| part.outputs.len() > xy.outputs.len())
+        );
+        for coords in [[-1.0, 1.0], [0.25, -0.5], [0.5, 0.0]] {
+            let spaces = [program.column(coords[0]), program.row(coords[1])];
+            let spaces = [&spaces[0][..], &spaces[1][..]];
+            let whole = run(&memoized, std::slice::from_ref(xy), spaces);
+            let chain = run(&memoized, &parts, spaces);
+            assert_eq!(whole, chain[..whole.len()]);
+        }
+    }
+}
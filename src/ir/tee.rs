@@ -0,0 +1,97 @@
+use super::{BinOp, Const, InstSink, Location, TernOp, UnOp, Var, VarSet};
+
+/// Forward every instruction to two sinks at once, such as collecting
+/// [`Insts`](super::Insts) to check a pass against while also feeding the
+/// pass itself, without parsing the source twice. Each sink sees only its
+/// own indexes, and the outputs of both are returned as a pair.
+pub struct Tee<A, B> {
+    a: A,
+    b: B,
+}
+
+impl<A: InstSink, B: InstSink> Tee<A, B> {
+    pub fn new(a: A, b: B) -> Self {
+        Tee { a, b }
+    }
+}
+
+impl<A: InstSink, B: InstSink> InstSink for Tee<A, B> {
+    type Idx = (A::Idx, B::Idx);
+    type Output = (A::Output, B::Output);
+
+    fn push_const(&mut self, value: Const) -> Self::Idx {
+        (self.a.push_const(value), self.b.push_const(value))
+    }
+
+    fn push_var(&mut self, var: Var) -> Self::Idx {
+        (self.a.push_var(var), self.b.push_var(var))
+    }
+
+    fn push_unop(&mut self, op: UnOp, (a, b): Self::Idx) -> Self::Idx {
+        (self.a.push_unop(op, a), self.b.push_unop(op, b))
+    }
+
+    fn push_binop(&mut self, op: BinOp, [(a0, b0), (a1, b1)]: [Self::Idx; 2]) -> Self::Idx {
+        (
+            self.a.push_binop(op, [a0, a1]),
+            self.b.push_binop(op, [b0, b1]),
+        )
+    }
+
+    fn push_fma(&mut self, [(a0, b0), (a1, b1), (a2, b2)]: [Self::Idx; 3]) -> Self::Idx {
+        (self.a.push_fma([a0, a1, a2]), self.b.push_fma([b0, b1, b2]))
+    }
+
+    fn push_ternop(
+        &mut self,
+        op: TernOp,
+        [(a0, b0), (a1, b1), (a2, b2)]: [Self::Idx; 3],
+    ) -> Self::Idx {
+        (
+            self.a.push_ternop(op, [a0, a1, a2]),
+            self.b.push_ternop(op, [b0, b1, b2]),
+        )
+    }
+
+    fn push_load(&mut self, vars: VarSet, loc: Location) -> Self::Idx {
+        (self.a.push_load(vars, loc), self.b.push_load(vars, loc))
+    }
+
+    fn finish(self, (a, b): Self::Idx) -> Self::Output {
+        (self.a.finish(a), self.b.finish(b))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corpus::shape;
+    use crate::ir::Insts;
+    use crate::ir::count::Count;
+    use crate::ir::io::{write, write_memoized};
+    use crate::ir::memoize::MemoBuilder;
+
+    #[test]
+    fn test_matches_separate_reads() {
+        // three sinks with different index types, fed by one parse
+        let shape = shape("ring").unwrap();
+        let tee = Tee::new(
+            Insts::default(),
+            Tee::new(MemoBuilder::new(), Count::default()),
+        );
+        let (insts, (memoized, counts)) = shape.read(tee);
+
+        let text = |insts: &Insts| {
+            let mut out = Vec::new();
+            write(&mut out, insts.pool.iter().cloned()).unwrap();
+            out
+        };
+        let expected = shape.insts();
+        assert_eq!(text(&insts), text(&expected));
+        assert_eq!(counts, expected.replay(Count::default()));
+        let (mut ours, mut theirs) = (Vec::new(), Vec::new());
+        write_memoized(&mut ours, &memoized).unwrap();
+        write_memoized(&mut theirs, &expected.replay(MemoBuilder::new())).unwrap();
+        assert_eq!(ours, theirs);
+    }
+}
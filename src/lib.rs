@@ -1,2 +1,47 @@
+// Without the default `std` feature, only the IR types, memoization, and the
+// bytecode VM are built, using nothing but `core` and `alloc`.
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(not(feature = "std"))]
+extern crate alloc;
+
+// The cdylib for C hosts needs std's panic handler and allocator to link, so
+// std is still linked wherever it exists, but not named, so nothing here can
+// use it. Targets without an operating system have no std, and rustc drops
+// the cdylib for them.
+#[cfg(all(not(feature = "std"), not(target_os = "none")))]
+extern crate std as _;
+
+#[cfg(feature = "alloc-stats")]
+pub mod alloc;
+#[cfg(feature = "std")]
+pub mod cancel;
+#[cfg(feature = "std")]
 pub mod codegen;
+#[cfg(all(feature = "std", not(target_family = "wasm")))]
+pub mod conformance;
+#[cfg(feature = "std")]
+pub mod corpus;
+#[cfg(feature = "std")]
+pub mod engine;
+#[cfg(feature = "std")]
+pub mod ffi;
+#[cfg(feature = "fonts")]
+pub mod font;
+#[cfg(feature = "std")]
+pub mod image;
 pub mod ir;
+#[cfg(feature = "python")]
+pub mod python;
+#[cfg(feature = "std")]
+pub mod render;
+#[cfg(all(feature = "std", not(target_family = "wasm")))]
+pub mod runtime;
+// WebAssembly usually can't spawn the threads that requests render on.
+#[cfg(all(feature = "std", not(target_family = "wasm")))]
+pub mod service;
+#[cfg(feature = "watch")]
+pub mod watch;
+
+#[cfg(feature = "std")]
+pub use engine::{compile, compile_batch};
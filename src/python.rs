@@ -0,0 +1,121 @@
+//! A Python module exposing the usual pipeline, built with the `python`
+//! feature as a shared object that Python can import directly:
+//!
+//! ```sh
+//! cargo build --release --lib --features python
+//! cp target/release/liblive_long_and_prospero.so live_long_and_prospero.so
+//! ```
+//!
+//! Every function takes program text in Matt Keeter's format and raises
+//! `ValueError` if it doesn't parse. Rendering releases the GIL, so other
+//! Python threads keep running meanwhile.
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use crate::cancel::CancelToken;
+use crate::engine::{self, Compiled};
+use crate::ir::Insts;
+use crate::ir::bytecode::Program;
+use crate::ir::io::{self, read, write};
+use crate::render::Config;
+
+fn value_error(err: io::Error) -> PyErr {
+    PyValueError::new_err(err.to_string())
+}
+
+fn to_text(insts: Insts) -> String {
+    let mut text = Vec::new();
+    write(&mut text, insts.pool).unwrap();
+    String::from_utf8(text).unwrap()
+}
+
+/// Parse a program and write it back out with instructions renamed in
+/// order, which also checks that it's valid.
+#[pyfunction]
+fn parse(text: &str) -> PyResult<String> {
+    let insts = read(text.as_bytes(), Insts::default()).map_err(value_error)?;
+    Ok(to_text(insts))
+}
+
+/// Simplify and reassociate a program, returning the result as text.
+#[pyfunction]
+fn optimize(text: &str) -> PyResult<String> {
+    Ok(to_text(engine::optimize(text).map_err(value_error)?))
+}
+
+/// Render a program as a `size`×`size` black-and-white PBM image, where
+/// `size` is at least 2.
+#[pyfunction]
+fn render<'py>(py: Python<'py>, text: &str, size: u16) -> PyResult<Bound<'py, PyBytes>> {
+    if size < 2 {
+        return Err(PyValueError::new_err(format!(
+            "image size {size} is under 2 pixels"
+        )));
+    }
+    let pbm = py.detach(|| {
+        let compiled = Compiled::new(text).map_err(value_error)?;
+        let image = compiled
+            .render(size, Config::default(), &CancelToken::new())
+            .unwrap();
+        let mut pbm = Vec::new();
+        image.write_pbm(&mut pbm).unwrap();
+        Ok::<_, PyErr>(pbm)
+    })?;
+    Ok(PyBytes::new(py, &pbm))
+}
+
+/// Compile a program into a callable `Shape`.
+#[pyfunction]
+fn compile(text: &str) -> PyResult<Shape> {
+    let program = Program::compile(&engine::optimize(text).map_err(value_error)?);
+    Ok(Shape { program })
+}
+
+/// A compiled program, which can be called like a function of `x`, `y`,
+/// and optionally `z`.
+#[pyclass(frozen)]
+struct Shape {
+    program: Program,
+}
+
+#[pymethods]
+impl Shape {
+    #[pyo3(signature = (x, y, z = 0.0))]
+    fn __call__(&self, x: f32, y: f32, z: f32) -> f32 {
+        self.program.eval(&mut self.program.registers(), &[x, y, z])
+    }
+
+    /// Evaluate the shape at every point given by equal-length lists of
+    /// coordinates, with `z` zero everywhere if it's omitted.
+    #[pyo3(signature = (xs, ys, zs = None))]
+    fn batch(
+        &self,
+        py: Python<'_>,
+        xs: Vec<f32>,
+        ys: Vec<f32>,
+        zs: Option<Vec<f32>>,
+    ) -> PyResult<Vec<f32>> {
+        let zs = zs.unwrap_or_else(|| vec![0.0; xs.len()]);
+        if ys.len() != xs.len() || zs.len() != xs.len() {
+            return Err(PyValueError::new_err("coordinate lists differ in length"));
+        }
+        Ok(py.detach(|| {
+            let mut regs = self.program.registers();
+            (0..xs.len())
+                .map(|idx| self.program.eval(&mut regs, &[xs[idx], ys[idx], zs[idx]]))
+                .collect()
+        }))
+    }
+}
+
+#[pymodule]
+fn live_long_and_prospero(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(parse, m)?)?;
+    m.add_function(wrap_pyfunction!(optimize, m)?)?;
+    m.add_function(wrap_pyfunction!(render, m)?)?;
+    m.add_function(wrap_pyfunction!(compile, m)?)?;
+    m.add_class::<Shape>()?;
+    Ok(())
+}
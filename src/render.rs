@@ -0,0 +1,1020 @@
+use clap::{Args, ValueEnum};
+use std::collections::{HashMap, VecDeque};
+use std::num::NonZero;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, mpsc};
+use std::thread;
+use std::time::Duration;
+
+use crate::cancel::{CancelToken, Cancelled};
+use crate::image::{Image, Orientation};
+use crate::ir::bytecode::{MemoizedProgram, Program};
+use crate::ir::interp::eval_point;
+use crate::ir::interval::{Interval, eval_interval};
+use crate::ir::memoize::Memoized;
+use crate::ir::{BinOp, Inst, InstIdx, Insts};
+
+#[derive(Args, Clone, Copy, Debug)]
+pub struct Config {
+    /// Width and height, in pixels, of the square tiles which are handed out
+    /// to rendering threads
+    #[arg(long, default_value_t = 32)]
+    pub tile_size: u16,
+
+    /// Number of rendering threads; 0 means one per available CPU
+    #[arg(long, default_value_t = 0)]
+    pub threads: usize,
+
+    /// Images smaller than this many pixels across are rendered without
+    /// memoization, since computing every column and row up front costs more
+    /// than it saves on tiny images
+    #[arg(long, default_value_t = 4)]
+    pub memoize_min_size: u16,
+
+    /// The order in which threads pick up tiles. Streaming renders always go
+    /// a row of tiles at a time, since that's the order rows are written
+    #[arg(long, default_value_t = TileOrder::RowMajor, value_enum)]
+    pub tile_order: TileOrder,
+}
+
+/// Paths through the grid of tiles. Neighboring tiles use the same columns
+/// and rows of memoized values, and write to nearby parts of the image, so
+/// the space-filling curves keep more of what each thread touches in cache
+/// than going across whole rows of tiles does.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum TileOrder {
+    /// Each row of tiles from left to right, starting at the top
+    #[default]
+    RowMajor,
+    /// The Z-order curve, which visits each quadrant before the next
+    Morton,
+    /// The Hilbert curve, which also visits each quadrant in turn, but only
+    /// ever steps to an adjacent tile
+    Hilbert,
+}
+
+impl TileOrder {
+    // How many steps along the path cover a square grid `tiles_per_row`
+    // tiles across. The curves only fill squares whose sides are powers of
+    // two, so they cover a bigger grid and skip the steps outside this one.
+    fn len(self, tiles_per_row: usize) -> usize {
+        match self {
+            TileOrder::RowMajor => tiles_per_row * tiles_per_row,
+            TileOrder::Morton | TileOrder::Hilbert => tiles_per_row.next_power_of_two().pow(2),
+        }
+    }
+
+    // The column and row of step `idx` along the path, which may be outside
+    // the grid.
+    fn position(self, idx: usize, tiles_per_row: usize) -> [usize; 2] {
+        match self {
+            TileOrder::RowMajor => [idx % tiles_per_row, idx / tiles_per_row],
+            // even bits of the index are the column and odd bits the row
+            TileOrder::Morton => [0, 1].map(|axis| {
+                (0..usize::BITS / 2).fold(0, |pos, bit| pos | (idx >> (2 * bit + axis) & 1) << bit)
+            }),
+            TileOrder::Hilbert => {
+                // from the `d2xy` function on Wikipedia's page on the curve
+                let [mut col, mut row] = [0, 0];
+                let mut rest = idx;
+                let mut side = 1;
+                while side < tiles_per_row {
+                    let right = 1 & (rest / 2);
+                    let down = 1 & (rest ^ right);
+                    if down == 0 {
+                        if right == 1 {
+                            col = side - 1 - col;
+                            row = side - 1 - row;
+                        }
+                        std::mem::swap(&mut col, &mut row);
+                    }
+                    col += side * right;
+                    row += side * down;
+                    rest /= 4;
+                    side *= 2;
+                }
+                [col, row]
+            }
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            tile_size: 32,
+            threads: 0,
+            memoize_min_size: 4,
+            tile_order: TileOrder::RowMajor,
+        }
+    }
+}
+
+impl Config {
+    fn threads(&self) -> usize {
+        if self.threads > 0 {
+            self.threads
+        } else {
+            thread::available_parallelism().map_or(1, NonZero::get)
+        }
+    }
+}
+
+/// Render the program in parallel. The image is split into tiles which
+/// threads pick up one at a time from a shared queue, so threads that finish
+/// their tiles early keep taking more work rather than sitting idle.
+pub fn render(insts: &Insts, size: u16, config: Config) -> Image {
+    render_cancellable(insts, size, config, &CancelToken::default()).unwrap()
+}
+
+/// Like [`render`], but threads stop picking up new tiles once `cancel` is
+/// triggered.
+pub fn render_cancellable(
+    insts: &Insts,
+    size: u16,
+    config: Config,
+    cancel: &CancelToken,
+) -> Result<Image, Cancelled> {
+    render_tree(insts, size, config, cancel, Collect)
+}
+
+/// Like [`render_cancellable`], but hands each row of the image to `emit`, in
+/// order starting from the first row in `orientation`, as soon as every tile
+/// covering it is finished. Only a few rows of tiles are held at once, so
+/// images far too big to keep in memory can be written out as they're
+/// rendered. Threads that get ahead of the oldest unfinished row of tiles
+/// wait for it, rather than piling up finished tiles. Any error from `emit`
+/// stops the render and is returned, as is [`Cancelled`] if `cancel` is
+/// triggered before every row has been emitted.
+pub fn stream_rows<E: From<Cancelled>>(
+    insts: &Insts,
+    size: u16,
+    orientation: Orientation,
+    config: Config,
+    cancel: &CancelToken,
+    emit: impl FnMut(&[f32]) -> Result<(), E>,
+) -> Result<(), E> {
+    render_tree(insts, size, config, cancel, Stream { orientation, emit })
+}
+
+fn render_tree<T: Tiles>(
+    insts: &Insts,
+    size: u16,
+    config: Config,
+    cancel: &CancelToken,
+    tiles: T,
+) -> Result<T::Output, T::Error> {
+    let scale = 2.0 / f32::from(size - 1);
+    tiles.run(size, config, cancel, || {
+        let mut regs = vec![0f32; insts.pool.len()];
+        per_pixel(move |col, row| {
+            let x = f32::from(col) * scale - 1.0;
+            let y = f32::from(size - 1 - row) * scale - 1.0;
+            eval_point(insts, &mut regs, &[x, y])
+        })
+    })
+}
+
+/// Like [`render_cancellable`], but evaluates a program which has already
+/// been compiled to bytecode, which is much faster than walking the
+/// instructions at every pixel.
+pub fn render_bytecode_cancellable(
+    program: &Program,
+    size: u16,
+    config: Config,
+    cancel: &CancelToken,
+) -> Result<Image, Cancelled> {
+    let scale = 2.0 / f32::from(size - 1);
+    render_tiles(size, config, cancel, || {
+        let mut regs = program.registers();
+        per_pixel(move |col, row| {
+            let x = f32::from(col) * scale - 1.0;
+            let y = f32::from(size - 1 - row) * scale - 1.0;
+            program.eval(&mut regs, &[x, y])
+        })
+    })
+}
+
+/// Like [`render`], but for a memoized program. Values which depend only on
+/// x or only on y are computed once per column or row up front, so only the
+/// `xy` function runs at every pixel, which is much faster.
+pub fn render_memoized(memoized: &Memoized, size: u16, config: Config) -> Image {
+    render_memoized_cancellable(memoized, size, config, &CancelToken::default()).unwrap()
+}
+
+/// Like [`render_memoized`], but threads stop picking up new tiles once
+/// `cancel` is triggered.
+pub fn render_memoized_cancellable(
+    memoized: &Memoized,
+    size: u16,
+    config: Config,
+    cancel: &CancelToken,
+) -> Result<Image, Cancelled> {
+    render_program_cancellable(&MemoizedProgram::compile(memoized), size, config, cancel)
+}
+
+/// Like [`render_memoized_cancellable`], for a program which has already been
+/// compiled, so callers rendering the same shape repeatedly can skip that.
+pub fn render_program_cancellable(
+    program: &MemoizedProgram,
+    size: u16,
+    config: Config,
+    cancel: &CancelToken,
+) -> Result<Image, Cancelled> {
+    render_program(program, size, config, cancel, Collect)
+}
+
+/// Like [`stream_rows`], but for a memoized program, as in
+/// [`render_memoized`].
+pub fn stream_rows_memoized<E: From<Cancelled>>(
+    memoized: &Memoized,
+    size: u16,
+    orientation: Orientation,
+    config: Config,
+    cancel: &CancelToken,
+    emit: impl FnMut(&[f32]) -> Result<(), E>,
+) -> Result<(), E> {
+    let program = MemoizedProgram::compile(memoized);
+    let stream = Stream { orientation, emit };
+    render_program(&program, size, config, cancel, stream)
+}
+
+fn render_program<T: Tiles>(
+    program: &MemoizedProgram,
+    size: u16,
+    config: Config,
+    cancel: &CancelToken,
+    tiles: T,
+) -> Result<T::Output, T::Error> {
+    let scale = 2.0 / f32::from(size - 1);
+    let columns: Vec<Vec<f32>> = (0..size)
+        .map(|col| program.column(f32::from(col) * scale - 1.0))
+        .collect();
+    cancel.check()?;
+    let rows: Vec<Vec<f32>> = (0..size)
+        .map(|row| program.row(f32::from(size - 1 - row) * scale - 1.0))
+        .collect();
+
+    tiles.run(size, config, cancel, || {
+        let mut regs = program.registers();
+        let (columns, rows) = (&columns, &rows);
+        per_pixel(move |col, row| {
+            program.pixel(
+                &mut regs,
+                &columns[usize::from(col)],
+                &rows[usize::from(row)],
+            )
+        })
+    })
+}
+
+/// Like [`render`], but skips regions of the image which are entirely inside
+/// or outside the shape. Each tile is first evaluated with interval
+/// arithmetic, and tiles which might contain the contour are split into
+/// quadrants until they're small enough to evaluate pixel by pixel. Pixels in
+/// skipped regions get a bound on their value rather than the exact value, so
+/// only their sign is meaningful, but that's all [`Image::write_pbm`] uses.
+pub fn render_adaptive(insts: &Insts, size: u16, config: Config) -> Image {
+    render_adaptive_cancellable(insts, size, config, &CancelToken::default()).unwrap()
+}
+
+/// Like [`render_adaptive`], but threads stop picking up new tiles once
+/// `cancel` is triggered.
+pub fn render_adaptive_cancellable(
+    insts: &Insts,
+    size: u16,
+    config: Config,
+    cancel: &CancelToken,
+) -> Result<Image, Cancelled> {
+    render_adaptive_tiles(insts, size, config, cancel, Collect)
+}
+
+/// Like [`stream_rows`], but skipping regions which are entirely inside or
+/// outside the shape, as in [`render_adaptive`].
+pub fn stream_rows_adaptive<E: From<Cancelled>>(
+    insts: &Insts,
+    size: u16,
+    orientation: Orientation,
+    config: Config,
+    cancel: &CancelToken,
+    emit: impl FnMut(&[f32]) -> Result<(), E>,
+) -> Result<(), E> {
+    let stream = Stream { orientation, emit };
+    render_adaptive_tiles(insts, size, config, cancel, stream)
+}
+
+fn render_adaptive_tiles<T: Tiles>(
+    insts: &Insts,
+    size: u16,
+    config: Config,
+    cancel: &CancelToken,
+    tiles: T,
+) -> Result<T::Output, T::Error> {
+    let evaluations = AtomicUsize::new(0);
+    let specialized = Mutex::new(HashMap::new());
+    let output = tiles.run(size, config, cancel, || {
+        let mut adaptive = Adaptive::new(insts, size, &evaluations, &specialized);
+        move |rect, tile| adaptive.fill(rect, rect, tile)
+    })?;
+    tracing::debug!(
+        evaluations = evaluations.into_inner(),
+        pixels = usize::from(size) * usize::from(size),
+        specialized = specialized.into_inner().unwrap().len(),
+        "adaptive render finished"
+    );
+    Ok(output)
+}
+
+// Regions with at most this many pixels are evaluated one pixel at a time
+// rather than subdivided further.
+const ADAPTIVE_LEAF_PIXELS: usize = 16;
+
+struct Adaptive<'a> {
+    insts: &'a Insts,
+    size: u16,
+    scale: f32,
+    regs: Vec<f32>,
+    intervals: Vec<Interval>,
+    evaluations: &'a AtomicUsize,
+    // Programs for regions where interval arithmetic proved which operand
+    // of some `min` or `max` instructions always wins, with the others left
+    // out, keyed by those choices. Regular patterns, such as a grid of
+    // letters, have many regions which make the same choices, so every
+    // thread shares one cache.
+    specialized: &'a Mutex<HashMap<Vec<u64>, Arc<Insts>>>,
+    // how many `min` and `max` instructions there are
+    choices: usize,
+    // scratch space for `specialize`
+    key: Vec<u64>,
+    winners: Vec<Option<InstIdx>>,
+    live: Vec<bool>,
+}
+
+impl<'a> Adaptive<'a> {
+    fn new(
+        insts: &'a Insts,
+        size: u16,
+        evaluations: &'a AtomicUsize,
+        specialized: &'a Mutex<HashMap<Vec<u64>, Arc<Insts>>>,
+    ) -> Self {
+        let len = insts.pool.len();
+        let choices = insts
+            .pool
+            .iter()
+            .filter(|inst| is_min_max(inst).is_some())
+            .count();
+        Adaptive {
+            insts,
+            size,
+            scale: 2.0 / f32::from(size - 1),
+            regs: vec![0.0; len],
+            intervals: vec![Interval::EVERYTHING; len],
+            evaluations,
+            specialized,
+            choices,
+            key: vec![0; choices.div_ceil(32)],
+            winners: vec![None; len],
+            live: vec![false; len],
+        }
+    }
+
+    fn x(&self, col: u16) -> f32 {
+        f32::from(col) * self.scale - 1.0
+    }
+
+    fn y(&self, row: u16) -> f32 {
+        f32::from(self.size - 1 - row) * self.scale - 1.0
+    }
+
+    // Fill in the part of `tile` covered by `region`.
+    fn fill(&mut self, tile: Rect, region: Rect, out: &mut [f32]) {
+        let xs = Interval::new(self.x(region.left), self.x(region.right - 1));
+        let ys = Interval::new(self.y(region.bottom - 1), self.y(region.top));
+        let bounds = eval_interval(self.insts, &mut self.intervals, &[xs, ys]);
+        self.evaluations.fetch_add(1, Ordering::Relaxed);
+
+        let value = if bounds.is_positive() {
+            Some(bounds.lo)
+        } else if bounds.is_negative() {
+            Some(bounds.hi)
+        } else {
+            None
+        };
+
+        let area = usize::from(region.width()) * usize::from(region.height());
+        if value.is_none() && area > ADAPTIVE_LEAF_PIXELS {
+            let mid_col = region.left + region.width() / 2;
+            let mid_row = region.top + region.height() / 2;
+            for (left, right) in [(region.left, mid_col), (mid_col, region.right)] {
+                for (top, bottom) in [(region.top, mid_row), (mid_row, region.bottom)] {
+                    if left < right && top < bottom {
+                        let quadrant = Rect {
+                            left,
+                            top,
+                            right,
+                            bottom,
+                        };
+                        self.fill(tile, quadrant, out);
+                    }
+                }
+            }
+            return;
+        }
+
+        let program = value.is_none().then(|| self.specialize());
+        let width = usize::from(tile.width());
+        for row in region.top..region.bottom {
+            let start = usize::from(row - tile.top) * width;
+            for col in region.left..region.right {
+                out[start + usize::from(col - tile.left)] = value.unwrap_or_else(|| {
+                    self.evaluations.fetch_add(1, Ordering::Relaxed);
+                    let vars = [self.x(col), self.y(row)];
+                    eval_point(program.as_ref().unwrap(), &mut self.regs, &vars)
+                });
+            }
+        }
+    }
+
+    // The program to evaluate pixels with in the region `intervals` were
+    // last computed for. Where an operand of `min` or `max` is always less
+    // than the other over the whole region, the result is exactly the
+    // winner's value, so the other operand and anything only it uses can be
+    // skipped. The key only records choices for the instructions the result
+    // still depends on, so regions that differ only in skipped parts of the
+    // program share a copy.
+    fn specialize(&mut self) -> Arc<Insts> {
+        let pool = &self.insts.pool;
+        self.key.fill(0);
+        self.live.fill(false);
+        *self.live.last_mut().unwrap() = true;
+        let mut choice = self.choices;
+        for (idx, inst) in pool.iter().enumerate().rev() {
+            let min_max = is_min_max(inst);
+            if min_max.is_some() {
+                choice -= 1;
+            }
+            self.winners[idx] = None;
+            if !self.live[idx] {
+                continue;
+            }
+            if let Some((op, [a, b])) = min_max
+                && let Some(winner) = winner(op, [a, b], &self.intervals)
+            {
+                self.winners[idx] = Some(winner);
+                self.live[winner.idx()] = true;
+                let bits = if winner == a { 1 } else { 2 };
+                self.key[choice / 32] |= bits << (choice % 32 * 2);
+                continue;
+            }
+            for arg in inst.args() {
+                self.live[arg.idx()] = true;
+            }
+        }
+
+        if let Some(program) = self.specialized.lock().unwrap().get(&self.key[..]) {
+            return program.clone();
+        }
+        // Everything the result depends on comes before it, and an
+        // instruction that's replaced by its winner isn't copied, so the
+        // result is still the last instruction.
+        let mut program = Insts::default();
+        let mut map: Vec<Option<InstIdx>> = vec![None; pool.len()];
+        for (idx, inst) in pool.iter().enumerate() {
+            if !self.live[idx] {
+                continue;
+            }
+            map[idx] = match self.winners[idx] {
+                Some(winner) => map[winner.idx()],
+                None => {
+                    let mut inst = inst.clone();
+                    for arg in inst.args_mut() {
+                        *arg = map[arg.idx()].unwrap();
+                    }
+                    program.pool.push(inst);
+                    Some((program.pool.len() - 1).try_into().unwrap())
+                }
+            };
+        }
+        let program = Arc::new(program);
+        let mut specialized = self.specialized.lock().unwrap();
+        specialized
+            .entry(self.key.clone())
+            .or_insert(program)
+            .clone()
+    }
+}
+
+fn is_min_max(inst: &Inst) -> Option<(BinOp, [InstIdx; 2])> {
+    match *inst {
+        Inst::BinOp {
+            op: op @ (BinOp::Min | BinOp::Max),
+            args,
+        } => Some((op, args)),
+        _ => None,
+    }
+}
+
+// The operand of `op` which always wins, if their intervals don't overlap.
+// Point evaluation uses the same operations as interval arithmetic, so a
+// point's operands are always within their intervals.
+fn winner(op: BinOp, [a, b]: [InstIdx; 2], intervals: &[Interval]) -> Option<InstIdx> {
+    let [lo, hi] = if intervals[a.idx()].hi < intervals[b.idx()].lo {
+        [a, b]
+    } else if intervals[b.idx()].hi < intervals[a.idx()].lo {
+        [b, a]
+    } else {
+        return None;
+    };
+    Some(if op == BinOp::Min { lo } else { hi })
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Rect {
+    left: u16,
+    top: u16,
+    right: u16,
+    bottom: u16,
+}
+
+impl Rect {
+    fn width(self) -> u16 {
+        self.right - self.left
+    }
+
+    fn height(self) -> u16 {
+        self.bottom - self.top
+    }
+
+    fn area(self) -> usize {
+        usize::from(self.width()) * usize::from(self.height())
+    }
+
+    // The tile in column `col` and row `row` of a `size`×`size` image cut
+    // into tiles `tile_size` pixels across.
+    fn tile(size: u16, tile_size: u16, col: usize, row: usize) -> Rect {
+        let left = u16::try_from(col).unwrap() * tile_size;
+        let top = u16::try_from(row).unwrap() * tile_size;
+        Rect {
+            left,
+            top,
+            right: left.saturating_add(tile_size).min(size),
+            bottom: top.saturating_add(tile_size).min(size),
+        }
+    }
+}
+
+// Adapt a function which evaluates one pixel, by column and row, to one which
+// fills in a whole tile.
+fn per_pixel(mut pixel: impl FnMut(u16, u16) -> f32) -> impl FnMut(Rect, &mut [f32]) {
+    move |rect, tile| {
+        let width = usize::from(rect.width());
+        for (row, values) in (rect.top..rect.bottom).zip(tile.chunks_mut(width)) {
+            for (col, value) in (rect.left..rect.right).zip(values) {
+                *value = pixel(col, row);
+            }
+        }
+    }
+}
+
+// Each thread calls `evaluator` once to set up its own state, then uses the
+// result to fill in tiles, given as a buffer in row-major order.
+fn render_tiles<F: FnMut(Rect, &mut [f32])>(
+    size: u16,
+    config: Config,
+    cancel: &CancelToken,
+    evaluator: impl Fn() -> F + Sync,
+) -> Result<Image, Cancelled> {
+    let tile_size = config.tile_size.max(1);
+    let tiles_per_row = usize::from(size.div_ceil(tile_size));
+    let tiles = tiles_per_row * tiles_per_row;
+
+    let threads = config.threads().min(tiles);
+    let _span = tracing::info_span!("render", size, tiles, threads).entered();
+
+    let order = config.tile_order;
+    let steps = order.len(tiles_per_row);
+    let next_step = AtomicUsize::new(0);
+    let finished_tiles = AtomicUsize::new(0);
+    let image = Mutex::new(Image::new(size));
+
+    let worker = || {
+        let mut fill = evaluator();
+        let mut tile = Vec::new();
+        while !cancel.is_cancelled() {
+            let idx = next_step.fetch_add(1, Ordering::Relaxed);
+            if idx >= steps {
+                break;
+            }
+            let [col, row] = order.position(idx, tiles_per_row);
+            if col >= tiles_per_row || row >= tiles_per_row {
+                continue;
+            }
+
+            let rect = Rect::tile(size, tile_size, col, row);
+            tile.clear();
+            tile.resize(rect.area(), 0.0);
+            fill(rect, &mut tile);
+
+            let mut image = image.lock().unwrap();
+            let width = usize::from(rect.width());
+            for (row, values) in (rect.top..rect.bottom).zip(tile.chunks(width)) {
+                let start = usize::from(row) * usize::from(size) + usize::from(rect.left);
+                image.pixels[start..start + width].copy_from_slice(values);
+            }
+            finished_tiles.fetch_add(1, Ordering::Relaxed);
+        }
+    };
+    // Spawning even one thread fails on targets without threads, such as
+    // WebAssembly, so a single worker runs on the caller's thread instead.
+    if threads == 1 {
+        worker();
+    } else {
+        thread::scope(|s| {
+            for _ in 0..threads {
+                s.spawn(worker);
+            }
+        });
+    }
+
+    if finished_tiles.into_inner() < tiles {
+        return Err(Cancelled);
+    }
+    Ok(image.into_inner().unwrap())
+}
+
+// Where finished tiles go: into a whole image, or out in order a row at a
+// time. Each renderer sets up its evaluator the same way for either.
+trait Tiles {
+    type Output;
+    type Error: From<Cancelled>;
+
+    fn run<F: FnMut(Rect, &mut [f32])>(
+        self,
+        size: u16,
+        config: Config,
+        cancel: &CancelToken,
+        evaluator: impl Fn() -> F + Sync,
+    ) -> Result<Self::Output, Self::Error>;
+}
+
+struct Collect;
+
+impl Tiles for Collect {
+    type Output = Image;
+    type Error = Cancelled;
+
+    fn run<F: FnMut(Rect, &mut [f32])>(
+        self,
+        size: u16,
+        config: Config,
+        cancel: &CancelToken,
+        evaluator: impl Fn() -> F + Sync,
+    ) -> Result<Image, Cancelled> {
+        render_tiles(size, config, cancel, evaluator)
+    }
+}
+
+struct Stream<Emit> {
+    orientation: Orientation,
+    emit: Emit,
+}
+
+impl<E: From<Cancelled>, Emit: FnMut(&[f32]) -> Result<(), E>> Tiles for Stream<Emit> {
+    type Output = ();
+    type Error = E;
+
+    fn run<F: FnMut(Rect, &mut [f32])>(
+        self,
+        size: u16,
+        config: Config,
+        cancel: &CancelToken,
+        evaluator: impl Fn() -> F + Sync,
+    ) -> Result<(), E> {
+        stream_tiles(size, self.orientation, config, cancel, evaluator, self.emit)
+    }
+}
+
+// Like `render_tiles`, but tiles are handed out a row of tiles at a time in
+// the order the rows are emitted, and finished tiles are sent back to the
+// calling thread, which pieces each row of tiles together and emits it once
+// it's complete. Threads wait rather than start on a row of tiles more than
+// `window` rows past the oldest one not yet emitted, which bounds how many
+// rows are held at once.
+fn stream_tiles<F: FnMut(Rect, &mut [f32]), E: From<Cancelled>>(
+    size: u16,
+    orientation: Orientation,
+    config: Config,
+    cancel: &CancelToken,
+    evaluator: impl Fn() -> F + Sync,
+    mut emit: impl FnMut(&[f32]) -> Result<(), E>,
+) -> Result<(), E> {
+    let tile_size = config.tile_size.max(1);
+    let tiles_per_row = usize::from(size.div_ceil(tile_size));
+    let tiles = tiles_per_row * tiles_per_row;
+
+    let threads = config.threads().min(tiles);
+    let _span = tracing::info_span!("stream", size, tiles, threads).entered();
+
+    // Tiles are numbered in the order their rows are emitted.
+    let rect = |idx: usize| {
+        let band = idx / tiles_per_row;
+        let row = match orientation {
+            Orientation::YUp => band,
+            Orientation::YDown => tiles_per_row - 1 - band,
+        };
+        Rect::tile(size, tile_size, idx % tiles_per_row, row)
+    };
+    let mut bands = Bands {
+        size,
+        orientation,
+        tiles_per_row,
+        pending: VecDeque::new(),
+        written: 0,
+    };
+
+    // Spawning even one thread fails on targets without threads, such as
+    // WebAssembly, so a single worker runs on the caller's thread instead.
+    if threads == 1 {
+        let mut fill = evaluator();
+        for idx in 0..tiles {
+            cancel.check()?;
+            let rect = rect(idx);
+            let mut tile = vec![0.0; rect.area()];
+            fill(rect, &mut tile);
+            bands.add(idx, rect, &tile, &mut emit)?;
+        }
+        return Ok(());
+    }
+
+    let window = threads.div_ceil(tiles_per_row) + 1;
+    let next_tile = AtomicUsize::new(0);
+    // the number of rows of tiles emitted so far
+    let written = Mutex::new(0);
+    let progress = Condvar::new();
+    let stop = AtomicBool::new(false);
+
+    let worker = |finished: mpsc::SyncSender<(usize, Vec<f32>)>| {
+        let mut fill = evaluator();
+        while !cancel.is_cancelled() {
+            let idx = next_tile.fetch_add(1, Ordering::Relaxed);
+            if idx >= tiles {
+                break;
+            }
+            let mut guard = written.lock().unwrap();
+            while idx / tiles_per_row >= *guard + window {
+                if cancel.is_cancelled() || stop.load(Ordering::Relaxed) {
+                    return;
+                }
+                // wake up now and then to notice cancellation
+                guard = progress
+                    .wait_timeout(guard, Duration::from_millis(10))
+                    .unwrap()
+                    .0;
+            }
+            drop(guard);
+
+            let rect = rect(idx);
+            let mut tile = vec![0.0; rect.area()];
+            fill(rect, &mut tile);
+            if finished.send((idx, tile)).is_err() {
+                // the caller gave up
+                break;
+            }
+        }
+    };
+
+    thread::scope(|s| {
+        let (finished, received) = mpsc::sync_channel(threads);
+        for _ in 0..threads {
+            let finished = finished.clone();
+            s.spawn(move || worker(finished));
+        }
+        drop(finished);
+
+        for (idx, tile) in received {
+            let result = bands.add(idx, rect(idx), &tile, &mut emit);
+            *written.lock().unwrap() = bands.written;
+            progress.notify_all();
+            if result.is_err() {
+                stop.store(true, Ordering::Relaxed);
+                return result;
+            }
+        }
+        Ok(())
+    })?;
+
+    if bands.written < tiles_per_row {
+        return Err(Cancelled.into());
+    }
+    Ok(())
+}
+
+// Rows of tiles which have been started but not yet emitted, oldest first.
+struct Bands {
+    size: u16,
+    orientation: Orientation,
+    tiles_per_row: usize,
+    // each row of tiles' pixels so far, and how many tiles it's missing
+    pending: VecDeque<(Vec<f32>, usize)>,
+    // the number of rows of tiles emitted so far
+    written: usize,
+}
+
+impl Bands {
+    // Copy tile `idx` into its row of tiles, then emit every row of tiles
+    // which is now complete and next in line.
+    fn add<E>(
+        &mut self,
+        idx: usize,
+        rect: Rect,
+        tile: &[f32],
+        emit: &mut impl FnMut(&[f32]) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let size = usize::from(self.size);
+        let band = idx / self.tiles_per_row - self.written;
+        while self.pending.len() <= band {
+            let rows = usize::from(rect.height());
+            self.pending
+                .push_back((vec![0.0; rows * size], self.tiles_per_row));
+        }
+        let (pixels, missing) = &mut self.pending[band];
+        let width = usize::from(rect.width());
+        for (row, values) in pixels.chunks_mut(size).zip(tile.chunks(width)) {
+            row[usize::from(rect.left)..][..width].copy_from_slice(values);
+        }
+        *missing -= 1;
+
+        while let Some((pixels, 0)) = self.pending.front() {
+            let mut rows = pixels.chunks(size.max(1));
+            match self.orientation {
+                Orientation::YUp => rows.try_for_each(&mut *emit)?,
+                Orientation::YDown => rows.rev().try_for_each(&mut *emit)?,
+            }
+            self.pending.pop_front();
+            self.written += 1;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corpus::shape;
+    use crate::image::is_inside;
+    use crate::ir::interp::interp_image;
+    use crate::ir::memoize::MemoBuilder;
+
+    #[test]
+    fn test_tiles_match_interpreter() {
+        let insts = shape("circle").unwrap().insts();
+        let config = Config {
+            tile_size: 5,
+            threads: 3,
+            ..Config::default()
+        };
+        let image = render(&insts, 37, config);
+        assert_eq!(image, interp_image(&insts, 37));
+        assert!(image.pixels[18 * 37 + 18] > 0.0);
+        assert!(image.pixels[0] < 0.0);
+    }
+
+    #[test]
+    fn test_tile_orders_cover_grid() {
+        for order in TileOrder::value_variants() {
+            let steps: Vec<[usize; 2]> = (0..order.len(5))
+                .map(|idx| order.position(idx, 5))
+                .filter(|&[col, row]| col < 5 && row < 5)
+                .collect();
+            let mut sorted = steps.clone();
+            sorted.sort_by_key(|&[col, row]| (row, col));
+            let grid: Vec<[usize; 2]> = (0..25).map(|idx| [idx % 5, idx / 5]).collect();
+            assert_eq!(sorted, grid, "{order:?}");
+            if *order == TileOrder::Hilbert {
+                // within the padded 8×8 grid, every step is to a neighbor
+                for idx in 1..64 {
+                    let [a, b] = [idx - 1, idx].map(|idx| order.position(idx, 5));
+                    assert_eq!(a[0].abs_diff(b[0]) + a[1].abs_diff(b[1]), 1);
+                }
+            }
+        }
+        assert_eq!(TileOrder::Morton.position(6, 4), [2, 1]);
+
+        let shape = shape("ring").unwrap();
+        let config = Config {
+            tile_size: 7,
+            threads: 3,
+            tile_order: TileOrder::Hilbert,
+            ..Config::default()
+        };
+        let image = render_memoized(&shape.read(MemoBuilder::new()), 41, config);
+        assert_eq!(image, interp_image(&shape.insts(), 41));
+    }
+
+    #[test]
+    fn test_memoized_matches_interpreter() {
+        let shape = shape("ring").unwrap();
+        let config = Config {
+            tile_size: 8,
+            threads: 2,
+            ..Config::default()
+        };
+        let image = render_memoized(&shape.read(MemoBuilder::new()), 41, config);
+        assert_eq!(image, interp_image(&shape.insts(), 41));
+    }
+
+    #[test]
+    fn test_adaptive_matches_signs() {
+        for shape in crate::corpus::SHAPES {
+            let insts = shape.insts();
+            let config = Config {
+                tile_size: 16,
+                threads: 2,
+                ..Config::default()
+            };
+            let adaptive = render_adaptive(&insts, 67, config);
+            let exact = interp_image(&insts, 67);
+            for (a, b) in adaptive.pixels.iter().zip(exact.pixels.iter()) {
+                assert_eq!(is_inside(*a), is_inside(*b), "{}", shape.name);
+            }
+        }
+    }
+
+    #[test]
+    fn test_adaptive_specializes() {
+        // near the edge of either circle, the other is always farther away
+        let text = "x var-x\ny var-y\ny2 square y\n\
+            a const 0.5\nax add x a\nax2 square ax\nad add ax2 y2\nadist sqrt ad\n\
+            bx sub x a\nbx2 square bx\nbd add bx2 y2\nbdist sqrt bd\n\
+            r const 0.3\nm min adist bdist\nout sub m r\n";
+        let insts = crate::ir::io::read(text.as_bytes(), Insts::default()).unwrap();
+        let evaluations = AtomicUsize::new(0);
+        let specialized = Mutex::new(HashMap::new());
+        let mut adaptive = Adaptive::new(&insts, 64, &evaluations, &specialized);
+        let mut pixels = vec![0.0; 64 * 64];
+        let image = Rect::tile(64, 64, 0, 0);
+        adaptive.fill(image, image, &mut pixels);
+
+        let exact = interp_image(&insts, 64);
+        for (a, b) in pixels.iter().zip(exact.pixels.iter()) {
+            assert_eq!(is_inside(*a), is_inside(*b));
+        }
+        // one program per circle, each without the other's 4 instructions
+        // or the `min`
+        let specialized = specialized.into_inner().unwrap();
+        assert_eq!(specialized.len(), 2);
+        for program in specialized.values() {
+            assert_eq!(program.pool.len(), insts.pool.len() - 5);
+        }
+    }
+
+    #[test]
+    fn test_streamed_rows_match_interpreter() {
+        let shape = shape("ring").unwrap();
+        let (insts, memoized) = (shape.insts(), shape.read(MemoBuilder::new()));
+        let cancel = CancelToken::default();
+        for threads in [1, 3] {
+            let config = Config {
+                tile_size: 5,
+                threads,
+                ..Config::default()
+            };
+            for orientation in [Orientation::YUp, Orientation::YDown] {
+                let mut expected = interp_image(&insts, 37);
+                expected.orient(orientation);
+                let mut pixels = Vec::new();
+                let collect = |row: &[f32]| {
+                    pixels.extend_from_slice(row);
+                    Ok::<_, Cancelled>(())
+                };
+                stream_rows(&insts, 37, orientation, config, &cancel, collect).unwrap();
+                assert_eq!(pixels, expected.pixels, "{threads} {orientation:?}");
+
+                pixels.clear();
+                let collect = |row: &[f32]| {
+                    pixels.extend_from_slice(row);
+                    Ok::<_, Cancelled>(())
+                };
+                stream_rows_memoized(&memoized, 37, orientation, config, &cancel, collect).unwrap();
+                assert_eq!(pixels, expected.pixels, "{threads} {orientation:?}");
+            }
+
+            // an error from the caller stops the render
+            let mut rows = 0;
+            let result = stream_rows(&insts, 37, Orientation::YUp, config, &cancel, |_| {
+                rows += 1;
+                if rows == 7 { Err(Cancelled) } else { Ok(()) }
+            });
+            assert_eq!((result, rows), (Err(Cancelled), 7));
+        }
+    }
+
+    #[test]
+    fn test_cancelled_render() {
+        let insts = shape("circle").unwrap().insts();
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        let result = render_cancellable(&insts, 37, Config::default(), &cancel);
+        assert_eq!(result, Err(Cancelled));
+    }
+}
@@ -0,0 +1,445 @@
+use std::ffi::OsStr;
+use thiserror::Error;
+
+use crate::codegen::abi::{ABI_VERSION, Manifest, part_name};
+use crate::codegen::loops::{BAND_ROWS, LoopNest, Step, UsesZ};
+use crate::image::{Image, Tile};
+use crate::ir::{Var, VarSet};
+
+type XFn = unsafe extern "C" fn(x_out: *mut f32);
+type YFn = unsafe extern "C" fn(unused: *mut f32, y_out: *mut f32);
+type XyFn = unsafe extern "C" fn(x_in: *const f32, y_in: *const f32, xy_out: *mut f32);
+
+// Vector loads and stores in generated code need buffers aligned to a whole
+// stride, and the x86 backend's stride is at most sixteen lanes.
+const MAX_STRIDE: usize = 16;
+
+#[repr(C, align(64))]
+#[derive(Clone, Copy)]
+struct Lanes([f32; MAX_STRIDE]);
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("failed to load shared object")]
+    Load(#[from] libloading::Error),
+    #[error("library has ABI version {found} but the manifest says {expected}")]
+    AbiVersion { found: u32, expected: u32 },
+    #[error("manifest has ABI version {0} but this crate supports {ABI_VERSION}")]
+    ManifestVersion(u32),
+    #[error("library has stride {found} but the manifest says {expected}")]
+    Stride { found: u16, expected: u16 },
+    #[error("library has xy_packed {0} but the manifest disagrees")]
+    Packed(bool),
+    #[error("library has vector_axis {found} but the manifest says {expected:?}")]
+    Axis { found: u8, expected: Var },
+    #[error("unsupported stride {0}")]
+    UnsupportedStride(u16),
+    #[error("this CPU can't run code with stride {0}")]
+    UnsupportedCpu(u16),
+    #[error("no variants to choose from")]
+    NoVariants,
+    #[error("manifest doesn't describe function {0:?}")]
+    MissingFunction(&'static str),
+    #[error(transparent)]
+    UsesZ(#[from] UsesZ),
+    #[error("{name}_size is {found} but the manifest says {expected}")]
+    Size {
+        name: &'static str,
+        found: u16,
+        expected: usize,
+    },
+}
+
+/// Code from the x86 backend, assembled into a shared object ahead of time
+/// and loaded with [`dlopen`]. Once loaded it's safe to call from any number
+/// of threads, since the generated functions only touch the buffers they're
+/// given and their own stack.
+pub struct Library {
+    // Keeps the shared object mapped for as long as the function pointers
+    // below are reachable.
+    _library: libloading::Library,
+    // each function's chain of parts, usually just one
+    x: Vec<XFn>,
+    y: Vec<YFn>,
+    xy: Vec<XyFn>,
+    xy_packed: bool,
+    nest: LoopNest,
+    // outputs of x, y, and xy, indexed by `VarSet::idx() - 1`
+    sizes: [usize; 3],
+}
+
+/// Load a shared object built from the x86 backend's output, such as with
+/// `gcc -shared -o shape.so shape.s`, and check its exported `abi_version`,
+/// `stride`, and `*_size` symbols against the manifest written alongside it.
+/// Only programs of x and y can be loaded, since images don't cover z.
+///
+/// # Safety
+///
+/// Loading a shared object runs its initialization code, and its functions
+/// are trusted to have the signatures the manifest describes, so `path` must
+/// be code generated by this crate. Everything after loading is safe.
+pub unsafe fn dlopen(path: impl AsRef<OsStr>, manifest: &Manifest) -> Result<Library, Error> {
+    if manifest.abi_version != ABI_VERSION {
+        return Err(Error::ManifestVersion(manifest.abi_version));
+    }
+    // functions are named after their variables
+    if manifest
+        .functions
+        .iter()
+        .any(|func| func.name.contains(Var::Z.name()) && func.insts > 0)
+    {
+        return Err(UsesZ.into());
+    }
+
+    let xy_vars = VarSet::from(Var::X) | VarSet::from(Var::Y);
+    let [x_names, y_names, xy_names] = [Var::X.into(), Var::Y.into(), xy_vars].map(|vars| {
+        let name = format!("{vars:?}");
+        let parts = manifest.function(&name).map_or(1, |func| func.parts);
+        (0..parts).map(move |part| part_name(vars, part))
+    });
+
+    let library = unsafe { libloading::Library::new(path)? };
+    let (abi_version, stride, xy_packed, vector_axis, x, y, xy, sizes) = unsafe {
+        let abi_version = **library.get::<*const u32>(b"abi_version")?;
+        let stride = **library.get::<*const u16>(b"stride")?;
+        let xy_packed = **library.get::<*const u8>(b"xy_packed")? != 0;
+        let vector_axis = **library.get::<*const u8>(b"vector_axis")?;
+        let x = symbols::<XFn>(&library, x_names)?;
+        let y = symbols::<YFn>(&library, y_names)?;
+        let xy = symbols::<XyFn>(&library, xy_names)?;
+        let sizes = [
+            **library.get::<*const u16>(b"x_size")?,
+            **library.get::<*const u16>(b"y_size")?,
+            **library.get::<*const u16>(b"xy_size")?,
+        ];
+        (abi_version, stride, xy_packed, vector_axis, x, y, xy, sizes)
+    };
+
+    if abi_version != manifest.abi_version {
+        return Err(Error::AbiVersion {
+            found: abi_version,
+            expected: manifest.abi_version,
+        });
+    }
+    if stride != manifest.stride {
+        return Err(Error::Stride {
+            found: stride,
+            expected: manifest.stride,
+        });
+    }
+    if xy_packed != manifest.xy_packed {
+        return Err(Error::Packed(xy_packed));
+    }
+    if vector_axis != manifest.vector_axis as u8 {
+        return Err(Error::Axis {
+            found: vector_axis,
+            expected: manifest.vector_axis,
+        });
+    }
+    if !stride.is_power_of_two() || usize::from(stride) > MAX_STRIDE {
+        return Err(Error::UnsupportedStride(stride));
+    }
+    if !cpu_supports(stride) {
+        return Err(Error::UnsupportedCpu(stride));
+    }
+    for (name, found) in ["x", "y", "xy"].into_iter().zip(sizes) {
+        let expected = manifest
+            .function(name)
+            .ok_or(Error::MissingFunction(name))?
+            .outputs;
+        // location 0 of x and y holds the input coordinate, and xy's holds
+        // the final result; any locations after that are scratch space
+        if usize::from(found) != expected || found < 1 {
+            return Err(Error::Size {
+                name,
+                found,
+                expected,
+            });
+        }
+    }
+
+    Ok(Library {
+        _library: library,
+        x,
+        y,
+        xy,
+        xy_packed,
+        nest: LoopNest::new(xy_vars, stride, manifest.vector_axis),
+        sizes: sizes.map(usize::from),
+    })
+}
+
+/// Load whichever of several builds of the same program suits this CPU best:
+/// the one with the widest stride whose instructions the CPU supports,
+/// according to [`cpu_supports`]. Only that library is opened, so the
+/// others can be built for CPUs this one has never heard of. The choice is
+/// made from the manifests, so a variant which then fails to load is an
+/// error rather than a reason to try the next one.
+///
+/// # Safety
+///
+/// The same as for [`dlopen`], for every variant.
+pub unsafe fn dlopen_best(variants: &[(impl AsRef<OsStr>, Manifest)]) -> Result<Library, Error> {
+    let supported = variants
+        .iter()
+        .filter(|(_, manifest)| cpu_supports(manifest.stride));
+    let Some((path, manifest)) = supported.max_by_key(|(_, manifest)| manifest.stride) else {
+        return Err(
+            match variants.iter().map(|(_, manifest)| manifest.stride).min() {
+                Some(stride) => Error::UnsupportedCpu(stride),
+                None => Error::NoVariants,
+            },
+        );
+    };
+    tracing::info!(stride = manifest.stride, "chose library variant");
+    unsafe { dlopen(path, manifest) }
+}
+
+/// Whether this CPU can run code generated by the x86 backend with the given
+/// stride. Every stride needs AVX, since even scalar code uses its
+/// encodings, and FMA3 for fused multiply-adds. 8 lanes also need AVX2 for
+/// the integer operations in `exp`, `ln`, `sin`, and `cos`, and 16 lanes need
+/// the AVX-512 foundation and doubleword instructions.
+pub fn cpu_supports(stride: u16) -> bool {
+    #[cfg(target_arch = "x86_64")]
+    {
+        use std::arch::is_x86_feature_detected;
+        let avx = is_x86_feature_detected!("avx") && is_x86_feature_detected!("fma");
+        match stride {
+            1 | 4 => avx,
+            8 => avx && is_x86_feature_detected!("avx2"),
+            16 => {
+                avx && is_x86_feature_detected!("avx512f") && is_x86_feature_detected!("avx512dq")
+            }
+            _ => false,
+        }
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        let _ = stride;
+        false
+    }
+}
+
+// Look up every part of a function's chain.
+unsafe fn symbols<T: Copy>(
+    library: &libloading::Library,
+    names: impl Iterator<Item = String>,
+) -> Result<Vec<T>, libloading::Error> {
+    names
+        .map(|name| unsafe { library.get::<T>(name.as_bytes()).map(|symbol| *symbol) })
+        .collect()
+}
+
+impl Library {
+    /// Evaluate the shape at every pixel, like
+    /// [`interp_image`](crate::ir::interp::interp_image), following the same
+    /// [`LoopNest`] as the generated C harness. If the library was built with
+    /// packed output, pixels are 0 or -1 depending on their sign, rather than
+    /// the actual values, which is enough for [`Image::write_pbm`].
+    pub fn render(&self, size: u16) -> Image {
+        let pixels = self.render_tile(size, Tile::full(size));
+        Image { size, pixels }
+    }
+
+    /// Evaluate only the pixels of a `size`×`size` image which are inside
+    /// `tile`, returning them in rows from the top of the tile, `tile.width`
+    /// pixels each. Pixels get the same coordinates as in [`render`], so
+    /// tiles rendered separately, even on different machines, piece together
+    /// into the same image.
+    ///
+    /// # Panics
+    ///
+    /// Panics unless the tile [fits](Tile::fits) in the image.
+    ///
+    /// [`render`]: Self::render
+    pub fn render_tile(&self, size: u16, tile: Tile) -> Vec<f32> {
+        assert!(
+            tile.fits(size),
+            "{tile:?} doesn't fit in a {size}x{size} image"
+        );
+        let extent = [usize::from(tile.width), usize::from(tile.height), 1];
+        // Buffers are allocated in whole vectors so they're suitably aligned.
+        let mut buffers: Vec<Vec<Lanes>> = self
+            .nest
+            .buffers
+            .iter()
+            .map(|buffer| {
+                let len = buffer.len(extent, self.sizes[buffer.func.idx() - 1]);
+                vec![Lanes([0.0; MAX_STRIDE]); len.div_ceil(MAX_STRIDE)]
+            })
+            .collect();
+        let mut walk = Walk {
+            library: self,
+            buffers: buffers.iter_mut().map(|buf| as_floats(buf)).collect(),
+            extent,
+            origin: [tile.left, tile.top].map(usize::from),
+            size: f32::from(size),
+            scale: 2.0 / f32::from(size - 1),
+            pos: [0; 3],
+            pixels: vec![0.0; extent[0] * extent[1]],
+        };
+        walk.steps(&self.nest.body, [None; 3]);
+        walk.pixels
+    }
+
+    /// Like [`render_tile`], but renders a band of rows at a time and hands
+    /// each row to `emit` in order as soon as its band is finished, so memory
+    /// use doesn't grow with the height of the tile. Bands line up with the
+    /// loop nest's groups of rows the same way the C harness's do, so the
+    /// pixels are exactly the same as from rendering the tile all at once.
+    /// Any error from `emit` stops the render and is returned.
+    ///
+    /// # Panics
+    ///
+    /// Panics unless the tile [fits](Tile::fits) in the image.
+    ///
+    /// [`render_tile`]: Self::render_tile
+    pub fn render_rows<E>(
+        &self,
+        size: u16,
+        tile: Tile,
+        mut emit: impl FnMut(&[f32]) -> Result<(), E>,
+    ) -> Result<(), E> {
+        assert!(
+            tile.fits(size),
+            "{tile:?} doesn't fit in a {size}x{size} image"
+        );
+        let band = self.nest.band_rows(BAND_ROWS);
+        for offset in (0..tile.height).step_by(band.into()) {
+            let band = Tile {
+                top: tile.top + offset,
+                height: band.min(tile.height - offset),
+                ..tile
+            };
+            let pixels = self.render_tile(size, band);
+            for row in pixels.chunks(tile.width.into()) {
+                emit(row)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Walk<'a> {
+    library: &'a Library,
+    buffers: Vec<&'a mut [f32]>,
+    // points in the tile along each variable
+    extent: [usize; 3],
+    // the column and row of the tile's top left corner in the whole image
+    origin: [usize; 2],
+    size: f32,
+    scale: f32,
+    // the first point each variable's innermost loop is visiting, relative
+    // to the tile
+    pos: [usize; 3],
+    // the tile's rows, top first
+    pixels: Vec<f32>,
+}
+
+impl Walk<'_> {
+    // `groups` holds the lanes of the innermost loop over each variable.
+    fn steps(&mut self, steps: &[Step], groups: [Option<u16>; 3]) {
+        for step in steps {
+            match step {
+                Step::Loop(level, body) => {
+                    let var = level.var as usize;
+                    let start = self.pos[var];
+                    let range = match groups[var] {
+                        Some(outer) => start..(start + usize::from(outer)).min(self.extent[var]),
+                        None => 0..self.extent[var],
+                    };
+                    let mut groups = groups;
+                    groups[var] = Some(level.lanes);
+                    for pos in range.step_by(level.lanes.into()) {
+                        self.pos[var] = pos;
+                        self.steps(body, groups);
+                    }
+                    self.pos[var] = start;
+                }
+                &Step::Call(func) => self.call(func),
+            }
+        }
+    }
+
+    fn call(&mut self, func: VarSet) {
+        let library = self.library;
+        let nest = &library.nest;
+        // where this function finds each space it can read
+        let offsets: Vec<Option<usize>> = nest
+            .buffers
+            .iter()
+            .map(|buffer| {
+                let outputs = library.sizes[buffer.func.idx() - 1];
+                ((buffer.func | func) == func)
+                    .then(|| buffer.offset(self.pos, self.extent, outputs))
+            })
+            .collect();
+        let idx = nest.buffers.iter().position(|b| b.func == func).unwrap();
+        let own = offsets[idx].unwrap();
+        let lanes = usize::from(nest.buffers[idx].lanes);
+
+        let x = VarSet::from(Var::X);
+        let y = VarSet::from(Var::Y);
+        if func == x || func == y {
+            // Fill location 0 with consecutive coordinates, one per lane,
+            // each computed the same way as `pixel_point` does, so they
+            // don't depend on how many lanes there are. Rows past the bottom
+            // of the image get coordinates too, but aren't kept.
+            let [col, row] = [0, 1].map(|var| (self.origin[var] + self.pos[var]) as f32);
+            for (lane, value) in self.buffers[idx][own..][..lanes].iter_mut().enumerate() {
+                let lane = lane as f32;
+                *value = if func == x {
+                    (col + lane) * self.scale - 1.0
+                } else {
+                    (self.size - 1.0 - (row + lane)) * self.scale - 1.0
+                };
+            }
+        }
+
+        let mut ptrs = self.buffers.iter_mut().zip(&offsets).map(|(buf, offset)| {
+            offset.map_or(std::ptr::null_mut(), |offset| buf[offset..].as_mut_ptr())
+        });
+        let [x_ptr, y_ptr, xy_ptr] = std::array::from_fn(|_| ptrs.next().unwrap());
+        unsafe {
+            if func == x {
+                library.x.iter().for_each(|part| part(x_ptr));
+            } else if func == y {
+                library.y.iter().for_each(|part| part(x_ptr, y_ptr));
+            } else {
+                library
+                    .xy
+                    .iter()
+                    .for_each(|part| part(x_ptr, y_ptr, xy_ptr));
+            }
+        }
+
+        if func == nest.output {
+            let out = &self.buffers[idx][own..][..lanes];
+            let lane_var = nest.buffers[idx].lane_var as usize;
+            for lane in 0..lanes {
+                let mut pos = self.pos;
+                pos[lane_var] += lane;
+                let [col, row, _] = pos;
+                let [width, height, _] = self.extent;
+                if row >= height || col >= width {
+                    continue;
+                }
+                self.pixels[row * width + col] = if library.xy_packed {
+                    // the low bits of the buffer hold one bit per lane
+                    let bits = out[0].to_bits();
+                    if bits >> lane & 1 != 0 { 0.0 } else { -1.0 }
+                } else {
+                    out[lane]
+                };
+            }
+        }
+    }
+}
+
+fn as_floats(lanes: &mut [Lanes]) -> &mut [f32] {
+    let len = lanes.len() * MAX_STRIDE;
+    // Lanes is a repr(C) wrapper around an array of f32, so a slice of them
+    // is a contiguous slice of f32 with no padding.
+    unsafe { std::slice::from_raw_parts_mut(lanes.as_mut_ptr().cast(), len) }
+}
@@ -0,0 +1,294 @@
+//! A render service for hosts that answer requests, such as a web preview
+//! server: each [`RenderRequest`] carries the program text and everything
+//! else about how to render it, and the service compiles, renders, and
+//! reports timings, caching compiled programs across requests.
+//!
+//! [`RenderService::render`] blocks until the image is done, which suits a
+//! thread per request or an async runtime's blocking pool.
+//! [`RenderService::spawn`] instead renders on a thread of its own and
+//! returns a [`RenderTask`] which can be awaited from any executor, and
+//! which cancels the render if it's dropped first, such as when the client
+//! disconnects.
+
+use std::future::Future;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+
+use crate::cancel::{CancelToken, Cancelled};
+use crate::engine::Engine;
+use crate::image::{Image, Orientation};
+use crate::ir::io;
+use crate::render;
+
+/// Everything about one render. Only `text` and `size` have to be given;
+/// the rest defaults to the service's configuration and no time limit.
+#[derive(Clone, Debug, Default)]
+pub struct RenderRequest {
+    /// The program, in the same text format every reader takes.
+    pub text: Arc<str>,
+    /// Number of pixels wide and tall.
+    pub size: u16,
+    pub orientation: Orientation,
+    /// Overrides the service's configuration for just this request.
+    pub config: Option<render::Config>,
+    /// How long the whole request may take before it's cancelled.
+    pub timeout: Option<Duration>,
+    /// Cancels the request when triggered from another thread. Requests
+    /// that share a token are cancelled together.
+    pub cancel: CancelToken,
+}
+
+/// A finished render, with how long each half of it took.
+#[derive(Debug)]
+pub struct Rendered {
+    pub image: Image,
+    /// Whether the compiled program was already cached, in which case
+    /// `compile_time` only covers looking it up.
+    pub cached: bool,
+    pub compile_time: Duration,
+    pub render_time: Duration,
+}
+
+#[derive(Debug, Error)]
+pub enum RenderError {
+    #[error("invalid program")]
+    Program(#[from] io::Error),
+    #[error(transparent)]
+    Cancelled(#[from] Cancelled),
+    #[error("images must be at least 2 pixels wide, not {0}")]
+    Size(u16),
+    #[error("render failed: {0}")]
+    Panicked(String),
+}
+
+pub type RenderResult = Result<Rendered, RenderError>;
+
+/// Compiles and renders requests, sharing one cache of compiled programs
+/// between all of them. Clones share the same cache, so give each request
+/// handler its own clone.
+#[derive(Clone, Default)]
+pub struct RenderService {
+    engine: Arc<Engine>,
+    config: render::Config,
+}
+
+impl RenderService {
+    /// A service which renders with `config` unless a request overrides it.
+    pub fn new(config: render::Config) -> Self {
+        RenderService {
+            engine: Arc::new(Engine::new(config)),
+            config,
+        }
+    }
+
+    /// The cache of compiled programs, such as for clearing it.
+    pub fn engine(&self) -> &Engine {
+        &self.engine
+    }
+
+    /// Compile and render `request` on the calling thread, and on as many
+    /// rendering threads as its configuration asks for.
+    pub fn render(&self, request: RenderRequest) -> RenderResult {
+        let cancel = match request.timeout {
+            Some(timeout) => request.cancel.limit(timeout),
+            None => request.cancel.clone(),
+        };
+        cancel.check()?;
+        // pixels are spaced 2 / (size - 1) apart
+        if request.size < 2 {
+            return Err(RenderError::Size(request.size));
+        }
+        let start = Instant::now();
+        let cached = self.engine.cached(&request.text);
+        let compiled = match cached.clone() {
+            Some(compiled) => compiled,
+            None => self.engine.compile(&request.text)?,
+        };
+        let compile_time = start.elapsed();
+
+        let start = Instant::now();
+        let config = request.config.unwrap_or(self.config);
+        let mut image = compiled.render(request.size, config, &cancel)?;
+        image.orient(request.orientation);
+        Ok(Rendered {
+            image,
+            cached: cached.is_some(),
+            compile_time,
+            render_time: start.elapsed(),
+        })
+    }
+
+    /// Render `request` on a new thread, returning a handle to wait on or
+    /// await its result. If rendering panics, the result is
+    /// [`RenderError::Panicked`], so the task still finishes.
+    pub fn spawn(&self, request: RenderRequest) -> RenderTask {
+        let service = self.clone();
+        let cancel = request.cancel.clone();
+        let state = Arc::new(Mutex::new(TaskState::default()));
+        let shared = state.clone();
+        let thread = thread::spawn(move || {
+            let result = catch_unwind(AssertUnwindSafe(|| service.render(request))).unwrap_or_else(
+                |payload| {
+                    let message = payload
+                        .downcast_ref::<&str>()
+                        .map(|message| message.to_string())
+                        .or_else(|| payload.downcast_ref::<String>().cloned())
+                        .unwrap_or_default();
+                    Err(RenderError::Panicked(message))
+                },
+            );
+            let mut state = shared.lock().unwrap();
+            state.result = Some(result);
+            state.done = true;
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        });
+        RenderTask {
+            state,
+            cancel,
+            thread: Some(thread),
+        }
+    }
+}
+
+#[derive(Default)]
+struct TaskState {
+    result: Option<RenderResult>,
+    // still set once the result has been taken
+    done: bool,
+    waker: Option<Waker>,
+}
+
+/// A render running on its own thread, from [`RenderService::spawn`]. It's
+/// a [`Future`] of the render's result, which needs no particular async
+/// runtime. Dropping it before the render finishes cancels the request's
+/// token, along with any other request sharing it.
+pub struct RenderTask {
+    state: Arc<Mutex<TaskState>>,
+    cancel: CancelToken,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl RenderTask {
+    /// Cancel the render. Awaiting the task afterward still waits for the
+    /// rendering threads to notice, which they do between rows.
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    /// Block the calling thread until the render finishes.
+    pub fn wait(mut self) -> RenderResult {
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap();
+        }
+        self.state.lock().unwrap().result.take().unwrap()
+    }
+}
+
+impl Future for RenderTask {
+    type Output = RenderResult;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<RenderResult> {
+        let mut state = self.state.lock().unwrap();
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for RenderTask {
+    fn drop(&mut self) {
+        if !self.state.lock().unwrap().done {
+            self.cancel.cancel();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corpus;
+    use crate::ir::interp::interp_image;
+    use std::task::Wake;
+
+    struct Unpark(thread::Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future + Unpin>(mut future: F) -> F::Output {
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(output) = Pin::new(&mut future).poll(&mut cx) {
+                return output;
+            }
+            thread::park();
+        }
+    }
+
+    #[test]
+    fn test_requests() {
+        let service = RenderService::default();
+        let shape = corpus::shape("ring").unwrap();
+        let mut expected = interp_image(&shape.insts(), 32);
+        let request = RenderRequest {
+            text: shape.text.into(),
+            size: 32,
+            ..RenderRequest::default()
+        };
+
+        let first = service.render(request.clone()).unwrap();
+        assert!(!first.cached);
+        assert_eq!(first.image, expected);
+
+        // the program is compiled once and shared with the spawned render
+        let flipped = RenderRequest {
+            orientation: Orientation::YDown,
+            ..request.clone()
+        };
+        let second = block_on(service.spawn(flipped)).unwrap();
+        assert!(second.cached);
+        expected.orient(Orientation::YDown);
+        assert_eq!(second.image, expected);
+
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        let cancelled = RenderRequest {
+            cancel,
+            ..request.clone()
+        };
+        assert!(matches!(
+            service.spawn(cancelled).wait(),
+            Err(RenderError::Cancelled(_))
+        ));
+        let invalid = RenderRequest {
+            text: "x var-q\n".into(),
+            ..request.clone()
+        };
+        assert!(matches!(
+            service.render(invalid),
+            Err(RenderError::Program(_))
+        ));
+        let tiny = RenderRequest { size: 1, ..request };
+        assert!(matches!(
+            block_on(service.spawn(tiny)),
+            Err(RenderError::Size(1))
+        ));
+        assert_eq!(service.engine().len(), 1);
+    }
+}
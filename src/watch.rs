@@ -0,0 +1,116 @@
+//! Live coding for implicit shapes, built with the `watch` feature: the
+//! program is read again and handed to a callback whenever its file changes,
+//! so an editor on one side and an image viewer on the other show each edit
+//! as soon as it's saved:
+//!
+//! ```sh
+//! cargo run --release --features watch --example watch -- shape.vm shape.pbm
+//! ```
+
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use notify::{RecursiveMode, Watcher};
+use thiserror::Error;
+
+/// How long to wait for a burst of events to end before reading the file.
+/// Editors often save by truncating and writing, or by writing a temporary
+/// file and renaming it, and only the end result is worth compiling.
+const SETTLE: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("watching for changes: {0}")]
+    Notify(#[from] notify::Error),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// Call `changed` with the contents of the file at `path`, then again every
+/// time the file changes, until `changed` breaks out of the loop. The
+/// directory holding the file is what's watched, so the file may be replaced
+/// by renaming another over it, or even deleted and created again; while it's
+/// missing, nothing is called.
+pub fn watch(path: &Path, mut changed: impl FnMut(&str) -> ControlFlow<()>) -> Result<(), Error> {
+    let name: OsString = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file name"))?
+        .into();
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+
+    let (send, recv) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(send)?;
+    watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+
+    let mut last = None;
+    loop {
+        match fs::read_to_string(path) {
+            // saving without changing anything isn't worth another render
+            Ok(text) if last.as_ref() != Some(&text) => {
+                if changed(&text).is_break() {
+                    return Ok(());
+                }
+                last = Some(text);
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        // wait for an event about this file, then for the burst to settle
+        loop {
+            let event = recv
+                .recv()
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))??;
+            if event.paths.iter().any(|p| p.file_name() == Some(&name)) {
+                break;
+            }
+        }
+        while recv.recv_timeout(SETTLE).is_ok() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watch() {
+        let dir = std::env::temp_dir().join(format!("watch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("shape.vm");
+        fs::write(&path, "x var-x\n").unwrap();
+
+        let mut seen = Vec::new();
+        watch(&path, |text| {
+            seen.push(text.to_string());
+            match seen.len() {
+                // replace it the way editors do, and also save it unchanged,
+                // which shouldn't be reported
+                1 => {
+                    let tmp = dir.join("shape.vm.tmp");
+                    fs::write(&tmp, "y var-y\n").unwrap();
+                    fs::rename(&tmp, &path).unwrap();
+                    fs::write(&path, "y var-y\n").unwrap();
+                    fs::write(dir.join("other.vm"), "z var-z\n").unwrap();
+                    fs::write(&path, "y var-y\nn neg y\n").unwrap();
+                    ControlFlow::Continue(())
+                }
+                _ => ControlFlow::Break(()),
+            }
+        })
+        .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        // the burst of writes settles into one change
+        assert_eq!(seen, ["x var-x\n", "y var-y\nn neg y\n"]);
+    }
+}